@@ -1,5 +1,10 @@
 from __future__ import annotations
 
+from collections.abc import Callable
+from typing import Literal, overload
+
+__version__: str
+
 class Block:
     @property
     def start_line(self) -> int: ...
@@ -25,16 +30,84 @@ class Fingerprint:
     def mtime(self) -> float: ...
     @property
     def blocks(self) -> list[Block] | None: ...
+    def content_id(self) -> str: ...
 
 class ChangedFiles:
     @property
     def modified(self) -> list[str]: ...
     @property
     def changed_blocks(self) -> dict[str, list[int]]: ...
+    @property
+    def missing_scope_paths(self) -> list[str]: ...
+    @property
+    def skipped_paths(self) -> list[str]: ...
+    @property
+    def encoding_mismatches(self) -> list[str]: ...
+    @property
+    def decisions(self) -> dict[str, str]: ...
+    @property
+    def baseline_empty(self) -> bool: ...
+    @property
+    def block_changes(self) -> list[tuple[str, str, str]]: ...
+    @property
+    def changed_block_types(self) -> dict[str, int]: ...
     def has_changes(self) -> bool: ...
     def total_changed_blocks(self) -> int: ...
+    def merge(self, other: ChangedFiles) -> ChangedFiles: ...
+
+class DetectionResult:
+    @property
+    def changes(self) -> ChangedFiles: ...
+    @property
+    def files_scanned(self) -> int: ...
+    @property
+    def baseline_count(self) -> int: ...
+    @property
+    def elapsed(self) -> float: ...
+
+class SaveResult:
+    @property
+    def count(self) -> int: ...
+    @property
+    def unstable(self) -> list[str]: ...
+
+class RenormalizeResult:
+    @property
+    def count(self) -> int: ...
+    @property
+    def skipped(self) -> list[str]: ...
+
+class SelectionResult:
+    @property
+    def selected(self) -> list[str]: ...
+    @property
+    def skipped(self) -> list[str]: ...
+    @property
+    def forced(self) -> list[str]: ...
+    @property
+    def unknown(self) -> list[str]: ...
+    def to_run(self) -> list[str]: ...
+
+class ChangedBlocks:
+    @property
+    def added(self) -> list[str]: ...
+    @property
+    def removed(self) -> list[str]: ...
+    @property
+    def modified(self) -> list[str]: ...
+    @property
+    def moved(self) -> list[str]: ...
+    def has_changes(self) -> bool: ...
 
 class TestExecution:
+    def __init__(
+        self,
+        test_name: str,
+        duration: float,
+        failed: bool,
+        forced: bool,
+        fingerprints: list[Fingerprint],
+    ) -> None: ...
     @property
     def test_name(self) -> str: ...
     @property
@@ -47,13 +120,27 @@ class TestExecution:
     def fingerprints(self) -> list[Fingerprint]: ...
 
 class ImportResult:
+    def __init__(self, baseline_count: int, test_execution_count: int) -> None: ...
     @property
     def baseline_count(self) -> int: ...
     @property
     def test_execution_count(self) -> int: ...
 
+class ValidationReport:
+    @property
+    def total(self) -> int: ...
+    @property
+    def fresh(self) -> int: ...
+    @property
+    def changed(self) -> int: ...
+    @property
+    def missing(self) -> int: ...
+    def fresh_ratio(self) -> float: ...
+
 class PytestDiffDatabase:
-    def __init__(self, db_path: str) -> None: ...
+    def __init__(
+        self, db_path: str, deferred: bool = False, cache_size: int | None = None
+    ) -> None: ...
     def save_test_execution(
         self,
         test_name: str,
@@ -61,15 +148,71 @@ class PytestDiffDatabase:
         duration: float,
         failed: bool,
         python_version: str = "3.12",
+        forced: bool = False,
+    ) -> None: ...
+    def save_test_executions(
+        self,
+        records: list[TestExecution],
+        python_version: str = "3.12",
+        batch_size: int = 500,
     ) -> None: ...
+    def get_test_execution(self, test_name: str) -> TestExecution | None: ...
     def get_affected_tests(self, changed_blocks: dict[str, list[int]]) -> list[str]: ...
+    def get_affected_tests_for(self, changed: ChangedFiles) -> list[str]: ...
+    def get_affected_test_counts(self, changed: ChangedFiles) -> dict[str, int]: ...
+    def get_tests_for_files(self, files: list[str]) -> list[str]: ...
+    def get_tests_for_checksum(self, filename: str, checksum: int) -> list[str]: ...
+    def estimate_selected_duration(
+        self, selected_tests: list[str], environment: int | None = None
+    ) -> tuple[float, int]: ...
+    def get_previous_failures(self, environment: int | None = None) -> list[str]: ...
     def get_recorded_tests(self) -> list[str]: ...
+    def prune_orphan_fingerprints(self) -> int: ...
+    def set_environment(self, name: str, python_version: str) -> int: ...
+    def list_environments(self) -> list[tuple[int, str, str]]: ...
+    def delete_environment(self, environment_id: int) -> bool: ...
     def get_fingerprint(self, filename: str) -> Fingerprint | None: ...
     def clear_cache(self) -> None: ...
+    def cache_stats(self) -> tuple[int, int, int]: ...
+    def refresh_cache_format(self) -> bool: ...
     def get_stats(self) -> dict[str, int]: ...
     def save_baseline_fingerprint(self, fingerprint: Fingerprint) -> None: ...
     def get_baseline_fingerprint(self, filename: str) -> Fingerprint | None: ...
-    def clear_baseline(self) -> None: ...
+    def get_parse_options(self) -> ParseOptions: ...
+    def get_normalization_mode(self) -> str: ...
+    def renormalize_baseline(self, project_root: str, mode: str) -> RenormalizeResult: ...
+    def get_block_granularity(self) -> str: ...
+    def set_block_granularity(self, mode: str) -> None: ...
+    def get_block_name_hashes(self) -> bool: ...
+    def set_block_name_hashes(self, enabled: bool) -> None: ...
+    def get_verify_reads(self) -> bool: ...
+    def set_verify_reads(self, enabled: bool) -> None: ...
+    def get_baseline_name_hashes(self, filename: str) -> list[tuple[int, int]] | None: ...
+    def get_max_function_lines(self) -> int | None: ...
+    def set_max_function_lines(self, max_lines: int | None) -> None: ...
+    def get_ignored_blocks(self) -> list[str]: ...
+    def set_ignored_blocks(self, patterns: list[str]) -> None: ...
+    def get_max_block_depth(self) -> int | None: ...
+    def set_max_block_depth(self, max_depth: int | None) -> None: ...
+    def get_lambda_blocks(self) -> bool: ...
+    def set_lambda_blocks(self, enabled: bool) -> None: ...
+    def get_test_history_depth(self) -> int | None: ...
+    def set_test_history_depth(self, max_history: int | None) -> None: ...
+    def get_writer_version(self) -> str | None: ...
+    def get_tracked_file_extensions(self) -> list[str]: ...
+    def set_tracked_file_extensions(self, extensions: list[str]) -> None: ...
+    def export_json(
+        self, baseline_name: str = "default", project_root: str | None = None
+    ) -> str: ...
+    def export_csv(self, dest: str) -> int: ...
+    def get_baseline_metadata(self, baseline_name: str = "default") -> dict[str, str]: ...
+    def recover_baseline(self, project_root: str, baseline_name: str = "default") -> int: ...
+    def validate_baseline(
+        self, project_root: str, baseline_name: str = "default"
+    ) -> ValidationReport: ...
+    def list_baseline_files(self, baseline_name: str = "default") -> list[str]: ...
+    def list_baselines(self) -> list[str]: ...
+    def clear_baseline(self, baseline_name: str = "default") -> None: ...
     def import_baseline_from(self, source_db_path: str) -> ImportResult: ...
     def merge_baseline_from(self, source_db_path: str) -> ImportResult: ...
     def get_external_metadata(self, source_db_path: str, key: str) -> str | None: ...
@@ -79,16 +222,121 @@ class PytestDiffDatabase:
     def get_file_dependents(self, filename: str) -> list[str]: ...
     def close(self) -> None: ...
 
+class ParseOptions:
+    def __init__(
+        self,
+        normalization: str = "exact",
+        granularity: str = "definitions",
+        max_function_lines: int | None = None,
+        ignored_blocks: list[str] | None = None,
+        max_block_depth: int | None = None,
+        lambda_blocks: bool = False,
+    ) -> None: ...
+    @property
+    def normalization(self) -> str: ...
+    @property
+    def granularity(self) -> str: ...
+    @property
+    def max_function_lines(self) -> int | None: ...
+    @property
+    def ignored_blocks(self) -> list[str]: ...
+    @property
+    def max_block_depth(self) -> int | None: ...
+    @property
+    def lambda_blocks(self) -> bool: ...
+
 class FingerprintCache:
-    def __init__(self, max_size: int | None = None) -> None: ...
+    def __init__(
+        self, max_size: int | None = None, options: ParseOptions | None = None
+    ) -> None: ...
     def get_or_calculate(self, path: str) -> Fingerprint: ...
+    def get_or_calculate_verified(self, path: str) -> Fingerprint: ...
+    def begin_phase(self) -> None: ...
+    def invalidate(self, path: str) -> bool: ...
     def clear(self) -> None: ...
     def stats(self) -> tuple[int, int, float]: ...
     def size(self) -> int: ...
     def max_size(self) -> int: ...
 
-def calculate_fingerprint(path: str, project_root: str | None = None) -> Fingerprint: ...
-def detect_changes(db_path: str, project_root: str, scope_paths: list[str]) -> ChangedFiles: ...
+def calculate_fingerprint(
+    path: str,
+    project_root: str | None = None,
+    options: ParseOptions | None = None,
+) -> Fingerprint: ...
+def fingerprint_source(
+    filename: str,
+    source: str,
+    mtime: float,
+    options: ParseOptions | None = None,
+) -> Fingerprint: ...
+@overload
+def detect_changes(
+    db_path: str,
+    project_root: str,
+    scope_paths: list[str],
+    test_classifier: Callable[[str], bool] | None = None,
+    baseline_name: str = "default",
+    explain: bool = False,
+    trust_mtime: bool = True,
+    with_metadata: Literal[False] = False,
+) -> ChangedFiles: ...
+@overload
+def detect_changes(
+    db_path: str,
+    project_root: str,
+    scope_paths: list[str],
+    test_classifier: Callable[[str], bool] | None = None,
+    baseline_name: str = "default",
+    explain: bool = False,
+    trust_mtime: bool = True,
+    *,
+    with_metadata: Literal[True],
+) -> DetectionResult: ...
+def compute_selection(
+    db_path: str,
+    project_root: str,
+    candidate_tests: list[str],
+    scope_paths: list[str],
+    baseline_name: str = "default",
+) -> SelectionResult: ...
+def detect_changes_from_snapshot(
+    snapshot_json: str,
+    project_root: str,
+    scope_paths: list[str],
+    test_classifier: Callable[[str], bool] | None = None,
+    explain: bool = False,
+    trust_mtime: bool = True,
+) -> ChangedFiles: ...
+def changed_files_only(
+    db_path: str,
+    project_root: str,
+    scope_paths: list[str],
+    baseline_name: str = "default",
+    trust_mtime: bool = True,
+) -> list[str]: ...
+def diff_trees(
+    old_root: str,
+    new_root: str,
+    scope_paths: list[str],
+    options: ParseOptions | None = None,
+    extensions: list[str] | None = None,
+) -> dict[str, ChangedBlocks]: ...
+def find_duplicate_blocks(
+    project_root: str,
+    scope_paths: list[str],
+    extensions: list[str] | None = None,
+) -> list[tuple[int, list[tuple[str, str]]]]: ...
+def find_uncovered_blocks(
+    db_path: str,
+    project_root: str,
+    scope_paths: list[str] | None = None,
+) -> tuple[list[tuple[str, str]], list[str]]: ...
+def hash_all_files(
+    project_root: str,
+    scope_paths: list[str],
+    extensions: list[str] | None = None,
+    since_mtime: float | None = None,
+) -> dict[str, str]: ...
 def process_coverage_data(
     coverage_data: dict[str, list[int]],
     project_root: str,
@@ -96,12 +344,63 @@ def process_coverage_data(
     verbose: bool,
     scope_paths: list[str],
     cache: FingerprintCache | None = None,
+    test_classifier: Callable[[str], bool] | None = None,
+    native_artifacts: list[str] = ...,
+    options: ParseOptions | None = None,
+    extensions: list[str] | None = None,
+    dependency_granularity: Literal["block", "file", "block_without_module"] = "block",
+    excluded_lines: dict[str, list[int]] | None = None,
+    setup_lines: dict[str, list[int]] | None = None,
 ) -> list[Fingerprint]: ...
+def explain_coverage_blocks(
+    coverage_data: dict[str, list[int]],
+    project_root: str,
+    options: ParseOptions | None = None,
+    dependency_granularity: Literal["block", "file", "block_without_module"] = "block",
+    excluded_lines: dict[str, list[int]] | None = None,
+    setup_lines: dict[str, list[int]] | None = None,
+) -> dict[str, list[tuple[str, int, int, list[int], bool]]]: ...
+@overload
 def save_baseline(
     db_path: str,
     project_root: str,
     verbose: bool,
     scope_paths: list[str],
     force: bool = False,
+    quiet: bool = False,
+    test_classifier: Callable[[str], bool] | None = None,
+    baseline_name: str = "default",
+    checkpoint: str = "truncate",
+    strict: bool = False,
+    trust_mtime: bool = False,
+    label: str | None = None,
+    progress_stream: Literal["auto", "stderr", "stdout", "none"] = "auto",
+    progress_interval: int = 50,
+    with_unstable: Literal[False] = False,
 ) -> int: ...
-def parse_module(source: str) -> list[Block]: ...
+@overload
+def save_baseline(
+    db_path: str,
+    project_root: str,
+    verbose: bool,
+    scope_paths: list[str],
+    force: bool = False,
+    quiet: bool = False,
+    test_classifier: Callable[[str], bool] | None = None,
+    baseline_name: str = "default",
+    checkpoint: str = "truncate",
+    strict: bool = False,
+    trust_mtime: bool = False,
+    label: str | None = None,
+    progress_stream: Literal["auto", "stderr", "stdout", "none"] = "auto",
+    progress_interval: int = 50,
+    *,
+    with_unstable: Literal[True],
+) -> SaveResult: ...
+def fork_baseline(base_db: str, temp_db: str) -> int: ...
+def update_baseline_file(
+    db_path: str, project_root: str, path: str, baseline_name: str = "default"
+) -> bool: ...
+def parse_module(source: str, options: ParseOptions | None = None) -> list[Block]: ...
+def parse_snippet(source: str) -> list[Block]: ...
+def block_name_hash(name: str) -> int: ...
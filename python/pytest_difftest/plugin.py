@@ -116,11 +116,16 @@ class PytestDiffPlugin:
         self.fp_cache: _core.FingerprintCache | None = (
             None  # Fingerprint cache for avoiding re-parsing
         )
+        self.parse_options: _core.ParseOptions | None = None  # Parse settings of the DB
+        self.tracked_extensions = [".py"]  # File extensions tracked by the DB
         self.deselected_items: list[Any] = []
         self._early_diff_data: dict[str, Any] | None = None
         self.current_test: str | None = None
         self.test_start_time: float | None = None
         self.test_files_executed: list[str] = []
+        # Lines executed during the current test's setup phase, when split out
+        self.exclude_setup_coverage: bool = config.getini("diff_exclude_setup_coverage")
+        self.setup_coverage: dict[str, list[int]] = {}
 
         # Get Python version for environment tracking
         self.python_version: str = (
@@ -134,6 +139,12 @@ class PytestDiffPlugin:
         # Cache size for fingerprints (configurable for large codebases)
         self.cache_max_size: int = get_config_value(config, "cache-size", "cache_size", 100_000)
 
+        # Where and how often the baseline save reports its progress
+        self.progress_stream: str = config.getini("diff_progress_stream") or "auto"
+        self.progress_interval: int = get_config_value(
+            config, "progress-interval", "progress_interval", 50
+        )
+
         # pytest's test file patterns (e.g. ["test_*.py", "*_test.py"])
         self._python_files: list[str] = config.getini("python_files")
 
@@ -154,6 +165,26 @@ class PytestDiffPlugin:
         filename = rel_path.replace("\\", "/").rsplit("/", 1)[-1]
         return any(fnmatch(filename, pat) for pat in self._python_files)
 
+    def _detect_changes(self, config: pytest.Config) -> _core.ChangedFiles:
+        """Run detect_changes against the baseline and log what it reports."""
+        result = _core.detect_changes(
+            str(self.db_path), str(get_rootdir(config)), self.scope_paths, with_metadata=True
+        )
+        changed = result.changes
+        for path in changed.missing_scope_paths:
+            logger.warning("⚠ pytest-difftest: Scope path does not exist: %s", path)
+        for path in changed.skipped_paths:
+            logger.warning("⚠ pytest-difftest: Skipped vanished or unreadable path: %s", path)
+        for path in changed.encoding_mismatches:
+            logger.warning("⚠ pytest-difftest: Encoding mismatch, not tracked: %s", path)
+        logger.debug(
+            "Scanned %s files against %s baseline files in %.3fs",
+            result.files_scanned,
+            result.baseline_count,
+            result.elapsed,
+        )
+        return changed
+
     def _run_early_diff_analysis(self, config: pytest.Config) -> None:
         """Run detect_changes + get_affected_tests + get_recorded_tests early.
 
@@ -165,9 +196,14 @@ class PytestDiffPlugin:
 
         try:
             start = time.time()
-            changed = _core.detect_changes(
-                str(self.db_path), str(get_rootdir(config)), self.scope_paths
-            )
+            changed = self._detect_changes(config)
+            writer_version = self.db.get_writer_version()
+            if writer_version is not None and writer_version != _core.__version__:
+                logger.warning(
+                    "⚠ pytest-difftest: Baseline was written by core %s, running %s",
+                    writer_version,
+                    _core.__version__,
+                )
             recorded_tests = set(self.db.get_recorded_tests())
             known_test_files: set[str] = {nid.split("::")[0] for nid in recorded_tests}
 
@@ -185,7 +221,7 @@ class PytestDiffPlugin:
 
             affected_test_files: set[str] = set()
             if changed.has_changes():
-                affected_tests = set(self.db.get_affected_tests(changed.changed_blocks))
+                affected_tests = set(self.db.get_affected_tests_for(changed))
                 affected_test_files = {nid.split("::")[0] for nid in affected_tests}
                 # Include modified test files themselves (may contain new tests)
                 affected_test_files |= {f for f in changed.modified if self._is_test_file(f)}
@@ -224,8 +260,11 @@ class PytestDiffPlugin:
         batch_len = len(self.test_execution_batch)
         logger.debug("pytest-difftest: Saving %s test executions to DB...", batch_len)
         flush_start = time.time()
-        for nodeid, fingerprints, duration, failed in self.test_execution_batch:
-            self.db.save_test_execution(nodeid, fingerprints, duration, failed, self.python_version)
+        records = [
+            _core.TestExecution(nodeid, duration, failed, False, fingerprints)
+            for nodeid, fingerprints, duration, failed in self.test_execution_batch
+        ]
+        self.db.save_test_executions(records, self.python_version)
         elapsed = time.time() - flush_start
         logger.debug("pytest-difftest: Saved %s test executions to DB in %.3fs", batch_len, elapsed)
         self.test_execution_batch = []
@@ -270,7 +309,9 @@ class PytestDiffPlugin:
 
         # Initialize fingerprint cache
         cache_start = time.time()
-        self.fp_cache = _core.FingerprintCache(self.cache_max_size)
+        self.parse_options = self.db.get_parse_options()
+        self.tracked_extensions = self.db.get_tracked_file_extensions()
+        self.fp_cache = _core.FingerprintCache(self.cache_max_size, self.parse_options)
         logger.debug(
             "Worker fingerprint cache initialized (max_size=%s) in %.3fs",
             self.cache_max_size,
@@ -338,7 +379,9 @@ class PytestDiffPlugin:
 
         # Initialize fingerprint cache with configurable size
         cache_start = time.time()
-        self.fp_cache = _core.FingerprintCache(self.cache_max_size)
+        self.parse_options = self.db.get_parse_options()
+        self.tracked_extensions = self.db.get_tracked_file_extensions()
+        self.fp_cache = _core.FingerprintCache(self.cache_max_size, self.parse_options)
         logger.debug(
             "Fingerprint cache initialized (max_size=%s) in %.3fs",
             self.cache_max_size,
@@ -467,7 +510,7 @@ class PytestDiffPlugin:
                             "\n✓ pytest-difftest: Incremental baseline — %s modified files",
                             len(changed.modified),
                         )
-                        affected_tests = set(self.db.get_affected_tests(changed.changed_blocks))
+                        affected_tests = set(self.db.get_affected_tests_for(changed))
                         affected_tests |= unrecorded_tests
                         if affected_tests:
                             selected = [item for item in items if item.nodeid in affected_tests]
@@ -523,14 +566,19 @@ class PytestDiffPlugin:
                 changed = self._early_diff_data["changed"]
                 recorded_tests = self._early_diff_data["recorded_tests"]
             else:
-                changed = _core.detect_changes(
-                    str(self.db_path), str(get_rootdir(config)), self.scope_paths
-                )
+                changed = self._detect_changes(config)
                 assert self.db is not None
                 recorded_tests = set(self.db.get_recorded_tests())
 
             assert self.db is not None
 
+            if changed.baseline_empty:
+                logger.warning(
+                    "\n⚠ pytest-difftest: No baseline found — running all tests"
+                    " (record one with --diff-baseline)"
+                )
+                return
+
             # Find tests with no recorded execution (e.g. previously failed)
             unrecorded_tests = {item.nodeid for item in items if item.nodeid not in recorded_tests}
             if unrecorded_tests:
@@ -543,7 +591,7 @@ class PytestDiffPlugin:
                 logger.info("  Changed blocks in %s files", len(changed.changed_blocks))
 
                 # Get affected tests from database
-                affected_tests = set(self.db.get_affected_tests(changed.changed_blocks))
+                affected_tests = set(self.db.get_affected_tests_for(changed))
 
                 # Also select tests living in modified files (new test files)
                 # changed.modified contains relative paths; resolve them against rootdir
@@ -614,6 +662,7 @@ class PytestDiffPlugin:
         self.current_test = item.nodeid
         self.test_start_time = time.time()
         self.test_files_executed = []
+        self.setup_coverage = {}
 
         # Start coverage collection (only in baseline mode; --diff mode has self.cov=None)
         if self.cov:
@@ -621,6 +670,20 @@ class PytestDiffPlugin:
                 logger.debug("Starting coverage for %s", item.nodeid)
             self.cov.start()
 
+    def _coverage_map(self, data: Any) -> dict[str, list[int]]:
+        """Executed lines of each tracked project file in coverage ``data``."""
+        tracked = tuple(self.tracked_extensions)
+        rootdir = str(get_rootdir(self.config))
+        coverage_map: dict[str, list[int]] = {}
+        for filename in data.measured_files():
+            filepath = Path(filename)
+            if str(filepath).endswith(tracked) and str(filepath).startswith(rootdir):
+                executed = data.lines(filename)
+                if executed is None:
+                    continue
+                coverage_map[str(filepath.resolve())] = list(executed)
+        return coverage_map
+
     def pytest_runtest_makereport(self, item: Any, call: Any) -> None:
         """Capture test result and save to database"""
         if not self.enabled:
@@ -646,7 +709,11 @@ class PytestDiffPlugin:
             test_file = Path(item.fspath).resolve()
             if test_file.exists() and test_file.suffix == ".py":
                 try:
-                    fp = _core.calculate_fingerprint(str(test_file), str(get_rootdir(self.config)))
+                    fp = _core.calculate_fingerprint(
+                        str(test_file),
+                        str(get_rootdir(self.config)),
+                        self.parse_options,
+                    )
                     self.test_execution_batch.append((item.nodeid, [fp], 0.0, False))
                     if len(self.test_execution_batch) >= self.batch_size:
                         self._flush_test_batch()
@@ -654,6 +721,12 @@ class PytestDiffPlugin:
                     pass
             return
 
+        # Remember what fixture setup executed, so the test body's own
+        # dependencies can be told apart from it
+        if call.when == "setup" and self.cov and self.exclude_setup_coverage:
+            self.setup_coverage = self._coverage_map(self.cov.get_data())
+            return
+
         # Only save after test execution (not setup/teardown)
         if call.when != "call":
             return
@@ -684,26 +757,17 @@ class PytestDiffPlugin:
                 test_file = Path(item.fspath).resolve()
                 test_file_str = str(test_file)
 
-                # Extract coverage data as dict: filename -> list of executed lines
+                # Extract coverage data as dict: filename -> executed lines
                 extract_start = time.time()
-                coverage_map: dict[str, list[int]] = {}
-                for filename in measured:
-                    filepath = Path(filename)
-                    if filepath.suffix == ".py" and str(filepath).startswith(
-                        str(get_rootdir(self.config))
-                    ):
-                        abs_path = str(filepath.resolve())
-                        lines = data.lines(filename)
-                        if lines is None:
-                            continue
-                        executed_lines = list(lines)
-                        coverage_map[abs_path] = executed_lines
+                coverage_map = self._coverage_map(data)
                 logger.debug(
                     "Extracted coverage for %s files in %.3fs",
                     len(coverage_map),
                     time.time() - extract_start,
                 )
 
+                if self.fp_cache is not None:
+                    self.fp_cache.begin_phase()
                 try:
                     process_start = time.time()
                     fingerprints = _core.process_coverage_data(
@@ -713,6 +777,8 @@ class PytestDiffPlugin:
                         self.config.option.verbose >= 2 or self.verbose,
                         self.scope_paths,
                         self.fp_cache,
+                        extensions=self.tracked_extensions,
+                        setup_lines=self.setup_coverage or None,
                     )
                     logger.debug(
                         "Rust processing took %.3fs, got %s fingerprints",
@@ -801,21 +867,31 @@ class PytestDiffPlugin:
                 )
                 logger.debug("pytest-difftest: Saving baseline fingerprints...%s", upload_msg)
                 start = time.time()
-                count = _core.save_baseline(
+                result = _core.save_baseline(
                     str(self.db_path),
                     str(get_rootdir(self.config)),
                     self.verbose,
                     self.scope_paths,
                     self.force,
+                    progress_stream=self.progress_stream,
+                    progress_interval=self.progress_interval,
+                    with_unstable=True,
                 )
                 elapsed = time.time() - start
                 logger.debug("Baseline save completed in %.3fs", elapsed)
                 db_size = self._format_size(self.db_path.stat().st_size)
                 terminalreporter.write_sep(
                     "=",
-                    f"pytest-difftest: Baseline saved for {count} files in {elapsed:.1f}s ({db_size})",
+                    f"pytest-difftest: Baseline saved for {result.count} files in {elapsed:.1f}s ({db_size})",
                     green=True,
                 )
+                if result.unstable:
+                    terminalreporter.write_line(
+                        f"pytest-difftest: {len(result.unstable)} files changed while being "
+                        "fingerprinted and kept their previous baseline: "
+                        + ", ".join(result.unstable),
+                        yellow=True,
+                    )
 
                 # Store git commit SHA in metadata for staleness detection
                 sha = get_git_commit_sha(str(get_rootdir(self.config)))
@@ -950,12 +1026,32 @@ def pytest_addoption(parser: pytest.Parser) -> None:
         default="100000",
         help="Maximum fingerprints to cache in memory",
     )
+    parser.addini(
+        "diff_progress_stream",
+        type="string",
+        default="auto",
+        help="Where the baseline save reports progress: auto (stderr with --diff-v), "
+        "stderr, stdout or none",
+    )
+    parser.addini(
+        "diff_progress_interval",
+        type="string",
+        default="50",
+        help="Files fingerprinted between two progress lines of the baseline save",
+    )
     parser.addini(
         "diff_remote_url",
         type="string",
         default="",
         help="Remote storage URL for a single baseline DB file (e.g. s3://bucket/baseline.db)",
     )
+    parser.addini(
+        "diff_exclude_setup_coverage",
+        type="bool",
+        default=False,
+        help="Only count module- and class-level lines executed by the test body, not by "
+        "fixture setup (imports done by fixtures then don't become dependencies)",
+    )
     parser.addini(
         "diff_remote_key",
         type="string",
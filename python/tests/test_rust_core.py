@@ -32,6 +32,12 @@ def test_calculate_fingerprint(tmp_path):
     assert fp.blocks is not None and len(fp.blocks) > 0
 
 
+def test_fingerprint_repr_short_hash():
+    """repr() truncates to the available hash length instead of panicking."""
+    fp = _core.Fingerprint("a.py", [1], "abc", 0.0)
+    assert repr(fp) == "Fingerprint(file='a.py', blocks=1, hash=abc..)"
+
+
 def test_fingerprint_cache_hit_miss(tmp_path):
     """Cache reports 0 hits/1 miss on first call, 1 hit on second."""
     f = tmp_path / "cached.py"
@@ -65,6 +71,31 @@ def test_fingerprint_cache_eviction(tmp_path):
     assert cache.max_size() == 2
 
 
+def test_fingerprint_cache_invalidate(tmp_path):
+    """invalidate() drops one entry; the other survives and still hits."""
+    a = tmp_path / "a.py"
+    b = tmp_path / "b.py"
+    a.write_text("x = 1\n")
+    b.write_text("y = 2\n")
+
+    cache = _core.FingerprintCache(100)
+    cache.get_or_calculate(str(a))
+    cache.get_or_calculate(str(b))
+
+    assert cache.invalidate(str(a)) is True
+    assert cache.invalidate(str(a)) is False
+    assert cache.size() == 1
+
+    cache.get_or_calculate(str(b))
+    hits, misses, _ = cache.stats()
+    assert (hits, misses) == (1, 2)
+
+    # The invalidated file is recomputed
+    cache.get_or_calculate(str(a))
+    hits, misses, _ = cache.stats()
+    assert (hits, misses) == (1, 3)
+
+
 def test_database_stats_empty(tmp_path):
     """New DB has test_count=0, file_count=0, baseline_count=0."""
     db_path = tmp_path / "test.db"
@@ -75,6 +106,39 @@ def test_database_stats_empty(tmp_path):
     assert stats["baseline_count"] == 0
 
 
+def test_database_parse_options(tmp_path):
+    """get_parse_options returns the DB's settings, ready to pass to parse functions."""
+    db = _core.PytestDiffDatabase(str(tmp_path / "test.db"))
+    assert db.get_parse_options() == _core.ParseOptions()
+
+    db.set_lambda_blocks(True)
+    db.set_ignored_blocks(["__version__"])
+    options = db.get_parse_options()
+    assert options == _core.ParseOptions(lambda_blocks=True, ignored_blocks=["__version__"])
+    assert options.normalization == "exact"
+    assert options.ignored_blocks == ["__version__"]
+
+    blocks = _core.parse_module("handler = lambda x: x\n", options)
+    assert "handler" in [b.name for b in blocks]
+
+
+def test_test_execution_round_trip_forced(tmp_path):
+    """get_test_execution returns the saved record, including the forced flag."""
+    db = _core.PytestDiffDatabase(str(tmp_path / "test.db"))
+    f = tmp_path / "module.py"
+    f.write_text("def hello():\n    return 'world'\n")
+    fp = _core.calculate_fingerprint(str(f))
+
+    db.save_test_execution("test_hello", [fp], 0.5, True, forced=True)
+
+    execution = db.get_test_execution("test_hello")
+    assert execution is not None
+    assert (execution.test_name, execution.duration) == ("test_hello", 0.5)
+    assert execution.failed and execution.forced
+    assert len(execution.fingerprints) == 1
+    assert db.get_test_execution("test_missing") is None
+
+
 def test_detect_changes_no_baseline(tmp_path):
     """Files with no baseline are detected as new/changed."""
     db_path = tmp_path / "test.db"
@@ -88,6 +152,7 @@ def test_detect_changes_no_baseline(tmp_path):
     # New files (no baseline) should be detected as changed
     assert changes.has_changes()
     assert len(changes.modified) == 1
+    assert changes.baseline_empty
 
 
 def test_import_baseline_returns_import_result(tmp_path):
@@ -174,6 +239,49 @@ def test_save_baseline_stores_relative_paths(tmp_path):
     assert fp.filename == "src/module.py"
 
 
+def test_save_baseline_quiet_writes_nothing_to_stderr(tmp_path, capfd):
+    """quiet=True suppresses all stderr output even when verbose=True."""
+    db_path = tmp_path / "test.db"
+    (tmp_path / "module.py").write_text("def foo(): pass\n")
+
+    count = _core.save_baseline(str(db_path), str(tmp_path), True, [], quiet=True)
+
+    assert count == 1
+    assert capfd.readouterr().err == ""
+
+
+def test_save_baseline_with_unstable_returns_save_result(tmp_path):
+    """with_unstable=True returns a SaveResult instead of the bare count."""
+    db_path = tmp_path / "test.db"
+    (tmp_path / "module.py").write_text("def foo(): pass\n")
+
+    result = _core.save_baseline(str(db_path), str(tmp_path), False, [], with_unstable=True)
+
+    assert result.count == 1
+    assert result.unstable == []
+
+
+def test_detect_changes_custom_test_classifier(tmp_path):
+    """A test_classifier callable replaces the built-in test/source heuristics."""
+    db_path = tmp_path / "test.db"
+    integration = tmp_path / "integration"
+    integration.mkdir()
+    (integration / "runner.py").write_text("x = 1\n")
+    (integration / "data.py").write_text("y = 2\n")
+
+    def is_test(path: str) -> bool:
+        return "/integration/" in path and not path.endswith("data.py")
+
+    # Empty baseline: every discovered file is reported as new
+    changes = _core.detect_changes(
+        str(db_path), str(tmp_path), [str(tmp_path / "unit")], test_classifier=is_test
+    )
+
+    # runner.py is a test outside the scope; data.py is source and always kept
+    assert "integration/data.py" in changes.modified
+    assert "integration/runner.py" not in changes.modified
+
+
 def test_import_copies_test_execution_coverage(tmp_path):
     """Imported test execution data enables get_affected_tests."""
     source_path = tmp_path / "source.db"
@@ -194,3 +302,27 @@ def test_import_copies_test_execution_coverage(tmp_path):
     # get_affected_tests should find the imported test
     affected = target_db.get_affected_tests({fp.filename: list(fp.checksums)})
     assert "test_hello" in affected
+
+
+def test_public_database_types():
+    """The database classes are exported under their public names."""
+    assert _core.PytestDiffDatabase.__name__ == "PytestDiffDatabase"
+    assert _core.ImportResult.__name__ == "ImportResult"
+
+    result = _core.ImportResult(3, 5)
+    assert (result.baseline_count, result.test_execution_count) == (3, 5)
+
+
+def test_import_returns_import_result(tmp_path):
+    """import_baseline_from reports its counts as an ImportResult."""
+    source_path = tmp_path / "source.db"
+    source_db = _core.PytestDiffDatabase(str(source_path))
+    f = tmp_path / "module.py"
+    f.write_text("x = 1\n")
+    source_db.save_baseline_fingerprint(_core.calculate_fingerprint(str(f)))
+    source_db.close()
+
+    target_db = _core.PytestDiffDatabase(str(tmp_path / "target.db"))
+    result = target_db.import_baseline_from(str(source_path))
+    assert isinstance(result, _core.ImportResult)
+    assert result.baseline_count == 1
@@ -0,0 +1,723 @@
+// Analysis tools built on fingerprinting
+//
+// This module handles:
+// - Diffing the blocks of two directory trees
+// - Finding duplicated and never-executed blocks
+// - Previewing which tests a run would select
+
+use anyhow::Result;
+use pyo3::prelude::*;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use crate::database::{normalize_filename, PytestDiffDatabase, DEFAULT_BASELINE, PYTHON_EXTENSION};
+use crate::fingerprint::{
+    calculate_fingerprint_internal, detect_changes_internal, find_python_files, make_relative,
+};
+use crate::parser::ParseOptions;
+use crate::types::{Block, ChangedBlocks, Fingerprint, SelectionResult};
+
+/// Block-level diff of two directory trees, without a database
+///
+/// Fingerprints both trees in parallel and compares each file's blocks,
+/// matched by qualified name (see `ChangedBlocks`). For reviewing refactors
+/// between two copies of a project.
+///
+/// # Arguments
+/// * `old_root`, `new_root` - Roots of the trees before and after
+/// * `scope_paths` - Paths to compare in both trees, as for `detect_changes`
+/// * `options` - Parse settings, as for `calculate_fingerprint`
+/// * `extensions` - File extensions to compare; defaults to `[".py"]`
+///
+/// # Returns
+/// * Map of relative filename -> its changed blocks, for files that differ.
+///   A file in only one tree has all its blocks added (or removed); files
+///   that fail to parse are left out.
+#[pyfunction]
+#[pyo3(signature = (old_root, new_root, scope_paths, options=None, extensions=None))]
+pub fn diff_trees(
+    old_root: &str,
+    new_root: &str,
+    scope_paths: Vec<String>,
+    options: Option<ParseOptions>,
+    extensions: Option<Vec<String>>,
+) -> PyResult<HashMap<String, ChangedBlocks>> {
+    let options = options.unwrap_or_default();
+    let extensions = extensions.unwrap_or_else(|| vec![PYTHON_EXTENSION.to_string()]);
+    diff_trees_internal(old_root, new_root, &scope_paths, options, &extensions).map_err(|e| {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to diff trees: {}", e))
+    })
+}
+
+fn diff_trees_internal(
+    old_root: &str,
+    new_root: &str,
+    scope_paths: &[String],
+    options: ParseOptions,
+    extensions: &[String],
+) -> Result<HashMap<String, ChangedBlocks>> {
+    let fingerprint_tree = |root: &str| -> Result<HashMap<String, Fingerprint>> {
+        let files = find_python_files(root, scope_paths, None, extensions, None)?.files;
+        Ok(files
+            .par_iter()
+            .filter_map(|path| {
+                let path = path.to_string_lossy();
+                let fp = calculate_fingerprint_internal(&path, options.clone()).ok()?;
+                Some((make_relative(&path, root), fp))
+            })
+            .collect())
+    };
+    let (old, new) = rayon::join(|| fingerprint_tree(old_root), || fingerprint_tree(new_root));
+    let (old, new) = (old?, new?);
+
+    let filenames: HashSet<&String> = old.keys().chain(new.keys()).collect();
+    let mut diffs = HashMap::new();
+    for filename in filenames {
+        let (old_fp, new_fp) = (old.get(filename), new.get(filename));
+        if let (Some(a), Some(b)) = (old_fp, new_fp) {
+            if a.file_hash == b.file_hash {
+                continue;
+            }
+        }
+        let blocks =
+            |fp: Option<&Fingerprint>| fp.and_then(|fp| fp.blocks.clone()).unwrap_or_default();
+        let diff = diff_blocks(&blocks(old_fp), &blocks(new_fp));
+        if diff.has_changes() {
+            diffs.insert(filename.clone(), diff);
+        }
+    }
+    Ok(diffs)
+}
+
+/// Block checksum with the `(filename, qualified name)` of each block having it
+type DuplicateBlock = (i32, Vec<(String, String)>);
+
+/// Blocks whose checksum appears in more than one place, as refactoring hints
+///
+/// Fingerprints the tracked files under `project_root` in parallel (default
+/// parse settings) and groups their blocks by checksum. A block's checksum
+/// covers its `def`/`class` line, so only copies that kept their name match.
+/// Module blocks are left out: empty `__init__.py` files would all match.
+///
+/// # Arguments
+/// * `project_root` - Root directory of the project
+/// * `scope_paths` - Paths to scan, as for `detect_changes`
+/// * `extensions` - File extensions to scan; defaults to `[".py"]`
+///
+/// # Returns
+/// * `(checksum, locations)` per duplicated block, each location being a
+///   relative filename and qualified block name. Locations are sorted, and
+///   groups ordered by their first location.
+#[pyfunction]
+#[pyo3(signature = (project_root, scope_paths, extensions=None))]
+pub fn find_duplicate_blocks(
+    project_root: &str,
+    scope_paths: Vec<String>,
+    extensions: Option<Vec<String>>,
+) -> PyResult<Vec<DuplicateBlock>> {
+    let extensions = extensions.unwrap_or_else(|| vec![PYTHON_EXTENSION.to_string()]);
+    find_duplicate_blocks_internal(project_root, &scope_paths, &extensions).map_err(|e| {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to find duplicate blocks: {}", e))
+    })
+}
+
+fn find_duplicate_blocks_internal(
+    project_root: &str,
+    scope_paths: &[String],
+    extensions: &[String],
+) -> Result<Vec<DuplicateBlock>> {
+    let files = find_python_files(project_root, scope_paths, None, extensions, None)?.files;
+    let located: Vec<(i32, String, String)> = files
+        .par_iter()
+        .flat_map_iter(|path| {
+            let path = path.to_string_lossy();
+            let filename = make_relative(&path, project_root);
+            let blocks = calculate_fingerprint_internal(&path, ParseOptions::default())
+                .ok()
+                .and_then(|fp| fp.blocks)
+                .unwrap_or_default();
+            let names = qualified_block_names(&blocks);
+            blocks
+                .into_iter()
+                .zip(names)
+                .filter(|(block, _)| block.block_type != "module")
+                .map(|(block, (_, name))| (block.checksum, filename.clone(), name))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut groups: HashMap<i32, Vec<(String, String)>> = HashMap::new();
+    for (checksum, filename, name) in located {
+        groups.entry(checksum).or_default().push((filename, name));
+    }
+    let mut duplicates: Vec<DuplicateBlock> = groups
+        .into_iter()
+        .filter(|(_, locations)| locations.len() > 1)
+        .map(|(checksum, mut locations)| {
+            locations.sort();
+            (checksum, locations)
+        })
+        .collect();
+    duplicates.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(duplicates)
+}
+
+/// Blocks no recorded test execution depends on, as dead-code hints
+///
+/// Fingerprints the tracked files under `project_root` with the database's
+/// parse settings and leaves out every block whose checksum a test recorded
+/// for that version of the file. A file no test ever imported reports all its
+/// blocks, `<module>` included.
+///
+/// Coverage is only known for file versions a test actually ran against: a
+/// file edited since its last recorded execution would report every edited
+/// block as dead code, so it's skipped and returned separately instead. Rerun
+/// its tests (or `--diff-force`) to get its blocks checked again.
+///
+/// # Arguments
+/// * `db_path` - Path to the pytest-difftest database
+/// * `project_root` - Root directory of the project
+/// * `scope_paths` - Paths to scan, as for `detect_changes` (default: everything)
+///
+/// # Returns
+/// * `(blocks, changed)`: `(filename, qualified block name)` per uncovered
+///   block, sorted by file and then in source order, and the sorted files
+///   skipped because they changed since their recorded executions
+#[pyfunction]
+#[pyo3(signature = (db_path, project_root, scope_paths=None))]
+pub fn find_uncovered_blocks(
+    db_path: &str,
+    project_root: &str,
+    scope_paths: Option<Vec<String>>,
+) -> PyResult<UncoveredBlocks> {
+    find_uncovered_blocks_internal(db_path, project_root, &scope_paths.unwrap_or_default()).map_err(
+        |e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to find uncovered blocks: {}",
+                e
+            ))
+        },
+    )
+}
+
+/// Uncovered `(filename, block name)` pairs, and the files skipped as changed
+type UncoveredBlocks = (Vec<(String, String)>, Vec<String>);
+
+/// Per-file outcome of `find_uncovered_blocks`
+enum FileCoverage {
+    Uncovered(Vec<(String, String)>),
+    Changed,
+}
+
+fn find_uncovered_blocks_internal(
+    db_path: &str,
+    project_root: &str,
+    scope_paths: &[String],
+) -> Result<UncoveredBlocks> {
+    let db = PytestDiffDatabase::open(db_path)?;
+    let options = db.get_parse_options_internal()?;
+    let executed = db.get_executed_checksums_internal()?;
+    let files = find_python_files(
+        project_root,
+        scope_paths,
+        None,
+        &db.get_tracked_extensions()?,
+        None,
+    )?
+    .files;
+
+    let mut results: Vec<(String, FileCoverage)> = files
+        .par_iter()
+        .filter_map(|path| {
+            let path = path.to_string_lossy();
+            let filename = make_relative(&path, project_root);
+            // Native modules and unparsable files have no blocks to report
+            let fingerprint = calculate_fingerprint_internal(&path, options.clone()).ok()?;
+            let blocks = fingerprint.blocks?;
+            let covered = match executed.get(&filename) {
+                Some(versions) => match versions.get(&fingerprint.file_hash) {
+                    Some(checksums) => Some(checksums),
+                    None => return Some((filename, FileCoverage::Changed)),
+                },
+                None => None,
+            };
+            let names = qualified_block_names(&blocks);
+            let blocks: Vec<(String, String)> = blocks
+                .iter()
+                .zip(names)
+                .filter(|(block, _)| !covered.is_some_and(|c| c.contains(&block.checksum)))
+                .map(|(_, (_, name))| (filename.clone(), name))
+                .collect();
+            (!blocks.is_empty()).then_some((filename, FileCoverage::Uncovered(blocks)))
+        })
+        .collect();
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut uncovered = Vec::new();
+    let mut changed = Vec::new();
+    for (filename, coverage) in results {
+        match coverage {
+            FileCoverage::Uncovered(blocks) => uncovered.extend(blocks),
+            FileCoverage::Changed => changed.push(filename),
+        }
+    }
+    Ok((uncovered, changed))
+}
+
+/// Name blocks (in source order) by their enclosing definitions
+///
+/// Returns `(parent, qualified name)` per block, where `parent` is the
+/// qualified name of the enclosing definition ("" at top level). Branch and
+/// part blocks already carry their function's name, so they're qualified by
+/// the function's parent instead, but grouped under the function.
+fn qualified_block_names(blocks: &[Block]) -> Vec<(String, String)> {
+    let qualify = |parent: &str, name: &str| {
+        if parent.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", parent, name)
+        }
+    };
+    // Enclosing definitions: (end line, qualified name), innermost last
+    let mut open: Vec<(usize, String)> = Vec::new();
+    let mut names = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        if block.block_type == "module" {
+            names.push((String::new(), block.name.clone()));
+            continue;
+        }
+        while open.last().is_some_and(|(end, _)| *end < block.start_line) {
+            open.pop();
+        }
+        let parent = open
+            .last()
+            .map(|(_, name)| name.clone())
+            .unwrap_or_default();
+        let qualified = match block.block_type.as_str() {
+            "branch" | "part" => {
+                let prefix = parent.rsplit_once('.').map_or("", |(prefix, _)| prefix);
+                qualify(prefix, &block.name)
+            }
+            _ => qualify(&parent, &block.name),
+        };
+        if matches!(
+            block.block_type.as_str(),
+            "class" | "function" | "async_function"
+        ) {
+            open.push((block.end_line, qualified.clone()));
+        }
+        names.push((parent, qualified));
+    }
+    names
+}
+
+/// Compare the blocks of one file before and after
+///
+/// Blocks sharing a qualified name are paired in source order. A paired block
+/// with an unchanged checksum counts as moved when it left the longest run of
+/// its siblings that kept their relative order.
+fn diff_blocks(old: &[Block], new: &[Block]) -> ChangedBlocks {
+    let old_names = qualified_block_names(old);
+    let new_names = qualified_block_names(new);
+
+    let mut unpaired: HashMap<&str, std::collections::VecDeque<usize>> = HashMap::new();
+    for (i, (_, name)) in old_names.iter().enumerate() {
+        unpaired.entry(name).or_default().push_back(i);
+    }
+
+    let mut diff = ChangedBlocks::default();
+    // Old positions of the paired blocks, per parent, in new source order
+    let mut siblings: HashMap<&str, Vec<(usize, usize)>> = HashMap::new();
+    for (j, (parent, name)) in new_names.iter().enumerate() {
+        match unpaired
+            .get_mut(name.as_str())
+            .and_then(|queue| queue.pop_front())
+        {
+            Some(i) => siblings.entry(parent).or_default().push((i, j)),
+            None => diff.added.push(name.clone()),
+        }
+    }
+    let mut removed: Vec<usize> = unpaired.into_values().flatten().collect();
+    removed.sort_unstable();
+    diff.removed = removed
+        .into_iter()
+        .map(|i| old_names[i].1.clone())
+        .collect();
+
+    let mut modified = Vec::new();
+    let mut moved = Vec::new();
+    for pairs in siblings.values() {
+        let in_order = longest_increasing_run(pairs);
+        for (k, &(i, j)) in pairs.iter().enumerate() {
+            if old[i].checksum != new[j].checksum {
+                modified.push(j);
+            } else if !in_order[k] {
+                moved.push(j);
+            }
+        }
+    }
+    modified.sort_unstable();
+    moved.sort_unstable();
+    diff.modified = modified
+        .into_iter()
+        .map(|j| new_names[j].1.clone())
+        .collect();
+    diff.moved = moved.into_iter().map(|j| new_names[j].1.clone()).collect();
+    diff
+}
+
+/// Which pairs belong to a longest subsequence with increasing old positions
+fn longest_increasing_run(pairs: &[(usize, usize)]) -> Vec<bool> {
+    let n = pairs.len();
+    // length[k]: longest increasing run ending at k; previous[k]: its predecessor
+    let mut length = vec![1; n];
+    let mut previous = vec![None; n];
+    for k in 0..n {
+        for p in 0..k {
+            if pairs[p].0 < pairs[k].0 && length[p] + 1 > length[k] {
+                length[k] = length[p] + 1;
+                previous[k] = Some(p);
+            }
+        }
+    }
+    let mut in_run = vec![false; n];
+    // On ties, keep the run ending latest: of two swapped blocks, the one
+    // moving up is reported
+    let mut current = (0..n).max_by_key(|&k| (length[k], k));
+    while let Some(k) = current {
+        in_run[k] = true;
+        current = previous[k];
+    }
+    in_run
+}
+
+/// Compute which of the candidate tests a run would select, without running them
+///
+/// Runs `detect_changes` and affected-test resolution exactly as the plugin
+/// does and partitions the candidates: tests without a recorded execution are
+/// `unknown`, recorded tests depending on a changed block are `selected`, tests
+/// living in a modified file are `forced`, and the rest are `skipped`. Without
+/// a baseline every recorded candidate is `forced`.
+///
+/// # Arguments
+/// * `db_path` - Path to the database file
+/// * `project_root` - Root directory of the project; candidate node IDs are
+///   relative to it, like pytest's
+/// * `candidate_tests` - Test node IDs to partition
+/// * `scope_paths` - Paths to check, as for `detect_changes`
+/// * `baseline_name` - Named baseline to compare against (default: the main baseline)
+#[pyfunction]
+#[pyo3(signature = (db_path, project_root, candidate_tests, scope_paths, baseline_name=DEFAULT_BASELINE))]
+pub fn compute_selection(
+    db_path: &str,
+    project_root: &str,
+    candidate_tests: Vec<String>,
+    scope_paths: Vec<String>,
+    baseline_name: &str,
+) -> PyResult<SelectionResult> {
+    compute_selection_internal(
+        db_path,
+        project_root,
+        candidate_tests,
+        scope_paths,
+        baseline_name,
+    )
+    .map_err(|e| {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to compute selection: {}", e))
+    })
+}
+
+fn compute_selection_internal(
+    db_path: &str,
+    project_root: &str,
+    candidate_tests: Vec<String>,
+    scope_paths: Vec<String>,
+    baseline_name: &str,
+) -> Result<SelectionResult> {
+    let changes = detect_changes_internal(
+        db_path,
+        project_root,
+        scope_paths,
+        None,
+        baseline_name,
+        true,
+        false,
+    )?
+    .changes;
+
+    let db = PytestDiffDatabase::open(db_path)?;
+    let recorded: HashSet<String> = db.get_recorded_tests_rust()?.into_iter().collect();
+    let affected: HashSet<String> = db
+        .get_affected_tests_for_rust(&changes)?
+        .into_iter()
+        .collect();
+    let modified: HashSet<&str> = changes.modified.iter().map(String::as_str).collect();
+
+    let mut result = SelectionResult::default();
+    for test in candidate_tests {
+        let file = test.split("::").next().unwrap_or_default();
+        let partition = if !recorded.contains(&test) {
+            &mut result.unknown
+        } else if changes.baseline_empty {
+            &mut result.forced
+        } else if affected.contains(&test) {
+            &mut result.selected
+        } else if modified.contains(normalize_filename(file).as_ref()) {
+            &mut result.forced
+        } else {
+            &mut result.skipped
+        };
+        partition.push(test);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Checkpoint;
+    use crate::fingerprint::tests::{py, rewrite};
+    use crate::fingerprint::{
+        process_coverage_data_internal, save_baseline_internal, CoverageOptions, SaveOptions,
+    };
+
+    #[test]
+    fn test_diff_trees_reports_precise_block_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = std::fs::canonicalize(dir.path()).unwrap();
+        let (old_root, new_root) = (base.join("old"), base.join("new"));
+        let source = "\
+class A:
+    def run(self):
+        return 1
+
+    def stop(self):
+        return 0
+
+
+def helper():
+    return 2
+
+
+def other():
+    return 3
+";
+        for root in [&old_root, &new_root] {
+            std::fs::create_dir_all(root.join("pkg")).unwrap();
+            std::fs::write(root.join("pkg").join("same.py"), source).unwrap();
+            std::fs::write(root.join("pkg").join("mod.py"), source).unwrap();
+        }
+        std::fs::write(old_root.join("pkg").join("gone.py"), "def f():\n    pass\n").unwrap();
+        std::fs::write(new_root.join("pkg").join("new.py"), "def g():\n    pass\n").unwrap();
+
+        // Edit `A.stop`, move `other` above `helper`, add `extra`
+        let edited = "\
+class A:
+    def run(self):
+        return 1
+
+    def stop(self):
+        return -1
+
+
+def other():
+    return 3
+
+
+def helper():
+    return 2
+
+
+def extra():
+    return 4
+";
+        std::fs::write(new_root.join("pkg").join("mod.py"), edited).unwrap();
+
+        let diffs = diff_trees_internal(
+            old_root.to_str().unwrap(),
+            new_root.to_str().unwrap(),
+            &[],
+            ParseOptions::default(),
+            &py(),
+        )
+        .unwrap();
+
+        let mut files: Vec<&String> = diffs.keys().collect();
+        files.sort();
+        assert_eq!(files, vec!["pkg/gone.py", "pkg/mod.py", "pkg/new.py"]);
+        assert_eq!(
+            diffs["pkg/mod.py"],
+            ChangedBlocks {
+                added: vec!["extra".to_string()],
+                removed: vec![],
+                // The module skeleton lists the signatures; the class checksum
+                // covers its methods
+                modified: vec![
+                    "<module>".to_string(),
+                    "A".to_string(),
+                    "A.stop".to_string()
+                ],
+                moved: vec!["other".to_string()],
+            }
+        );
+        assert_eq!(diffs["pkg/new.py"].added, vec!["<module>", "g"]);
+        assert_eq!(diffs["pkg/gone.py"].removed, vec!["<module>", "f"]);
+    }
+
+    #[test]
+    fn test_find_duplicate_blocks_reports_every_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        let helper = "def helper(x):\n    return x * 2\n";
+        std::fs::create_dir_all(root.join("pkg")).unwrap();
+        std::fs::write(root.join("pkg/__init__.py"), "").unwrap();
+        std::fs::write(root.join("a.py"), format!("import os\n\n{}", helper)).unwrap();
+        std::fs::write(
+            root.join("pkg/b.py"),
+            format!("{}\ndef other(x):\n    return x * 3\n", helper),
+        )
+        .unwrap();
+        std::fs::write(root.join("c.py"), "").unwrap();
+        let root_str = root.to_str().unwrap();
+
+        let duplicates = find_duplicate_blocks_internal(root_str, &[], &py()).unwrap();
+        let checksum = calculate_fingerprint_internal(
+            root.join("a.py").to_str().unwrap(),
+            ParseOptions::default(),
+        )
+        .unwrap()
+        .checksums[1];
+        let location = |file: &str| (file.to_string(), "helper".to_string());
+        assert_eq!(
+            duplicates,
+            vec![(checksum, vec![location("a.py"), location("pkg/b.py")])]
+        );
+    }
+
+    #[test]
+    fn test_find_uncovered_blocks_reports_never_executed_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("lib.py"),
+            "def used():\n    return 1\n\n\ndef dead():\n    return 2\n\n\nclass A:\n    def m(self):\n        return 3\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("unused.py"), "def f():\n    return 4\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        // A test that imported lib.py and called used() and A.m()
+        let lib = root.join("lib.py").to_string_lossy().into_owned();
+        let fingerprints = process_coverage_data_internal(
+            HashMap::from([(lib, vec![1, 2, 5, 9, 10, 11])]),
+            root_str,
+            "",
+            None,
+            &CoverageOptions::default(),
+        )
+        .unwrap();
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        db.save_test_execution_rust("test_lib", fingerprints, 0.1, false, "3.12", false)
+            .unwrap();
+        drop(db);
+
+        let (uncovered, changed) = find_uncovered_blocks_internal(db_path, root_str, &[]).unwrap();
+        let block = |file: &str, name: &str| (file.to_string(), name.to_string());
+        assert_eq!(
+            uncovered,
+            vec![
+                block("lib.py", "dead"),
+                block("unused.py", "<module>"),
+                block("unused.py", "f"),
+            ]
+        );
+        assert!(changed.is_empty());
+
+        // Editing used() must not turn it into dead code: the file is skipped
+        rewrite(
+            root.join("lib.py"),
+            "def used():\n    return 10\n\n\ndef dead():\n    return 2\n\n\nclass A:\n    def m(self):\n        return 3\n",
+        );
+        let (uncovered, changed) = find_uncovered_blocks_internal(db_path, root_str, &[]).unwrap();
+        assert_eq!(
+            uncovered,
+            vec![block("unused.py", "<module>"), block("unused.py", "f")]
+        );
+        assert_eq!(changed, vec!["lib.py".to_string()]);
+    }
+
+    #[test]
+    fn test_compute_selection_partitions_candidates() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        std::fs::write(
+            &module,
+            "def f():\n    return 1\n\n\ndef g():\n    return 2\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("test_new.py"), "def test_x():\n    pass\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let module_str = module.to_str().unwrap().to_string();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        for (test, lines) in [
+            ("test_mod.py::test_f", vec![1, 2]),
+            ("test_mod.py::test_g", vec![5, 6]),
+            ("test_new.py::test_x", vec![5, 6]),
+        ] {
+            let fingerprints = process_coverage_data_internal(
+                HashMap::from([(module_str.clone(), lines)]),
+                root_str,
+                "",
+                None,
+                &CoverageOptions::default(),
+            )
+            .unwrap();
+            db.save_test_execution_rust(test, fingerprints, 0.1, false, "3.12", false)
+                .unwrap();
+        }
+        drop(db);
+
+        // f changes, and the file holding test_x is edited
+        rewrite(
+            &module,
+            "def f():\n    return 3\n\n\ndef g():\n    return 2\n",
+        );
+        rewrite(root.join("test_new.py"), "def test_x():\n    assert True\n");
+
+        let candidates = [
+            "test_mod.py::test_g",
+            "test_mod.py::test_unrecorded",
+            "test_new.py::test_x",
+            "test_mod.py::test_f",
+        ];
+        let selection = compute_selection_internal(
+            db_path,
+            root_str,
+            candidates.iter().map(|t| t.to_string()).collect(),
+            vec![],
+            DEFAULT_BASELINE,
+        )
+        .unwrap();
+        assert_eq!(selection.selected, vec!["test_mod.py::test_f"]);
+        assert_eq!(selection.skipped, vec!["test_mod.py::test_g"]);
+        assert_eq!(selection.forced, vec!["test_new.py::test_x"]);
+        assert_eq!(selection.unknown, vec!["test_mod.py::test_unrecorded"]);
+    }
+}
@@ -6,23 +6,34 @@
 use lru::LruCache;
 use parking_lot::RwLock;
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::types::Fingerprint;
+use crate::types::{Fingerprint, FINGERPRINT_FORMAT};
+
+/// Default number of entries kept per cached table
+pub const DEFAULT_CACHE_SIZE: usize = 10_000;
 
 /// LRU cache for database queries
 pub struct Cache {
     /// Cached file fingerprints: path -> fingerprint
     fingerprints: RwLock<LruCache<PathBuf, Fingerprint>>,
 
+    /// Fingerprint format of the cached entries; switching clears them
+    format: RwLock<u32>,
+
     /// Cached test mappings: test_name -> list of checksums
     test_mappings: RwLock<LruCache<String, Vec<i32>>>,
+
+    /// Fingerprint lookups served from / missing in the cache
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
 impl Cache {
     /// Create a new cache with default size limit
     pub fn new() -> Self {
-        Self::with_capacity(10_000)
+        Self::with_capacity(DEFAULT_CACHE_SIZE)
     }
 
     /// Create a new cache with specified capacity
@@ -31,12 +42,30 @@ impl Cache {
         Self {
             fingerprints: RwLock::new(LruCache::new(cap)),
             test_mappings: RwLock::new(LruCache::new(cap)),
+            format: RwLock::new(FINGERPRINT_FORMAT),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
         }
     }
 
     /// Get a fingerprint from cache (promotes to most-recently-used)
-    pub fn get_fingerprint(&self, path: &PathBuf) -> Option<Fingerprint> {
-        self.fingerprints.write().get(path).cloned()
+    pub fn get_fingerprint(&self, path: &Path) -> Option<Fingerprint> {
+        let fp = self.fingerprints.write().get(path).cloned();
+        if fp.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        fp
+    }
+
+    /// Fingerprint lookup statistics: (hits, misses, cached fingerprints)
+    pub fn stats(&self) -> (u64, u64, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.fingerprints.read().len(),
+        )
     }
 
     /// Insert a fingerprint into cache (auto-evicts LRU entry when full)
@@ -44,6 +73,23 @@ impl Cache {
         self.fingerprints.write().put(path, fp);
     }
 
+    /// Fingerprint format the cached entries belong to
+    pub fn format(&self) -> u32 {
+        *self.format.read()
+    }
+
+    /// Switch to fingerprint format `format`, clearing all cached data if it
+    /// differs from the current one; returns whether it did
+    pub fn set_format(&self, format: u32) -> bool {
+        let mut current = self.format.write();
+        if *current == format {
+            return false;
+        }
+        *current = format;
+        self.clear();
+        true
+    }
+
     /// Get test mapping from cache (promotes to most-recently-used)
     #[allow(dead_code)]
     pub fn get_test_mapping(&self, test_name: &str) -> Option<Vec<i32>> {
@@ -56,7 +102,7 @@ impl Cache {
         self.test_mappings.write().put(test_name, checksums);
     }
 
-    /// Clear all cached data
+    /// Clear all cached data (statistics are kept)
     pub fn clear(&self) {
         self.fingerprints.write().clear();
         self.test_mappings.write().clear();
@@ -124,6 +170,30 @@ mod tests {
         assert!(cache.get_fingerprint(&path).is_none());
     }
 
+    #[test]
+    fn test_entries_of_old_format_are_not_served() {
+        let cache = Cache::new();
+        let path = PathBuf::from("test.py");
+        let fp = Fingerprint {
+            filename: "test.py".to_string(),
+            checksums: vec![123],
+            file_hash: "abc".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+
+        cache.insert_fingerprint(path.clone(), fp);
+        assert!(!cache.set_format(FINGERPRINT_FORMAT));
+        assert!(cache.get_fingerprint(&path).is_some());
+
+        assert!(cache.set_format(FINGERPRINT_FORMAT + 1));
+        assert!(cache.get_fingerprint(&path).is_none());
+        // Switching back doesn't resurrect the old entries either
+        cache.set_format(FINGERPRINT_FORMAT);
+        assert!(cache.get_fingerprint(&path).is_none());
+        assert_eq!(cache.stats(), (1, 2, 0));
+    }
+
     #[test]
     fn test_lru_eviction_order() {
         // Create a cache with capacity 2
@@ -163,5 +233,6 @@ mod tests {
         assert!(cache.get_fingerprint(&PathBuf::from("a.py")).is_some());
         assert!(cache.get_fingerprint(&PathBuf::from("b.py")).is_none()); // evicted
         assert!(cache.get_fingerprint(&PathBuf::from("c.py")).is_some());
+        assert_eq!(cache.stats(), (3, 1, 2));
     }
 }
@@ -11,16 +11,168 @@ use anyhow::{Context, Result};
 use parking_lot::RwLock;
 use pyo3::prelude::*;
 use rusqlite::{params, Connection, OptionalExtension};
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use crate::cache::Cache;
-use crate::types::Fingerprint;
+use crate::fingerprint::SaveOptions;
+use crate::parser::{
+    block_name_hash, parse_module_with_options, Granularity, IgnoredNames, Normalization,
+    ParseOptions,
+};
+use crate::types::{
+    Block, ChangedFiles, Fingerprint, RenormalizeResult, TestExecution, FINGERPRINT_FORMAT,
+};
 
 /// Default busy timeout in milliseconds for concurrent access
 const BUSY_TIMEOUT_MS: i32 = 30_000; // 30 seconds
 
+/// Records committed per transaction by `save_test_executions` by default
+pub const DEFAULT_SAVE_BATCH_SIZE: usize = 500;
+
+/// Name of the baseline stored in `baseline_fp`, used when no name is given
+pub const DEFAULT_BASELINE: &str = "default";
+
+/// WAL checkpoint run after a bulk write such as `save_baseline`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Checkpoint {
+    /// Leave the WAL alone (SQLite still checkpoints when it grows large)
+    None,
+    /// Merge what can be merged without waiting on other connections
+    Passive,
+    /// Merge everything and truncate the WAL file to zero bytes
+    #[default]
+    Truncate,
+}
+
+impl std::str::FromStr for Checkpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Checkpoint::None),
+            "passive" => Ok(Checkpoint::Passive),
+            "truncate" => Ok(Checkpoint::Truncate),
+            _ => anyhow::bail!(
+                "Unknown checkpoint mode '{}' (expected 'none', 'passive' or 'truncate')",
+                s
+            ),
+        }
+    }
+}
+
+/// Extension of the files every database tracks
+pub const PYTHON_EXTENSION: &str = ".py";
+
+/// Metadata key holding the comma-separated tracked file extensions
+const TRACKED_EXTENSIONS_KEY: &str = "tracked_extensions";
+
+/// Metadata key holding the checksum normalization mode of the database
+const NORMALIZATION_KEY: &str = "normalization";
+
+/// Metadata key holding the block granularity of the database
+const GRANULARITY_KEY: &str = "block_granularity";
+
+/// Metadata key holding the line count above which functions are split into parts
+const MAX_FUNCTION_LINES_KEY: &str = "max_function_lines";
+
+/// Metadata key holding the JSON list of name patterns left out of checksums
+const IGNORED_BLOCKS_KEY: &str = "ignored_blocks";
+
+/// Metadata key holding the deepest nesting level that gets its own block
+const MAX_BLOCK_DEPTH_KEY: &str = "max_block_depth";
+
+/// Metadata key set when lambdas at module and class level get their own blocks
+const LAMBDA_BLOCKS_KEY: &str = "lambda_blocks";
+
+/// Version of the `export_json` snapshot format
+const SNAPSHOT_FORMAT: u32 = 1;
+
+/// A baseline with the settings needed to compare files against it, as
+/// exported by `export_json`
+///
+/// Lets change detection run without the database, e.g. on CI runners that
+/// ship the baseline as a JSON artifact.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BaselineSnapshot {
+    pub format: u32,
+    pub normalization: String,
+    pub granularity: String,
+    pub max_function_lines: Option<usize>,
+    #[serde(default)]
+    pub ignored_blocks: Vec<String>,
+    #[serde(default)]
+    pub max_block_depth: Option<usize>,
+    #[serde(default)]
+    pub lambda_blocks: bool,
+    pub tracked_extensions: Vec<String>,
+    pub fingerprints: Vec<Fingerprint>,
+}
+
+impl BaselineSnapshot {
+    /// Parse an exported snapshot, rejecting unknown formats
+    pub fn from_json(json: &str) -> Result<Self> {
+        let snapshot: Self = serde_json::from_str(json).context("Invalid baseline snapshot")?;
+        if snapshot.format != SNAPSHOT_FORMAT {
+            anyhow::bail!(
+                "Unsupported baseline snapshot format {} (expected {})",
+                snapshot.format,
+                SNAPSHOT_FORMAT
+            );
+        }
+        Ok(snapshot)
+    }
+
+    /// Parse options the baseline checksums were computed with
+    pub fn parse_options(&self) -> Result<ParseOptions> {
+        Ok(ParseOptions {
+            normalization: self.normalization.parse()?,
+            granularity: self.granularity.parse()?,
+            max_block_depth: self.max_block_depth,
+            lambda_blocks: self.lambda_blocks,
+            max_function_lines: self.max_function_lines,
+            ignored_names: IgnoredNames::new(self.ignored_blocks.clone()),
+        })
+    }
+}
+
+/// Metadata key set when baselines store a name hash with each block checksum
+const BLOCK_NAME_HASHES_KEY: &str = "block_name_hashes";
+
+/// Metadata key set when baseline saves re-read each fingerprinted file
+const VERIFY_READS_KEY: &str = "verify_reads";
+
+/// Metadata key holding how many executions are kept per test and environment
+const MAX_TEST_HISTORY_KEY: &str = "max_test_history";
+
+/// Metadata key holding the core version that last wrote the baseline
+const WRITER_VERSION_KEY: &str = "writer_version";
+
+/// Metadata key holding the fingerprint format the baseline was written in
+const FINGERPRINT_FORMAT_KEY: &str = "fingerprint_format";
+
+/// Metadata key holding the label of the last default baseline save; named
+/// baselines append `:<name>`
+const BASELINE_LABEL_KEY: &str = "baseline_label";
+
+/// Metadata key holding the mtime aggregate recorded by the last default
+/// baseline save (see `set_baseline_aggregate`); named baselines append `:<name>`
+const BASELINE_AGGREGATE_KEY: &str = "baseline_aggregate";
+
+/// Metadata key holding the git commit the baseline was saved at (set by the plugin)
+const BASELINE_COMMIT_KEY: &str = "baseline_commit";
+
+/// Metadata key holding the scope (JSON list of root-relative paths) the
+/// baseline was saved with
+const BASELINE_SCOPE_KEY: &str = "baseline_scope";
+
+/// Stored `MAX_TEST_HISTORY_KEY` value meaning no cap
+const UNLIMITED_HISTORY: &str = "unlimited";
+
 /// Result of an import or merge operation
 #[pyclass]
 #[derive(Clone, Debug)]
@@ -31,6 +183,58 @@ pub struct ImportResult {
     pub test_execution_count: usize,
 }
 
+#[pymethods]
+impl ImportResult {
+    #[new]
+    fn new(baseline_count: usize, test_execution_count: usize) -> Self {
+        Self {
+            baseline_count,
+            test_execution_count,
+        }
+    }
+}
+
+/// Freshness of the baseline against the files currently on disk
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct ValidationReport {
+    /// Files recorded in the baseline
+    #[pyo3(get)]
+    pub total: usize,
+    /// Baseline files whose content still matches the recorded hash
+    #[pyo3(get)]
+    pub fresh: usize,
+    /// Baseline files that exist but were changed since
+    #[pyo3(get)]
+    pub changed: usize,
+    /// Baseline files that no longer exist (or can't be read)
+    #[pyo3(get)]
+    pub missing: usize,
+}
+
+#[pymethods]
+impl ValidationReport {
+    /// Share of baseline files that are still fresh (1.0 for an empty baseline)
+    fn fresh_ratio(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.fresh as f64 / self.total as f64
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "ValidationReport({:.1}% fresh: fresh={}, changed={}, missing={}, total={})",
+            self.fresh_ratio() * 100.0,
+            self.fresh,
+            self.changed,
+            self.missing,
+            self.total
+        )
+    }
+}
+
 /// Main database interface for pytest-difftest
 ///
 /// Manages the pytest-difftest SQLite database with optimizations:
@@ -41,6 +245,8 @@ pub struct ImportResult {
 #[pyclass(unsendable)]
 pub struct PytestDiffDatabase {
     conn: Arc<RwLock<Connection>>,
+    /// Whether pragmas and schema are still to be applied (see `open_deferred`)
+    setup_pending: AtomicBool,
     cache: Arc<Cache>,
     current_environment_id: Arc<RwLock<Option<i64>>>,
 }
@@ -51,8 +257,39 @@ impl PytestDiffDatabase {
         Self::new_internal(path)
     }
 
+    /// Open a database connection without touching the file yet
+    ///
+    /// Pragmas and schema are applied on first use instead, so this only opens
+    /// the SQLite handle: cheap enough to call from an event loop.
+    pub fn open_deferred(path: &str) -> Result<Self> {
+        let conn = Self::open_connection(path)?;
+
+        #[allow(clippy::arc_with_non_send_sync)]
+        Ok(Self {
+            conn: Arc::new(RwLock::new(conn)),
+            setup_pending: AtomicBool::new(true),
+            cache: Arc::new(Cache::new()),
+            current_environment_id: Arc::new(RwLock::new(None)),
+        })
+    }
+
+    /// Replace the query cache with one holding up to `max_size` entries per table
+    pub fn with_cache_size(mut self, max_size: usize) -> Self {
+        let cache = Cache::with_capacity(max_size);
+        cache.set_format(self.cache.format());
+        self.cache = Arc::new(cache);
+        self
+    }
+
     /// Create a new database connection with optimizations
     fn new_internal(path: &str) -> Result<Self> {
+        let db = Self::open_deferred(path)?;
+        db.conn()?;
+        Ok(db)
+    }
+
+    /// Open the SQLite handle, creating the parent directory if needed
+    fn open_connection(path: &str) -> Result<Connection> {
         let path_obj = Path::new(path);
 
         // Create parent directory if it doesn't exist
@@ -69,6 +306,11 @@ impl PytestDiffDatabase {
         conn.busy_timeout(std::time::Duration::from_millis(BUSY_TIMEOUT_MS as u64))
             .context("Failed to set busy timeout")?;
 
+        Ok(conn)
+    }
+
+    /// Apply pragmas and create the schema
+    fn set_up(conn: &Connection) -> Result<()> {
         // Apply performance optimizations
         conn.execute_batch(
             "
@@ -83,14 +325,51 @@ impl PytestDiffDatabase {
         .context("Failed to set SQLite pragmas")?;
 
         // Create schema
-        Self::create_schema(&conn)?;
+        Self::create_schema(conn)
+    }
 
-        #[allow(clippy::arc_with_non_send_sync)]
-        Ok(Self {
-            conn: Arc::new(RwLock::new(conn)),
-            cache: Arc::new(Cache::new()),
-            current_environment_id: Arc::new(RwLock::new(None)),
+    /// The connection, set up first if the database was opened deferred
+    fn conn(&self) -> Result<&RwLock<Connection>> {
+        if self.setup_pending.load(Ordering::Acquire) {
+            let conn = self.conn.write();
+            // Another caller may have finished the setup while we waited
+            if self.setup_pending.load(Ordering::Acquire) {
+                Self::set_up(&conn)?;
+                self.cache
+                    .set_format(Self::stored_fingerprint_format(&conn)?);
+                self.setup_pending.store(false, Ordering::Release);
+            }
+        }
+        Ok(&self.conn)
+    }
+
+    /// Fingerprint format of the stored baseline (0 if never recorded, i.e.
+    /// saved before formats were, or not saved yet)
+    fn stored_fingerprint_format(conn: &Connection) -> Result<u32> {
+        conn.query_row(
+            "SELECT data FROM metadata WHERE dataid = ?1",
+            params![FINGERPRINT_FORMAT_KEY],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()
+        .context("Failed to get metadata")?
+        .map(|value| {
+            value
+                .parse()
+                .with_context(|| format!("Invalid {} value '{}'", FINGERPRINT_FORMAT_KEY, value))
         })
+        .transpose()
+        .map(|format| format.unwrap_or(0))
+    }
+
+    /// Re-key the query cache on the stored fingerprint format, clearing it if
+    /// the format changed since it was last read; returns whether it did
+    ///
+    /// Runs when the database is set up and on every baseline save; call it
+    /// after another process may have rewritten the baseline in a new format.
+    pub fn refresh_cache_format_internal(&self) -> Result<bool> {
+        let format = Self::stored_fingerprint_format(&self.conn()?.read())?;
+        Ok(self.cache.set_format(format))
     }
 
     /// Create database schema if it doesn't exist
@@ -102,14 +381,26 @@ impl PytestDiffDatabase {
 
     /// Close database and checkpoint WAL (public Rust API)
     pub fn close_and_checkpoint(&self) -> Result<()> {
-        let conn = self.conn.write();
-        // Checkpoint WAL to merge changes into main database file
-        conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+        self.checkpoint(Checkpoint::Truncate)
+    }
+
+    /// Checkpoint the WAL into the main database file, keeping the connection open
+    pub fn checkpoint(&self, mode: Checkpoint) -> Result<()> {
+        let pragma = match mode {
+            Checkpoint::None => return Ok(()),
+            Checkpoint::Passive => "PRAGMA wal_checkpoint(PASSIVE);",
+            Checkpoint::Truncate => "PRAGMA wal_checkpoint(TRUNCATE);",
+        };
+        let conn = self.conn()?.write();
+        conn.execute_batch(pragma)
             .context("Failed to checkpoint WAL")?;
         Ok(())
     }
 
     /// Get or create environment ID for current Python environment
+    ///
+    /// Once resolved (or chosen with `set_environment_internal`), the ID is
+    /// cached and the arguments are ignored.
     fn get_or_create_environment(&self, env_name: &str, python_version: &str) -> Result<i64> {
         // Check cache first
         {
@@ -119,7 +410,29 @@ impl PytestDiffDatabase {
             }
         }
 
-        let conn = self.conn.write();
+        let env_id = self.find_or_create_environment(env_name, python_version)?;
+
+        // Cache the environment ID
+        {
+            let mut cached_id = self.current_environment_id.write();
+            *cached_id = Some(env_id);
+        }
+
+        Ok(env_id)
+    }
+
+    /// Switch the environment later test executions are saved under
+    ///
+    /// Replaces the cached environment ID, creating the environment if needed.
+    pub fn set_environment_internal(&self, env_name: &str, python_version: &str) -> Result<i64> {
+        let env_id = self.find_or_create_environment(env_name, python_version)?;
+        *self.current_environment_id.write() = Some(env_id);
+        Ok(env_id)
+    }
+
+    /// Look up an environment by name and Python version, inserting it if missing
+    fn find_or_create_environment(&self, env_name: &str, python_version: &str) -> Result<i64> {
+        let conn = self.conn()?.write();
 
         // Try to find existing environment
         let existing_id: Option<i64> = conn
@@ -145,29 +458,24 @@ impl PytestDiffDatabase {
             conn.last_insert_rowid()
         };
 
-        // Cache the environment ID
-        {
-            let mut cached_id = self.current_environment_id.write();
-            *cached_id = Some(env_id);
-        }
-
         Ok(env_id)
     }
 
     /// Store or retrieve fingerprint ID (used in tests)
     #[cfg(test)]
     fn get_or_create_fingerprint(&self, fp: &Fingerprint) -> Result<i64> {
-        let conn = self.conn.write();
+        let conn = self.conn()?.write();
 
         // Serialize checksums to blob
         let checksums_blob = serialize_checksums(&fp.checksums);
+        let filename = normalize_filename(&fp.filename);
 
         // Try to find existing fingerprint
         let existing_id: Option<i64> = conn
             .query_row(
                 "SELECT id FROM file_fp
                  WHERE filename = ?1 AND fsha = ?2 AND method_checksums = ?3",
-                params![&fp.filename, &fp.file_hash, checksums_blob],
+                params![filename, &fp.file_hash, checksums_blob],
                 |row| row.get(0),
             )
             .optional()
@@ -180,7 +488,7 @@ impl PytestDiffDatabase {
             conn.execute(
                 "INSERT INTO file_fp (filename, method_checksums, mtime, fsha)
                  VALUES (?1, ?2, ?3, ?4)",
-                params![&fp.filename, checksums_blob, fp.mtime, &fp.file_hash],
+                params![filename, checksums_blob, fp.mtime, &fp.file_hash],
             )
             .context("Failed to insert fingerprint")?;
 
@@ -188,6 +496,317 @@ impl PytestDiffDatabase {
         }
     }
 
+    /// Checksum normalization mode of this database (defaults to exact)
+    pub fn get_normalization(&self) -> Result<Normalization> {
+        match self.get_metadata_internal(NORMALIZATION_KEY)? {
+            Some(mode) => mode.parse(),
+            None => Ok(Normalization::Exact),
+        }
+    }
+
+    /// Record the checksum normalization mode without touching stored checksums
+    ///
+    /// Only valid on an empty database; use `renormalize_baseline_internal` to
+    /// switch an existing one.
+    pub fn set_normalization(&self, normalization: Normalization) -> Result<()> {
+        self.set_metadata_internal(NORMALIZATION_KEY, normalization.as_str())
+    }
+
+    /// Block granularity of this database (defaults to definitions)
+    pub fn get_granularity(&self) -> Result<Granularity> {
+        match self.get_metadata_internal(GRANULARITY_KEY)? {
+            Some(mode) => mode.parse(),
+            None => Ok(Granularity::Definitions),
+        }
+    }
+
+    /// Record the block granularity; fails unless the database is empty
+    ///
+    /// Stored checksums can't be converted between granularities, since the
+    /// blocks themselves differ.
+    pub fn set_granularity(&self, granularity: Granularity) -> Result<()> {
+        if granularity != self.get_granularity()? {
+            let stats = self.get_stats_internal()?;
+            if stats["baseline_count"] > 0 || stats["fingerprint_count"] > 0 {
+                anyhow::bail!(
+                    "Block granularity can only be changed on an empty database; \
+                     clear it and save a new baseline"
+                );
+            }
+        }
+        self.set_metadata_internal(GRANULARITY_KEY, granularity.as_str())
+    }
+
+    /// Line count above which functions are split into part blocks (None = never)
+    pub fn get_max_function_lines_internal(&self) -> Result<Option<usize>> {
+        self.get_metadata_internal(MAX_FUNCTION_LINES_KEY)?
+            .map(|value| {
+                value.parse().with_context(|| {
+                    format!("Invalid {} value '{}'", MAX_FUNCTION_LINES_KEY, value)
+                })
+            })
+            .transpose()
+    }
+
+    /// Record the function part-splitting threshold; fails unless the database is empty
+    ///
+    /// Like the granularity, it decides which blocks exist, so stored
+    /// checksums can't be converted.
+    pub fn set_max_function_lines_internal(&self, max_lines: Option<usize>) -> Result<()> {
+        if max_lines == Some(0) {
+            anyhow::bail!("max_function_lines must be at least 1");
+        }
+        if max_lines != self.get_max_function_lines_internal()? {
+            let stats = self.get_stats_internal()?;
+            if stats["baseline_count"] > 0 || stats["fingerprint_count"] > 0 {
+                anyhow::bail!(
+                    "max_function_lines can only be changed on an empty database; \
+                     clear it and save a new baseline"
+                );
+            }
+        }
+        match max_lines {
+            Some(max_lines) => {
+                self.set_metadata_internal(MAX_FUNCTION_LINES_KEY, &max_lines.to_string())
+            }
+            None => {
+                self.conn()?.write().execute(
+                    "DELETE FROM metadata WHERE dataid = ?1",
+                    params![MAX_FUNCTION_LINES_KEY],
+                )?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Name patterns whose code is left out of every checksum (see `IgnoredNames`)
+    pub fn get_ignored_blocks_internal(&self) -> Result<Vec<String>> {
+        self.get_metadata_internal(IGNORED_BLOCKS_KEY)?
+            .map(|value| {
+                serde_json::from_str(&value)
+                    .with_context(|| format!("Invalid {} value '{}'", IGNORED_BLOCKS_KEY, value))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Record the ignored name patterns; fails unless the database is empty
+    ///
+    /// They change which code every checksum covers, so stored checksums
+    /// can't be converted.
+    pub fn set_ignored_blocks_internal(&self, patterns: &[String]) -> Result<()> {
+        if patterns.iter().any(|pattern| pattern.is_empty()) {
+            anyhow::bail!("Ignored block patterns must not be empty");
+        }
+        if patterns != self.get_ignored_blocks_internal()? {
+            let stats = self.get_stats_internal()?;
+            if stats["baseline_count"] > 0 || stats["fingerprint_count"] > 0 {
+                anyhow::bail!(
+                    "Ignored blocks can only be changed on an empty database; \
+                     clear it and save a new baseline"
+                );
+            }
+        }
+        if patterns.is_empty() {
+            self.conn()?.write().execute(
+                "DELETE FROM metadata WHERE dataid = ?1",
+                params![IGNORED_BLOCKS_KEY],
+            )?;
+            Ok(())
+        } else {
+            self.set_metadata_internal(IGNORED_BLOCKS_KEY, &serde_json::to_string(patterns)?)
+        }
+    }
+
+    /// Deepest nesting level that gets its own block (None = unlimited)
+    pub fn get_max_block_depth_internal(&self) -> Result<Option<usize>> {
+        self.get_metadata_internal(MAX_BLOCK_DEPTH_KEY)?
+            .map(|value| {
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid {} value '{}'", MAX_BLOCK_DEPTH_KEY, value))
+            })
+            .transpose()
+    }
+
+    /// Record the block nesting limit; fails unless the database is empty
+    ///
+    /// Like the granularity, it decides which blocks exist, so stored
+    /// checksums can't be converted.
+    pub fn set_max_block_depth_internal(&self, max_depth: Option<usize>) -> Result<()> {
+        if max_depth != self.get_max_block_depth_internal()? {
+            let stats = self.get_stats_internal()?;
+            if stats["baseline_count"] > 0 || stats["fingerprint_count"] > 0 {
+                anyhow::bail!(
+                    "max_block_depth can only be changed on an empty database; \
+                     clear it and save a new baseline"
+                );
+            }
+        }
+        match max_depth {
+            Some(max_depth) => {
+                self.set_metadata_internal(MAX_BLOCK_DEPTH_KEY, &max_depth.to_string())
+            }
+            None => {
+                self.conn()?.write().execute(
+                    "DELETE FROM metadata WHERE dataid = ?1",
+                    params![MAX_BLOCK_DEPTH_KEY],
+                )?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether lambdas at module and class level get their own blocks (off by default)
+    pub fn lambda_blocks_enabled(&self) -> Result<bool> {
+        Ok(self.get_metadata_internal(LAMBDA_BLOCKS_KEY)?.as_deref() == Some("1"))
+    }
+
+    /// Record whether lambdas get their own blocks; fails unless the database is empty
+    ///
+    /// Lambda blocks are left out of their enclosing block's checksum, so
+    /// stored checksums can't be converted.
+    pub fn set_lambda_blocks_internal(&self, enabled: bool) -> Result<()> {
+        if enabled != self.lambda_blocks_enabled()? {
+            let stats = self.get_stats_internal()?;
+            if stats["baseline_count"] > 0 || stats["fingerprint_count"] > 0 {
+                anyhow::bail!(
+                    "Lambda blocks can only be changed on an empty database; \
+                     clear it and save a new baseline"
+                );
+            }
+        }
+        self.set_metadata_internal(LAMBDA_BLOCKS_KEY, if enabled { "1" } else { "0" })
+    }
+
+    /// Whether baselines store a name hash with each block checksum (off by default)
+    pub fn block_name_hashes_enabled(&self) -> Result<bool> {
+        Ok(self
+            .get_metadata_internal(BLOCK_NAME_HASHES_KEY)?
+            .as_deref()
+            == Some("1"))
+    }
+
+    /// Store a name hash with each block checksum in baselines saved from now on
+    ///
+    /// Both blob formats decode everywhere, so this can be toggled at any time;
+    /// files keep their format until they are saved again (renormalizing
+    /// rewrites them without name hashes).
+    pub fn set_block_name_hashes_internal(&self, enabled: bool) -> Result<()> {
+        self.set_metadata_internal(BLOCK_NAME_HASHES_KEY, if enabled { "1" } else { "0" })
+    }
+
+    /// Whether baseline saves re-read files to catch torn reads (off by default)
+    pub fn verify_reads_enabled(&self) -> Result<bool> {
+        Ok(self.get_metadata_internal(VERIFY_READS_KEY)?.as_deref() == Some("1"))
+    }
+
+    /// Re-read and re-hash every file `save_baseline` fingerprints, leaving out
+    /// files whose two reads differ (see `UnstableRead`)
+    pub fn set_verify_reads_internal(&self, enabled: bool) -> Result<()> {
+        self.set_metadata_internal(VERIFY_READS_KEY, if enabled { "1" } else { "0" })
+    }
+
+    /// Executions kept per test and environment (None = unlimited)
+    ///
+    /// Defaults to 1: only the latest execution is kept, so test selection
+    /// reflects what each test covered on its last run.
+    pub fn get_max_test_history(&self) -> Result<Option<usize>> {
+        match self.get_metadata_internal(MAX_TEST_HISTORY_KEY)?.as_deref() {
+            None => Ok(Some(1)),
+            Some(UNLIMITED_HISTORY) => Ok(None),
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .with_context(|| format!("Invalid {} value '{}'", MAX_TEST_HISTORY_KEY, value)),
+        }
+    }
+
+    /// Cap the executions kept per test and environment (None = unlimited)
+    ///
+    /// Older executions are deleted as new ones are saved. While more than
+    /// one is kept, every kept execution counts for test selection.
+    pub fn set_max_test_history(&self, max_history: Option<usize>) -> Result<()> {
+        let value = match max_history {
+            Some(0) => anyhow::bail!("max_test_history must be at least 1"),
+            Some(n) => n.to_string(),
+            None => UNLIMITED_HISTORY.to_string(),
+        };
+        self.set_metadata_internal(MAX_TEST_HISTORY_KEY, &value)
+    }
+
+    /// Core version that last saved the baseline (None if never saved)
+    pub fn get_writer_version_internal(&self) -> Result<Option<String>> {
+        self.get_metadata_internal(WRITER_VERSION_KEY)
+    }
+
+    /// Stamp the running core version as the baseline writer
+    pub fn record_writer_version(&self) -> Result<()> {
+        self.set_metadata_internal(WRITER_VERSION_KEY, env!("CARGO_PKG_VERSION"))
+    }
+
+    /// Stamp the current fingerprint format as the baseline's, re-keying the
+    /// query cache on it
+    pub fn record_fingerprint_format(&self) -> Result<()> {
+        self.set_metadata_internal(FINGERPRINT_FORMAT_KEY, &FINGERPRINT_FORMAT.to_string())?;
+        self.refresh_cache_format_internal()?;
+        Ok(())
+    }
+
+    /// Record the label of the latest save of a baseline (`None` clears it)
+    pub fn set_baseline_label(&self, baseline_name: &str, label: Option<&str>) -> Result<()> {
+        let key = baseline_label_key(baseline_name);
+        match label {
+            Some(label) => self.set_metadata_internal(&key, label),
+            None => {
+                self.conn()?
+                    .write()
+                    .execute("DELETE FROM metadata WHERE dataid = ?1", params![key])?;
+                Ok(())
+            }
+        }
+    }
+
+    /// File extensions discovered and fingerprinted (defaults to `.py` only)
+    pub fn get_tracked_extensions(&self) -> Result<Vec<String>> {
+        Ok(match self.get_metadata_internal(TRACKED_EXTENSIONS_KEY)? {
+            Some(value) => value.split(',').map(str::to_string).collect(),
+            None => vec![PYTHON_EXTENSION.to_string()],
+        })
+    }
+
+    /// Set the tracked file extensions, e.g. `[".py", ".py.jinja"]`
+    ///
+    /// `.py` is always tracked. Files with another extension that don't parse
+    /// as Python are fingerprinted as a single block keyed on their hash.
+    pub fn set_tracked_extensions(&self, extensions: &[String]) -> Result<()> {
+        let mut tracked = vec![PYTHON_EXTENSION.to_string()];
+        for ext in extensions {
+            if !ext.starts_with('.') || ext.len() < 2 || ext.contains(',') {
+                anyhow::bail!(
+                    "Invalid extension '{}' (expected e.g. '.py.jinja', without commas)",
+                    ext
+                );
+            }
+            if !tracked.contains(ext) {
+                tracked.push(ext.clone());
+            }
+        }
+        self.set_metadata_internal(TRACKED_EXTENSIONS_KEY, &tracked.join(","))
+    }
+
+    /// Parse options every checksum stored in this database was computed with
+    pub fn get_parse_options_internal(&self) -> Result<ParseOptions> {
+        Ok(ParseOptions {
+            normalization: self.get_normalization()?,
+            granularity: self.get_granularity()?,
+            max_block_depth: self.get_max_block_depth_internal()?,
+            lambda_blocks: self.lambda_blocks_enabled()?,
+            max_function_lines: self.get_max_function_lines_internal()?,
+            ignored_names: IgnoredNames::new(self.get_ignored_blocks_internal()?),
+        })
+    }
+
     /// Get stored fingerprint for a file (public Rust API)
     pub fn get_fingerprint_rust(&self, filename: &str) -> Result<Option<Fingerprint>> {
         self.get_fingerprint_internal(filename)
@@ -198,10 +817,49 @@ impl PytestDiffDatabase {
         self.get_baseline_fingerprint_internal(filename)
     }
 
+    /// Save a test execution record (public Rust API)
+    pub fn save_test_execution_rust(
+        &mut self,
+        test_name: &str,
+        fingerprints: Vec<Fingerprint>,
+        duration: f64,
+        failed: bool,
+        python_version: &str,
+        forced: bool,
+    ) -> Result<()> {
+        self.save_test_execution_internal(
+            test_name,
+            fingerprints,
+            duration,
+            failed,
+            python_version,
+            forced,
+        )
+    }
+
+    /// Get tests affected by a `detect_changes` result (public Rust API)
+    pub fn get_affected_tests_for_rust(&self, changed: &ChangedFiles) -> Result<Vec<String>> {
+        self.get_affected_tests_for_internal(changed)
+    }
+
+    /// Get every test with a recorded execution (public Rust API)
+    pub fn get_recorded_tests_rust(&self) -> Result<Vec<String>> {
+        self.get_recorded_tests_internal()
+    }
+
+    /// Get tests affected by changed blocks (public Rust API)
+    pub fn get_affected_tests_rust(
+        &self,
+        changed_blocks: HashMap<String, Vec<i32>>,
+    ) -> Result<Vec<String>> {
+        self.get_affected_tests_internal(changed_blocks)
+    }
+
     /// Get stored fingerprint from database, bypassing cache
     /// This should be used for change detection to ensure we get the latest stored value
     pub fn get_fingerprint_no_cache(&self, filename: &str) -> Result<Option<Fingerprint>> {
-        let conn = self.conn.read();
+        let filename = normalize_filename(filename);
+        let conn = self.conn()?.read();
 
         conn.query_row(
             "SELECT filename, method_checksums, mtime, fsha
@@ -211,8 +869,7 @@ impl PytestDiffDatabase {
                  LIMIT 1",
             params![filename],
             |row| {
-                let checksums_blob: Vec<u8> = row.get(1)?;
-                let checksums = deserialize_checksums(&checksums_blob);
+                let checksums = checksums_column(row, 1)?;
 
                 Ok(Fingerprint {
                     filename: row.get(0)?,
@@ -229,13 +886,14 @@ impl PytestDiffDatabase {
 
     /// Get stored fingerprint for a file (if exists)
     fn get_fingerprint_internal(&self, filename: &str) -> Result<Option<Fingerprint>> {
+        let filename = normalize_filename(filename);
         // Check cache first
-        let path = Path::new(filename).to_path_buf();
+        let path = Path::new(filename.as_ref()).to_path_buf();
         if let Some(cached) = self.cache.get_fingerprint(&path) {
             return Ok(Some(cached));
         }
 
-        let conn = self.conn.read();
+        let conn = self.conn()?.read();
 
         let result = conn
             .query_row(
@@ -246,8 +904,7 @@ impl PytestDiffDatabase {
                  LIMIT 1",
                 params![filename],
                 |row| {
-                    let checksums_blob: Vec<u8> = row.get(1)?;
-                    let checksums = deserialize_checksums(&checksums_blob);
+                    let checksums = checksums_column(row, 1)?;
 
                     Ok(Fingerprint {
                         filename: row.get(0)?,
@@ -272,9 +929,25 @@ impl PytestDiffDatabase {
 
 #[pymethods]
 impl PytestDiffDatabase {
+    /// Open the database at `path`
+    ///
+    /// With `deferred=True`, pragmas and schema are applied on first use
+    /// instead, so construction is cheap enough for an async event loop.
+    /// `cache_size` caps the entries of the in-memory query cache (10,000
+    /// by default); see `cache_stats` to tune it.
     #[new]
-    fn new(path: &str) -> PyResult<Self> {
-        Self::new_internal(path).map_err(|e| {
+    #[pyo3(signature = (path, deferred = false, cache_size = None))]
+    fn new(path: &str, deferred: bool, cache_size: Option<usize>) -> PyResult<Self> {
+        let db = if deferred {
+            Self::open_deferred(path)
+        } else {
+            Self::new_internal(path)
+        };
+        let db = db.map(|db| match cache_size {
+            Some(size) => db.with_cache_size(size),
+            None => db,
+        });
+        db.map_err(|e| {
             pyo3::exceptions::PyIOError::new_err(format!("Failed to open database: {}", e))
         })
     }
@@ -286,8 +959,10 @@ impl PytestDiffDatabase {
     /// * `fingerprints` - List of file fingerprints the test touched
     /// * `duration` - Test execution time in seconds
     /// * `failed` - Whether the test failed
-    /// * `python_version` - Python version string (e.g., "3.12.0")
-    #[pyo3(signature = (test_name, fingerprints, duration, failed, python_version = "3.12"))]
+    /// * `python_version` - Python version string (e.g., "3.12.0"); ignored once
+    ///   an environment is cached or set with `set_environment`
+    /// * `forced` - Whether the test was force-run rather than selected by change detection
+    #[pyo3(signature = (test_name, fingerprints, duration, failed, python_version = "3.12", forced = false))]
     fn save_test_execution(
         &mut self,
         test_name: &str,
@@ -295,23 +970,67 @@ impl PytestDiffDatabase {
         duration: f64,
         failed: bool,
         python_version: &str,
+        forced: bool,
+    ) -> PyResult<()> {
+        self.save_test_execution_internal(
+            test_name,
+            fingerprints,
+            duration,
+            failed,
+            python_version,
+            forced,
+        )
+        .map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to save test execution: {}",
+                e
+            ))
+        })
+    }
+
+    /// Save many test executions, committing every `batch_size` records
+    ///
+    /// Equivalent to calling `save_test_execution` for each record, but much
+    /// faster for large batches (one write lock, shared fingerprint lookups
+    /// per transaction). Smaller batches hold the write lock for less time;
+    /// larger ones commit less often. A failure rolls back only the batch
+    /// holding the failing record: earlier batches stay committed.
+    #[pyo3(signature = (records, python_version = "3.12", batch_size = DEFAULT_SAVE_BATCH_SIZE))]
+    fn save_test_executions(
+        &mut self,
+        records: Vec<TestExecution>,
+        python_version: &str,
+        batch_size: usize,
     ) -> PyResult<()> {
-        self.save_test_execution_internal(test_name, fingerprints, duration, failed, python_version)
+        self.save_test_executions_internal(&records, python_version, batch_size)
             .map_err(|e| {
                 pyo3::exceptions::PyRuntimeError::new_err(format!(
-                    "Failed to save test execution: {}",
+                    "Failed to save test executions: {}",
                     e
                 ))
             })
     }
 
-    /// Get list of tests affected by changed blocks
-    ///
-    /// # Arguments
-    /// * `changed_blocks` - Map of filename -> list of changed checksums
+    /// Get the most recent execution record of a test, with its fingerprints
+    ///
+    /// Returns None if the test has never been recorded.
+    fn get_test_execution(&self, test_name: &str) -> PyResult<Option<TestExecution>> {
+        self.get_test_execution_internal(test_name).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get test execution: {}",
+                e
+            ))
+        })
+    }
+
+    /// Get list of tests affected by changed blocks
+    ///
+    /// # Arguments
+    /// * `changed_blocks` - Map of filename -> list of changed checksums
     ///
     /// # Returns
-    /// * List of test names that should be run
+    /// * List of test names that should be run, deduplicated and sorted by
+    ///   name so the output is stable regardless of input or query order
     fn get_affected_tests(
         &self,
         changed_blocks: HashMap<String, Vec<i32>>,
@@ -325,6 +1044,103 @@ impl PytestDiffDatabase {
             })
     }
 
+    /// Get list of tests affected by a `detect_changes` result
+    ///
+    /// Modified files select tests block by block, like `get_affected_tests`.
+    /// New files select every test that recorded them: their blocks have no
+    /// baseline to be matched against.
+    fn get_affected_tests_for(&self, changed: PyRef<'_, ChangedFiles>) -> PyResult<Vec<String>> {
+        self.get_affected_tests_for_internal(&changed).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get affected tests: {}",
+                e
+            ))
+        })
+    }
+
+    /// Number of tests each changed file of a `detect_changes` result selects
+    ///
+    /// Uses the same selection as `get_affected_tests_for`, so callers can
+    /// gauge the impact of a change before running anything. Changed files
+    /// that select no test map to 0.
+    fn get_affected_test_counts(
+        &self,
+        changed: PyRef<'_, ChangedFiles>,
+    ) -> PyResult<HashMap<String, usize>> {
+        self.get_affected_test_counts_internal(&changed)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to count affected tests: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Get every test that recorded any of `files`, ignoring block checksums
+    ///
+    /// The safe file-level fallback when only "these files changed" is known
+    /// (e.g. from `git diff --name-only`). Sorted by test name.
+    fn get_tests_for_files(&self, files: Vec<String>) -> PyResult<Vec<String>> {
+        self.get_tests_for_files_internal(&files).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get tests for files: {}",
+                e
+            ))
+        })
+    }
+
+    /// Get every test whose recorded fingerprint of `filename` contains `checksum`
+    ///
+    /// The per-block match behind `get_affected_tests`, for inspecting who
+    /// depends on one block. Sorted by test name.
+    fn get_tests_for_checksum(&self, filename: &str, checksum: i32) -> PyResult<Vec<String>> {
+        self.get_tests_for_checksum_internal(filename, checksum)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to get tests for checksum: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Expected cost of running `selected_tests`, e.g. to size CI timeouts
+    ///
+    /// Returns the sum of each test's latest recorded duration, and the number
+    /// of tests without one (never recorded, or no duration stored) that the
+    /// sum leaves out. `environment` restricts the lookup to one environment
+    /// ID (see `list_environments`); by default any environment counts.
+    #[pyo3(signature = (selected_tests, environment=None))]
+    fn estimate_selected_duration(
+        &self,
+        selected_tests: Vec<String>,
+        environment: Option<i64>,
+    ) -> PyResult<(f64, usize)> {
+        self.estimate_selected_duration_internal(&selected_tests, environment)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to estimate selected duration: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Tests whose latest recorded execution failed, sorted
+    ///
+    /// Union them with `get_affected_tests` to rerun last run's failures along
+    /// with whatever the changes affect. `environment` restricts the lookup to
+    /// one environment ID (see `list_environments`); by default the latest
+    /// execution in any environment decides.
+    #[pyo3(signature = (environment=None))]
+    fn get_previous_failures(&self, environment: Option<i64>) -> PyResult<Vec<String>> {
+        self.get_previous_failures_internal(environment)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to get previous failures: {}",
+                    e
+                ))
+            })
+    }
+
     /// Get all test names that have recorded executions in the current environment
     fn get_recorded_tests(&self) -> PyResult<Vec<String>> {
         self.get_recorded_tests_internal().map_err(|e| {
@@ -335,6 +1151,43 @@ impl PytestDiffDatabase {
         })
     }
 
+    /// Save later test executions under the given environment
+    ///
+    /// The environment is created if needed and its ID returned. Until this is
+    /// called, executions go to the "default" environment of the first
+    /// `python_version` saved with; afterwards `python_version` arguments are
+    /// ignored. Lets one database object record runs of several interpreters.
+    fn set_environment(&self, name: &str, python_version: &str) -> PyResult<i64> {
+        self.set_environment_internal(name, python_version)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to set environment: {}",
+                    e
+                ))
+            })
+    }
+
+    /// List recorded environments as `(id, name, python_version)`, sorted by id
+    fn list_environments(&self) -> PyResult<Vec<(i64, String, String)>> {
+        self.list_environments_internal().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to list environments: {}", e))
+        })
+    }
+
+    /// Delete an environment together with all its test executions, and the
+    /// file fingerprints no remaining execution depends on
+    ///
+    /// Returns whether the environment existed.
+    fn delete_environment(&self, environment_id: i64) -> PyResult<bool> {
+        self.delete_environment_internal(environment_id)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to delete environment: {}",
+                    e
+                ))
+            })
+    }
+
     /// Get stored fingerprint for a file
     fn get_fingerprint(&self, filename: &str) -> PyResult<Option<Fingerprint>> {
         self.get_fingerprint_internal(filename).map_err(|e| {
@@ -348,6 +1201,24 @@ impl PytestDiffDatabase {
         Ok(())
     }
 
+    /// Re-read the stored fingerprint format, clearing the cache if it changed
+    /// (e.g. another process rewrote the baseline); returns whether it did
+    fn refresh_cache_format(&self) -> PyResult<bool> {
+        self.refresh_cache_format_internal().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to refresh cache format: {}",
+                e
+            ))
+        })
+    }
+
+    /// Query cache statistics: (hits, misses, cached fingerprints)
+    ///
+    /// Hits and misses count `get_fingerprint` lookups; they survive `clear_cache`.
+    fn cache_stats(&self) -> (u64, u64, usize) {
+        self.cache.stats()
+    }
+
     /// Get database statistics
     fn get_stats(&self) -> PyResult<HashMap<String, i64>> {
         self.get_stats_internal().map_err(|e| {
@@ -380,175 +1251,678 @@ impl PytestDiffDatabase {
             })
     }
 
-    /// Clear all baseline fingerprints
-    fn clear_baseline(&mut self) -> PyResult<()> {
-        let conn = self.conn.write();
-        conn.execute("DELETE FROM baseline_fp", []).map_err(|e| {
-            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to clear baseline: {}", e))
-        })?;
-        Ok(())
+    /// All parse settings of this database, to pass to `FingerprintCache`,
+    /// `calculate_fingerprint` and the other functions that fingerprint
+    /// sources, so their checksums line up with the stored ones
+    fn get_parse_options(&self) -> PyResult<ParseOptions> {
+        self.get_parse_options_internal().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to get parse options: {}", e))
+        })
     }
 
-    /// Import baseline and test execution data from another database file using ATTACH DATABASE.
-    ///
-    /// Bulk-copies `baseline_fp`, `environment`, `file_fp`, `test_execution`, and
-    /// `test_execution_file_fp` rows from `source_db_path` into the local database,
-    /// replacing any existing data. Returns an `ImportResult` with counts.
-    fn import_baseline_from(&mut self, source_db_path: &str) -> PyResult<ImportResult> {
-        self.import_baseline_from_internal(source_db_path)
+    /// Checksum normalization mode of this database ("exact", "ignore_docstrings"
+    /// or "ignore_blank_lines")
+    fn get_normalization_mode(&self) -> PyResult<String> {
+        self.get_normalization()
+            .map(|mode| mode.as_str().to_string())
             .map_err(|e| {
                 pyo3::exceptions::PyRuntimeError::new_err(format!(
-                    "Failed to import baseline: {}",
+                    "Failed to get normalization mode: {}",
                     e
                 ))
             })
     }
 
-    /// Merge baseline and test execution data from another database file using ATTACH DATABASE.
+    /// Whether baselines store a name hash with each block checksum
+    fn get_block_name_hashes(&self) -> PyResult<bool> {
+        self.block_name_hashes_enabled().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get block name hashes setting: {}",
+                e
+            ))
+        })
+    }
+
+    /// Store a `block_name_hash` of each block's name next to its checksum in
+    /// baselines saved from now on, so changed checksums can be traced back to
+    /// named blocks from the baseline alone (see `get_baseline_name_hashes`)
+    fn set_block_name_hashes(&self, enabled: bool) -> PyResult<()> {
+        self.set_block_name_hashes_internal(enabled).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to set block name hashes setting: {}",
+                e
+            ))
+        })
+    }
+
+    /// Whether baseline saves re-read each fingerprinted file to catch torn reads
+    fn get_verify_reads(&self) -> PyResult<bool> {
+        self.verify_reads_enabled().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get verify reads setting: {}",
+                e
+            ))
+        })
+    }
+
+    /// Make `save_baseline` read and hash each file it fingerprints a second
+    /// time; a file whose content changed in between (e.g. still being
+    /// written) is reported as unstable and keeps its previous baseline entry,
+    /// instead of storing a torn read
+    fn set_verify_reads(&self, enabled: bool) -> PyResult<()> {
+        self.set_verify_reads_internal(enabled).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to set verify reads setting: {}",
+                e
+            ))
+        })
+    }
+
+    /// `(checksum, name_hash)` of each block of a baseline file, in block order
     ///
-    /// Unlike `import_baseline_from`, this does NOT clear existing baselines first.
-    /// Uses INSERT OR REPLACE to accumulate baselines from multiple sources,
-    /// allowing incremental merging of databases from parallel CI jobs.
-    /// Returns an `ImportResult` with counts.
-    fn merge_baseline_from(&mut self, source_db_path: &str) -> PyResult<ImportResult> {
-        self.merge_baseline_from_internal(source_db_path)
+    /// `None` if the file isn't in the baseline or was saved without name hashes.
+    fn get_baseline_name_hashes(&self, filename: &str) -> PyResult<Option<Vec<(i32, u32)>>> {
+        self.get_baseline_name_hashes_internal(filename)
             .map_err(|e| {
                 pyo3::exceptions::PyRuntimeError::new_err(format!(
-                    "Failed to merge baseline: {}",
+                    "Failed to get baseline name hashes: {}",
                     e
                 ))
             })
     }
 
-    /// Read a metadata value from an external database file without importing it.
-    ///
-    /// Useful for checking metadata (e.g., baseline_commit) before merging.
-    fn get_external_metadata(&self, source_db_path: &str, key: &str) -> PyResult<Option<String>> {
-        self.get_external_metadata_internal(source_db_path, key)
+    /// Block granularity of this database ("definitions" or "branches")
+    fn get_block_granularity(&self) -> PyResult<String> {
+        self.get_granularity()
+            .map(|mode| mode.as_str().to_string())
             .map_err(|e| {
                 pyo3::exceptions::PyRuntimeError::new_err(format!(
-                    "Failed to get external metadata: {}",
+                    "Failed to get block granularity: {}",
                     e
                 ))
             })
     }
 
-    /// Store a metadata key-value pair (INSERT OR REPLACE)
-    fn set_metadata(&self, key: &str, value: &str) -> PyResult<()> {
-        self.set_metadata_internal(key, value).map_err(|e| {
-            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to set metadata: {}", e))
+    /// Line count above which functions are split into part blocks (None = never)
+    fn get_max_function_lines(&self) -> PyResult<Option<usize>> {
+        self.get_max_function_lines_internal().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get max function lines: {}",
+                e
+            ))
         })
     }
 
-    /// Retrieve a metadata value by key, or None if not found
-    fn get_metadata(&self, key: &str) -> PyResult<Option<String>> {
-        self.get_metadata_internal(key).map_err(|e| {
-            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to get metadata: {}", e))
-        })
+    /// Split functions longer than `max_lines` lines into part blocks of
+    /// consecutive statements, so tests only depend on the parts they ran
+    /// (None disables splitting)
+    ///
+    /// Only allowed while the database holds no baseline or test data.
+    fn set_max_function_lines(&self, max_lines: Option<usize>) -> PyResult<()> {
+        self.set_max_function_lines_internal(max_lines)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to set max function lines: {}",
+                    e
+                ))
+            })
     }
 
-    /// Get filenames that a test depends on (via test_execution_file_fp)
-    fn get_test_dependencies(&self, test_name: &str) -> PyResult<Vec<String>> {
-        self.get_test_dependencies_internal(test_name).map_err(|e| {
+    /// Deepest nesting level that gets its own block (None = unlimited)
+    fn get_max_block_depth(&self) -> PyResult<Option<usize>> {
+        self.get_max_block_depth_internal().map_err(|e| {
             pyo3::exceptions::PyRuntimeError::new_err(format!(
-                "Failed to get test dependencies: {}",
+                "Failed to get max block depth: {}",
                 e
             ))
         })
     }
 
-    /// Get test names that depend on a file (via test_execution_file_fp)
-    fn get_file_dependents(&self, filename: &str) -> PyResult<Vec<String>> {
-        self.get_file_dependents_internal(filename).map_err(|e| {
+    /// Fold definitions nested deeper than `max_depth` into their enclosing
+    /// block (0 keeps only the module block, 1 adds top-level functions and
+    /// classes, ...), trading selection precision for fewer, coarser blocks
+    /// (None = unlimited)
+    ///
+    /// Only allowed while the database holds no baseline or test data.
+    fn set_max_block_depth(&self, max_depth: Option<usize>) -> PyResult<()> {
+        self.set_max_block_depth_internal(max_depth).map_err(|e| {
             pyo3::exceptions::PyRuntimeError::new_err(format!(
-                "Failed to get file dependents: {}",
+                "Failed to set max block depth: {}",
                 e
             ))
         })
     }
 
-    /// Close the database and checkpoint WAL to remove -wal and -shm files
-    fn close(&self) -> PyResult<()> {
-        let conn = self.conn.write();
-        // Checkpoint WAL to merge it into main database file
-        // TRUNCATE mode will truncate the WAL file to zero bytes
-        conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
-            .map_err(|e| {
-                pyo3::exceptions::PyRuntimeError::new_err(format!(
-                    "Failed to checkpoint WAL: {}",
-                    e
-                ))
-            })?;
-        Ok(())
+    /// Whether lambdas at module and class level get their own blocks
+    fn get_lambda_blocks(&self) -> PyResult<bool> {
+        self.lambda_blocks_enabled().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get lambda blocks setting: {}",
+                e
+            ))
+        })
     }
-}
-
-// Internal implementation methods
-impl PytestDiffDatabase {
-    fn save_test_execution_internal(
-        &mut self,
-        test_name: &str,
-        fingerprints: Vec<Fingerprint>,
-        duration: f64,
-        failed: bool,
-        python_version: &str,
-    ) -> Result<()> {
-        // Get or create environment
-        let env_id = self.get_or_create_environment("default", python_version)?;
 
-        let mut conn = self.conn.write();
+    /// Give `name = lambda ...` assignments at module and class level, and the
+    /// lambdas nested in expressions there, a block of their own, so editing a
+    /// lambda only reselects the tests that called it (off by default: noisy)
+    ///
+    /// Only allowed while the database holds no baseline or test data.
+    fn set_lambda_blocks(&self, enabled: bool) -> PyResult<()> {
+        self.set_lambda_blocks_internal(enabled).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to set lambda blocks setting: {}",
+                e
+            ))
+        })
+    }
 
-        // Use BEGIN IMMEDIATE for fail-fast on write conflicts (pytest-xdist compatibility)
-        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    /// Name patterns whose code is left out of every checksum
+    fn get_ignored_blocks(&self) -> PyResult<Vec<String>> {
+        self.get_ignored_blocks_internal().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get ignored blocks: {}",
+                e
+            ))
+        })
+    }
 
-        // Delete previous executions for this test in this environment
-        // This keeps the database from growing unbounded
-        tx.execute(
-            "DELETE FROM test_execution
-             WHERE environment_id = ?1 AND test_name = ?2",
-            params![env_id, test_name],
-        )
-        .context("Failed to delete old test execution")?;
+    /// Leave code out of every checksum so edits to it never select tests
+    ///
+    /// Patterns (`*` and `?` wildcards) match qualified definition names
+    /// (`Class.method`), module- and class-level assignment targets
+    /// (`__version__`) and `if NAME:` conditions (`TYPE_CHECKING`). Only
+    /// allowed while the database holds no baseline or test data.
+    fn set_ignored_blocks(&self, patterns: Vec<String>) -> PyResult<()> {
+        self.set_ignored_blocks_internal(&patterns).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to set ignored blocks: {}",
+                e
+            ))
+        })
+    }
 
-        // Insert test execution
-        tx.execute(
-            "INSERT INTO test_execution (environment_id, test_name, duration, failed, forced)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![env_id, test_name, duration, if failed { 1 } else { 0 }, 0],
-        )
-        .context("Failed to insert test execution")?;
+    /// Set the block granularity ("definitions" or "branches")
+    ///
+    /// Only allowed while the database holds no baseline or test data.
+    fn set_block_granularity(&self, mode: &str) -> PyResult<()> {
+        let granularity = mode
+            .parse()
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{}", e)))?;
+        self.set_granularity(granularity).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to set block granularity: {}",
+                e
+            ))
+        })
+    }
 
-        let test_execution_id = tx.last_insert_rowid();
+    /// Executions kept per test and environment (None = unlimited)
+    fn get_test_history_depth(&self) -> PyResult<Option<usize>> {
+        self.get_max_test_history().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get test history depth: {}",
+                e
+            ))
+        })
+    }
 
-        // Insert fingerprints and link to test
-        for fp in fingerprints {
-            let fp_id = self.get_or_create_fingerprint_in_tx(&tx, &fp)?;
+    /// Keep at most `max_history` executions per test and environment
+    ///
+    /// `None` keeps every execution. The default is 1 (latest run only).
+    #[pyo3(signature = (max_history))]
+    fn set_test_history_depth(&self, max_history: Option<usize>) -> PyResult<()> {
+        self.set_max_test_history(max_history).map_err(|e| {
+            pyo3::exceptions::PyValueError::new_err(format!(
+                "Failed to set test history depth: {}",
+                e
+            ))
+        })
+    }
 
-            tx.execute(
-                "INSERT INTO test_execution_file_fp (test_execution_id, fingerprint_id)
-                 VALUES (?1, ?2)",
-                params![test_execution_id, fp_id],
-            )
-            .context("Failed to link test to fingerprint")?;
-        }
+    /// pytest-difftest core version that last saved the baseline
+    ///
+    /// Returns None for databases whose baseline was never saved, or was
+    /// saved before versions were recorded.
+    fn get_writer_version(&self) -> PyResult<Option<String>> {
+        self.get_writer_version_internal().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get writer version: {}",
+                e
+            ))
+        })
+    }
 
-        tx.commit().context("Failed to commit transaction")?;
+    /// File extensions discovered and fingerprinted (`[".py"]` by default)
+    fn get_tracked_file_extensions(&self) -> PyResult<Vec<String>> {
+        self.get_tracked_extensions().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get tracked extensions: {}",
+                e
+            ))
+        })
+    }
 
-        Ok(())
+    /// Track files with extra extensions besides `.py` (e.g. `.py.jinja`)
+    fn set_tracked_file_extensions(&self, extensions: Vec<String>) -> PyResult<()> {
+        self.set_tracked_extensions(&extensions).map_err(|e| {
+            pyo3::exceptions::PyValueError::new_err(format!(
+                "Failed to set tracked extensions: {}",
+                e
+            ))
+        })
     }
 
-    fn get_or_create_fingerprint_in_tx(
-        &self,
-        tx: &rusqlite::Transaction,
-        fp: &Fingerprint,
+    /// Recompute stored checksums under a new normalization mode
+    ///
+    /// Re-reads baseline files from `project_root` (tests are not re-run).
+    /// Files changed since the baseline are skipped, since recomputing them
+    /// would silently absorb the change; so are missing and unparseable ones.
+    /// Fails while named baselines exist: their files aren't in `project_root`,
+    /// so they must be cleared and saved again under the new mode.
+    /// Recorded test dependencies on the current version of each file are
+    /// remapped block by block.
+    /// Returns the number of baseline entries recomputed and the skipped files.
+    fn renormalize_baseline(
+        &mut self,
+        project_root: &str,
+        mode: &str,
+    ) -> PyResult<RenormalizeResult> {
+        let mode = crate::fingerprint::parse_normalization(mode)?;
+        self.renormalize_baseline_internal(project_root, mode)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to renormalize baseline: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Export a baseline and its parse settings as a JSON snapshot
+    ///
+    /// `detect_changes_from_snapshot` compares a project against it without
+    /// opening a database. Filenames are exported as forward-slash paths
+    /// relative to the project root, whatever OS wrote the database, so the
+    /// snapshot resolves against the project root of any runner. Pass
+    /// `project_root` to also strip it from filenames stored absolute.
+    #[pyo3(signature = (baseline_name=DEFAULT_BASELINE, project_root=None))]
+    fn export_json(&self, baseline_name: &str, project_root: Option<&str>) -> PyResult<String> {
+        self.baseline_snapshot(baseline_name, project_root)
+            .and_then(|snapshot| Ok(serde_json::to_string(&snapshot)?))
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to export baseline: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Export recorded test dependencies as CSV for spreadsheet analysis
+    ///
+    /// Writes `dest` with a `test_name,duration,failed,filename,block_count`
+    /// header and one row per file each recorded execution depends on, where
+    /// `block_count` is the number of that file's blocks the test depends on.
+    /// Returns the number of rows written.
+    fn export_csv(&self, dest: &str) -> PyResult<usize> {
+        self.export_csv_internal(dest).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to export CSV: {}", e))
+        })
+    }
+
+    /// Descriptive metadata of a baseline's latest save
+    ///
+    /// Keys present only when recorded: `label` (see `save_baseline`),
+    /// `writer_version`, `commit` and `scope` (a JSON list of paths). All but
+    /// `label` are shared by every baseline in the database.
+    #[pyo3(signature = (baseline_name=DEFAULT_BASELINE))]
+    fn get_baseline_metadata(&self, baseline_name: &str) -> PyResult<HashMap<String, String>> {
+        self.get_baseline_metadata_internal(baseline_name)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to get baseline metadata: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Rebuild a baseline from scratch, keeping test history
+    ///
+    /// Recovery for a baseline that can't be read or trusted any more (corrupt
+    /// rows, checksums written by an incompatible version). Only the rows of
+    /// `baseline_name` are dropped; recorded test executions stay, so selection
+    /// keeps working. The baseline is re-saved from `project_root` under the
+    /// stored scope and its label, and the old rows are only replaced once that
+    /// save succeeded. Returns the number of files saved.
+    #[pyo3(signature = (project_root, baseline_name=DEFAULT_BASELINE))]
+    fn recover_baseline(&mut self, project_root: &str, baseline_name: &str) -> PyResult<usize> {
+        self.recover_baseline_internal(project_root, baseline_name)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to recover baseline: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Check the baseline called `baseline_name` against the files under
+    /// `project_root`
+    ///
+    /// Counts baseline files that still match their recorded hash, that were
+    /// changed, and that are gone. Read-only: nothing in the database changes.
+    #[pyo3(signature = (project_root, baseline_name=DEFAULT_BASELINE))]
+    fn validate_baseline(
+        &self,
+        project_root: &str,
+        baseline_name: &str,
+    ) -> PyResult<ValidationReport> {
+        self.validate_baseline_internal(project_root, baseline_name)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to validate baseline: {}",
+                    e
+                ))
+            })
+    }
+
+    /// List the files tracked in the baseline called `baseline_name`, sorted by name
+    #[pyo3(signature = (baseline_name=DEFAULT_BASELINE))]
+    fn list_baseline_files(&self, baseline_name: &str) -> PyResult<Vec<String>> {
+        self.list_baseline_files_internal(baseline_name)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to list baseline files: {}",
+                    e
+                ))
+            })
+    }
+
+    /// List the names of all non-empty baselines, sorted by name
+    fn list_baselines(&self) -> PyResult<Vec<String>> {
+        self.list_baselines_internal().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to list baselines: {}", e))
+        })
+    }
+
+    /// Clear all fingerprints of a baseline (the default one unless named)
+    #[pyo3(signature = (baseline_name=DEFAULT_BASELINE))]
+    fn clear_baseline(&mut self, baseline_name: &str) -> PyResult<()> {
+        let result = self.conn().and_then(|conn| {
+            let conn = conn.write();
+            let deleted = if baseline_name == DEFAULT_BASELINE {
+                conn.execute("DELETE FROM baseline_fp", [])
+            } else {
+                conn.execute(
+                    "DELETE FROM named_baseline_fp WHERE baseline_name = ?1",
+                    params![baseline_name],
+                )
+            };
+            Ok(deleted?)
+        });
+        result.map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to clear baseline: {}", e))
+        })?;
+        Ok(())
+    }
+
+    /// Delete recorded fingerprints that no test execution links to
+    ///
+    /// Pruning old executions (see `set_max_test_history`) leaves the file
+    /// versions only they used behind. Returns the number of rows deleted.
+    fn prune_orphan_fingerprints(&mut self) -> PyResult<usize> {
+        self.prune_orphan_fingerprints_internal().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to prune orphan fingerprints: {}",
+                e
+            ))
+        })
+    }
+
+    /// Import baseline and test execution data from another database file using ATTACH DATABASE.
+    ///
+    /// Bulk-copies `baseline_fp`, `environment`, `file_fp`, `test_execution`, and
+    /// `test_execution_file_fp` rows from `source_db_path` into the local database,
+    /// replacing any existing data. Returns an `ImportResult` with counts.
+    fn import_baseline_from(&mut self, source_db_path: &str) -> PyResult<ImportResult> {
+        self.import_baseline_from_internal(source_db_path)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to import baseline: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Merge baseline and test execution data from another database file using ATTACH DATABASE.
+    ///
+    /// Unlike `import_baseline_from`, this does NOT clear existing baselines first.
+    /// Uses INSERT OR REPLACE to accumulate baselines from multiple sources,
+    /// allowing incremental merging of databases from parallel CI jobs.
+    /// Returns an `ImportResult` with counts.
+    fn merge_baseline_from(&mut self, source_db_path: &str) -> PyResult<ImportResult> {
+        self.merge_baseline_from_internal(source_db_path)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to merge baseline: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Read a metadata value from an external database file without importing it.
+    ///
+    /// Useful for checking metadata (e.g., baseline_commit) before merging.
+    fn get_external_metadata(&self, source_db_path: &str, key: &str) -> PyResult<Option<String>> {
+        self.get_external_metadata_internal(source_db_path, key)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to get external metadata: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Store a metadata key-value pair (INSERT OR REPLACE)
+    fn set_metadata(&self, key: &str, value: &str) -> PyResult<()> {
+        self.set_metadata_internal(key, value).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to set metadata: {}", e))
+        })
+    }
+
+    /// Retrieve a metadata value by key, or None if not found
+    fn get_metadata(&self, key: &str) -> PyResult<Option<String>> {
+        self.get_metadata_internal(key).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to get metadata: {}", e))
+        })
+    }
+
+    /// Get filenames that a test depends on (via test_execution_file_fp)
+    fn get_test_dependencies(&self, test_name: &str) -> PyResult<Vec<String>> {
+        self.get_test_dependencies_internal(test_name).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get test dependencies: {}",
+                e
+            ))
+        })
+    }
+
+    /// Get test names that depend on a file (via test_execution_file_fp)
+    fn get_file_dependents(&self, filename: &str) -> PyResult<Vec<String>> {
+        self.get_file_dependents_internal(filename).map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Failed to get file dependents: {}",
+                e
+            ))
+        })
+    }
+
+    /// Close the database and checkpoint WAL to remove -wal and -shm files
+    fn close(&self) -> PyResult<()> {
+        // TRUNCATE mode will truncate the WAL file to zero bytes
+        self.close_and_checkpoint().map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to close database: {}", e))
+        })
+    }
+}
+
+// Internal implementation methods
+impl PytestDiffDatabase {
+    fn save_test_execution_internal(
+        &mut self,
+        test_name: &str,
+        fingerprints: Vec<Fingerprint>,
+        duration: f64,
+        failed: bool,
+        python_version: &str,
+        forced: bool,
+    ) -> Result<()> {
+        let record = TestExecution {
+            test_name: test_name.to_string(),
+            duration,
+            failed,
+            forced,
+            fingerprints,
+        };
+        self.save_test_executions_internal(&[record], python_version, 1)
+    }
+
+    /// Save test executions, one transaction per `batch_size` records
+    ///
+    /// Fingerprints shared between records of a batch are looked up or
+    /// inserted once. A failure rolls back the batch holding it; the write
+    /// lock is released between batches.
+    fn save_test_executions_internal(
+        &mut self,
+        records: &[TestExecution],
+        python_version: &str,
+        batch_size: usize,
+    ) -> Result<()> {
+        if batch_size == 0 {
+            anyhow::bail!("batch_size must be at least 1");
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        // Get or create environment
+        let env_id = self.get_or_create_environment("default", python_version)?;
+        // SQLite treats a negative LIMIT as no limit
+        let keep = self
+            .get_max_test_history()?
+            .map_or(-1, |n| i64::try_from(n).unwrap_or(i64::MAX));
+
+        for batch in records.chunks(batch_size) {
+            self.save_test_execution_batch(batch, env_id, keep)?;
+        }
+        Ok(())
+    }
+
+    fn prune_orphan_fingerprints_internal(&mut self) -> Result<usize> {
+        let deleted = self.conn()?.write().execute(
+            "DELETE FROM file_fp
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM test_execution_file_fp teff
+                 WHERE teff.fingerprint_id = file_fp.id
+             )",
+            [],
+        )?;
+        // Cached lookups may point at deleted rows
+        self.cache.clear();
+        Ok(deleted)
+    }
+
+    /// Save test executions in a single transaction
+    ///
+    /// `keep` is the number of executions kept per test (negative: all).
+    fn save_test_execution_batch(
+        &self,
+        records: &[TestExecution],
+        env_id: i64,
+        keep: i64,
+    ) -> Result<()> {
+        let mut conn = self.conn()?.write();
+
+        // Use BEGIN IMMEDIATE for fail-fast on write conflicts (pytest-xdist compatibility)
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+
+        {
+            let mut prune_stmt = tx.prepare_cached(
+                "DELETE FROM test_execution
+                 WHERE environment_id = ?1 AND test_name = ?2
+                   AND id NOT IN (
+                       SELECT id FROM test_execution
+                       WHERE environment_id = ?1 AND test_name = ?2
+                       ORDER BY id DESC
+                       LIMIT ?3
+                   )",
+            )?;
+            let mut insert_stmt = tx.prepare_cached(
+                "INSERT INTO test_execution (environment_id, test_name, duration, failed, forced)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            let mut link_stmt = tx.prepare_cached(
+                "INSERT INTO test_execution_file_fp (test_execution_id, fingerprint_id)
+                 VALUES (?1, ?2)",
+            )?;
+            let mut fp_ids: HashMap<(&str, &str, Vec<u8>), i64> = HashMap::new();
+
+            for record in records {
+                let test_execution_id = insert_stmt
+                    .insert(params![
+                        env_id,
+                        &record.test_name,
+                        record.duration,
+                        record.failed,
+                        record.forced
+                    ])
+                    .context("Failed to insert test execution")?;
+
+                // Insert fingerprints and link to test
+                for fp in &record.fingerprints {
+                    let key = (
+                        fp.filename.as_str(),
+                        fp.file_hash.as_str(),
+                        serialize_checksums(&fp.checksums),
+                    );
+                    let fp_id = match fp_ids.get(&key) {
+                        Some(&id) => id,
+                        None => {
+                            let id = self.get_or_create_fingerprint_in_tx(&tx, fp)?;
+                            fp_ids.insert(key, id);
+                            id
+                        }
+                    };
+
+                    link_stmt
+                        .execute(params![test_execution_id, fp_id])
+                        .context("Failed to link test to fingerprint")?;
+                }
+
+                // Drop executions beyond the history cap for this test in this
+                // environment. This keeps the database from growing unbounded
+                prune_stmt
+                    .execute(params![env_id, &record.test_name, keep])
+                    .context("Failed to delete old test executions")?;
+            }
+        }
+
+        tx.commit().context("Failed to commit transaction")?;
+
+        Ok(())
+    }
+
+    fn get_or_create_fingerprint_in_tx(
+        &self,
+        tx: &rusqlite::Transaction,
+        fp: &Fingerprint,
     ) -> Result<i64> {
         let checksums_blob = serialize_checksums(&fp.checksums);
+        let filename = normalize_filename(&fp.filename);
 
         let existing_id: Option<i64> = tx
             .query_row(
                 "SELECT id FROM file_fp
                  WHERE filename = ?1 AND fsha = ?2 AND method_checksums = ?3",
-                params![&fp.filename, &fp.file_hash, checksums_blob],
+                params![filename, &fp.file_hash, checksums_blob],
                 |row| row.get(0),
             )
             .optional()?;
@@ -563,7 +1937,7 @@ impl PytestDiffDatabase {
             tx.execute(
                 "INSERT INTO file_fp (filename, method_checksums, mtime, fsha)
                  VALUES (?1, ?2, ?3, ?4)",
-                params![&fp.filename, checksums_blob, fp.mtime, &fp.file_hash],
+                params![filename, checksums_blob, fp.mtime, &fp.file_hash],
             )?;
             Ok(tx.last_insert_rowid())
         }
@@ -573,11 +1947,29 @@ impl PytestDiffDatabase {
         &self,
         changed_blocks: HashMap<String, Vec<i32>>,
     ) -> Result<Vec<String>> {
+        // BTreeSet keeps the result deduplicated and sorted by test name
+        let affected_tests: BTreeSet<String> = self
+            .affected_tests_by_file(changed_blocks)?
+            .into_values()
+            .flatten()
+            .collect();
+        Ok(affected_tests.into_iter().collect())
+    }
+
+    /// Tests affected by each changed file, matched on changed block checksums
+    fn affected_tests_by_file(
+        &self,
+        changed_blocks: HashMap<String, Vec<i32>>,
+    ) -> Result<HashMap<String, BTreeSet<String>>> {
         if changed_blocks.is_empty() {
-            return Ok(vec![]);
+            return Ok(HashMap::new());
         }
 
-        let conn = self.conn.read();
+        let changed_blocks: HashMap<String, Vec<i32>> = changed_blocks
+            .into_iter()
+            .map(|(filename, checksums)| (normalize_filename(&filename).into_owned(), checksums))
+            .collect();
+        let conn = self.conn()?.read();
 
         // Build a single query for all changed files (more efficient than N queries)
         let filenames: Vec<&str> = changed_blocks.keys().map(|s| s.as_str()).collect();
@@ -614,7 +2006,7 @@ impl PytestDiffDatabase {
         // Cache deserialized blobs to avoid re-deserializing the same blob
         let mut blob_cache: HashMap<Vec<u8>, Vec<i32>> = HashMap::new();
 
-        let mut affected_tests: HashSet<String> = HashSet::new();
+        let mut affected_tests: HashMap<String, BTreeSet<String>> = HashMap::new();
 
         let rows = stmt.query_map(params.as_slice(), |row| {
             let test_name: String = row.get(0)?;
@@ -627,48 +2019,245 @@ impl PytestDiffDatabase {
             let (test_name, filename, blob) = row_result?;
 
             // Get or compute deserialized checksums (cache for efficiency)
-            let file_checksums = blob_cache
-                .entry(blob.clone())
-                .or_insert_with(|| deserialize_checksums(&blob));
+            let file_checksums = match blob_cache.entry(blob) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let checksums = deserialize_checksums(entry.key())?;
+                    entry.insert(checksums)
+                }
+            };
 
             // Check if any changed checksum for this file matches
             if let Some(changed_set) = changed_checksum_sets.get(filename.as_str()) {
                 if file_checksums.iter().any(|c| changed_set.contains(c)) {
-                    affected_tests.insert(test_name);
+                    affected_tests
+                        .entry(filename)
+                        .or_default()
+                        .insert(test_name);
                 }
             }
         }
 
-        // Convert HashSet to sorted Vec for consistent ordering
-        let mut result: Vec<String> = affected_tests.into_iter().collect();
-        result.sort();
+        Ok(affected_tests)
+    }
 
-        Ok(result)
+    fn get_affected_tests_for_internal(&self, changed: &ChangedFiles) -> Result<Vec<String>> {
+        let affected: BTreeSet<String> = self
+            .affected_tests_by_changed_file(changed)?
+            .into_values()
+            .flatten()
+            .collect();
+        Ok(affected.into_iter().collect())
     }
 
-    fn get_recorded_tests_internal(&self) -> Result<Vec<String>> {
-        let conn = self.conn.read();
-        let mut stmt = conn.prepare("SELECT DISTINCT test_name FROM test_execution")?;
-        let rows = stmt.query_map([], |row| row.get(0))?;
-        let mut tests: Vec<String> = rows.collect::<std::result::Result<_, _>>()?;
-        tests.sort();
-        Ok(tests)
+    fn get_affected_test_counts_internal(
+        &self,
+        changed: &ChangedFiles,
+    ) -> Result<HashMap<String, usize>> {
+        let mut counts: HashMap<String, usize> = changed
+            .modified
+            .iter()
+            .chain(changed.changed_blocks.keys())
+            .map(|file| (file.clone(), 0))
+            .collect();
+        for (file, tests) in self.affected_tests_by_changed_file(changed)? {
+            counts.insert(file, tests.len());
+        }
+        Ok(counts)
     }
 
-    fn get_stats_internal(&self) -> Result<HashMap<String, i64>> {
-        let conn = self.conn.read();
-        let mut stats = HashMap::new();
+    /// Tests selected by each file of a `detect_changes` result
+    fn affected_tests_by_changed_file(
+        &self,
+        changed: &ChangedFiles,
+    ) -> Result<HashMap<String, BTreeSet<String>>> {
+        // Only new files are reported with "added" block changes
+        let new_files: BTreeSet<&str> = changed
+            .block_changes
+            .iter()
+            .filter(|(_, _, kind)| kind == "added")
+            .map(|(file, _, _)| file.as_str())
+            .collect();
 
-        // Count tests
-        let test_count: i64 =
-            conn.query_row("SELECT COUNT(*) FROM test_execution", [], |row| row.get(0))?;
-        stats.insert("test_count".to_string(), test_count);
+        let block_level = changed
+            .changed_blocks
+            .iter()
+            .filter(|(file, _)| !new_files.contains(file.as_str()))
+            .map(|(file, blocks)| (file.clone(), blocks.clone()))
+            .collect();
+        let mut affected = self.affected_tests_by_file(block_level)?;
+        for file in new_files {
+            let tests = self.get_file_dependents_internal(file)?;
+            if !tests.is_empty() {
+                affected.insert(file.to_string(), tests.into_iter().collect());
+            }
+        }
+        Ok(affected)
+    }
 
-        // Count files
-        let file_count: i64 =
-            conn.query_row("SELECT COUNT(DISTINCT filename) FROM file_fp", [], |row| {
-                row.get(0)
-            })?;
+    fn get_tests_for_files_internal(&self, files: &[String]) -> Result<Vec<String>> {
+        if files.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let conn = self.conn()?.read();
+        let placeholders: String = (1..=files.len())
+            .map(|i| format!("?{}", i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let query = format!(
+            "SELECT DISTINCT te.test_name
+             FROM test_execution te
+             JOIN test_execution_file_fp teff ON te.id = teff.test_execution_id
+             JOIN file_fp fp ON teff.fingerprint_id = fp.id
+             WHERE fp.filename IN ({})
+             ORDER BY te.test_name",
+            placeholders
+        );
+        let mut stmt = conn.prepare(&query)?;
+        let files = files.iter().map(|f| normalize_filename(f));
+        let rows = stmt.query_map(rusqlite::params_from_iter(files), |row| row.get(0))?;
+        let tests: Vec<String> = rows.collect::<std::result::Result<_, _>>()?;
+        Ok(tests)
+    }
+
+    fn get_tests_for_checksum_internal(
+        &self,
+        filename: &str,
+        checksum: i32,
+    ) -> Result<Vec<String>> {
+        let filename = normalize_filename(filename).into_owned();
+        let tests = self
+            .affected_tests_by_file(HashMap::from([(filename, vec![checksum])]))?
+            .into_values()
+            .flatten()
+            .collect();
+        Ok(tests)
+    }
+
+    pub fn estimate_selected_duration_internal(
+        &self,
+        selected_tests: &[String],
+        environment: Option<i64>,
+    ) -> Result<(f64, usize)> {
+        let durations = self.latest_durations(selected_tests, environment)?;
+        let unique: HashSet<&String> = selected_tests.iter().collect();
+        let total = unique.iter().filter_map(|test| durations.get(*test)).sum();
+        Ok((total, unique.len() - durations.len()))
+    }
+
+    /// Latest recorded duration of each of `test_names` that has one, in
+    /// `environment` (an ID) or any environment if `None`
+    fn latest_durations(
+        &self,
+        test_names: &[String],
+        environment: Option<i64>,
+    ) -> Result<HashMap<String, f64>> {
+        let conn = self.conn()?.read();
+        let mut stmt = conn.prepare(
+            "SELECT duration FROM test_execution
+             WHERE test_name = ?1 AND (?2 IS NULL OR environment_id = ?2)
+             ORDER BY id DESC
+             LIMIT 1",
+        )?;
+        let mut durations = HashMap::new();
+        for test in test_names {
+            let duration: Option<f64> = stmt
+                .query_row(params![test, environment], |row| row.get(0))
+                .optional()
+                .context("Failed to query test duration")?
+                .flatten();
+            if let Some(duration) = duration {
+                durations.insert(test.clone(), duration);
+            }
+        }
+        Ok(durations)
+    }
+
+    fn get_recorded_tests_internal(&self) -> Result<Vec<String>> {
+        let conn = self.conn()?.read();
+        let mut stmt = conn.prepare("SELECT DISTINCT test_name FROM test_execution")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        let mut tests: Vec<String> = rows.collect::<std::result::Result<_, _>>()?;
+        tests.sort();
+        Ok(tests)
+    }
+
+    pub(crate) fn get_previous_failures_internal(
+        &self,
+        environment: Option<i64>,
+    ) -> Result<Vec<String>> {
+        let conn = self.conn()?.read();
+        let mut stmt = conn.prepare(
+            "SELECT te.test_name FROM test_execution te
+             WHERE te.failed = 1
+               AND te.id = (
+                   SELECT MAX(latest.id) FROM test_execution latest
+                   WHERE latest.test_name = te.test_name
+                     AND (?1 IS NULL OR latest.environment_id = ?1)
+               )
+             ORDER BY te.test_name",
+        )?;
+        let rows = stmt.query_map(params![environment], |row| row.get(0))?;
+        let tests: Vec<String> = rows.collect::<std::result::Result<_, _>>()?;
+        Ok(tests)
+    }
+
+    fn list_environments_internal(&self) -> Result<Vec<(i64, String, String)>> {
+        let conn = self.conn()?.read();
+        let mut stmt = conn.prepare(
+            "SELECT id, COALESCE(environment_name, ''), COALESCE(python_version, '')
+             FROM environment ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        let environments = rows.collect::<std::result::Result<_, _>>()?;
+        Ok(environments)
+    }
+
+    fn delete_environment_internal(&self, environment_id: i64) -> Result<bool> {
+        let mut conn = self.conn()?.write();
+        let tx = conn.transaction()?;
+        // Executions and their fingerprint links cascade (foreign_keys is on)
+        let deleted = tx.execute(
+            "DELETE FROM environment WHERE id = ?1",
+            params![environment_id],
+        )?;
+        // Fingerprints only the deleted executions depended on
+        tx.execute(
+            "DELETE FROM file_fp
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM test_execution_file_fp teff
+                 WHERE teff.fingerprint_id = file_fp.id
+             )",
+            [],
+        )?;
+        tx.commit()?;
+        drop(conn);
+        // Cached lookups may point at deleted rows
+        self.cache.clear();
+
+        // The next save must not reuse a cached id of the deleted environment
+        let mut cached_id = self.current_environment_id.write();
+        if *cached_id == Some(environment_id) {
+            *cached_id = None;
+        }
+        Ok(deleted > 0)
+    }
+
+    pub(crate) fn get_stats_internal(&self) -> Result<HashMap<String, i64>> {
+        let conn = self.conn()?.read();
+        let mut stats = HashMap::new();
+
+        // Count tests
+        let test_count: i64 =
+            conn.query_row("SELECT COUNT(*) FROM test_execution", [], |row| row.get(0))?;
+        stats.insert("test_count".to_string(), test_count);
+
+        // Count files
+        let file_count: i64 =
+            conn.query_row("SELECT COUNT(DISTINCT filename) FROM file_fp", [], |row| {
+                row.get(0)
+            })?;
         stats.insert("file_count".to_string(), file_count);
 
         // Count fingerprints
@@ -684,14 +2273,20 @@ impl PytestDiffDatabase {
     }
 
     pub fn save_baseline_fingerprint_internal(&mut self, fp: Fingerprint) -> Result<()> {
-        let conn = self.conn.write();
-        let checksums_blob = serialize_checksums(&fp.checksums);
+        let named = self.block_name_hashes_enabled()?;
+        let conn = self.conn()?.write();
+        let checksums_blob = baseline_checksums_blob(&fp, named);
 
         // Use INSERT OR REPLACE to update existing baseline
         conn.execute(
             "INSERT OR REPLACE INTO baseline_fp (filename, method_checksums, mtime, fsha)
              VALUES (?1, ?2, ?3, ?4)",
-            params![&fp.filename, checksums_blob, fp.mtime, &fp.file_hash],
+            params![
+                normalize_filename(&fp.filename),
+                checksums_blob,
+                fp.mtime,
+                &fp.file_hash
+            ],
         )
         .context("Failed to save baseline fingerprint")?;
 
@@ -703,26 +2298,83 @@ impl PytestDiffDatabase {
         &mut self,
         fingerprints: Vec<Fingerprint>,
     ) -> Result<usize> {
-        let mut conn = self.conn.write();
+        self.write_baseline_rows(DEFAULT_BASELINE, fingerprints, false)
+    }
+
+    /// Batch save fingerprints into the baseline called `baseline_name`
+    pub fn save_named_baseline_fingerprints_batch(
+        &mut self,
+        baseline_name: &str,
+        fingerprints: Vec<Fingerprint>,
+    ) -> Result<usize> {
+        self.write_baseline_rows(baseline_name, fingerprints, false)
+    }
+
+    /// Replace every row of the baseline called `baseline_name` with
+    /// `fingerprints`, in one transaction
+    ///
+    /// Files without a fingerprint here are dropped from the baseline. If the
+    /// write fails, the previous rows are left as they were.
+    pub fn replace_named_baseline_fingerprints(
+        &mut self,
+        baseline_name: &str,
+        fingerprints: Vec<Fingerprint>,
+    ) -> Result<usize> {
+        self.write_baseline_rows(baseline_name, fingerprints, true)
+    }
 
-        // Start transaction
+    fn write_baseline_rows(
+        &mut self,
+        baseline_name: &str,
+        fingerprints: Vec<Fingerprint>,
+        replace: bool,
+    ) -> Result<usize> {
+        let named = self.block_name_hashes_enabled()?;
+        let default = baseline_name == DEFAULT_BASELINE;
+        let mut conn = self.conn()?.write();
         let tx = conn.transaction()?;
 
+        if replace {
+            if default {
+                tx.execute("DELETE FROM baseline_fp", [])?;
+            } else {
+                tx.execute(
+                    "DELETE FROM named_baseline_fp WHERE baseline_name = ?1",
+                    params![baseline_name],
+                )?;
+            }
+        }
+
         let mut count = 0;
         for fp in fingerprints {
-            let checksums_blob = serialize_checksums(&fp.checksums);
-
-            tx.execute(
-                "INSERT OR REPLACE INTO baseline_fp (filename, method_checksums, mtime, fsha)
-                 VALUES (?1, ?2, ?3, ?4)",
-                params![&fp.filename, checksums_blob, fp.mtime, &fp.file_hash],
-            )
-            .context("Failed to save baseline fingerprint in batch")?;
+            let filename = normalize_filename(&fp.filename);
+            let checksums_blob = baseline_checksums_blob(&fp, named);
+            if default {
+                tx.execute(
+                    "INSERT OR REPLACE INTO baseline_fp (filename, method_checksums, mtime, fsha)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![filename, checksums_blob, fp.mtime, &fp.file_hash],
+                )
+                .context("Failed to save baseline fingerprint in batch")?;
+            } else {
+                tx.execute(
+                    "INSERT OR REPLACE INTO named_baseline_fp
+                     (baseline_name, filename, method_checksums, mtime, fsha)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        baseline_name,
+                        filename,
+                        checksums_blob,
+                        fp.mtime,
+                        &fp.file_hash
+                    ],
+                )
+                .context("Failed to save named baseline fingerprint in batch")?;
+            }
 
             count += 1;
         }
 
-        // Commit transaction
         tx.commit()?;
 
         Ok(count)
@@ -812,7 +2464,7 @@ impl PytestDiffDatabase {
             anyhow::bail!("Source database does not exist: {}", source_db_path);
         }
 
-        let conn = self.conn.write();
+        let conn = self.conn()?.write();
 
         // Attach the source database
         conn.execute("ATTACH DATABASE ?1 AS source_db", params![source_db_path])
@@ -826,7 +2478,7 @@ impl PytestDiffDatabase {
             let baseline_count = conn
                 .execute(
                     "INSERT INTO baseline_fp (filename, method_checksums, mtime, fsha, created_at)
-                 SELECT filename, method_checksums, mtime, fsha, created_at
+                 SELECT REPLACE(filename, '\\', '/'), method_checksums, mtime, fsha, created_at
                  FROM source_db.baseline_fp",
                     [],
                 )
@@ -863,7 +2515,7 @@ impl PytestDiffDatabase {
 
                 conn.execute(
                     "INSERT INTO file_fp (id, filename, method_checksums, mtime, fsha)
-                         SELECT id, filename, method_checksums, mtime, fsha
+                         SELECT id, REPLACE(filename, '\\', '/'), method_checksums, mtime, fsha
                          FROM source_db.file_fp",
                     [],
                 )
@@ -911,13 +2563,74 @@ impl PytestDiffDatabase {
         result
     }
 
+    /// Seed this (empty) database with the baselines and metadata of another one,
+    /// leaving out its test executions; returns the number of baseline rows copied
+    ///
+    /// Gives each parallel CI shard its own database that starts from the
+    /// shared baseline and records executions independently, to be merged
+    /// back later with `merge_baseline_from`.
+    pub fn fork_baseline_from_internal(&mut self, source_db_path: &str) -> Result<usize> {
+        if !Path::new(source_db_path).exists() {
+            anyhow::bail!("Source database does not exist: {}", source_db_path);
+        }
+        let stats = self.get_stats_internal()?;
+        if stats["baseline_count"] > 0 || stats["fingerprint_count"] > 0 {
+            anyhow::bail!("Baselines can only be forked into an empty database");
+        }
+
+        let mut conn = self.conn()?.write();
+        conn.execute("ATTACH DATABASE ?1 AS source_db", params![source_db_path])
+            .with_context(|| format!("Failed to attach source database: {}", source_db_path))?;
+
+        let result = (|| -> Result<usize> {
+            let tx = conn.transaction()?;
+            let mut baseline_count = tx
+                .execute(
+                    "INSERT INTO baseline_fp (filename, method_checksums, mtime, fsha, created_at)
+                     SELECT REPLACE(filename, '\\', '/'), method_checksums, mtime, fsha, created_at
+                     FROM source_db.baseline_fp",
+                    [],
+                )
+                .context("Failed to copy baselines from source")?;
+            if Self::source_table_exists(&tx, "named_baseline_fp")? {
+                baseline_count += tx
+                    .execute(
+                        "INSERT INTO named_baseline_fp
+                             (baseline_name, filename, method_checksums, mtime, fsha, created_at)
+                         SELECT baseline_name, REPLACE(filename, '\\', '/'), method_checksums,
+                                mtime, fsha, created_at
+                         FROM source_db.named_baseline_fp",
+                        [],
+                    )
+                    .context("Failed to copy named baselines from source")?;
+            }
+            // Settings too, so the shard checksums files the way the baseline did
+            tx.execute(
+                "INSERT OR REPLACE INTO metadata (dataid, data)
+                 SELECT dataid, data FROM source_db.metadata",
+                [],
+            )
+            .context("Failed to copy metadata from source")?;
+            tx.commit()?;
+            Ok(baseline_count)
+        })();
+
+        // Always detach, even if the copy failed
+        conn.execute("DETACH DATABASE source_db", [])
+            .context("Failed to detach source database")?;
+        drop(conn);
+        self.refresh_cache_format_internal()?;
+
+        result
+    }
+
     fn merge_baseline_from_internal(&mut self, source_db_path: &str) -> Result<ImportResult> {
         // Verify source file exists
         if !Path::new(source_db_path).exists() {
             anyhow::bail!("Source database does not exist: {}", source_db_path);
         }
 
-        let conn = self.conn.write();
+        let conn = self.conn()?.write();
 
         // Attach the source database
         conn.execute("ATTACH DATABASE ?1 AS source_db", params![source_db_path])
@@ -928,7 +2641,7 @@ impl PytestDiffDatabase {
             let baseline_count = conn
                 .execute(
                     "INSERT OR REPLACE INTO baseline_fp (filename, method_checksums, mtime, fsha, created_at)
-                     SELECT filename, method_checksums, mtime, fsha, created_at
+                     SELECT REPLACE(filename, '\\', '/'), method_checksums, mtime, fsha, created_at
                      FROM source_db.baseline_fp",
                     [],
                 )
@@ -961,7 +2674,7 @@ impl PytestDiffDatabase {
                     // 2. Merge file fingerprints (natural key: filename+fsha+checksums)
                     conn.execute(
                         "INSERT OR IGNORE INTO file_fp (filename, method_checksums, mtime, fsha)
-                         SELECT filename, method_checksums, mtime, fsha
+                         SELECT REPLACE(filename, '\\', '/'), method_checksums, mtime, fsha
                          FROM source_db.file_fp",
                         [],
                     )
@@ -997,7 +2710,7 @@ impl PytestDiffDatabase {
                          CREATE TEMP TABLE _fp_map AS
                          SELECT sfp.id AS src, fp.id AS dst
                          FROM source_db.file_fp sfp
-                         JOIN file_fp fp ON fp.filename = sfp.filename
+                         JOIN file_fp fp ON fp.filename = REPLACE(sfp.filename, '\\', '/')
                              AND fp.fsha = sfp.fsha
                              AND fp.method_checksums = sfp.method_checksums;
 
@@ -1098,7 +2811,7 @@ impl PytestDiffDatabase {
         }
 
         // ATTACH requires a write lock
-        let conn = self.conn.write();
+        let conn = self.conn()?.write();
 
         // Attach the source database
         conn.execute("ATTACH DATABASE ?1 AS source_db", params![source_db_path])
@@ -1121,7 +2834,7 @@ impl PytestDiffDatabase {
     }
 
     fn set_metadata_internal(&self, key: &str, value: &str) -> Result<()> {
-        let conn = self.conn.write();
+        let conn = self.conn()?.write();
         conn.execute(
             "INSERT OR REPLACE INTO metadata (dataid, data) VALUES (?1, ?2)",
             params![key, value],
@@ -1131,7 +2844,7 @@ impl PytestDiffDatabase {
     }
 
     fn get_metadata_internal(&self, key: &str) -> Result<Option<String>> {
-        let conn = self.conn.read();
+        let conn = self.conn()?.read();
         conn.query_row(
             "SELECT data FROM metadata WHERE dataid = ?1",
             params![key],
@@ -1142,7 +2855,7 @@ impl PytestDiffDatabase {
     }
 
     fn get_test_dependencies_internal(&self, test_name: &str) -> Result<Vec<String>> {
-        let conn = self.conn.read();
+        let conn = self.conn()?.read();
         let mut stmt = conn.prepare(
             "SELECT DISTINCT fp.filename
              FROM test_execution te
@@ -1156,8 +2869,61 @@ impl PytestDiffDatabase {
         Ok(files)
     }
 
+    fn get_test_execution_internal(&self, test_name: &str) -> Result<Option<TestExecution>> {
+        let conn = self.conn()?.read();
+        let execution = conn
+            .query_row(
+                "SELECT id, duration, failed, forced
+                 FROM test_execution
+                 WHERE test_name = ?1
+                 ORDER BY id DESC
+                 LIMIT 1",
+                params![test_name],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, f64>(1)?,
+                        row.get::<_, bool>(2)?,
+                        row.get::<_, bool>(3)?,
+                    ))
+                },
+            )
+            .optional()
+            .context("Failed to query test execution")?;
+        let Some((id, duration, failed, forced)) = execution else {
+            return Ok(None);
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT fp.filename, fp.method_checksums, fp.mtime, fp.fsha
+             FROM test_execution_file_fp teff
+             JOIN file_fp fp ON teff.fingerprint_id = fp.id
+             WHERE teff.test_execution_id = ?1
+             ORDER BY fp.filename",
+        )?;
+        let rows = stmt.query_map(params![id], |row| {
+            Ok(Fingerprint {
+                filename: row.get(0)?,
+                checksums: checksums_column(row, 1)?,
+                mtime: row.get(2)?,
+                file_hash: row.get(3)?,
+                blocks: None,
+            })
+        })?;
+        let fingerprints = rows.collect::<std::result::Result<_, _>>()?;
+
+        Ok(Some(TestExecution {
+            test_name: test_name.to_string(),
+            duration,
+            failed,
+            forced,
+            fingerprints,
+        }))
+    }
+
     fn get_file_dependents_internal(&self, filename: &str) -> Result<Vec<String>> {
-        let conn = self.conn.read();
+        let filename = normalize_filename(filename);
+        let conn = self.conn()?.read();
         let mut stmt = conn.prepare(
             "SELECT DISTINCT te.test_name
              FROM test_execution te
@@ -1171,8 +2937,63 @@ impl PytestDiffDatabase {
         Ok(tests)
     }
 
+    /// Checksums of every block some recorded test execution depends on, by
+    /// file and then by the file hash they were recorded against
+    pub(crate) fn get_executed_checksums_internal(
+        &self,
+    ) -> Result<HashMap<String, HashMap<String, HashSet<i32>>>> {
+        let conn = self.conn()?.read();
+        let mut stmt = conn.prepare(
+            "SELECT fp.filename, fp.fsha, fp.method_checksums
+             FROM file_fp fp
+             WHERE EXISTS (
+                 SELECT 1 FROM test_execution_file_fp teff WHERE teff.fingerprint_id = fp.id
+             )",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                checksums_column(row, 2)?,
+            ))
+        })?;
+        let mut executed: HashMap<String, HashMap<String, HashSet<i32>>> = HashMap::new();
+        for row in rows {
+            let (filename, fsha, checksums) = row?;
+            executed
+                .entry(filename)
+                .or_default()
+                .entry(fsha)
+                .or_default()
+                .extend(checksums);
+        }
+        Ok(executed)
+    }
+
+    pub fn get_baseline_name_hashes_internal(
+        &self,
+        filename: &str,
+    ) -> Result<Option<Vec<(i32, u32)>>> {
+        let filename = normalize_filename(filename);
+        let blob: Option<Vec<u8>> = self
+            .conn()?
+            .read()
+            .query_row(
+                "SELECT method_checksums FROM baseline_fp WHERE filename = ?1",
+                params![filename],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to query baseline fingerprint")?;
+        match blob {
+            Some(blob) => Ok(deserialize_named_checksums(&blob)?),
+            None => Ok(None),
+        }
+    }
+
     fn get_baseline_fingerprint_internal(&self, filename: &str) -> Result<Option<Fingerprint>> {
-        let conn = self.conn.read();
+        let filename = normalize_filename(filename);
+        let conn = self.conn()?.read();
 
         conn.query_row(
             "SELECT filename, method_checksums, mtime, fsha
@@ -1180,8 +3001,7 @@ impl PytestDiffDatabase {
              WHERE filename = ?1",
             params![filename],
             |row| {
-                let checksums_blob: Vec<u8> = row.get(1)?;
-                let checksums = deserialize_checksums(&checksums_blob);
+                let checksums = checksums_column(row, 1)?;
 
                 Ok(Fingerprint {
                     filename: row.get(0)?,
@@ -1196,114 +3016,1559 @@ impl PytestDiffDatabase {
         .context("Failed to query baseline fingerprint")
     }
 
-    /// Get all baseline fingerprints in a single query
+    /// Get the fingerprint of one file in the baseline called `baseline_name`
+    pub fn get_named_baseline_fingerprint(
+        &self,
+        baseline_name: &str,
+        filename: &str,
+    ) -> Result<Option<Fingerprint>> {
+        if baseline_name == DEFAULT_BASELINE {
+            return self.get_baseline_fingerprint_internal(filename);
+        }
+        let filename = normalize_filename(filename);
+        let conn = self.conn()?.read();
+
+        conn.query_row(
+            "SELECT filename, method_checksums, mtime, fsha
+             FROM named_baseline_fp
+             WHERE baseline_name = ?1 AND filename = ?2",
+            params![baseline_name, filename],
+            |row| {
+                Ok(Fingerprint {
+                    filename: row.get(0)?,
+                    checksums: checksums_column(row, 1)?,
+                    mtime: row.get(2)?,
+                    file_hash: row.get(3)?,
+                    blocks: None,
+                })
+            },
+        )
+        .optional()
+        .context("Failed to query named baseline fingerprint")
+    }
+
+    /// Get every recorded fingerprint of native (non-Python) test dependencies
     ///
-    /// Returns a HashMap of filename -> Fingerprint for efficient lookup
-    pub fn get_all_baseline_fingerprints(&self) -> Result<HashMap<String, Fingerprint>> {
-        let conn = self.conn.read();
+    /// Only native artifacts are stored in `file_fp` under a name without a
+    /// tracked extension, so this returns filename -> all distinct recorded
+    /// versions of that artifact. Versions no kept test execution links to
+    /// are left out: no test would be selected through them, and they'd
+    /// report the artifact as changed until pruned.
+    pub fn get_native_dependency_fingerprints(&self) -> Result<HashMap<String, Vec<Fingerprint>>> {
+        let extensions = self.get_tracked_extensions()?;
+        let conn = self.conn()?.read();
+        let mut stmt = conn.prepare(
+            "SELECT filename, method_checksums, mtime, fsha
+             FROM file_fp
+             WHERE filename NOT LIKE '%.py'
+               AND EXISTS (
+                   SELECT 1 FROM test_execution_file_fp teff
+                   WHERE teff.fingerprint_id = file_fp.id
+               )",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Fingerprint {
+                filename: row.get(0)?,
+                checksums: checksums_column(row, 1)?,
+                mtime: row.get(2)?,
+                file_hash: row.get(3)?,
+                blocks: None,
+            })
+        })?;
 
-        let mut stmt =
-            conn.prepare("SELECT filename, method_checksums, mtime, fsha FROM baseline_fp")?;
+        let mut by_file: HashMap<String, Vec<Fingerprint>> = HashMap::new();
+        for row in rows {
+            let fp = row?;
+            if extensions
+                .iter()
+                .any(|ext| fp.filename.ends_with(ext.as_str()))
+            {
+                continue;
+            }
+            by_file.entry(fp.filename.clone()).or_default().push(fp);
+        }
+        Ok(by_file)
+    }
 
-        let fingerprints = stmt
-            .query_map([], |row| {
-                let filename: String = row.get(0)?;
-                let checksums_blob: Vec<u8> = row.get(1)?;
-                let checksums = deserialize_checksums(&checksums_blob);
+    pub fn renormalize_baseline_internal(
+        &mut self,
+        project_root: &str,
+        mode: Normalization,
+    ) -> Result<RenormalizeResult> {
+        let current = self.get_parse_options_internal()?;
+        let target = ParseOptions {
+            normalization: mode,
+            ..current.clone()
+        };
 
-                Ok((
-                    filename.clone(),
-                    Fingerprint {
-                        filename,
-                        checksums,
-                        mtime: row.get(2)?,
-                        file_hash: row.get(3)?,
-                        blocks: None,
-                    },
-                ))
-            })?
-            .filter_map(|r| r.ok())
+        // Named baselines were saved from other trees, so their files can't be
+        // re-read here, and they'd keep checksums of the old mode
+        let named: Vec<String> = self
+            .list_baselines_internal()?
+            .into_iter()
+            .filter(|name| name != DEFAULT_BASELINE)
             .collect();
+        if !named.is_empty() {
+            anyhow::bail!(
+                "Named baselines can't be renormalized ({}); clear them with \
+                 clear_baseline(name) and save them again afterwards",
+                named.join(", ")
+            );
+        }
 
-        Ok(fingerprints)
-    }
-}
+        let baselines = self.get_all_baseline_fingerprints()?;
+        let named = self.block_name_hashes_enabled()?;
 
-/// Serialize checksums (Vec<i32>) to blob
-fn serialize_checksums(checksums: &[i32]) -> Vec<u8> {
-    checksums.iter().flat_map(|c| c.to_le_bytes()).collect()
-}
+        let mut conn = self.conn()?.write();
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        let mut updated = 0;
+        let mut skipped = Vec::new();
+
+        for fp in baselines.values() {
+            let path = Path::new(project_root).join(&fp.filename);
+            // Read like save_baseline does, so encoding mismatches are skipped too
+            let content = crate::encoding::read_python_source(&path)
+                .ok()
+                .filter(|content| {
+                    blake3::hash(content.as_bytes()).to_hex().as_str() == fp.file_hash
+                });
+            let Some(content) = content else {
+                skipped.push(fp.filename.clone());
+                continue;
+            };
+            let (Ok(old_blocks), Ok(new_blocks)) = (
+                parse_module_with_options(&content, &current),
+                parse_module_with_options(&content, &target),
+            ) else {
+                skipped.push(fp.filename.clone());
+                continue;
+            };
 
-/// Deserialize checksums from blob to Vec<i32>
-fn deserialize_checksums(blob: &[u8]) -> Vec<i32> {
-    blob.chunks_exact(4)
-        .map(|chunk| i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
-        .collect()
-}
+            // Same source, same block order: map block by block. Blocks sharing
+            // an old checksum may part ways, so an old checksum maps to each
+            // new checksum of its blocks
+            let mut mapping: HashMap<i32, Vec<i32>> = HashMap::new();
+            for (old, new) in old_blocks.iter().zip(&new_blocks) {
+                let targets = mapping.entry(old.checksum).or_default();
+                if !targets.contains(&new.checksum) {
+                    targets.push(new.checksum);
+                }
+            }
+            let renormalized = Fingerprint {
+                checksums: new_blocks.iter().map(|b| b.checksum).collect(),
+                blocks: Some(new_blocks),
+                ..fp.clone()
+            };
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
+            tx.execute(
+                "UPDATE baseline_fp SET method_checksums = ?1 WHERE filename = ?2",
+                params![baseline_checksums_blob(&renormalized, named), &fp.filename],
+            )
+            .context("Failed to update baseline checksums")?;
 
-    #[test]
-    fn test_database_creation() {
-        let temp_db = NamedTempFile::new().unwrap();
-        let db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap());
-        assert!(db.is_ok());
-    }
+            Self::remap_file_fp_checksums(&tx, &fp.filename, &mapping)?;
+            updated += 1;
+        }
 
-    #[test]
-    fn test_save_and_retrieve_fingerprint() {
-        let temp_db = NamedTempFile::new().unwrap();
-        let db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        tx.execute(
+            "INSERT OR REPLACE INTO metadata (dataid, data) VALUES (?1, ?2)",
+            params![NORMALIZATION_KEY, mode.as_str()],
+        )
+        .context("Failed to record normalization mode")?;
+        tx.commit().context("Failed to commit renormalization")?;
 
-        let fp = Fingerprint {
-            filename: "test.py".to_string(),
+        drop(conn);
+        self.cache.clear();
+        skipped.sort();
+        Ok(RenormalizeResult {
+            count: updated,
+            skipped,
+        })
+    }
+
+    /// Rewrite recorded test fingerprints of every version of a file through
+    /// `mapping`
+    ///
+    /// Older versions share the checksums of the blocks that didn't change
+    /// since, which are the ones selection can still match. A checksum mapped
+    /// to several new ones is replaced by all of them, so no dependency is
+    /// lost. When the remapped checksums collide with an existing row (unique
+    /// on filename + hash + checksums), links are moved to that row instead.
+    fn remap_file_fp_checksums(
+        tx: &rusqlite::Transaction,
+        filename: &str,
+        mapping: &HashMap<i32, Vec<i32>>,
+    ) -> Result<()> {
+        let rows: Vec<(i64, String, Vec<u8>)> = {
+            let mut stmt =
+                tx.prepare("SELECT id, fsha, method_checksums FROM file_fp WHERE filename = ?1")?;
+            let rows = stmt.query_map(params![filename], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+            rows.collect::<std::result::Result<_, _>>()?
+        };
+
+        for (id, fsha, blob) in rows {
+            let mut expanded = HashSet::new();
+            let mut remapped = Vec::new();
+            for checksum in deserialize_checksums(&blob)? {
+                match mapping.get(&checksum).map(Vec::as_slice) {
+                    Some([target]) => remapped.push(*target),
+                    Some(targets) => {
+                        if expanded.insert(checksum) {
+                            remapped.extend_from_slice(targets);
+                        }
+                    }
+                    None => remapped.push(checksum),
+                }
+            }
+            let remapped_blob = serialize_checksums(&remapped);
+            if remapped_blob == blob {
+                continue;
+            }
+
+            let existing: Option<i64> = tx
+                .query_row(
+                    "SELECT id FROM file_fp
+                     WHERE filename = ?1 AND fsha = ?2 AND method_checksums = ?3",
+                    params![filename, fsha, remapped_blob],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            match existing {
+                Some(target) => {
+                    tx.execute(
+                        "UPDATE OR IGNORE test_execution_file_fp SET fingerprint_id = ?1
+                         WHERE fingerprint_id = ?2",
+                        params![target, id],
+                    )?;
+                    tx.execute("DELETE FROM file_fp WHERE id = ?1", params![id])?;
+                }
+                None => {
+                    tx.execute(
+                        "UPDATE file_fp SET method_checksums = ?1 WHERE id = ?2",
+                        params![remapped_blob, id],
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Snapshot of a baseline with portable filenames (see `portable_filename`)
+    pub fn baseline_snapshot(
+        &self,
+        baseline_name: &str,
+        project_root: Option<&str>,
+    ) -> Result<BaselineSnapshot> {
+        let options = self.get_parse_options_internal()?;
+        let mut fingerprints: Vec<Fingerprint> = self
+            .get_named_baseline_fingerprints(baseline_name)?
+            .into_values()
+            .map(|fp| Fingerprint {
+                filename: portable_filename(&fp.filename, project_root),
+                ..fp
+            })
+            .collect();
+        fingerprints.sort_by(|a, b| a.filename.cmp(&b.filename));
+        Ok(BaselineSnapshot {
+            format: SNAPSHOT_FORMAT,
+            normalization: options.normalization.as_str().to_string(),
+            granularity: options.granularity.as_str().to_string(),
+            max_function_lines: options.max_function_lines,
+            ignored_blocks: options.ignored_names.patterns().to_vec(),
+            max_block_depth: options.max_block_depth,
+            lambda_blocks: options.lambda_blocks,
+            tracked_extensions: self.get_tracked_extensions()?,
+            fingerprints,
+        })
+    }
+
+    /// Write one CSV row per (test execution, file) dependency to `dest`
+    ///
+    /// Returns the number of rows written, header excluded.
+    pub fn export_csv_internal(&self, dest: &str) -> Result<usize> {
+        use std::io::Write;
+
+        let conn = self.conn()?.read();
+        let mut stmt = conn.prepare(
+            "SELECT te.test_name, te.duration, te.failed, fp.filename, fp.method_checksums
+             FROM test_execution te
+             JOIN test_execution_file_fp teff ON te.id = teff.test_execution_id
+             JOIN file_fp fp ON teff.fingerprint_id = fp.id
+             ORDER BY te.test_name, te.id, fp.filename",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<f64>>(1)?,
+                row.get::<_, bool>(2)?,
+                row.get::<_, String>(3)?,
+                checksums_column(row, 4)?.len(),
+            ))
+        })?;
+
+        let file = std::fs::File::create(dest)
+            .with_context(|| format!("Failed to create CSV file: {}", dest))?;
+        let mut out = std::io::BufWriter::new(file);
+        writeln!(out, "test_name,duration,failed,filename,block_count")?;
+        let mut count = 0;
+        for row in rows {
+            let (test_name, duration, failed, filename, block_count) = row?;
+            // Executions recorded without a duration get an empty field
+            let duration = duration.map(|d| d.to_string()).unwrap_or_default();
+            writeln!(
+                out,
+                "{},{},{},{},{}",
+                csv_field(&test_name),
+                duration,
+                u8::from(failed),
+                csv_field(&filename),
+                block_count
+            )?;
+            count += 1;
+        }
+        out.flush()
+            .with_context(|| format!("Failed to write CSV file: {}", dest))?;
+        Ok(count)
+    }
+
+    /// Row count and highest row ID of a baseline
+    ///
+    /// Row IDs only ever grow, so any insert, replace or delete of the
+    /// baseline's rows changes the pair.
+    fn baseline_row_guard(&self, baseline_name: &str) -> Result<(i64, i64)> {
+        let conn = self.conn()?.read();
+        let guard = if baseline_name == DEFAULT_BASELINE {
+            conn.query_row(
+                "SELECT COUNT(*), COALESCE(MAX(id), 0) FROM baseline_fp",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+        } else {
+            conn.query_row(
+                "SELECT COUNT(*), COALESCE(MAX(id), 0) FROM named_baseline_fp
+                 WHERE baseline_name = ?1",
+                params![baseline_name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+        };
+        Ok(guard)
+    }
+
+    /// `(filename, mtime)` of every file of a baseline, without decoding checksums
+    pub fn get_baseline_mtimes(&self, baseline_name: &str) -> Result<Vec<(String, f64)>> {
+        let conn = self.conn()?.read();
+        let map_row = |row: &rusqlite::Row| {
+            let filename: String = row.get(0)?;
+            Ok((normalize_filename(&filename).into_owned(), row.get(1)?))
+        };
+        let mtimes = if baseline_name == DEFAULT_BASELINE {
+            let mut stmt = conn.prepare("SELECT filename, mtime FROM baseline_fp")?;
+            let rows = stmt.query_map([], map_row)?;
+            rows.collect::<std::result::Result<_, _>>()?
+        } else {
+            let mut stmt = conn.prepare(
+                "SELECT filename, mtime FROM named_baseline_fp WHERE baseline_name = ?1",
+            )?;
+            let rows = stmt.query_map(params![baseline_name], map_row)?;
+            rows.collect::<std::result::Result<_, _>>()?
+        };
+        Ok(mtimes)
+    }
+
+    /// Record a digest of the baseline's files and mtimes, as it stands now
+    ///
+    /// `detect_changes` compares it with the project to skip per-file work
+    /// when nothing was touched. It is stored with the baseline's row guard,
+    /// so any later write to the baseline's rows invalidates it.
+    pub fn set_baseline_aggregate(&self, baseline_name: &str, aggregate: &str) -> Result<()> {
+        let (count, max_id) = self.baseline_row_guard(baseline_name)?;
+        self.set_metadata_internal(
+            &baseline_key(BASELINE_AGGREGATE_KEY, baseline_name),
+            &format!("{}:{}:{}", count, max_id, aggregate),
+        )
+    }
+
+    /// Digest recorded by `set_baseline_aggregate`, unless the baseline's rows
+    /// changed since
+    pub fn get_baseline_aggregate(&self, baseline_name: &str) -> Result<Option<String>> {
+        let Some(stored) =
+            self.get_metadata_internal(&baseline_key(BASELINE_AGGREGATE_KEY, baseline_name))?
+        else {
+            return Ok(None);
+        };
+        let (count, max_id) = self.baseline_row_guard(baseline_name)?;
+        let guard = format!("{}:{}:", count, max_id);
+        Ok(stored.strip_prefix(&guard).map(str::to_string))
+    }
+
+    pub fn get_baseline_metadata_internal(
+        &self,
+        baseline_name: &str,
+    ) -> Result<HashMap<String, String>> {
+        let mut metadata = HashMap::new();
+        for (name, key) in [
+            ("label", baseline_label_key(baseline_name)),
+            ("writer_version", WRITER_VERSION_KEY.to_string()),
+            ("commit", BASELINE_COMMIT_KEY.to_string()),
+            ("scope", BASELINE_SCOPE_KEY.to_string()),
+        ] {
+            if let Some(value) = self.get_metadata_internal(&key)? {
+                metadata.insert(name.to_string(), value);
+            }
+        }
+        Ok(metadata)
+    }
+
+    pub fn recover_baseline_internal(
+        &mut self,
+        project_root: &str,
+        baseline_name: &str,
+    ) -> Result<usize> {
+        let scope_paths: Vec<String> = match self.get_metadata_internal(BASELINE_SCOPE_KEY)? {
+            Some(raw) => serde_json::from_str::<Vec<String>>(&raw)
+                .context("Invalid stored baseline scope")?
+                .iter()
+                .map(|p| {
+                    Path::new(project_root)
+                        .join(p)
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let label = self.get_metadata_internal(&baseline_label_key(baseline_name))?;
+
+        // The old rows are only dropped in the transaction that writes the new
+        // ones, once the project root was walked and every file fingerprinted
+        crate::fingerprint::save_baseline_with_db(
+            self,
+            project_root,
+            &SaveOptions {
+                scope_paths,
+                force: true,
+                quiet: true,
+                baseline_name,
+                label: label.as_deref(),
+                replace: true,
+                ..SaveOptions::default()
+            },
+        )
+        .map(|result| result.count)
+    }
+
+    pub fn validate_baseline_internal(
+        &self,
+        project_root: &str,
+        baseline_name: &str,
+    ) -> Result<ValidationReport> {
+        let baselines = self.get_named_baseline_fingerprints(baseline_name)?;
+        let mut report = ValidationReport {
+            total: baselines.len(),
+            fresh: 0,
+            changed: 0,
+            missing: 0,
+        };
+
+        for fp in baselines.values() {
+            let path = Path::new(project_root).join(&fp.filename);
+            let Ok(bytes) = std::fs::read(&path) else {
+                report.missing += 1;
+                continue;
+            };
+            // Native artifacts are hashed as raw bytes, sources as decoded text
+            let matches = blake3::hash(&bytes).to_hex().as_str() == fp.file_hash
+                || crate::encoding::read_python_source(&path).is_ok_and(|content| {
+                    blake3::hash(content.as_bytes()).to_hex().as_str() == fp.file_hash
+                });
+            if matches {
+                report.fresh += 1;
+            } else {
+                report.changed += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub fn list_baseline_files_internal(&self, baseline_name: &str) -> Result<Vec<String>> {
+        let conn = self.conn()?.read();
+        let files: Vec<String> = if baseline_name == DEFAULT_BASELINE {
+            let mut stmt =
+                conn.prepare("SELECT DISTINCT filename FROM baseline_fp ORDER BY filename")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<std::result::Result<_, _>>()?
+        } else {
+            let mut stmt = conn.prepare(
+                "SELECT DISTINCT filename FROM named_baseline_fp
+                 WHERE baseline_name = ?1 ORDER BY filename",
+            )?;
+            let rows = stmt.query_map(params![baseline_name], |row| row.get(0))?;
+            rows.collect::<std::result::Result<_, _>>()?
+        };
+        Ok(files)
+    }
+
+    pub fn list_baselines_internal(&self) -> Result<Vec<String>> {
+        let conn = self.conn()?.read();
+        let mut stmt = conn.prepare(
+            "SELECT ?1 WHERE EXISTS (SELECT 1 FROM baseline_fp)
+             UNION
+             SELECT DISTINCT baseline_name FROM named_baseline_fp
+             ORDER BY 1",
+        )?;
+        let rows = stmt.query_map(params![DEFAULT_BASELINE], |row| row.get(0))?;
+        let names: Vec<String> = rows.collect::<std::result::Result<_, _>>()?;
+        Ok(names)
+    }
+
+    /// Get all baseline fingerprints in a single query
+    ///
+    /// Returns a HashMap of filename -> Fingerprint for efficient lookup
+    pub fn get_all_baseline_fingerprints(&self) -> Result<HashMap<String, Fingerprint>> {
+        self.get_named_baseline_fingerprints(DEFAULT_BASELINE)
+    }
+
+    /// Get all fingerprints of the baseline called `baseline_name`
+    ///
+    /// An unknown name yields an empty map, like a database with no baseline.
+    pub fn get_named_baseline_fingerprints(
+        &self,
+        baseline_name: &str,
+    ) -> Result<HashMap<String, Fingerprint>> {
+        let conn = self.conn()?.read();
+
+        let map_row = |row: &rusqlite::Row| {
+            let filename: String = row.get(0)?;
+            let checksums = checksums_column(row, 1)?;
+
+            // Rows stored before filenames were normalized may use backslashes
+            Ok((
+                normalize_filename(&filename).into_owned(),
+                Fingerprint {
+                    filename,
+                    checksums,
+                    mtime: row.get(2)?,
+                    file_hash: row.get(3)?,
+                    blocks: None,
+                },
+            ))
+        };
+
+        let fingerprints = if baseline_name == DEFAULT_BASELINE {
+            let mut stmt =
+                conn.prepare("SELECT filename, method_checksums, mtime, fsha FROM baseline_fp")?;
+            let rows = stmt.query_map([], map_row)?;
+            rows.collect::<rusqlite::Result<_>>()?
+        } else {
+            let mut stmt = conn.prepare(
+                "SELECT filename, method_checksums, mtime, fsha FROM named_baseline_fp
+                 WHERE baseline_name = ?1",
+            )?;
+            let rows = stmt.query_map(params![baseline_name], map_row)?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+
+        Ok(fingerprints)
+    }
+}
+
+/// Metadata key of a baseline's label
+fn baseline_label_key(baseline_name: &str) -> String {
+    baseline_key(BASELINE_LABEL_KEY, baseline_name)
+}
+
+/// Metadata key of a per-baseline setting: `key` for the default baseline,
+/// `key:<name>` for named ones
+fn baseline_key(key: &str, baseline_name: &str) -> String {
+    if baseline_name == DEFAULT_BASELINE {
+        key.to_string()
+    } else {
+        format!("{}:{}", key, baseline_name)
+    }
+}
+
+/// Normalize a stored filename to forward slashes
+///
+/// Baselines written on Windows then match the forward-slash paths that
+/// coverage, git and other platforms report. Applied when storing and when
+/// looking up filenames.
+pub(crate) fn normalize_filename(filename: &str) -> Cow<'_, str> {
+    if filename.contains('\\') {
+        Cow::Owned(filename.replace('\\', "/"))
+    } else {
+        Cow::Borrowed(filename)
+    }
+}
+
+/// `filename` as a forward-slash path relative to `project_root`
+///
+/// Handles paths written on any OS: an absolute path under the root (in either
+/// slash style) loses the root prefix, anything else is only normalized.
+fn portable_filename(filename: &str, project_root: Option<&str>) -> String {
+    let filename = normalize_filename(filename);
+    let Some(root) = project_root else {
+        return filename.into_owned();
+    };
+    let root = normalize_filename(root);
+    let root = root.trim_end_matches('/');
+    match filename.strip_prefix(root) {
+        Some(relative) if !root.is_empty() && relative.starts_with('/') => {
+            relative.trim_start_matches('/').to_string()
+        }
+        _ => filename.into_owned(),
+    }
+}
+
+/// Serialize checksums (Vec<i32>) to blob
+///
+/// Order and duplicates are kept: identical blocks legitimately share a
+/// checksum, and change detection compares checksums as multisets.
+fn serialize_checksums(checksums: &[i32]) -> Vec<u8> {
+    checksums.iter().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+/// First byte of a blob storing a name hash with each checksum
+///
+/// Plain blobs are a whole number of 4-byte checksums, while tagged blobs are
+/// one tag byte plus 8-byte pairs, so their lengths never collide.
+const NAMED_CHECKSUMS_TAG: u8 = 0xB1;
+
+/// Serialize `(checksum, block_name_hash)` pairs of blocks behind the format tag
+fn serialize_named_checksums(blocks: &[Block]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(1 + blocks.len() * 8);
+    blob.push(NAMED_CHECKSUMS_TAG);
+    for block in blocks {
+        blob.extend(block.checksum.to_le_bytes());
+        blob.extend(block_name_hash(&block.name).to_le_bytes());
+    }
+    blob
+}
+
+/// Blob of a baseline fingerprint: with name hashes when enabled and the
+/// fingerprint carries its blocks, plain checksums otherwise
+fn baseline_checksums_blob(fp: &Fingerprint, named: bool) -> Vec<u8> {
+    match &fp.blocks {
+        Some(blocks) if named && blocks.len() == fp.checksums.len() => {
+            serialize_named_checksums(blocks)
+        }
+        _ => serialize_checksums(&fp.checksums),
+    }
+}
+
+fn is_named_blob(blob: &[u8]) -> bool {
+    blob.len() % 4 == 1 && blob[0] == NAMED_CHECKSUMS_TAG
+}
+
+/// Decode the `(checksum, name_hash)` pairs of a tagged blob (`None` for plain blobs)
+fn deserialize_named_checksums(
+    blob: &[u8],
+) -> std::result::Result<Option<Vec<(i32, u32)>>, CorruptChecksumBlob> {
+    if !is_named_blob(blob) {
+        deserialize_checksums(blob)?;
+        return Ok(None);
+    }
+    let pairs = &blob[1..];
+    if !pairs.len().is_multiple_of(8) {
+        return Err(CorruptChecksumBlob { len: blob.len() });
+    }
+    Ok(Some(
+        pairs
+            .chunks_exact(8)
+            .map(|pair| {
+                let checksum = i32::from_le_bytes([pair[0], pair[1], pair[2], pair[3]]);
+                let name_hash = u32::from_le_bytes([pair[4], pair[5], pair[6], pair[7]]);
+                (checksum, name_hash)
+            })
+            .collect(),
+    ))
+}
+
+/// A stored checksum blob that isn't a whole number of checksums
+#[derive(Debug)]
+pub struct CorruptChecksumBlob {
+    pub len: usize,
+}
+
+impl std::fmt::Display for CorruptChecksumBlob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Corrupt checksum blob: {} bytes is not a multiple of 4",
+            self.len
+        )
+    }
+}
+
+impl std::error::Error for CorruptChecksumBlob {}
+
+/// Deserialize checksums from blob to Vec<i32>
+///
+/// Rejects blobs with a trailing partial checksum instead of dropping it:
+/// a truncated list would silently mis-select tests. Blobs with name hashes
+/// decode to their checksums.
+fn deserialize_checksums(blob: &[u8]) -> std::result::Result<Vec<i32>, CorruptChecksumBlob> {
+    if is_named_blob(blob) {
+        let pairs = deserialize_named_checksums(blob)?.unwrap_or_default();
+        return Ok(pairs.into_iter().map(|(checksum, _)| checksum).collect());
+    }
+    if !blob.len().is_multiple_of(4) {
+        return Err(CorruptChecksumBlob { len: blob.len() });
+    }
+    Ok(blob
+        .chunks_exact(4)
+        .map(|chunk| i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
+
+/// Quote a CSV field if it holds a separator, quote or line break
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Read a checksum blob column, failing the query on a corrupt blob
+fn checksums_column(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Vec<i32>> {
+    let blob: Vec<u8> = row.get(idx)?;
+    deserialize_checksums(&blob).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Blob, Box::new(e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_database_creation() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap());
+        assert!(db.is_ok());
+    }
+
+    #[test]
+    fn test_save_and_retrieve_fingerprint() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+
+        let fp = Fingerprint {
+            filename: "test.py".to_string(),
             checksums: vec![123, 456, 789],
             file_hash: "abc123".to_string(),
             mtime: 1.0,
             blocks: None,
         };
 
-        let fp_id = db.get_or_create_fingerprint(&fp).unwrap();
-        assert!(fp_id > 0);
+        let fp_id = db.get_or_create_fingerprint(&fp).unwrap();
+        assert!(fp_id > 0);
+
+        // Retrieving again should return same ID
+        let fp_id2 = db.get_or_create_fingerprint(&fp).unwrap();
+        assert_eq!(fp_id, fp_id2);
+    }
+
+    #[test]
+    fn test_save_test_execution() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+
+        let fp = Fingerprint {
+            filename: "test.py".to_string(),
+            checksums: vec![123],
+            file_hash: "abc".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+
+        db.save_test_execution_internal("test_example", vec![fp], 0.5, false, "3.12", false)
+            .unwrap();
+
+        let stats = db.get_stats_internal().unwrap();
+        assert_eq!(stats["test_count"], 1);
+        assert_eq!(stats["file_count"], 1);
+    }
+
+    #[test]
+    fn test_set_environment_switches_where_executions_land() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let path = temp_db.path().to_str().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(path).unwrap();
+        let environment_of = |db: &PytestDiffDatabase, test: &str| -> (String, String) {
+            db.conn
+                .read()
+                .query_row(
+                    "SELECT e.environment_name, e.python_version
+                     FROM test_execution te JOIN environment e ON te.environment_id = e.id
+                     WHERE te.test_name = ?1",
+                    params![test],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap()
+        };
+
+        let py311 = db.set_environment_internal("py311", "3.11").unwrap();
+        db.save_test_execution_internal("test_a", vec![], 0.1, false, "3.12", false)
+            .unwrap();
+        let py312 = db.set_environment_internal("py312", "3.12").unwrap();
+        assert_ne!(py311, py312);
+        db.save_test_execution_internal("test_b", vec![], 0.1, false, "3.12", false)
+            .unwrap();
+        // Switching back reuses the existing environment
+        assert_eq!(db.set_environment_internal("py311", "3.11").unwrap(), py311);
+        db.save_test_execution_internal("test_c", vec![], 0.1, false, "3.12", false)
+            .unwrap();
+
+        let expected = |name: &str, version: &str| (name.to_string(), version.to_string());
+        assert_eq!(environment_of(&db, "test_a"), expected("py311", "3.11"));
+        assert_eq!(environment_of(&db, "test_b"), expected("py312", "3.12"));
+        assert_eq!(environment_of(&db, "test_c"), expected("py311", "3.11"));
+        assert_eq!(db.list_environments_internal().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_export_csv_writes_one_row_per_dependency() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db =
+            PytestDiffDatabase::new_internal(dir.path().join("diff.db").to_str().unwrap()).unwrap();
+        let fp = |filename: &str, checksums: Vec<i32>| Fingerprint {
+            filename: filename.to_string(),
+            checksums,
+            file_hash: format!("hash-{}", filename),
+            mtime: 1.0,
+            blocks: None,
+        };
+        db.save_test_execution_internal(
+            "test_a[x,\"y\"]",
+            vec![fp("lib.py", vec![1, 2, 3]), fp("util.py", vec![4])],
+            0.5,
+            true,
+            "3.12",
+            false,
+        )
+        .unwrap();
+        db.save_test_execution_internal(
+            "test_b",
+            vec![fp("lib.py", vec![1, 2, 3])],
+            0.25,
+            false,
+            "3.12",
+            false,
+        )
+        .unwrap();
+        // Executions imported from older databases may lack a duration
+        db.save_test_execution_internal(
+            "test_c",
+            vec![fp("util.py", vec![4])],
+            1.0,
+            false,
+            "3.12",
+            false,
+        )
+        .unwrap();
+        db.conn
+            .read()
+            .execute(
+                "UPDATE test_execution SET duration = NULL WHERE test_name = 'test_c'",
+                [],
+            )
+            .unwrap();
+
+        let dest = dir.path().join("deps.csv");
+        let rows = db.export_csv_internal(dest.to_str().unwrap()).unwrap();
+        assert_eq!(rows, 4);
+        let csv = std::fs::read_to_string(&dest).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                "test_name,duration,failed,filename,block_count",
+                "\"test_a[x,\"\"y\"\"]\",0.5,1,lib.py,3",
+                "\"test_a[x,\"\"y\"\"]\",0.5,1,util.py,1",
+                "test_b,0.25,0,lib.py,3",
+                "test_c,,0,util.py,1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_estimate_selected_duration_sums_latest_durations() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let path = temp_db.path().to_str().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(path).unwrap();
+        let py311 = db.set_environment_internal("py311", "3.11").unwrap();
+        for (test, duration) in [("test_a", 1.0), ("test_b", 2.0), ("test_a", 1.5)] {
+            db.save_test_execution_internal(test, vec![], duration, false, "3.11", false)
+                .unwrap();
+        }
+        let py312 = db.set_environment_internal("py312", "3.12").unwrap();
+        db.save_test_execution_internal("test_c", vec![], 4.0, false, "3.12", false)
+            .unwrap();
+        db.save_test_execution_internal("test_a", vec![], 8.0, false, "3.12", false)
+            .unwrap();
+
+        let selected =
+            |tests: &[&str]| -> Vec<String> { tests.iter().map(|t| t.to_string()).collect() };
+        // Latest execution wins; test_new has no history and a repeat counts once
+        let tests = selected(&["test_a", "test_b", "test_new", "test_b"]);
+        assert_eq!(
+            db.estimate_selected_duration_internal(&tests, None)
+                .unwrap(),
+            (10.0, 1)
+        );
+        assert_eq!(
+            db.estimate_selected_duration_internal(&tests, Some(py311))
+                .unwrap(),
+            (3.5, 1)
+        );
+        assert_eq!(
+            db.estimate_selected_duration_internal(&tests, Some(py312))
+                .unwrap(),
+            (8.0, 2)
+        );
+        assert_eq!(
+            db.estimate_selected_duration_internal(&[], None).unwrap(),
+            (0.0, 0)
+        );
+    }
+
+    #[test]
+    fn test_previous_failures_follow_latest_execution() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let path = temp_db.path().to_str().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(path).unwrap();
+        db.set_max_test_history(None).unwrap();
+        let py311 = db.set_environment_internal("py311", "3.11").unwrap();
+        for (test, failed) in [
+            ("test_fixed", true),
+            ("test_fixed", false),
+            ("test_broken", false),
+            ("test_broken", true),
+            ("test_flaky", true),
+            ("test_ok", false),
+        ] {
+            db.save_test_execution_internal(test, vec![], 0.1, failed, "3.11", false)
+                .unwrap();
+        }
+        let py312 = db.set_environment_internal("py312", "3.12").unwrap();
+        db.save_test_execution_internal("test_flaky", vec![], 0.1, false, "3.12", false)
+            .unwrap();
+        db.save_test_execution_internal("test_ok", vec![], 0.1, true, "3.12", false)
+            .unwrap();
+
+        let names =
+            |tests: &[&str]| -> Vec<String> { tests.iter().map(|t| t.to_string()).collect() };
+        assert_eq!(
+            db.get_previous_failures_internal(None).unwrap(),
+            names(&["test_broken", "test_ok"])
+        );
+        assert_eq!(
+            db.get_previous_failures_internal(Some(py311)).unwrap(),
+            names(&["test_broken", "test_flaky"])
+        );
+        assert_eq!(
+            db.get_previous_failures_internal(Some(py312)).unwrap(),
+            names(&["test_ok"])
+        );
+    }
+
+    #[test]
+    fn test_delete_environment_cascades_to_executions() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let path = temp_db.path().to_str().unwrap();
+        let fp = Fingerprint {
+            filename: "a.py".to_string(),
+            checksums: vec![1],
+            file_hash: "abc".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+        // Only recorded under 3.11
+        let old_fp = Fingerprint {
+            filename: "old.py".to_string(),
+            ..fp.clone()
+        };
+        // Each database handle caches its environment, so use one per interpreter
+        for (version, tests) in [
+            ("3.11", ["test_a", "test_b"]),
+            ("3.12", ["test_a", "test_c"]),
+        ] {
+            let mut db = PytestDiffDatabase::new_internal(path).unwrap();
+            for test in tests {
+                let mut fingerprints = vec![fp.clone()];
+                if version == "3.11" {
+                    fingerprints.push(old_fp.clone());
+                }
+                db.save_test_execution_internal(test, fingerprints, 0.1, false, version, false)
+                    .unwrap();
+            }
+        }
+
+        let db = PytestDiffDatabase::new_internal(path).unwrap();
+        let environments = db.list_environments_internal().unwrap();
+        assert_eq!(
+            environments
+                .iter()
+                .map(|(_, name, version)| (name.as_str(), version.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("default", "3.11"), ("default", "3.12")]
+        );
+        let (old_id, new_id) = (environments[0].0, environments[1].0);
+
+        assert!(db.delete_environment_internal(old_id).unwrap());
+        assert!(!db.delete_environment_internal(old_id).unwrap());
+
+        let executions = |env_id: i64| -> i64 {
+            db.conn
+                .read()
+                .query_row(
+                    "SELECT COUNT(*) FROM test_execution WHERE environment_id = ?1",
+                    params![env_id],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(executions(old_id), 0);
+        assert_eq!(executions(new_id), 2);
+        // old.py's fingerprint went with the 3.11 executions
+        assert!(db.get_fingerprint_internal("old.py").unwrap().is_none());
+        assert!(db.get_fingerprint_internal("a.py").unwrap().is_some());
+        assert_eq!(db.list_environments_internal().unwrap().len(), 1);
+        assert_eq!(
+            db.get_recorded_tests_internal().unwrap(),
+            vec!["test_a", "test_c"]
+        );
+    }
+
+    #[test]
+    fn test_get_test_execution_round_trip() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+
+        let fingerprints = vec![
+            Fingerprint {
+                filename: "a.py".to_string(),
+                checksums: vec![1, -2, 3],
+                file_hash: "aaa".to_string(),
+                mtime: 1.0,
+                blocks: None,
+            },
+            Fingerprint {
+                filename: "b.py".to_string(),
+                checksums: vec![4],
+                file_hash: "bbb".to_string(),
+                mtime: 2.0,
+                blocks: None,
+            },
+        ];
+        db.save_test_execution_internal("test_example", fingerprints, 0.25, true, "3.12", false)
+            .unwrap();
+
+        let execution = db
+            .get_test_execution_internal("test_example")
+            .unwrap()
+            .unwrap();
+        assert_eq!(execution.test_name, "test_example");
+        assert_eq!(execution.duration, 0.25);
+        assert!(execution.failed);
+        assert_eq!(execution.fingerprints.len(), 2);
+        assert_eq!(execution.fingerprints[0].filename, "a.py");
+        assert_eq!(execution.fingerprints[0].checksums, vec![1, -2, 3]);
+
+        assert!(db
+            .get_test_execution_internal("test_missing")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_save_test_executions_batch() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+
+        let shared = Fingerprint {
+            filename: "shared.py".to_string(),
+            checksums: vec![1, 2],
+            file_hash: "aaa".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+        let own = Fingerprint {
+            filename: "own.py".to_string(),
+            checksums: vec![3],
+            file_hash: "bbb".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+        let record = |name: &str, fingerprints: Vec<Fingerprint>| TestExecution {
+            test_name: name.to_string(),
+            duration: 0.1,
+            failed: false,
+            forced: false,
+            fingerprints,
+        };
+
+        db.save_test_executions_internal(
+            &[
+                record("test_one", vec![shared.clone()]),
+                record("test_two", vec![shared.clone(), own.clone()]),
+            ],
+            "3.12",
+            DEFAULT_SAVE_BATCH_SIZE,
+        )
+        .unwrap();
+
+        // Same result as saving one by one: shared fingerprint stored once
+        let stats = db.get_stats_internal().unwrap();
+        assert_eq!(stats["test_count"], 2);
+        assert_eq!(stats["fingerprint_count"], 2);
+        let two = db.get_test_execution_internal("test_two").unwrap().unwrap();
+        assert_eq!(two.fingerprints.len(), 2);
+        let affected = db
+            .get_affected_tests_internal(HashMap::from([("shared.py".to_string(), vec![1])]))
+            .unwrap();
+        assert_eq!(affected, vec!["test_one", "test_two"]);
+
+        // A failing record rolls back the whole batch: it's one transaction
+        let err = db.save_test_executions_internal(
+            &[
+                record("test_three", vec![own.clone()]),
+                record("test_bad", vec![own.clone(), own]),
+            ],
+            "3.12",
+            DEFAULT_SAVE_BATCH_SIZE,
+        );
+        assert!(err.is_err());
+        assert!(db
+            .get_test_execution_internal("test_three")
+            .unwrap()
+            .is_none());
+        assert_eq!(db.get_stats_internal().unwrap()["test_count"], 2);
+    }
+
+    #[test]
+    fn test_save_test_executions_commits_in_batches() {
+        let fp = |i: usize| Fingerprint {
+            filename: format!("mod_{}.py", i % 7),
+            checksums: vec![(i % 7) as i32],
+            file_hash: format!("hash_{}", i % 7),
+            mtime: 1.0,
+            blocks: None,
+        };
+        let records: Vec<TestExecution> = (0..50)
+            .map(|i| TestExecution {
+                test_name: format!("test_{}", i),
+                duration: 0.1,
+                failed: false,
+                forced: false,
+                fingerprints: vec![fp(i), fp(i + 1)],
+            })
+            .collect();
+        let state = |db: &PytestDiffDatabase| {
+            let changed = (0..7).map(|i| (format!("mod_{}.py", i), vec![i])).collect();
+            (
+                db.get_stats_internal().unwrap(),
+                db.get_affected_tests_internal(changed).unwrap(),
+            )
+        };
+
+        let (one_db, batched_db) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let mut one = PytestDiffDatabase::new_internal(one_db.path().to_str().unwrap()).unwrap();
+        one.save_test_executions_internal(&records, "3.12", records.len())
+            .unwrap();
+        let mut batched =
+            PytestDiffDatabase::new_internal(batched_db.path().to_str().unwrap()).unwrap();
+        batched
+            .save_test_executions_internal(&records, "3.12", 6)
+            .unwrap();
+        assert_eq!(state(&batched), state(&one));
+        assert_eq!(state(&batched).0["test_count"], 50);
+
+        // Batches before a failing one stay committed, the failing one rolls back
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        let mut bad = records[..10].to_vec();
+        bad[7].fingerprints.push(fp(7));
+        assert!(db.save_test_executions_internal(&bad, "3.12", 3).is_err());
+        assert_eq!(db.get_stats_internal().unwrap()["test_count"], 6);
+
+        assert!(db
+            .save_test_executions_internal(&records, "3.12", 0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_prune_orphan_fingerprints() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        db.set_max_test_history(Some(1)).unwrap();
+        let fp = |filename: &str, hash: &str| Fingerprint {
+            filename: filename.to_string(),
+            checksums: vec![1],
+            file_hash: hash.to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+
+        db.save_test_execution_internal(
+            "test_a",
+            vec![fp("a.py", "v1"), fp("shared.py", "s")],
+            0.1,
+            false,
+            "3.12",
+            false,
+        )
+        .unwrap();
+        db.save_test_execution_internal(
+            "test_b",
+            vec![fp("shared.py", "s")],
+            0.1,
+            false,
+            "3.12",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_fingerprint_internal("a.py")
+                .unwrap()
+                .unwrap()
+                .file_hash,
+            "v1"
+        );
+        // The rerun prunes test_a's first execution, orphaning a.py@v1
+        db.save_test_execution_internal(
+            "test_a",
+            vec![fp("a.py", "v2")],
+            0.1,
+            false,
+            "3.12",
+            false,
+        )
+        .unwrap();
+        assert_eq!(db.get_stats_internal().unwrap()["fingerprint_count"], 3);
+
+        assert_eq!(db.prune_orphan_fingerprints_internal().unwrap(), 1);
+        assert_eq!(db.get_stats_internal().unwrap()["fingerprint_count"], 2);
+        assert_eq!(
+            db.get_fingerprint_internal("a.py")
+                .unwrap()
+                .unwrap()
+                .file_hash,
+            "v2"
+        );
+        assert_eq!(
+            db.get_affected_tests_internal(HashMap::from([("shared.py".to_string(), vec![1])]))
+                .unwrap(),
+            vec!["test_b"]
+        );
+        assert_eq!(db.prune_orphan_fingerprints_internal().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cache_stats_track_fingerprint_lookups() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap())
+            .unwrap()
+            .with_cache_size(1);
+        for (filename, hash) in [("a.py", "ha"), ("b.py", "hb")] {
+            db.get_or_create_fingerprint(&Fingerprint {
+                filename: filename.to_string(),
+                checksums: vec![1],
+                file_hash: hash.to_string(),
+                mtime: 1.0,
+                blocks: None,
+            })
+            .unwrap();
+        }
+        assert_eq!(db.cache.stats(), (0, 0, 0));
+
+        // First lookup misses and fills the cache, the second one hits it
+        assert!(db.get_fingerprint_internal("a.py").unwrap().is_some());
+        assert_eq!(db.cache.stats(), (0, 1, 1));
+        assert!(db.get_fingerprint_internal("a.py").unwrap().is_some());
+        assert_eq!(db.cache.stats(), (1, 1, 1));
+
+        // The cache holds a single entry: b.py evicts a.py
+        assert!(db.get_fingerprint_internal("b.py").unwrap().is_some());
+        assert!(db.get_fingerprint_internal("a.py").unwrap().is_some());
+        assert_eq!(db.cache.stats(), (1, 3, 1));
+    }
+
+    #[test]
+    fn test_unrecorded_fingerprint_format_is_format_zero() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let db = PytestDiffDatabase::open(temp_db.path().to_str().unwrap()).unwrap();
+        assert_eq!(db.cache.format(), 0);
+
+        // Stamping the writer version alone leaves the format alone
+        db.record_writer_version().unwrap();
+        assert_eq!(db.cache.format(), 0);
+
+        db.record_fingerprint_format().unwrap();
+        assert_eq!(db.cache.format(), FINGERPRINT_FORMAT);
+        assert_eq!(
+            db.get_metadata_internal(FINGERPRINT_FORMAT_KEY)
+                .unwrap()
+                .as_deref(),
+            Some(FINGERPRINT_FORMAT.to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn test_cache_is_not_served_across_fingerprint_formats() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let path = temp_db.path().to_str().unwrap();
+        let old_format = FINGERPRINT_FORMAT - 1;
+        let db = PytestDiffDatabase::open(path).unwrap();
+        db.set_metadata_internal(FINGERPRINT_FORMAT_KEY, &old_format.to_string())
+            .unwrap();
+        drop(db);
+
+        // Opening keys the cache on the stored format
+        let db = PytestDiffDatabase::open(path).unwrap().with_cache_size(10);
+        assert_eq!(db.cache.format(), old_format);
+        db.get_or_create_fingerprint(&Fingerprint {
+            filename: "a.py".to_string(),
+            checksums: vec![1],
+            file_hash: "ha".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        })
+        .unwrap();
+        assert_eq!(
+            db.get_fingerprint_internal("a.py")
+                .unwrap()
+                .unwrap()
+                .checksums,
+            vec![1]
+        );
+
+        // Another writer rewrites the row in the current format
+        let other = PytestDiffDatabase::open(path).unwrap();
+        other
+            .conn()
+            .unwrap()
+            .write()
+            .execute(
+                "UPDATE file_fp SET method_checksums = ?1 WHERE filename = 'a.py'",
+                params![serialize_checksums(&[2])],
+            )
+            .unwrap();
+        other.record_fingerprint_format().unwrap();
+        assert_eq!(
+            db.get_fingerprint_internal("a.py")
+                .unwrap()
+                .unwrap()
+                .checksums,
+            vec![1]
+        );
+
+        assert!(db.refresh_cache_format_internal().unwrap());
+        assert!(!db.refresh_cache_format_internal().unwrap());
+        assert_eq!(db.cache.format(), FINGERPRINT_FORMAT);
+        assert_eq!(
+            db.get_fingerprint_internal("a.py")
+                .unwrap()
+                .unwrap()
+                .checksums,
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn test_open_deferred_creates_schema_on_first_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("deferred.db");
+        let db_path_str = db_path.to_str().unwrap();
+
+        let start = std::time::Instant::now();
+        let db = PytestDiffDatabase::open_deferred(db_path_str).unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+        // Nothing was written yet: no pragmas, no schema
+        let table_count = || -> i64 {
+            Connection::open(&db_path)
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(table_count(), 0);
+
+        db.set_metadata_internal("key", "value").unwrap();
+        assert!(table_count() > 0);
+        assert_eq!(
+            db.get_metadata_internal("key").unwrap().as_deref(),
+            Some("value")
+        );
+    }
+
+    #[test]
+    fn test_max_test_history_keeps_latest_executions() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        let count = |db: &PytestDiffDatabase| -> i64 {
+            db.conn
+                .read()
+                .query_row(
+                    "SELECT COUNT(*) FROM test_execution WHERE test_name = 'test_a'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+
+        // Default: only the latest execution survives
+        assert_eq!(db.get_max_test_history().unwrap(), Some(1));
+        db.save_test_execution_internal("test_a", vec![], 0.1, false, "3.12", false)
+            .unwrap();
+        db.save_test_execution_internal("test_a", vec![], 0.2, false, "3.12", false)
+            .unwrap();
+        assert_eq!(count(&db), 1);
 
-        // Retrieving again should return same ID
-        let fp_id2 = db.get_or_create_fingerprint(&fp).unwrap();
-        assert_eq!(fp_id, fp_id2);
+        let n = 3;
+        db.set_max_test_history(Some(n)).unwrap();
+        for i in 0..n + 2 {
+            db.save_test_execution_internal("test_a", vec![], i as f64, false, "3.12", false)
+                .unwrap();
+        }
+        assert_eq!(count(&db), n as i64);
+        let durations: Vec<f64> = db
+            .conn
+            .read()
+            .prepare("SELECT duration FROM test_execution ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(durations, vec![2.0, 3.0, 4.0]);
+
+        db.set_max_test_history(None).unwrap();
+        for _ in 0..2 {
+            db.save_test_execution_internal("test_a", vec![], 0.1, false, "3.12", false)
+                .unwrap();
+        }
+        assert_eq!(count(&db), n as i64 + 2);
+
+        assert!(db.set_max_test_history(Some(0)).is_err());
     }
 
     #[test]
-    fn test_save_test_execution() {
+    fn test_forced_flag_round_trip() {
         let temp_db = NamedTempFile::new().unwrap();
         let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
 
-        let fp = Fingerprint {
-            filename: "test.py".to_string(),
-            checksums: vec![123],
-            file_hash: "abc".to_string(),
-            mtime: 1.0,
-            blocks: None,
-        };
-
-        db.save_test_execution_internal("test_example", vec![fp], 0.5, false, "3.12")
+        db.save_test_execution_internal("test_forced", vec![], 0.1, false, "3.12", true)
+            .unwrap();
+        db.save_test_execution_internal("test_selected", vec![], 0.1, false, "3.12", false)
             .unwrap();
 
-        let stats = db.get_stats_internal().unwrap();
-        assert_eq!(stats["test_count"], 1);
-        assert_eq!(stats["file_count"], 1);
+        let forced = db
+            .get_test_execution_internal("test_forced")
+            .unwrap()
+            .unwrap();
+        assert!(forced.forced);
+        let selected = db
+            .get_test_execution_internal("test_selected")
+            .unwrap()
+            .unwrap();
+        assert!(!selected.forced);
     }
 
     #[test]
     fn test_checksum_serialization() {
         let checksums = vec![123, -456, 789, -1];
         let blob = serialize_checksums(&checksums);
-        let deserialized = deserialize_checksums(&blob);
+        let deserialized = deserialize_checksums(&blob).unwrap();
 
         assert_eq!(checksums, deserialized);
     }
 
+    #[test]
+    fn test_deserialize_rejects_truncated_blob() {
+        let blob = serialize_checksums(&[123, -456]);
+        for len in [1, 2, 3, 5, 6, 7] {
+            let err = deserialize_checksums(&blob[..len]).unwrap_err();
+            assert_eq!(err.len, len);
+        }
+        assert_eq!(deserialize_checksums(&[]).unwrap(), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_corrupt_blob_is_a_query_error() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        db.conn
+            .read()
+            .execute(
+                "INSERT INTO baseline_fp (filename, method_checksums, mtime, fsha)
+                 VALUES ('a.py', x'0102030405', 1.0, 'abc')",
+                [],
+            )
+            .unwrap();
+
+        let err = db.get_baseline_fingerprint_internal("a.py").unwrap_err();
+        assert!(format!("{:#}", err).contains("Corrupt checksum blob: 5 bytes"));
+    }
+
+    #[test]
+    fn test_named_checksums_decode_alongside_plain_blobs() {
+        let block = |name: &str, checksum: i32| Block {
+            start_line: 1,
+            end_line: 1,
+            checksum,
+            name: name.to_string(),
+            block_type: "function".to_string(),
+            body_start_line: 1,
+        };
+        let blob = serialize_named_checksums(&[block("<module>", 7), block("f", -3)]);
+        assert_eq!(blob.len(), 17);
+        assert_eq!(deserialize_checksums(&blob).unwrap(), vec![7, -3]);
+        assert_eq!(
+            deserialize_named_checksums(&blob).unwrap(),
+            Some(vec![
+                (7, block_name_hash("<module>")),
+                (-3, block_name_hash("f"))
+            ])
+        );
+
+        // Old blobs still decode, without names
+        let plain = serialize_checksums(&[7, -3]);
+        assert_eq!(deserialize_named_checksums(&plain).unwrap(), None);
+        // A truncated tagged blob is corrupt, not silently shortened
+        assert!(deserialize_checksums(&blob[..13]).is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_deserialize_never_mangles(blob in proptest::collection::vec(proptest::num::u8::ANY, 0..64)) {
+            match deserialize_checksums(&blob) {
+                // Whole blobs round-trip exactly
+                Ok(checksums) if is_named_blob(&blob) => {
+                    let pairs = deserialize_named_checksums(&blob).unwrap().unwrap();
+                    proptest::prop_assert_eq!(pairs.len(), checksums.len());
+                    let mut reencoded = vec![NAMED_CHECKSUMS_TAG];
+                    for (checksum, name_hash) in pairs {
+                        reencoded.extend(checksum.to_le_bytes());
+                        reencoded.extend(name_hash.to_le_bytes());
+                    }
+                    proptest::prop_assert_eq!(reencoded, blob);
+                }
+                Ok(checksums) => proptest::prop_assert_eq!(serialize_checksums(&checksums), blob),
+                Err(e) => {
+                    proptest::prop_assert!(!blob.len().is_multiple_of(4));
+                    proptest::prop_assert_eq!(e.len, blob.len());
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_import_baseline_from() {
         // Create source database with baseline fingerprints
@@ -1358,6 +4623,232 @@ mod tests {
         assert_eq!(imported_fp.file_hash, "hash_foo");
     }
 
+    #[test]
+    fn test_list_baseline_files() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+
+        assert!(db
+            .list_baseline_files_internal(DEFAULT_BASELINE)
+            .unwrap()
+            .is_empty());
+
+        for name in ["src/b.py", "src/a.py", "tests/test_a.py"] {
+            db.save_baseline_fingerprint_internal(Fingerprint {
+                filename: name.to_string(),
+                checksums: vec![1],
+                file_hash: format!("hash_{}", name),
+                mtime: 1.0,
+                blocks: None,
+            })
+            .unwrap();
+        }
+        // Re-saving a file must not duplicate it
+        db.save_baseline_fingerprint_internal(Fingerprint {
+            filename: "src/a.py".to_string(),
+            checksums: vec![2],
+            file_hash: "hash_new".to_string(),
+            mtime: 2.0,
+            blocks: None,
+        })
+        .unwrap();
+
+        assert_eq!(
+            db.list_baseline_files_internal(DEFAULT_BASELINE).unwrap(),
+            vec!["src/a.py", "src/b.py", "tests/test_a.py"]
+        );
+
+        // Named baselines list their own files
+        let b = db.get_baseline_fingerprint_internal("src/b.py").unwrap();
+        db.save_named_baseline_fingerprints_batch("pr", b.into_iter().collect())
+            .unwrap();
+        assert_eq!(
+            db.list_baseline_files_internal("pr").unwrap(),
+            vec!["src/b.py"]
+        );
+        assert!(db.list_baseline_files_internal("other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_baseline_counts_stale_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        let db_path = dir.path().join("test.db");
+        let mut db = PytestDiffDatabase::new_internal(db_path.to_str().unwrap()).unwrap();
+
+        for name in ["src/a.py", "src/b.py", "src/c.py", "src/d.py"] {
+            let content = format!("# {}\n", name);
+            std::fs::write(root.join(name), &content).unwrap();
+            db.save_baseline_fingerprint_internal(Fingerprint {
+                filename: name.to_string(),
+                checksums: vec![1],
+                file_hash: blake3::hash(content.as_bytes()).to_hex().to_string(),
+                mtime: 1.0,
+                blocks: None,
+            })
+            .unwrap();
+        }
+        std::fs::write(root.join("src/b.py"), "# edited\n").unwrap();
+        std::fs::remove_file(root.join("src/d.py")).unwrap();
+
+        let report = db
+            .validate_baseline_internal(root.to_str().unwrap(), DEFAULT_BASELINE)
+            .unwrap();
+        assert_eq!(report.total, 4);
+        assert_eq!(report.fresh, 2);
+        assert_eq!(report.changed, 1);
+        assert_eq!(report.missing, 1);
+        assert_eq!(report.fresh_ratio(), 0.5);
+        // Validation leaves the baseline untouched
+        assert_eq!(
+            db.list_baseline_files_internal(DEFAULT_BASELINE)
+                .unwrap()
+                .len(),
+            4
+        );
+
+        // A named baseline is validated against its own rows
+        let a = db.get_baseline_fingerprint_internal("src/a.py").unwrap();
+        db.save_named_baseline_fingerprints_batch("pr", a.into_iter().collect())
+            .unwrap();
+        let report = db
+            .validate_baseline_internal(root.to_str().unwrap(), "pr")
+            .unwrap();
+        assert_eq!((report.total, report.fresh), (1, 1));
+    }
+
+    #[test]
+    fn test_recover_baseline_keeps_test_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "def f():\n    return 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let mut db = PytestDiffDatabase::new_internal(db_path.to_str().unwrap()).unwrap();
+
+        let fp = crate::fingerprint::calculate_fingerprint_internal(
+            root.join("a.py").to_str().unwrap(),
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let recorded = Fingerprint {
+            filename: "a.py".to_string(),
+            blocks: None,
+            ..fp.clone()
+        };
+        db.save_test_execution_internal("test_f", vec![recorded], 0.5, false, "3.12", false)
+            .unwrap();
+
+        // Baseline written by an incompatible version: unreadable checksums
+        db.set_metadata_internal(WRITER_VERSION_KEY, "0.0.1")
+            .unwrap();
+        db.conn
+            .read()
+            .execute(
+                "INSERT INTO baseline_fp (filename, method_checksums, mtime, fsha)
+                 VALUES ('a.py', x'0102030405', 1.0, 'stale')",
+                [],
+            )
+            .unwrap();
+        assert!(db.get_baseline_fingerprint_internal("a.py").is_err());
+        db.set_baseline_label(DEFAULT_BASELINE, Some("nightly"))
+            .unwrap();
+
+        assert_eq!(
+            db.recover_baseline_internal(root_str, DEFAULT_BASELINE)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            db.get_baseline_metadata_internal(DEFAULT_BASELINE).unwrap()["label"],
+            "nightly"
+        );
+
+        let baseline = db.get_all_baseline_fingerprints().unwrap();
+        assert_eq!(baseline["a.py"].checksums, fp.checksums);
+        assert_eq!(baseline["a.py"].file_hash, fp.file_hash);
+        assert_eq!(
+            db.get_writer_version_internal().unwrap().as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        // Test history survives the rebuild
+        assert_eq!(db.get_recorded_tests_internal().unwrap(), vec!["test_f"]);
+        assert_eq!(
+            db.get_test_dependencies_internal("test_f").unwrap(),
+            vec!["a.py"]
+        );
+    }
+
+    #[test]
+    fn test_failed_recovery_keeps_previous_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "def f():\n    return 1\n").unwrap();
+        let db_path = dir.path().join("test.db");
+        let mut db = PytestDiffDatabase::new_internal(db_path.to_str().unwrap()).unwrap();
+        crate::fingerprint::save_baseline_with_db(
+            &mut db,
+            root.to_str().unwrap(),
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+        )
+        .unwrap();
+
+        let missing = dir.path().join("moved");
+        assert!(db
+            .recover_baseline_internal(missing.to_str().unwrap(), DEFAULT_BASELINE)
+            .is_err());
+        assert_eq!(
+            db.list_baseline_files_internal(DEFAULT_BASELINE).unwrap(),
+            vec!["a.py"]
+        );
+    }
+
+    #[test]
+    fn test_named_baselines_are_independent() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        let mut db = PytestDiffDatabase::open(db_path.to_str().unwrap()).unwrap();
+
+        let fp = |hash: &str| Fingerprint {
+            filename: "a.py".to_string(),
+            checksums: vec![1],
+            file_hash: hash.to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+        db.save_named_baseline_fingerprints_batch("main", vec![fp("main")])
+            .unwrap();
+        db.save_named_baseline_fingerprints_batch("release", vec![fp("release")])
+            .unwrap();
+
+        assert_eq!(
+            db.list_baselines_internal().unwrap(),
+            vec!["main", "release"]
+        );
+        assert!(db.get_all_baseline_fingerprints().unwrap().is_empty());
+        let main = db.get_named_baseline_fingerprints("main").unwrap();
+        assert_eq!(main["a.py"].file_hash, "main");
+        let release = db.get_named_baseline_fingerprints("release").unwrap();
+        assert_eq!(release["a.py"].file_hash, "release");
+
+        db.save_baseline_fingerprints_batch(vec![fp("default")])
+            .unwrap();
+        assert_eq!(
+            db.list_baselines_internal().unwrap(),
+            vec!["default", "main", "release"]
+        );
+        assert!(db
+            .get_named_baseline_fingerprints("missing")
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn test_import_baseline_from_nonexistent() {
         let temp_db = NamedTempFile::new().unwrap();
@@ -1582,9 +5073,9 @@ mod tests {
             blocks: None,
         };
 
-        db.save_test_execution_internal("test_one", vec![fp.clone()], 0.1, false, "3.12")
+        db.save_test_execution_internal("test_one", vec![fp.clone()], 0.1, false, "3.12", false)
             .unwrap();
-        db.save_test_execution_internal("test_two", vec![fp], 0.2, false, "3.12")
+        db.save_test_execution_internal("test_two", vec![fp], 0.2, false, "3.12", false)
             .unwrap();
 
         let mut changed = HashMap::new();
@@ -1596,6 +5087,293 @@ mod tests {
         assert!(affected.contains(&"test_two".to_string()));
     }
 
+    #[test]
+    fn test_get_affected_tests_for_new_and_modified_files() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        let fp = |filename: &str, checksums: Vec<i32>| Fingerprint {
+            filename: filename.to_string(),
+            checksums,
+            file_hash: format!("hash_{}", filename),
+            mtime: 1.0,
+            blocks: None,
+        };
+        for (test, dependency) in [
+            ("test_f", fp("old.py", vec![1])),
+            ("test_g", fp("old.py", vec![2])),
+            ("test_new", fp("new.py", vec![7])),
+        ] {
+            db.save_test_execution_internal(test, vec![dependency], 0.1, false, "3.12", false)
+                .unwrap();
+        }
+
+        // old.py had block 1 modified; new.py has no baseline, so its current
+        // checksums can't match what test_new recorded
+        let changed = ChangedFiles {
+            modified: vec!["old.py".to_string(), "new.py".to_string()],
+            changed_blocks: [
+                ("old.py".to_string(), vec![1]),
+                ("new.py".to_string(), vec![8, 9]),
+            ]
+            .into_iter()
+            .collect(),
+            missing_scope_paths: vec![],
+            skipped_paths: vec![],
+            encoding_mismatches: vec![],
+            decisions: HashMap::new(),
+            baseline_empty: false,
+            block_changes: vec![
+                ("old.py".into(), "f".into(), "modified".into()),
+                ("new.py".into(), "<module>".into(), "added".into()),
+                ("new.py".into(), "g".into(), "added".into()),
+            ],
+            changed_block_types: HashMap::new(),
+            block_types_by_file: HashMap::new(),
+        };
+
+        assert_eq!(
+            db.get_affected_tests_internal(changed.changed_blocks.clone())
+                .unwrap(),
+            vec!["test_f"]
+        );
+        assert_eq!(
+            db.get_affected_tests_for_internal(&changed).unwrap(),
+            vec!["test_f", "test_new"]
+        );
+    }
+
+    #[test]
+    fn test_get_affected_test_counts_per_changed_file() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        let fp = |filename: &str, checksums: Vec<i32>| Fingerprint {
+            filename: filename.to_string(),
+            checksums,
+            file_hash: format!("hash_{}", filename),
+            mtime: 1.0,
+            blocks: None,
+        };
+        for (test, dependencies) in [
+            ("test_1", vec![fp("a.py", vec![1]), fp("b.py", vec![5])]),
+            ("test_2", vec![fp("a.py", vec![1, 2])]),
+            ("test_3", vec![fp("a.py", vec![3]), fp("b.py", vec![5])]),
+            ("test_4", vec![fp("new.py", vec![9])]),
+            ("test_5", vec![fp("new.py", vec![9]), fp("c.py", vec![6])]),
+        ] {
+            db.save_test_execution_internal(test, dependencies, 0.1, false, "3.12", false)
+                .unwrap();
+        }
+
+        let changed = ChangedFiles {
+            modified: vec![
+                "a.py".to_string(),
+                "b.py".to_string(),
+                "c.py".to_string(),
+                "new.py".to_string(),
+            ],
+            changed_blocks: [
+                ("a.py".to_string(), vec![1]),
+                ("b.py".to_string(), vec![5]),
+                ("c.py".to_string(), vec![7]),
+                ("new.py".to_string(), vec![10]),
+            ]
+            .into_iter()
+            .collect(),
+            missing_scope_paths: vec![],
+            skipped_paths: vec![],
+            encoding_mismatches: vec![],
+            decisions: HashMap::new(),
+            baseline_empty: false,
+            block_changes: vec![("new.py".into(), "<module>".into(), "added".into())],
+            changed_block_types: HashMap::new(),
+            block_types_by_file: HashMap::new(),
+        };
+
+        let counts = db.get_affected_test_counts_internal(&changed).unwrap();
+        let expected: HashMap<String, usize> =
+            [("a.py", 2), ("b.py", 2), ("c.py", 0), ("new.py", 2)]
+                .into_iter()
+                .map(|(file, count)| (file.to_string(), count))
+                .collect();
+        assert_eq!(counts, expected);
+        assert_eq!(
+            db.get_affected_tests_for_internal(&changed).unwrap(),
+            vec!["test_1", "test_2", "test_3", "test_4", "test_5"]
+        );
+    }
+
+    #[test]
+    fn test_backslash_filenames_match_forward_slash_lookups() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        let fp = Fingerprint {
+            filename: "src\\pkg\\mod.py".to_string(),
+            checksums: vec![1, 2],
+            file_hash: "abc".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+        db.save_baseline_fingerprint_internal(fp.clone()).unwrap();
+        db.save_test_execution_internal("test_mod", vec![fp], 0.1, false, "3.12", false)
+            .unwrap();
+
+        let baseline = db
+            .get_baseline_fingerprint_internal("src/pkg/mod.py")
+            .unwrap()
+            .unwrap();
+        assert_eq!(baseline.filename, "src/pkg/mod.py");
+        assert!(db
+            .get_named_baseline_fingerprints(DEFAULT_BASELINE)
+            .unwrap()
+            .contains_key("src/pkg/mod.py"));
+        assert!(db
+            .get_fingerprint_internal("src/pkg/mod.py")
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            db.get_affected_tests_internal([("src/pkg/mod.py".to_string(), vec![2])].into())
+                .unwrap(),
+            vec!["test_mod"]
+        );
+        // Backslash lookups are normalized the same way
+        assert_eq!(
+            db.get_file_dependents_internal("src\\pkg\\mod.py").unwrap(),
+            vec!["test_mod"]
+        );
+    }
+
+    #[test]
+    fn test_get_tests_for_files_ignores_checksums() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        let fp = |filename: &str, checksums: Vec<i32>| Fingerprint {
+            filename: filename.to_string(),
+            checksums,
+            file_hash: format!("hash_{}", filename),
+            mtime: 1.0,
+            blocks: None,
+        };
+        for (test, dependencies) in [
+            ("test_a1", vec![fp("a.py", vec![1])]),
+            ("test_a2", vec![fp("a.py", vec![2, 3])]),
+            ("test_ab", vec![fp("a.py", vec![4]), fp("b.py", vec![5])]),
+            ("test_b", vec![fp("b.py", vec![6])]),
+            ("test_c", vec![fp("c.py", vec![7])]),
+        ] {
+            db.save_test_execution_internal(test, dependencies, 0.1, false, "3.12", false)
+                .unwrap();
+        }
+
+        assert_eq!(
+            db.get_tests_for_files_internal(&["a.py".to_string()])
+                .unwrap(),
+            vec!["test_a1", "test_a2", "test_ab"]
+        );
+        assert_eq!(
+            db.get_tests_for_files_internal(&["b.py".to_string(), "missing.py".to_string()])
+                .unwrap(),
+            vec!["test_ab", "test_b"]
+        );
+        assert!(db.get_tests_for_files_internal(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_tests_for_checksum() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+        let fp = |filename: &str, checksums: Vec<i32>| Fingerprint {
+            filename: filename.to_string(),
+            checksums,
+            file_hash: format!("hash_{}", filename),
+            mtime: 1.0,
+            blocks: None,
+        };
+        for (test, dependencies) in [
+            ("test_z", vec![fp("a.py", vec![1, 2])]),
+            ("test_y", vec![fp("a.py", vec![2, 3])]),
+            ("test_x", vec![fp("a.py", vec![3])]),
+            // Same checksum in another file doesn't count
+            ("test_other", vec![fp("b.py", vec![2])]),
+        ] {
+            db.save_test_execution_internal(test, dependencies, 0.1, false, "3.12", false)
+                .unwrap();
+        }
+
+        assert_eq!(
+            db.get_tests_for_checksum_internal("a.py", 2).unwrap(),
+            vec!["test_y", "test_z"]
+        );
+        assert_eq!(
+            db.get_tests_for_checksum_internal("a.py", 1).unwrap(),
+            vec!["test_z"]
+        );
+        assert!(db
+            .get_tests_for_checksum_internal("a.py", 9)
+            .unwrap()
+            .is_empty());
+        assert!(db
+            .get_tests_for_checksum_internal("missing.py", 2)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_affected_tests_stable_ordering() {
+        let temp_db = NamedTempFile::new().unwrap();
+        let mut db = PytestDiffDatabase::new_internal(temp_db.path().to_str().unwrap()).unwrap();
+
+        let fp_a = Fingerprint {
+            filename: "a.py".to_string(),
+            checksums: vec![1, 2],
+            file_hash: "hash_a".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+        let fp_b = Fingerprint {
+            filename: "b.py".to_string(),
+            checksums: vec![3],
+            file_hash: "hash_b".to_string(),
+            mtime: 1.0,
+            blocks: None,
+        };
+
+        // Record tests in non-alphabetical order
+        for name in ["test_zeta", "test_alpha", "test_mid"] {
+            db.save_test_execution_internal(
+                name,
+                vec![fp_a.clone(), fp_b.clone()],
+                0.1,
+                false,
+                "3.12",
+                false,
+            )
+            .unwrap();
+        }
+
+        let entries = [
+            ("a.py".to_string(), vec![2, 1]),
+            ("b.py".to_string(), vec![3]),
+        ];
+        let forward: HashMap<String, Vec<i32>> = entries.iter().cloned().collect();
+        let reversed: HashMap<String, Vec<i32>> = entries
+            .iter()
+            .rev()
+            .map(|(f, c)| (f.clone(), c.iter().rev().copied().collect()))
+            .collect();
+
+        let expected = vec!["test_alpha", "test_mid", "test_zeta"];
+        for _ in 0..5 {
+            assert_eq!(
+                db.get_affected_tests_internal(forward.clone()).unwrap(),
+                expected
+            );
+            assert_eq!(
+                db.get_affected_tests_internal(reversed.clone()).unwrap(),
+                expected
+            );
+        }
+    }
+
     #[test]
     fn test_import_baseline_copies_test_executions() {
         // Create source database with test execution data
@@ -1612,10 +5390,10 @@ mod tests {
         };
 
         source_db
-            .save_test_execution_internal("test_one", vec![fp.clone()], 0.1, false, "3.12")
+            .save_test_execution_internal("test_one", vec![fp.clone()], 0.1, false, "3.12", false)
             .unwrap();
         source_db
-            .save_test_execution_internal("test_two", vec![fp], 0.2, false, "3.12")
+            .save_test_execution_internal("test_two", vec![fp], 0.2, false, "3.12", false)
             .unwrap();
         source_db
             .save_baseline_fingerprint_internal(Fingerprint {
@@ -1664,7 +5442,7 @@ mod tests {
             blocks: None,
         };
         source1_db
-            .save_test_execution_internal("test_alpha", vec![fp1], 0.1, false, "3.12")
+            .save_test_execution_internal("test_alpha", vec![fp1], 0.1, false, "3.12", false)
             .unwrap();
         source1_db.close_and_checkpoint().unwrap();
 
@@ -1681,7 +5459,7 @@ mod tests {
             blocks: None,
         };
         source2_db
-            .save_test_execution_internal("test_beta", vec![fp2], 0.2, false, "3.12")
+            .save_test_execution_internal("test_beta", vec![fp2], 0.2, false, "3.12", false)
             .unwrap();
         source2_db.close_and_checkpoint().unwrap();
 
@@ -0,0 +1,182 @@
+// Source file decoding with PEP 263 encoding declaration checks
+//
+// Python sources are UTF-8 unless a `coding` comment in the first two lines
+// says otherwise. Checksums are computed over the decoded text, so a file whose
+// bytes disagree with its declaration is reported as a distinct error instead
+// of being fingerprinted (or skipped) like any other unreadable file.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// A file whose bytes don't match its declared encoding
+#[derive(Debug)]
+pub struct EncodingMismatch {
+    pub path: PathBuf,
+    /// Encoding named by the PEP 263 declaration
+    pub declared: String,
+    /// Why the bytes don't match it
+    pub reason: String,
+}
+
+impl fmt::Display for EncodingMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Encoding mismatch in {}: declared '{}' but {}",
+            self.path.display(),
+            self.declared,
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for EncodingMismatch {}
+
+/// Read a Python source file as text, validating its encoding declaration
+///
+/// Fails with [`EncodingMismatch`] when the file declares UTF-8 but contains
+/// invalid UTF-8, or starts with a UTF-8 BOM while declaring something else.
+pub(crate) fn read_python_source(path: &Path) -> Result<String> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    let declared = declared_encoding(&bytes);
+    let mismatch = |declared: &str, reason: String| EncodingMismatch {
+        path: path.to_path_buf(),
+        declared: declared.to_string(),
+        reason,
+    };
+
+    match String::from_utf8(bytes) {
+        Ok(content) => match declared {
+            Some(name) if content.as_bytes().starts_with(UTF8_BOM) && !is_utf8(&name) => {
+                Err(mismatch(&name, "the file starts with a UTF-8 BOM".to_string()).into())
+            }
+            _ => Ok(content),
+        },
+        Err(e) => match declared {
+            Some(name) if is_utf8(&name) => Err(mismatch(
+                &name,
+                format!("byte {} is not valid UTF-8", e.utf8_error().valid_up_to()),
+            )
+            .into()),
+            _ => Err(anyhow::Error::new(e.utf8_error()))
+                .with_context(|| format!("Failed to read file: {}", path.display())),
+        },
+    }
+}
+
+/// Encoding named by a PEP 263 declaration in the first two lines, if any
+///
+/// Matches `^[ \t\f]*#.*?coding[:=][ \t]*([-\w.]+)`; the second line is only
+/// considered when the first one is blank or a comment, as in CPython.
+fn declared_encoding(bytes: &[u8]) -> Option<String> {
+    let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes);
+    let mut lines = bytes.split(|&b| b == b'\n');
+
+    let first = lines.next()?;
+    if let Some(name) = coding_comment(first) {
+        return Some(name);
+    }
+    let first = trim_start(first);
+    if !first.is_empty() && first[0] != b'#' && first != b"\r" {
+        return None;
+    }
+    lines.next().and_then(coding_comment)
+}
+
+fn coding_comment(line: &[u8]) -> Option<String> {
+    let comment = trim_start(line).strip_prefix(b"#")?;
+    let pos = comment.windows(6).position(|w| w == b"coding")?;
+    let rest = comment[pos + 6..]
+        .strip_prefix(b":")
+        .or_else(|| comment[pos + 6..].strip_prefix(b"="))?;
+    let rest = rest
+        .iter()
+        .position(|&b| b != b' ' && b != b'\t')
+        .map(|i| &rest[i..])?;
+    let len = rest
+        .iter()
+        .position(|&b| !(b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.')))
+        .unwrap_or(rest.len());
+    (len > 0).then(|| String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+fn trim_start(line: &[u8]) -> &[u8] {
+    let start = line
+        .iter()
+        .position(|&b| !matches!(b, b' ' | b'\t' | b'\x0c'))
+        .unwrap_or(line.len());
+    &line[start..]
+}
+
+/// Whether an encoding name is a spelling of UTF-8 (`utf-8`, `UTF8`, `utf_8-sig`, ...)
+fn is_utf8(name: &str) -> bool {
+    let name = name.to_ascii_lowercase().replace('_', "-");
+    name == "utf8" || name == "utf-8" || name.starts_with("utf-8-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_declared_encoding() {
+        assert_eq!(
+            declared_encoding(b"# -*- coding: utf-8 -*-\nx = 1\n").as_deref(),
+            Some("utf-8")
+        );
+        assert_eq!(
+            declared_encoding(b"#!/usr/bin/env python\n# vim: set fileencoding=latin-1 :\n")
+                .as_deref(),
+            Some("latin-1")
+        );
+        // Only the first two lines count, and line 2 only after a comment/blank line
+        assert_eq!(declared_encoding(b"\n\n# coding: utf-8\n"), None);
+        assert_eq!(declared_encoding(b"x = 1\n# coding: utf-8\n"), None);
+        assert_eq!(declared_encoding(b"x = 1  # coding: utf-8\n"), None);
+        assert_eq!(declared_encoding(b"x = 1\n"), None);
+    }
+
+    #[test]
+    fn test_is_utf8() {
+        assert!(is_utf8("utf-8"));
+        assert!(is_utf8("UTF8"));
+        assert!(is_utf8("utf_8"));
+        assert!(is_utf8("utf-8-sig"));
+        assert!(!is_utf8("latin-1"));
+        assert!(!is_utf8("utf-16"));
+    }
+
+    #[test]
+    fn test_read_python_source_reports_mislabeled_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mislabeled.py");
+        // 0xe9 is "é" in latin-1 but invalid on its own in UTF-8
+        std::fs::write(&path, b"# coding: utf-8\nname = 'caf\xe9'\n").unwrap();
+
+        let err = read_python_source(&path).unwrap_err();
+        let mismatch = err.downcast_ref::<EncodingMismatch>().unwrap();
+        assert_eq!(mismatch.declared, "utf-8");
+        assert!(err.to_string().contains("Encoding mismatch"));
+        assert!(err.to_string().contains("byte 27 is not valid UTF-8"));
+
+        // Undeclared invalid UTF-8 is a plain read failure, not a mismatch
+        std::fs::write(&path, b"name = 'caf\xe9'\n").unwrap();
+        let err = read_python_source(&path).unwrap_err();
+        assert!(err.downcast_ref::<EncodingMismatch>().is_none());
+
+        // A BOM contradicts any non-UTF-8 declaration
+        std::fs::write(&path, b"\xef\xbb\xbf# coding: latin-1\nx = 1\n").unwrap();
+        let err = read_python_source(&path).unwrap_err();
+        assert!(err.downcast_ref::<EncodingMismatch>().is_some());
+
+        std::fs::write(&path, b"# coding: utf-8\nname = 'caf\xc3\xa9'\n").unwrap();
+        assert_eq!(
+            read_python_source(&path).unwrap(),
+            "# coding: utf-8\nname = 'café'\n"
+        );
+    }
+}
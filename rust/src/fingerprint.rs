@@ -14,30 +14,172 @@ use std::path::{Path, PathBuf};
 use std::time::UNIX_EPOCH;
 use walkdir::WalkDir;
 
-use crate::database::PytestDiffDatabase;
-use crate::parser::parse_module_internal;
-use crate::types::{Block, ChangedFiles, Fingerprint};
+use crate::database::{
+    normalize_filename, BaselineSnapshot, Checkpoint, PytestDiffDatabase, DEFAULT_BASELINE,
+    PYTHON_EXTENSION,
+};
+use crate::encoding::{read_python_source, EncodingMismatch};
+use crate::parser::{
+    calculate_checksum, parse_checksums_with_options, parse_module_with_options, Normalization,
+    ParseOptions,
+};
+use crate::types::{Block, ChangedFiles, DetectionResult, Fingerprint, SaveResult};
 
 /// Convert an absolute path to a relative path by stripping the project root prefix.
 /// Falls back to the original path if it doesn't start with project_root.
-fn make_relative(abs_path: &str, project_root: &str) -> String {
-    Path::new(abs_path)
+/// Separators are normalized to `/` so keys match baselines from any platform.
+pub(crate) fn make_relative(abs_path: &str, project_root: &str) -> String {
+    let relative = Path::new(abs_path)
         .strip_prefix(project_root)
         .map(|p| p.to_string_lossy().to_string())
-        .unwrap_or_else(|_| abs_path.to_string())
+        .unwrap_or_else(|_| abs_path.to_string());
+    normalize_filename(&relative).into_owned()
+}
+
+/// Resolve a path to the real casing of the file it names on disk
+///
+/// A path that exists as spelled is returned unchanged (on case-insensitive
+/// filesystems every spelling does, so this targets case-sensitive ones,
+/// where tools with inconsistent roots disagree on casing). Otherwise each
+/// missing component is matched case-insensitively against its directory's
+/// entries and replaced by the one entry that matches, so `Src/Mod.py` from
+/// one tool and `src/mod.py` from another resolve to the same key. Returns
+/// `None` when a component matches no entry or several (e.g. `mod.py` and
+/// `Mod.py` both exist on a case-sensitive filesystem): the spelling doesn't
+/// identify one real file.
+pub(crate) fn resolve_path_case(path: &Path) -> Option<PathBuf> {
+    use std::path::Component;
+
+    if path.symlink_metadata().is_ok() {
+        return Some(path.to_path_buf());
+    }
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        let Component::Normal(name) = component else {
+            resolved.push(component);
+            continue;
+        };
+        let exact = resolved.join(name);
+        if exact.symlink_metadata().is_ok() {
+            resolved = exact;
+            continue;
+        }
+        let wanted = name.to_string_lossy().to_lowercase();
+        let dir = if resolved.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &resolved
+        };
+        let mut matches = std::fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().to_lowercase() == wanted);
+        let entry = matches.next()?;
+        if matches.next().is_some() {
+            return None;
+        }
+        resolved.push(entry.file_name());
+    }
+    Some(resolved)
+}
+
+/// Re-key coverage by the real casing of each file, merging the executed lines
+/// of spellings that resolve to the same file
+///
+/// Only tracked files under `project_root` are resolved, so installed packages
+/// and other files filtered out later cost no filesystem lookups. Paths that
+/// don't resolve are kept as given. `save_baseline` and `detect_changes` need
+/// no such step: they walk the project, so their paths carry the real casing.
+fn resolve_coverage_path_case(
+    coverage_data: HashMap<String, Vec<usize>>,
+    project_root: &Path,
+    extensions: &[String],
+) -> HashMap<String, Vec<usize>> {
+    let mut resolved: HashMap<String, Vec<usize>> = HashMap::with_capacity(coverage_data.len());
+    for (filename, lines) in coverage_data {
+        let path = Path::new(&filename);
+        let key = is_project_python_file(path, project_root, extensions)
+            .then(|| resolve_path_case(path))
+            .flatten()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or(filename);
+        resolved.entry(key).or_default().extend(lines);
+    }
+    for lines in resolved.values_mut() {
+        lines.sort_unstable();
+        lines.dedup();
+    }
+    resolved
+}
+
+/// Decides whether a path is a test file, overriding the built-in heuristics
+pub(crate) type TestClassifier<'a> = dyn Fn(&Path) -> Result<bool> + 'a;
+
+/// Wrap an optional Python callable `(path: str) -> bool` as a `TestClassifier`
+///
+/// The callable needs the GIL, so the returned closure must only be invoked
+/// from the calling thread, never from inside a Rayon parallel iterator.
+fn python_classifier<'py>(
+    callable: Option<&'py Bound<'py, PyAny>>,
+) -> Option<impl Fn(&Path) -> Result<bool> + 'py> {
+    callable.map(|callable| {
+        move |path: &Path| {
+            callable
+                .call1((path.to_string_lossy().as_ref(),))
+                .and_then(|result| result.is_truthy())
+                .map_err(|e| {
+                    anyhow::anyhow!("Test classifier failed for {}: {}", path.display(), e)
+                })
+        }
+    })
+}
+
+/// Built-in test file heuristic: `test_*.py` / `*_test.py` filenames, or any
+/// file below a `tests` or `test` directory
+///
+/// Uses Path components for cross-platform compatibility (works on both / and \)
+fn is_test_path(path: &Path) -> bool {
+    let filename = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+    let is_test_filename = filename.starts_with("test_") || filename.ends_with("_test.py");
+    let in_tests_dir = path.components().any(|c| {
+        if let std::path::Component::Normal(name) = c {
+            let name_str = name.to_string_lossy();
+            name_str == "tests" || name_str == "test"
+        } else {
+            false
+        }
+    });
+    is_test_filename || in_tests_dir
+}
+
+/// Classify a path with the custom classifier if given, else the built-in heuristic
+fn classify_test_path(path: &Path, classifier: Option<&TestClassifier>) -> Result<bool> {
+    match classifier {
+        Some(classify) => classify(path),
+        None => Ok(is_test_path(path)),
+    }
 }
 
 /// Calculate fingerprint for a single Python file
 ///
 /// # Arguments
 /// * `path` - Path to the Python file
+/// * `project_root` - If given, the returned filename is made relative to it
+/// * `options` - Parse settings, those of the database the fingerprint is
+///   compared with (see `PytestDiffDatabase.get_parse_options`); defaults to
+///   `ParseOptions()`
 ///
 /// # Returns
 /// * Fingerprint containing blocks, checksums, hash, and mtime
 #[pyfunction]
-#[pyo3(signature = (path, project_root=None))]
-pub fn calculate_fingerprint(path: &str, project_root: Option<&str>) -> PyResult<Fingerprint> {
-    let mut fingerprint = calculate_fingerprint_internal(path).map_err(|e| {
+#[pyo3(signature = (path, project_root=None, options=None))]
+pub fn calculate_fingerprint(
+    path: &str,
+    project_root: Option<&str>,
+    options: Option<ParseOptions>,
+) -> PyResult<Fingerprint> {
+    let options = options.unwrap_or_default();
+    let mut fingerprint = calculate_fingerprint_internal(path, options).map_err(|e| {
         pyo3::exceptions::PyIOError::new_err(format!("Failed to calculate fingerprint: {}", e))
     })?;
 
@@ -48,22 +190,176 @@ pub fn calculate_fingerprint(path: &str, project_root: Option<&str>) -> PyResult
     Ok(fingerprint)
 }
 
-pub(crate) fn calculate_fingerprint_internal(path: &str) -> Result<Fingerprint> {
-    let path = Path::new(path);
+/// Calculate a fingerprint for Python source held in memory
+///
+/// Same as `calculate_fingerprint`, but for content that was never written
+/// to disk (generated code, notebooks). `filename` and `mtime` are stored
+/// as given; the filename's extension decides whether unparseable content
+/// falls back to a single opaque block, as for tracked non-`.py` files.
+#[pyfunction]
+#[pyo3(signature = (filename, source, mtime, options=None))]
+pub fn fingerprint_source(
+    filename: &str,
+    source: &str,
+    mtime: f64,
+    options: Option<ParseOptions>,
+) -> PyResult<Fingerprint> {
+    fingerprint_source_internal(filename, source, mtime, options.unwrap_or_default()).map_err(|e| {
+        pyo3::exceptions::PySyntaxError::new_err(format!("Failed to fingerprint source: {}", e))
+    })
+}
 
-    // Read file content
-    let content = std::fs::read_to_string(path)
-        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+/// Parse a normalization mode name passed from Python
+pub(crate) fn parse_normalization(mode: &str) -> PyResult<Normalization> {
+    mode.parse()
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{}", e)))
+}
 
-    // Calculate file-level hash using Blake3 (fast!)
-    let file_hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+/// What a test depends on in each file it touches
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DependencyGranularity {
+    /// Only the blocks the test executed (most precise)
+    #[default]
+    Block,
+    /// Every block of the file, so any change to it reselects the test
+    File,
+    /// Executed blocks except the `<module>` block, which every test touching
+    /// the file executes: top-level edits (imports, constants, signatures) no
+    /// longer reselect tests that only imported the module, at the cost of
+    /// missing tests that depend on top-level values
+    ///
+    /// This under-selects: a test calling `f()` that returns a module
+    /// constant isn't reselected when only the constant changes, nor when an
+    /// import, a decorator or a class attribute it relies on does. Only use it
+    /// where top-level code is declarative and a periodic full run (or
+    /// `--diff-force`) catches the rest.
+    BlockWithoutModule,
+}
+
+impl std::str::FromStr for DependencyGranularity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "block" => Ok(DependencyGranularity::Block),
+            "file" => Ok(DependencyGranularity::File),
+            "block_without_module" => Ok(DependencyGranularity::BlockWithoutModule),
+            _ => anyhow::bail!(
+                "Unknown dependency granularity '{}' (expected 'block', 'file' or 'block_without_module')",
+                s
+            ),
+        }
+    }
+}
+
+pub(crate) fn calculate_fingerprint_internal(
+    path: &str,
+    options: ParseOptions,
+) -> Result<Fingerprint> {
+    calculate_fingerprint_with_mtime_internal(path, None, options)
+}
+
+/// Same as `calculate_fingerprint_internal`, but a caller that has just
+/// stat'ed the file passes its `mtime` to skip a second `metadata` call
+pub(crate) fn calculate_fingerprint_with_mtime_internal(
+    path: &str,
+    mtime: Option<f64>,
+    options: ParseOptions,
+) -> Result<Fingerprint> {
+    fingerprint_file(
+        Path::new(path),
+        mtime,
+        |content| parse_blocks(content, &options),
+        None,
+    )
+}
+
+/// Fingerprint in-memory source as if it were the file `filename`
+pub(crate) fn fingerprint_source_internal(
+    filename: &str,
+    source: &str,
+    mtime: f64,
+    options: ParseOptions,
+) -> Result<Fingerprint> {
+    fingerprint_content(
+        Path::new(filename),
+        source,
+        mtime,
+        |content| parse_blocks(content, &options),
+        None,
+    )
+}
+
+/// Fingerprint without block details (`blocks: None`), as stored in baselines
+///
+/// Streams checksums instead of collecting blocks, which keeps peak memory
+/// down on very large files.
+pub(crate) fn calculate_baseline_fingerprint_internal(
+    path: &str,
+    options: ParseOptions,
+) -> Result<Fingerprint> {
+    fingerprint_file(
+        Path::new(path),
+        None,
+        |content| parse_checksums(content, &options),
+        None,
+    )
+}
 
-    // Parse and extract blocks
-    let blocks = parse_module_internal(&content)
-        .map_err(|e| anyhow::anyhow!("Failed to parse Python file: {}", e))?;
+/// Baseline fingerprint of a file, as `calculate_fingerprint_internal` (with
+/// `keep_blocks`) or `calculate_baseline_fingerprint_internal` compute it,
+/// adding the time of each phase to `timings`
+fn baseline_fingerprint(
+    path: &Path,
+    options: &ParseOptions,
+    keep_blocks: bool,
+    timings: &PhaseTimings,
+) -> Result<Fingerprint> {
+    let parse = |content: &str| {
+        if keep_blocks {
+            parse_blocks(content, options)
+        } else {
+            parse_checksums(content, options)
+        }
+    };
+    fingerprint_file(path, None, parse, Some(timings))
+}
+
+/// Block checksums and, if kept, the blocks themselves
+type ParsedSource = (Vec<i32>, Option<Vec<Block>>);
+
+/// Block checksums of a source along with the blocks, as `fingerprint_content`
+/// expects them from its parser
+fn parse_blocks(content: &str, options: &ParseOptions) -> Result<ParsedSource> {
+    let blocks = parse_module_with_options(content, options)?;
+    let checksums = blocks.iter().map(|b| b.checksum).collect();
+    Ok((checksums, Some(blocks)))
+}
 
-    // Extract checksums
-    let checksums: Vec<i32> = blocks.iter().map(|b| b.checksum).collect();
+/// Block checksums of a source, streamed without collecting the blocks
+fn parse_checksums(content: &str, options: &ParseOptions) -> Result<ParsedSource> {
+    Ok((parse_checksums_with_options(content, options)?, None))
+}
+
+/// Read and fingerprint a file; `timings`, if given, collects the time of
+/// each phase
+fn fingerprint_file(
+    path: &Path,
+    mtime: Option<f64>,
+    parse: impl FnOnce(&str) -> Result<ParsedSource>,
+    timings: Option<&PhaseTimings>,
+) -> Result<Fingerprint> {
+    let (content, mtime) = PhaseTimings::measure(timings, Phase::Read, || match mtime {
+        Some(mtime) => Ok::<_, anyhow::Error>((read_python_source(path)?, mtime)),
+        None => read_source_with_mtime(path),
+    })?;
+    fingerprint_content(path, &content, mtime, parse, timings)
+}
+
+/// Read a source file and its modification time
+fn read_source_with_mtime(path: &Path) -> Result<(String, f64)> {
+    // Read file content
+    let content = read_python_source(path)?;
 
     // Get modification time
     let metadata = std::fs::metadata(path)
@@ -75,12 +371,293 @@ pub(crate) fn calculate_fingerprint_internal(path: &str) -> Result<Fingerprint>
         .with_context(|| "Invalid modification time")?
         .as_secs_f64();
 
+    Ok((content, mtime))
+}
+
+/// Phase of fingerprinting a file
+#[derive(Clone, Copy, Debug)]
+enum Phase {
+    Read,
+    Hash,
+    Parse,
+}
+
+/// Time spent in each fingerprinting phase, summed across threads
+#[derive(Debug, Default)]
+struct PhaseTimings {
+    read_nanos: std::sync::atomic::AtomicU64,
+    hash_nanos: std::sync::atomic::AtomicU64,
+    parse_nanos: std::sync::atomic::AtomicU64,
+}
+
+impl PhaseTimings {
+    /// Run `f`, adding its duration to `phase` of `timings` if given
+    fn measure<T>(timings: Option<&PhaseTimings>, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let Some(timings) = timings else {
+            return f();
+        };
+        let start = std::time::Instant::now();
+        let result = f();
+        let counter = match phase {
+            Phase::Read => &timings.read_nanos,
+            Phase::Hash => &timings.hash_nanos,
+            Phase::Parse => &timings.parse_nanos,
+        };
+        let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        counter.fetch_add(nanos, std::sync::atomic::Ordering::Relaxed);
+        result
+    }
+
+    fn secs(counter: &std::sync::atomic::AtomicU64) -> f64 {
+        counter.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1e9
+    }
+
+    /// Seconds spent reading, hashing and parsing
+    fn totals(&self) -> (f64, f64, f64) {
+        (
+            Self::secs(&self.read_nanos),
+            Self::secs(&self.hash_nanos),
+            Self::secs(&self.parse_nanos),
+        )
+    }
+}
+
+/// Where `save_baseline` reports how many files it has fingerprinted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgressStream {
+    /// stderr, only while `verbose` is set
+    #[default]
+    Auto,
+    /// stderr, even without `verbose`
+    Stderr,
+    /// stdout, even without `verbose`
+    Stdout,
+    /// Never report progress, even with `verbose`
+    None,
+}
+
+impl std::str::FromStr for ProgressStream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ProgressStream::Auto),
+            "stderr" => Ok(ProgressStream::Stderr),
+            "stdout" => Ok(ProgressStream::Stdout),
+            "none" => Ok(ProgressStream::None),
+            _ => anyhow::bail!(
+                "Unknown progress stream '{}' (expected 'auto', 'stderr', 'stdout' or 'none')",
+                s
+            ),
+        }
+    }
+}
+
+/// Files fingerprinted between two progress lines of `save_baseline` by default
+pub const DEFAULT_PROGRESS_INTERVAL: usize = 50;
+
+/// Counts fingerprinted files and writes a progress line every `interval`
+/// files (and after the last one) to its sink, if any
+struct ProgressReporter {
+    sink: Option<parking_lot::Mutex<Box<dyn std::io::Write + Send>>>,
+    interval: usize,
+    total: usize,
+    count: std::sync::atomic::AtomicUsize,
+}
+
+impl ProgressReporter {
+    fn new(sink: Option<Box<dyn std::io::Write + Send>>, interval: usize, total: usize) -> Self {
+        ProgressReporter {
+            sink: sink.map(parking_lot::Mutex::new),
+            interval: interval.max(1),
+            total,
+            count: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    /// Reporter writing to the stream a save with these flags should use
+    fn for_save(
+        stream: ProgressStream,
+        interval: usize,
+        total: usize,
+        verbose: bool,
+        quiet: bool,
+    ) -> Self {
+        let sink: Option<Box<dyn std::io::Write + Send>> = match stream {
+            _ if quiet => None,
+            ProgressStream::Auto if verbose => Some(Box::new(std::io::stderr())),
+            ProgressStream::Stderr => Some(Box::new(std::io::stderr())),
+            ProgressStream::Stdout => Some(Box::new(std::io::stdout())),
+            ProgressStream::Auto | ProgressStream::None => None,
+        };
+        Self::new(sink, interval, total)
+    }
+
+    /// Count one more file, reporting progress when due
+    fn tick(&self) {
+        let count = self
+            .count
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        if count.is_multiple_of(self.interval) || count == self.total {
+            // Progress is best effort: a closed stream must not fail the save
+            let _ = writeln!(
+                sink.lock(),
+                "pytest-difftest: Fingerprinting files... {}/{} ({:.0}%)",
+                count,
+                self.total,
+                count as f64 / self.total as f64 * 100.0
+            );
+        }
+    }
+}
+
+/// A file whose content changed between two reads while being fingerprinted
+///
+/// Typically a file still being written (e.g. a fixture a test generates):
+/// its fingerprint may describe a torn read, so it isn't stored.
+#[derive(Debug)]
+pub struct UnstableRead {
+    pub path: PathBuf,
+}
+
+impl std::fmt::Display for UnstableRead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Content of {} changed while it was fingerprinted",
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for UnstableRead {}
+
+/// Check that `fingerprint` still describes the file, by reading and hashing
+/// it again once it's computed
+///
+/// Fails with [`UnstableRead`] if the file changed in between.
+fn verify_stable_read(
+    path: &Path,
+    fingerprint: impl FnOnce() -> Result<Fingerprint>,
+    reread: impl FnOnce(&Path) -> Result<String>,
+) -> Result<Fingerprint> {
+    let fp = fingerprint()?;
+    let reread = reread(path)?;
+    if blake3::hash(reread.as_bytes()).to_hex().as_str() != fp.file_hash {
+        return Err(UnstableRead {
+            path: path.to_path_buf(),
+        }
+        .into());
+    }
+    Ok(fp)
+}
+
+/// Hash and parse content already in memory; `path` is only its logical name
+fn fingerprint_content(
+    path: &Path,
+    content: &str,
+    mtime: f64,
+    parse: impl FnOnce(&str) -> Result<ParsedSource>,
+    timings: Option<&PhaseTimings>,
+) -> Result<Fingerprint> {
+    // Calculate file-level hash using Blake3 (fast!)
+    let file_hash = PhaseTimings::measure(timings, Phase::Hash, || {
+        blake3::hash(content.as_bytes()).to_hex().to_string()
+    });
+
+    // Parse and extract block checksums
+    let (checksums, blocks) = match PhaseTimings::measure(timings, Phase::Parse, || parse(content))
+    {
+        Ok(parsed) => parsed,
+        Err(_) if !is_python_source(path) => {
+            let block = opaque_file_block(content, &file_hash);
+            (vec![block.checksum], Some(vec![block]))
+        }
+        Err(e) => anyhow::bail!("Failed to parse Python file: {}", e),
+    };
+
     Ok(Fingerprint {
         filename: path.to_string_lossy().to_string(),
         checksums,
         file_hash,
         mtime,
-        blocks: Some(blocks),
+        blocks,
+    })
+}
+
+/// Whether a tracked file is Python source proper (`.py`), as opposed to one
+/// tracked through an extra extension (templates, DSLs)
+fn is_python_source(path: &Path) -> bool {
+    path.extension().and_then(|s| s.to_str()) == Some("py")
+}
+
+/// Single block covering a tracked non-`.py` file that doesn't parse as Python
+///
+/// Its checksum is derived from the file hash, so any edit changes it.
+fn opaque_file_block(content: &str, file_hash: &str) -> Block {
+    Block {
+        start_line: 1,
+        end_line: content.lines().count().max(1),
+        checksum: calculate_checksum(file_hash),
+        name: "<file>".to_string(),
+        block_type: "file".to_string(),
+        body_start_line: 1,
+    }
+}
+
+/// Blocks of a tracked file, falling back to `opaque_file_block` for non-`.py`
+/// files that don't parse
+fn parse_tracked_source(
+    path: &Path,
+    content: &str,
+    file_hash: &str,
+    options: &ParseOptions,
+) -> Result<Vec<Block>> {
+    match parse_module_with_options(content, options) {
+        Ok(blocks) => Ok(blocks),
+        Err(_) if !is_python_source(path) => Ok(vec![opaque_file_block(content, file_hash)]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether a file name ends with one of the tracked extensions
+fn has_tracked_extension(path: &Path, extensions: &[String]) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| extensions.iter().any(|ext| name.ends_with(ext.as_str())))
+}
+
+/// Fingerprint a compiled artifact (`.so`, `.pyd`, ...) as one opaque block
+///
+/// Native modules produce no Python coverage, so the whole file is tracked as a
+/// single block whose checksum is derived from the file hash: rebuilding the
+/// artifact changes that checksum and re-selects every test that loaded it.
+pub(crate) fn calculate_native_fingerprint_internal(path: &str) -> Result<Fingerprint> {
+    let path = Path::new(path);
+
+    let content =
+        std::fs::read(path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    let file_hash = blake3::hash(&content).to_hex().to_string();
+
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Failed to get metadata for: {}", path.display()))?;
+    let mtime = metadata
+        .modified()
+        .with_context(|| "Failed to get modification time")?
+        .duration_since(UNIX_EPOCH)
+        .with_context(|| "Invalid modification time")?
+        .as_secs_f64();
+
+    Ok(Fingerprint {
+        filename: path.to_string_lossy().to_string(),
+        checksums: vec![calculate_checksum(&file_hash)],
+        file_hash,
+        mtime,
+        blocks: None,
     })
 }
 
@@ -95,48 +672,250 @@ pub(crate) fn calculate_fingerprint_internal(path: &str) -> Result<Fingerprint>
 /// * `verbose` - Whether to print debug information
 /// * `scope_paths` - List of directory paths to limit the scope (e.g., ["tests/unit/"])
 /// * `force` - Force recomputation of all fingerprints, even for unchanged files
+/// * `quiet` - Suppress all stderr output, even when `verbose` is set
+/// * `test_classifier` - Optional callable `(path) -> bool` deciding which files are
+///   tests (scope paths only apply to tests), overriding the built-in heuristics
+/// * `baseline_name` - Which named baseline to save (e.g. a branch name)
+/// * `checkpoint` - WAL checkpoint to run once saved: "truncate" (default) leaves
+///   a single database file, "passive" doesn't wait on other connections, and
+///   "none" leaves the WAL to SQLite
+/// * `strict` - Fail, listing the offending files, if any file can't be read or
+///   parsed. By default such files are reported and left out of the baseline.
+/// * `trust_mtime` - Skip files whose mtime matches their baseline without
+///   reading them, so an update only stats unchanged files. Only safe when
+///   mtimes reflect edits (not after checkouts or cache restores).
+/// * `label` - Description of this save (e.g. "before refactor"), returned by
+///   `PytestDiffDatabase.get_baseline_metadata`. Replaces the label of the
+///   previous save; saving without one clears it.
+/// * `progress_stream` - Where to report how many files were fingerprinted:
+///   "auto" (default) prints to stderr only with `verbose`, "stderr" and
+///   "stdout" print regardless of `verbose`, and "none" never prints. `quiet`
+///   still silences it.
+/// * `progress_interval` - Files fingerprinted between two progress lines
+///   (50 by default); the last file is always reported
+/// * `with_unstable` - Return a `SaveResult` that also lists the files left out
+///   because they changed while being fingerprinted (see `set_verify_reads`)
 ///
 /// # Returns
-/// * Number of files added to baseline
+/// * Number of files in the baseline, or a `SaveResult` when `with_unstable` is set
 #[pyfunction]
-#[pyo3(signature = (db_path, project_root, verbose, scope_paths, force=false))]
+#[pyo3(signature = (db_path, project_root, verbose, scope_paths, force=false, quiet=false, test_classifier=None, baseline_name=DEFAULT_BASELINE, checkpoint="truncate", strict=false, trust_mtime=false, label=None, progress_stream="auto", progress_interval=DEFAULT_PROGRESS_INTERVAL, with_unstable=false))]
+#[allow(clippy::too_many_arguments)]
 pub fn save_baseline(
+    py: Python<'_>,
     db_path: &str,
     project_root: &str,
     verbose: bool,
     scope_paths: Vec<String>,
     force: bool,
-) -> PyResult<usize> {
-    let count = save_baseline_internal(db_path, project_root, verbose, scope_paths, force)
-        .map_err(|e| {
-            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to save baseline: {}", e))
-        })?;
+    quiet: bool,
+    test_classifier: Option<&Bound<'_, PyAny>>,
+    baseline_name: &str,
+    checkpoint: &str,
+    strict: bool,
+    trust_mtime: bool,
+    label: Option<String>,
+    progress_stream: &str,
+    progress_interval: usize,
+    with_unstable: bool,
+) -> PyResult<PyObject> {
+    let checkpoint: Checkpoint = checkpoint
+        .parse()
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{}", e)))?;
+    let progress_stream: ProgressStream = progress_stream
+        .parse()
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{}", e)))?;
+    if progress_interval == 0 {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "progress_interval must be at least 1",
+        ));
+    }
+    let classifier = python_classifier(test_classifier);
+    let result = save_baseline_internal(
+        db_path,
+        project_root,
+        &SaveOptions {
+            verbose,
+            scope_paths,
+            force,
+            quiet,
+            test_classifier: classifier.as_ref().map(|c| c as &TestClassifier),
+            baseline_name,
+            strict,
+            trust_mtime,
+            label: label.as_deref(),
+            progress_stream,
+            progress_interval,
+            ..SaveOptions::default()
+        },
+        checkpoint,
+    )
+    .map_err(|e| {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to save baseline: {}", e))
+    })?;
 
-    Ok(count)
+    if with_unstable {
+        Ok(Py::new(py, result)?.into_any())
+    } else {
+        Ok(result.count.into_pyobject(py)?.into_any().unbind())
+    }
 }
 
-fn save_baseline_internal(
+pub(crate) fn save_baseline_internal(
     db_path: &str,
     project_root: &str,
-    verbose: bool,
-    scope_paths: Vec<String>,
-    force: bool,
-) -> Result<usize> {
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::sync::Arc;
-    use std::time::Instant;
-
-    let start = Instant::now();
+    options: &SaveOptions,
+    checkpoint: Checkpoint,
+) -> Result<SaveResult> {
+    let start = std::time::Instant::now();
     let mut db = PytestDiffDatabase::open(db_path)?;
-    if verbose {
+    if options.verbose && !options.quiet {
         eprintln!(
             "[rust] Database opened in {:.3}s",
             start.elapsed().as_secs_f64()
         );
     }
 
+    let result = save_baseline_with_db(&mut db, project_root, options)?;
+    db.checkpoint(checkpoint)?;
+    Ok(result)
+}
+
+/// Create a per-shard database seeded with the baselines of a shared one
+///
+/// Copies the default and named baselines and the settings of `base_db` into
+/// `temp_db` (created if missing, and required to be empty), but no test
+/// executions. Parallel CI shards can then each record into their own copy
+/// and merge the results back with `PytestDiffDatabase.merge_baseline_from`.
+///
+/// # Returns
+/// * Number of baseline rows copied
+#[pyfunction]
+pub fn fork_baseline(base_db: &str, temp_db: &str) -> PyResult<usize> {
+    fork_baseline_internal(base_db, temp_db).map_err(|e| {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to fork baseline: {}", e))
+    })
+}
+
+pub(crate) fn fork_baseline_internal(base_db: &str, temp_db: &str) -> Result<usize> {
+    let mut db = PytestDiffDatabase::open(temp_db)?;
+    let count = db.fork_baseline_from_internal(base_db)?;
+    db.close_and_checkpoint()?;
+    Ok(count)
+}
+
+/// Settings of a baseline save
+///
+/// Mirrors the keyword arguments of `save_baseline` (see there for what each
+/// one does), minus the WAL checkpoint, which only applies when the save owns
+/// the connection.
+#[derive(Clone)]
+pub struct SaveOptions<'a> {
+    pub verbose: bool,
+    pub scope_paths: Vec<String>,
+    pub force: bool,
+    pub quiet: bool,
+    pub test_classifier: Option<&'a TestClassifier<'a>>,
+    pub baseline_name: &'a str,
+    pub strict: bool,
+    pub trust_mtime: bool,
+    pub label: Option<&'a str>,
+    /// Drop the baseline's previous rows instead of reusing them, in the
+    /// transaction that writes the new ones (see `recover_baseline`)
+    pub replace: bool,
+    pub progress_stream: ProgressStream,
+    pub progress_interval: usize,
+}
+
+impl Default for SaveOptions<'_> {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            scope_paths: Vec::new(),
+            force: false,
+            quiet: false,
+            test_classifier: None,
+            baseline_name: DEFAULT_BASELINE,
+            strict: false,
+            trust_mtime: false,
+            label: None,
+            replace: false,
+            progress_stream: ProgressStream::Auto,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+        }
+    }
+}
+
+/// Save baseline fingerprints through a connection the caller keeps open
+///
+/// Same as `save_baseline`, but neither checkpoints the WAL nor closes the
+/// connection, so embedding callers decide both.
+pub fn save_baseline_with_db(
+    db: &mut PytestDiffDatabase,
+    project_root: &str,
+    options: &SaveOptions,
+) -> Result<SaveResult> {
+    save_baseline_with_reader(db, project_root, options, read_python_source)
+}
+
+/// `save_baseline_with_db`, with `reread` doing the second read of verified reads
+fn save_baseline_with_reader(
+    db: &mut PytestDiffDatabase,
+    project_root: &str,
+    options: &SaveOptions,
+    reread: impl Fn(&Path) -> Result<String> + Sync,
+) -> Result<SaveResult> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    let SaveOptions {
+        verbose,
+        ref scope_paths,
+        force,
+        quiet,
+        test_classifier,
+        baseline_name,
+        strict,
+        trust_mtime,
+        label,
+        replace,
+        progress_stream,
+        progress_interval,
+    } = *options;
+
+    // Every stderr write below is gated on `verbose`, so quiet simply overrides it
+    let verbose = verbose && !quiet;
+
+    let options = db.get_parse_options_internal()?;
+    let extensions = db.get_tracked_extensions()?;
+    // Name hashes are stored from the blocks, so keep them when enabled
+    let keep_blocks = db.block_name_hashes_enabled()?;
+    let verify_reads = db.verify_reads_enabled()?;
+
     let find_start = Instant::now();
-    let python_files = find_python_files(project_root, &scope_paths)?;
+    let discovery = find_python_files(
+        project_root,
+        scope_paths,
+        test_classifier,
+        &extensions,
+        None,
+    )?;
+    if !quiet {
+        for path in &discovery.missing_scope_paths {
+            eprintln!(
+                "pytest-difftest: Warning: scope path does not exist: {}",
+                path
+            );
+        }
+        for path in &discovery.skipped {
+            eprintln!(
+                "pytest-difftest: Warning: skipped path that vanished or is unreadable: {}",
+                path
+            );
+        }
+    }
+    let python_files = discovery.files;
     if verbose {
         eprintln!(
             "pytest-difftest: Scanning {} Python files... ({:.3}s)",
@@ -147,7 +926,12 @@ fn save_baseline_internal(
 
     // Load ALL existing baselines in a single query (much faster than N queries)
     let baseline_start = Instant::now();
-    let existing_baselines = db.get_all_baseline_fingerprints()?;
+    // Rows about to be replaced may be unreadable, so they aren't loaded
+    let existing_baselines = if replace {
+        HashMap::new()
+    } else {
+        db.get_named_baseline_fingerprints(baseline_name)?
+    };
 
     if verbose {
         eprintln!(
@@ -161,8 +945,15 @@ fn save_baseline_internal(
     let total_files = python_files.len();
 
     // Progress counters for parallel processing
-    let progress_counter = Arc::new(AtomicUsize::new(0));
+    let progress = ProgressReporter::for_save(
+        progress_stream,
+        progress_interval,
+        total_files,
+        verbose,
+        quiet,
+    );
     let skipped_unchanged = Arc::new(AtomicUsize::new(0));
+    let timings = PhaseTimings::default();
 
     if verbose {
         let mode = if force { "force" } else { "incremental" };
@@ -171,36 +962,40 @@ fn save_baseline_internal(
 
     // PARALLEL: Calculate fingerprints, skipping unchanged files (unless force=true)
     let fp_calc_start = Instant::now();
-    let fingerprints: Vec<(String, Option<Fingerprint>)> = python_files
+    let fingerprints: Vec<(String, Result<Option<Fingerprint>>)> = python_files
         .par_iter()
         .map(|path| {
             let path_str = path.to_string_lossy().to_string();
             let rel_path = make_relative(&path_str, project_root);
 
-            // Update progress counter
-            let count = progress_counter.fetch_add(1, Ordering::Relaxed) + 1;
-            // Print progress every 50 files in verbose mode
-            if verbose && (count.is_multiple_of(50) || count == total_files) {
-                eprintln!(
-                    "pytest-difftest: Fingerprinting files... {}/{} ({:.0}%)",
-                    count,
-                    total_files,
-                    count as f64 / total_files as f64 * 100.0
-                );
-            }
+            progress.tick();
 
             // Check if we can skip this file (hash unchanged) - only when not forcing
             // Lookup by relative path since baselines are stored with relative paths
             if !force {
                 if let Some(existing) = existing_baselines.get(&rel_path) {
+                    // A matching mtime avoids even reading the file
+                    if trust_mtime
+                        && file_mtime(path).is_some_and(|mtime| mtimes_equal(mtime, existing.mtime))
+                    {
+                        skipped_unchanged.fetch_add(1, Ordering::Relaxed);
+                        return (rel_path, Ok(None));
+                    }
+
                     // Compute Blake3 hash (cheap: ~1ms for typical file)
-                    if let Ok(content) = std::fs::read_to_string(path) {
-                        let current_hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+                    let content = PhaseTimings::measure(Some(&timings), Phase::Read, || {
+                        read_python_source(path)
+                    });
+                    if let Ok(content) = content {
+                        let current_hash =
+                            PhaseTimings::measure(Some(&timings), Phase::Hash, || {
+                                blake3::hash(content.as_bytes()).to_hex().to_string()
+                            });
 
                         if current_hash == existing.file_hash {
                             // Hash matches - file content unchanged, skip expensive AST parsing
                             skipped_unchanged.fetch_add(1, Ordering::Relaxed);
-                            return (rel_path, None); // None means "keep existing"
+                            return (rel_path, Ok(None)); // None means "keep existing"
                         }
                     }
                 }
@@ -208,7 +1003,12 @@ fn save_baseline_internal(
 
             // File is new or changed (or force=true) - compute full fingerprint
             let fp_start = Instant::now();
-            let result = calculate_fingerprint_internal(&path_str);
+            let fingerprint = || baseline_fingerprint(path, &options, keep_blocks, &timings);
+            let result = if verify_reads {
+                verify_stable_read(path, fingerprint, &reread)
+            } else {
+                fingerprint()
+            };
 
             // Log slow files
             if verbose && fp_start.elapsed().as_millis() > 100 {
@@ -219,22 +1019,66 @@ fn save_baseline_internal(
                 );
             }
 
-            match result {
-                Ok(mut fp) => {
-                    // Store relative path in the fingerprint
-                    fp.filename = rel_path.clone();
-                    (rel_path, Some(fp))
-                }
-                Err(e) => {
-                    if verbose {
-                        eprintln!("[rust]   Skipping {}: {}", rel_path, e);
-                    }
-                    (rel_path, None)
-                }
-            }
+            let result = result.map(|mut fp| {
+                // Store relative path in the fingerprint
+                fp.filename = rel_path.clone();
+                Some(fp)
+            });
+            (rel_path, result)
         })
         .collect();
 
+    // Files that couldn't be read or parsed would silently drop out of the baseline
+    let mut fingerprints_to_save = Vec::new();
+    let mut failures = Vec::new();
+    let mut unstable = Vec::new();
+    for (rel_path, result) in fingerprints {
+        match result {
+            Ok(Some(fp)) => fingerprints_to_save.push(fp),
+            // Unchanged: the existing baseline is kept
+            Ok(None) => {}
+            // Changed while read: the existing baseline (if any) is kept
+            Err(e) if e.is::<UnstableRead>() => unstable.push(rel_path),
+            Err(e) => failures.push(format!("{}: {}", rel_path, e)),
+        }
+    }
+    if !unstable.is_empty() {
+        if strict {
+            anyhow::bail!(
+                "{} files changed while being fingerprinted:\n  {}",
+                unstable.len(),
+                unstable.join("\n  ")
+            );
+        }
+        if !quiet {
+            eprintln!(
+                "pytest-difftest: Warning: {} files changed while being fingerprinted (still being written?) and were not saved:",
+                unstable.len()
+            );
+            for path in &unstable {
+                eprintln!("  {}", path);
+            }
+        }
+    }
+    if !failures.is_empty() {
+        if strict {
+            anyhow::bail!(
+                "{} files could not be fingerprinted:\n  {}",
+                failures.len(),
+                failures.join("\n  ")
+            );
+        }
+        if !quiet {
+            eprintln!(
+                "pytest-difftest: Warning: {} files could not be fingerprinted and are not in the baseline:",
+                failures.len()
+            );
+            for failure in &failures {
+                eprintln!("  {}", failure);
+            }
+        }
+    }
+
     let unchanged_count = skipped_unchanged.load(Ordering::Relaxed);
     let changed_file_count = total_files - unchanged_count;
     if verbose {
@@ -245,29 +1089,28 @@ fn save_baseline_internal(
             unchanged_count,
             fp_calc_start.elapsed().as_secs_f64()
         );
+        let (read, hash, parse) = timings.totals();
+        eprintln!(
+            "[rust] Fingerprint phases (summed across threads): read {:.3}s, hash {:.3}s, parse {:.3}s",
+            read, hash, parse
+        );
     }
 
     // SEQUENTIAL: Save only changed fingerprints to database
     let db_save_start = Instant::now();
-    let mut fingerprints_to_save = Vec::new();
-
-    for (_path_str, maybe_fp) in fingerprints {
-        if let Some(fp) = maybe_fp {
-            fingerprints_to_save.push(fp);
-        }
-        // If None and we have existing baseline, it's already in DB (unchanged)
-        // If None and no existing baseline, it was an error (already logged)
-    }
-
     let changed_count = fingerprints_to_save.len();
-    let count = if changed_count > 0 {
+    let count = if changed_count > 0 || replace {
         if verbose {
             eprint!(
                 "pytest-difftest: Writing {} fingerprints to database...",
                 changed_count
             );
         }
-        let c = db.save_baseline_fingerprints_batch(fingerprints_to_save)?;
+        let c = if replace {
+            db.replace_named_baseline_fingerprints(baseline_name, fingerprints_to_save)?
+        } else {
+            db.save_named_baseline_fingerprints_batch(baseline_name, fingerprints_to_save)?
+        };
         if verbose {
             eprintln!(" done ({:.1}s)", db_save_start.elapsed().as_secs_f64());
         }
@@ -275,6 +1118,11 @@ fn save_baseline_internal(
     } else {
         0
     };
+    db.record_writer_version()?;
+    db.record_fingerprint_format()?;
+    db.set_baseline_label(baseline_name, label)?;
+    let aggregate = mtime_aggregate(db.get_baseline_mtimes(baseline_name)?);
+    db.set_baseline_aggregate(baseline_name, &aggregate)?;
 
     if verbose {
         eprintln!(
@@ -292,11 +1140,117 @@ fn save_baseline_internal(
         );
     }
 
-    // Checkpoint WAL to remove -wal and -shm files
+    // Total baseline count (unchanged + updated)
+    unstable.sort_unstable();
+    Ok(SaveResult {
+        count: unchanged_count + count,
+        unstable,
+    })
+}
+
+/// Update the baseline entry of a single file without rescanning the project
+///
+/// Recomputes the fingerprint of `path` and upserts it into the baseline.
+/// Intended for editor save hooks where a full `save_baseline` is too slow.
+///
+/// # Arguments
+/// * `db_path` - Path to the pytest-difftest database
+/// * `project_root` - Root directory of the project
+/// * `path` - File to update (absolute, or relative to `project_root`)
+/// * `baseline_name` - Which named baseline to update
+///
+/// # Returns
+/// * `true` if the file content differs from its previous baseline (or had none)
+#[pyfunction]
+#[pyo3(signature = (db_path, project_root, path, baseline_name=DEFAULT_BASELINE))]
+pub fn update_baseline_file(
+    db_path: &str,
+    project_root: &str,
+    path: &str,
+    baseline_name: &str,
+) -> PyResult<bool> {
+    update_baseline_file_internal(db_path, project_root, path, baseline_name).map_err(|e| {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to update baseline: {}", e))
+    })
+}
+
+fn update_baseline_file_internal(
+    db_path: &str,
+    project_root: &str,
+    path: &str,
+    baseline_name: &str,
+) -> Result<bool> {
+    let abs_path = Path::new(project_root).join(path);
+    let abs_path = resolve_path_case(&abs_path).unwrap_or(abs_path);
+    let abs_path = abs_path.to_string_lossy();
+    let rel_path = make_relative(&abs_path, project_root);
+
+    let mut db = PytestDiffDatabase::open(db_path)?;
+    let existing = db.get_named_baseline_fingerprint(baseline_name, &rel_path)?;
+
+    let options = db.get_parse_options_internal()?;
+    let mut fp = if db.block_name_hashes_enabled()? {
+        calculate_fingerprint_internal(&abs_path, options)?
+    } else {
+        calculate_baseline_fingerprint_internal(&abs_path, options)?
+    };
+    fp.filename = rel_path;
+
+    let changed =
+        existing.is_none_or(|old| old.file_hash != fp.file_hash || old.checksums != fp.checksums);
+
+    // Always upsert so the stored mtime stays fresh for level-1 detection
+    db.save_named_baseline_fingerprints_batch(baseline_name, vec![fp])?;
     db.close_and_checkpoint()?;
 
-    // Return total baseline count (unchanged + updated)
-    Ok(unchanged_count + count)
+    Ok(changed)
+}
+
+/// Hash every discovered file without parsing it
+///
+/// A cheap "did anything change at all" pre-check: the hashes are the
+/// `file_hash` that `calculate_fingerprint` would compute, so they can be
+/// compared with the baseline before running full detection.
+///
+/// # Arguments
+/// * `project_root` - Root directory of the project
+/// * `scope_paths` - Same test scope as `save_baseline` and `detect_changes`
+/// * `extensions` - Tracked file extensions (see
+///   `PytestDiffDatabase.get_tracked_file_extensions`); defaults to `[".py"]`
+/// * `since_mtime` - Only hash files modified at or after this Unix timestamp
+///
+/// # Returns
+/// * Map of relative filename -> blake3 hash. Unreadable files are left out.
+#[pyfunction]
+#[pyo3(signature = (project_root, scope_paths, extensions=None, since_mtime=None))]
+pub fn hash_all_files(
+    project_root: &str,
+    scope_paths: Vec<String>,
+    extensions: Option<Vec<String>>,
+    since_mtime: Option<f64>,
+) -> PyResult<HashMap<String, String>> {
+    let extensions = extensions.unwrap_or_else(|| vec![PYTHON_EXTENSION.to_string()]);
+    hash_all_files_internal(project_root, &scope_paths, &extensions, since_mtime).map_err(|e| {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to hash files: {}", e))
+    })
+}
+
+fn hash_all_files_internal(
+    project_root: &str,
+    scope_paths: &[String],
+    extensions: &[String],
+    since_mtime: Option<f64>,
+) -> Result<HashMap<String, String>> {
+    let discovery = find_python_files(project_root, scope_paths, None, extensions, since_mtime)?;
+    Ok(discovery
+        .files
+        .par_iter()
+        .filter_map(|path| {
+            let content = read_python_source(path).ok()?;
+            let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+            Some((make_relative(&path.to_string_lossy(), project_root), hash))
+        })
+        .collect())
 }
 
 /// Detect changes between current filesystem state and database
@@ -306,154 +1260,704 @@ fn save_baseline_internal(
 /// 2. file hash check (fast - blake3 hash of entire file)
 /// 3. block checksum comparison (precise - per-function/class checksums)
 ///
+/// With `trust_mtime`, a level 0 runs first: if the project's files and mtimes
+/// still digest to the aggregate `save_baseline` stored, nothing changed and
+/// the baseline isn't even loaded. Any write to the baseline since the save
+/// disables it until the next save.
+///
 /// # Arguments
 /// * `db_path` - Path to the pytest-difftest database
 /// * `project_root` - Root directory of the project
 /// * `scope_paths` - List of directory paths to limit the scope (e.g., ["tests/unit/"])
+/// * `test_classifier` - Optional callable `(path) -> bool` overriding the built-in
+///   test file heuristics (see `save_baseline`)
+/// * `baseline_name` - Which named baseline to compare against
+/// * `explain` - Also record, per examined file, which detection level decided
+///   its classification (see `ChangedFiles.decisions`)
+/// * `trust_mtime` - Whether a matching mtime proves a file unchanged. Pass
+///   `false` after checkouts or cache restores, where mtimes don't reflect
+///   content: levels 0 and 1 are skipped and every file is verified by hash.
+/// * `with_metadata` - Return a `DetectionResult` wrapping the changes with
+///   scan metadata (files scanned, baseline size, elapsed time)
 ///
 /// # Returns
-/// * ChangedFiles containing list of modified files and changed blocks
+/// * ChangedFiles containing list of modified files and changed blocks, or a
+///   `DetectionResult` when `with_metadata` is set
 #[pyfunction]
+#[pyo3(signature = (db_path, project_root, scope_paths, test_classifier=None, baseline_name=DEFAULT_BASELINE, explain=false, trust_mtime=true, with_metadata=false))]
+#[allow(clippy::too_many_arguments)]
 pub fn detect_changes(
+    py: Python<'_>,
     db_path: &str,
     project_root: &str,
     scope_paths: Vec<String>,
-) -> PyResult<ChangedFiles> {
-    let changes = detect_changes_internal(db_path, project_root, scope_paths).map_err(|e| {
+    test_classifier: Option<&Bound<'_, PyAny>>,
+    baseline_name: &str,
+    explain: bool,
+    trust_mtime: bool,
+    with_metadata: bool,
+) -> PyResult<PyObject> {
+    let classifier = python_classifier(test_classifier);
+    let result = detect_changes_internal(
+        db_path,
+        project_root,
+        scope_paths,
+        classifier.as_ref().map(|c| c as &TestClassifier),
+        baseline_name,
+        trust_mtime,
+        explain,
+    )
+    .map_err(|e| {
         pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to detect changes: {}", e))
     })?;
 
-    Ok(changes)
+    if with_metadata {
+        Ok(Py::new(py, result)?.into_any())
+    } else {
+        Ok(Py::new(py, result.changes)?.into_any())
+    }
 }
 
-fn detect_changes_internal(
+pub(crate) fn detect_changes_internal(
     db_path: &str,
     project_root: &str,
     scope_paths: Vec<String>,
-) -> Result<ChangedFiles> {
+    test_classifier: Option<&TestClassifier>,
+    baseline_name: &str,
+    trust_mtime: bool,
+    explain: bool,
+) -> Result<DetectionResult> {
+    let start = std::time::Instant::now();
+
     // Open database
     let db = PytestDiffDatabase::open(db_path)?;
 
-    // Find all Python files in the project
-    let python_files = find_python_files(project_root, &scope_paths)?;
+    let native_changes = find_changed_native_dependencies(&db, project_root)?;
+    let discovery = find_python_files(
+        project_root,
+        &scope_paths,
+        test_classifier,
+        &db.get_tracked_extensions()?,
+        None,
+    )?;
+
+    // Level 0: nothing touched since the save, so skip loading the baseline
+    if trust_mtime && native_changes.is_empty() {
+        if let Some(aggregate) = db.get_baseline_aggregate(baseline_name)? {
+            if aggregate_matches(&discovery.files, project_root, &aggregate) {
+                let mut result = unchanged_result(discovery, project_root, explain);
+                result.elapsed = start.elapsed().as_secs_f64();
+                return Ok(result);
+            }
+        }
+    }
 
     // Load ALL baselines in a single query (much faster than N queries)
-    let baselines = db.get_all_baseline_fingerprints()?;
+    let baselines = db.get_named_baseline_fingerprints(baseline_name)?;
+
+    let mut result = detect_changes_against(
+        &baselines,
+        project_root,
+        discovery,
+        db.get_parse_options_internal()?,
+        trust_mtime,
+        explain,
+        native_changes,
+    )?;
+    result.elapsed = start.elapsed().as_secs_f64();
+    Ok(result)
+}
+
+/// Detect changes against a baseline exported with `PytestDiffDatabase.export_json`
+///
+/// Runs the same three-level detection as `detect_changes`, with the baseline
+/// and its parse settings loaded from the JSON snapshot instead of a database,
+/// for ephemeral CI runners that ship the baseline as an artifact. Native
+/// artifacts are not checked: their hashes live with the recorded tests, which
+/// a snapshot doesn't hold.
+///
+/// # Arguments
+/// * `snapshot_json` - Output of `export_json`
+/// * `project_root`, `scope_paths`, `test_classifier`, `explain`, `trust_mtime` -
+///   As for `detect_changes`
+#[pyfunction]
+#[pyo3(signature = (snapshot_json, project_root, scope_paths, test_classifier=None, explain=false, trust_mtime=true))]
+pub fn detect_changes_from_snapshot(
+    snapshot_json: &str,
+    project_root: &str,
+    scope_paths: Vec<String>,
+    test_classifier: Option<&Bound<'_, PyAny>>,
+    explain: bool,
+    trust_mtime: bool,
+) -> PyResult<ChangedFiles> {
+    let classifier = python_classifier(test_classifier);
+    let result = BaselineSnapshot::from_json(snapshot_json)
+        .and_then(|snapshot| {
+            detect_changes_from_snapshot_internal(
+                &snapshot,
+                project_root,
+                scope_paths,
+                classifier.as_ref().map(|c| c as &TestClassifier),
+                trust_mtime,
+                explain,
+            )
+        })
+        .map_err(|e| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to detect changes: {}", e))
+        })?;
+    Ok(result.changes)
+}
+
+fn detect_changes_from_snapshot_internal(
+    snapshot: &BaselineSnapshot,
+    project_root: &str,
+    scope_paths: Vec<String>,
+    test_classifier: Option<&TestClassifier>,
+    trust_mtime: bool,
+    explain: bool,
+) -> Result<DetectionResult> {
+    let baselines: HashMap<String, Fingerprint> = snapshot
+        .fingerprints
+        .iter()
+        .map(|fp| (normalize_filename(&fp.filename).into_owned(), fp.clone()))
+        .collect();
+    let discovery = find_python_files(
+        project_root,
+        &scope_paths,
+        test_classifier,
+        &snapshot.tracked_extensions,
+        None,
+    )?;
+    detect_changes_against(
+        &baselines,
+        project_root,
+        discovery,
+        snapshot.parse_options()?,
+        trust_mtime,
+        explain,
+        Vec::new(),
+    )
+}
+
+/// Order-independent digest of `(relative filename, mtime)` pairs
+///
+/// `save_baseline` stores it for the saved baseline (see
+/// `PytestDiffDatabase.set_baseline_aggregate`); `detect_changes` computes it
+/// for the discovered files. Equal digests mean the same files with the same
+/// mtimes, i.e. nothing for level 1 to find.
+pub(crate) fn mtime_aggregate(entries: impl IntoIterator<Item = (String, f64)>) -> String {
+    let mut entries: Vec<(String, f64)> = entries.into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut hasher = blake3::Hasher::new();
+    for (filename, mtime) in &entries {
+        hasher.update(filename.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&mtime.to_bits().to_le_bytes());
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Whether `files` hash to the stored baseline `aggregate` (see `mtime_aggregate`)
+///
+/// Only stats the files. A file that can't be stat'ed leaves the decision to
+/// the per-file levels.
+fn aggregate_matches(files: &[PathBuf], project_root: &str, aggregate: &str) -> bool {
+    let entries: Option<Vec<(String, f64)>> = files
+        .par_iter()
+        .map(|path| {
+            let rel_path = make_relative(&path.to_string_lossy(), project_root);
+            Some((rel_path, file_mtime(path)?))
+        })
+        .collect();
+    entries.is_some_and(|entries| mtime_aggregate(entries) == aggregate)
+}
+
+/// Detection result for a project whose files all match the baseline aggregate
+///
+/// Decisions are only listed with `explain`, as building them costs one
+/// path conversion per file on the path meant to be the fastest.
+fn unchanged_result(discovery: Discovery, project_root: &str, explain: bool) -> DetectionResult {
+    let decisions: HashMap<String, String> = if explain {
+        discovery
+            .files
+            .iter()
+            .map(|path| {
+                (
+                    make_relative(&path.to_string_lossy(), project_root),
+                    ChangeDecision::AggregateUnchanged.as_str().to_string(),
+                )
+            })
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let files_scanned = discovery.files.len();
+    DetectionResult {
+        changes: ChangedFiles {
+            modified: Vec::new(),
+            changed_blocks: HashMap::new(),
+            missing_scope_paths: discovery.missing_scope_paths,
+            skipped_paths: discovery.skipped,
+            encoding_mismatches: Vec::new(),
+            decisions,
+            baseline_empty: files_scanned == 0,
+            block_changes: Vec::new(),
+            changed_block_types: HashMap::new(),
+            block_types_by_file: HashMap::new(),
+        },
+        files_scanned,
+        // The discovered files are exactly the baseline's
+        baseline_count: files_scanned,
+        elapsed: 0.0,
+    }
+}
+
+/// Compare the discovered files with `baselines` (keyed by relative filename)
+///
+/// `native_changes` are changed native artifacts with their stale checksums
+/// (see `find_changed_native_dependencies`), reported as modified files.
+/// Per-file decisions are only kept with `explain`.
+fn detect_changes_against(
+    baselines: &HashMap<String, Fingerprint>,
+    project_root: &str,
+    discovery: Discovery,
+    options: ParseOptions,
+    trust_mtime: bool,
+    explain: bool,
+    native_changes: Vec<(String, Vec<i32>)>,
+) -> Result<DetectionResult> {
+    let start = std::time::Instant::now();
+
+    let python_files = discovery.files;
+
+    let baseline_empty = baselines.is_empty();
+    let files_scanned = python_files.len();
 
     // Process files in PARALLEL using rayon
     // Now that we have all baselines in memory, we don't need DB access per file
-    let changed_entries: Vec<_> = python_files
+    let results: Vec<_> = python_files
         .par_iter()
-        .filter_map(|path| {
+        .map(|path| {
             let abs_path = path.to_string_lossy().to_string();
             let rel_path = make_relative(&abs_path, project_root);
-            match check_file_changed_with_baseline(&baselines, path, &rel_path) {
-                Ok(Some(change)) => Some(change),
-                Ok(None) => None,
-                Err(_) => None,
-            }
+            let result = check_file_changed_with_baseline(
+                baselines,
+                path,
+                &rel_path,
+                options.clone(),
+                trust_mtime,
+            );
+            (rel_path, result)
         })
         .collect();
 
+    // Unreadable/unparseable files are skipped, but encoding mismatches are reported
+    let mut changed_entries = Vec::new();
+    let mut encoding_mismatches = Vec::new();
+    let mut decisions = HashMap::new();
+    for (rel_path, result) in results {
+        match result {
+            Ok((decision, change)) => {
+                if explain {
+                    decisions.insert(rel_path, decision.as_str().to_string());
+                }
+                changed_entries.extend(change);
+            }
+            Err(e) if e.is::<EncodingMismatch>() => encoding_mismatches.push(rel_path),
+            Err(_) => {}
+        }
+    }
+
     // Separate modified files from changed blocks
     let mut modified = Vec::new();
     let mut changed_blocks = HashMap::new();
 
-    for (file, blocks) in changed_entries {
+    let mut block_changes = Vec::new();
+    let mut block_types_by_file: HashMap<String, HashMap<String, usize>> = HashMap::new();
+
+    let native_entries = native_changes.into_iter().map(|(file, blocks)| {
+        let named = vec![("<native>".to_string(), "native".to_string(), "modified")];
+        (file, blocks, named)
+    });
+
+    for (file, blocks, named) in changed_entries.into_iter().chain(native_entries) {
+        for (name, block_type, kind) in named {
+            *block_types_by_file
+                .entry(file.clone())
+                .or_default()
+                .entry(block_type)
+                .or_default() += 1;
+            block_changes.push((file.clone(), name, kind.to_string()));
+        }
         modified.push(file.clone());
         if !blocks.is_empty() {
             changed_blocks.insert(file, blocks);
         }
     }
 
-    Ok(ChangedFiles {
+    let mut changed_block_types: HashMap<String, usize> = HashMap::new();
+    for (block_type, count) in block_types_by_file.values().flatten() {
+        *changed_block_types.entry(block_type.clone()).or_default() += count;
+    }
+
+    let changes = ChangedFiles {
         modified,
         changed_blocks,
+        missing_scope_paths: discovery.missing_scope_paths,
+        skipped_paths: discovery.skipped,
+        encoding_mismatches,
+        decisions,
+        baseline_empty,
+        block_changes,
+        changed_block_types,
+        block_types_by_file,
+    };
+    Ok(DetectionResult {
+        changes,
+        files_scanned,
+        baseline_count: baselines.len(),
+        elapsed: start.elapsed().as_secs_f64(),
+    })
+}
+
+/// List the tracked files whose content differs from the baseline
+///
+/// A fast path for coarse gating (e.g. pre-push hooks): only detection levels
+/// 1 and 2 run, so no file is parsed. Unlike `detect_changes`, a file whose
+/// edit leaves every block checksum intact (comments, formatting) is reported,
+/// and so is a file that can't be read, to stay on the safe side. Files
+/// without a baseline entry count as changed. Native artifacts are not checked.
+///
+/// # Arguments
+/// * `db_path` - Path to the database file
+/// * `project_root` - Root directory of the project
+/// * `scope_paths` - Paths to check, as for `detect_changes`
+/// * `baseline_name` - Named baseline to compare against (default: the main baseline)
+/// * `trust_mtime` - As for `detect_changes`
+///
+/// # Returns
+/// * Sorted relative filenames of the changed files
+#[pyfunction]
+#[pyo3(signature = (db_path, project_root, scope_paths, baseline_name=DEFAULT_BASELINE, trust_mtime=true))]
+pub fn changed_files_only(
+    db_path: &str,
+    project_root: &str,
+    scope_paths: Vec<String>,
+    baseline_name: &str,
+    trust_mtime: bool,
+) -> PyResult<Vec<String>> {
+    changed_files_only_internal(
+        db_path,
+        project_root,
+        &scope_paths,
+        baseline_name,
+        trust_mtime,
+    )
+    .map_err(|e| {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to list changed files: {}", e))
     })
 }
 
+fn changed_files_only_internal(
+    db_path: &str,
+    project_root: &str,
+    scope_paths: &[String],
+    baseline_name: &str,
+    trust_mtime: bool,
+) -> Result<Vec<String>> {
+    let db = PytestDiffDatabase::open(db_path)?;
+    let baselines = db.get_named_baseline_fingerprints(baseline_name)?;
+    let extensions = db.get_tracked_extensions()?;
+    let files = find_python_files(project_root, scope_paths, None, &extensions, None)?.files;
+
+    let mut changed: Vec<String> = files
+        .par_iter()
+        .filter_map(|path| {
+            let rel_path = make_relative(&path.to_string_lossy(), project_root);
+            let Some(stored_fp) = baselines.get(&rel_path) else {
+                return Some(rel_path);
+            };
+            if trust_mtime && mtime_matches(path, stored_fp).unwrap_or(false) {
+                return None;
+            }
+            let unchanged = read_python_source(path).is_ok_and(|content| {
+                blake3::hash(content.as_bytes()).to_hex().as_str() == stored_fp.file_hash
+            });
+            (!unchanged).then_some(rel_path)
+        })
+        .collect();
+    changed.sort();
+    Ok(changed)
+}
+
+/// Find native artifacts recorded as test dependencies whose content changed
+///
+/// Native artifacts are not part of the baseline, so each one is compared
+/// against the hashes recorded by the kept test executions. Returns the
+/// recorded checksums of every stale version, so that exactly the tests that
+/// loaded them re-run.
+fn find_changed_native_dependencies(
+    db: &PytestDiffDatabase,
+    project_root: &str,
+) -> Result<Vec<(String, Vec<i32>)>> {
+    let mut changes = Vec::new();
+
+    for (filename, recorded) in db.get_native_dependency_fingerprints()? {
+        let abs_path = Path::new(project_root).join(&filename);
+        // A missing artifact counts as changed
+        let current_hash = std::fs::read(&abs_path)
+            .ok()
+            .map(|content| blake3::hash(&content).to_hex().to_string());
+
+        let mut stale: Vec<i32> = recorded
+            .iter()
+            .filter(|fp| current_hash.as_deref() != Some(fp.file_hash.as_str()))
+            .flat_map(|fp| fp.checksums.iter().copied())
+            .collect();
+        stale.sort_unstable();
+        stale.dedup();
+
+        if !stale.is_empty() {
+            changes.push((filename, stale));
+        }
+    }
+
+    Ok(changes)
+}
+
+/// A changed block: its name, block type and kind ("added" or "modified")
+type BlockChange = (String, String, &'static str);
+
+/// A changed file (relative path), the checksums that changed in it, and the
+/// current blocks behind those changes
+type FileChange = (String, Vec<i32>, Vec<BlockChange>);
+
+/// Which detection level classified a file, and how
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeDecision {
+    /// Level 0: every file matches the mtime aggregate stored with the baseline
+    AggregateUnchanged,
+    /// No baseline entry: the file is new
+    New,
+    /// Level 1: mtime matches the baseline
+    MtimeUnchanged,
+    /// Level 2: mtime differs but the file hash matches
+    HashUnchanged,
+    /// Level 3: hash differs but every block checksum matches
+    ChecksumsUnchanged,
+    /// Level 3: at least one block checksum differs
+    ChecksumsChanged,
+}
+
+impl ChangeDecision {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::AggregateUnchanged => "aggregate_unchanged",
+            Self::New => "new",
+            Self::MtimeUnchanged => "mtime_unchanged",
+            Self::HashUnchanged => "hash_unchanged",
+            Self::ChecksumsUnchanged => "checksums_unchanged",
+            Self::ChecksumsChanged => "checksums_changed",
+        }
+    }
+}
+
 /// Check if a file has changed using three-level detection (with pre-loaded baseline)
 ///
 /// This version takes a pre-loaded HashMap of baselines for parallel processing.
 /// `rel_filename` is the path relative to project root, used for DB lookups and return values.
 /// `path` is the absolute path, used for file I/O operations.
+/// `options` must match the ones the baseline was saved with. Without
+/// `trust_mtime`, the level-1 mtime shortcut is skipped.
+///
+/// Returns the level that decided, plus the changed checksums if the file changed.
 fn check_file_changed_with_baseline(
     baselines: &HashMap<String, Fingerprint>,
     path: &Path,
     rel_filename: &str,
-) -> Result<Option<(String, Vec<i32>)>> {
+    options: ParseOptions,
+    trust_mtime: bool,
+) -> Result<(ChangeDecision, Option<FileChange>)> {
     // Get baseline fingerprint from pre-loaded map (keyed by relative path)
     let stored_fp = match baselines.get(rel_filename) {
         Some(fp) => fp,
         None => {
             // No baseline for this file - it's new, treat as changed
             // Parse to get checksums so new tests in this file can be selected
-            let current_fp = calculate_fingerprint_internal(path.to_string_lossy().as_ref())?;
-            let checksums = current_fp.checksums.clone();
-            return Ok(Some((rel_filename.to_string(), checksums)));
+            let current_fp =
+                calculate_fingerprint_internal(path.to_string_lossy().as_ref(), options)?;
+            let named = current_fp
+                .blocks
+                .unwrap_or_default()
+                .into_iter()
+                .map(|b| (b.name, b.block_type, "added"))
+                .collect();
+            return Ok((
+                ChangeDecision::New,
+                Some((rel_filename.to_string(), current_fp.checksums, named)),
+            ));
         }
     };
 
     // Level 1: mtime check (fastest)
-    let metadata = std::fs::metadata(path)?;
-    let current_mtime = metadata
-        .modified()?
-        .duration_since(UNIX_EPOCH)?
-        .as_secs_f64();
-
-    if (current_mtime - stored_fp.mtime).abs() < 0.001 {
+    if trust_mtime && mtime_matches(path, stored_fp)? {
         // mtime unchanged - file definitely not modified
-        return Ok(None);
+        return Ok((ChangeDecision::MtimeUnchanged, None));
     }
 
     // Level 2: file hash check (fast)
-    let content = std::fs::read_to_string(path)?;
+    let content = read_python_source(path)?;
     let current_hash = blake3::hash(content.as_bytes()).to_hex().to_string();
 
     if current_hash == stored_fp.file_hash {
         // Hash unchanged - content is identical (mtime changed but not content)
-        return Ok(None);
+        return Ok((ChangeDecision::HashUnchanged, None));
     }
 
     // Level 3: block checksum comparison (precise)
-    let current_blocks = parse_module_internal(&content)
+    let current_blocks = parse_tracked_source(path, &content, &current_hash, &options)
         .map_err(|e| anyhow::anyhow!("Parse error in {}: {}", rel_filename, e))?;
 
     let current_checksums: Vec<i32> = current_blocks.iter().map(|b| b.checksum).collect();
 
-    if current_checksums == stored_fp.checksums {
-        // Checksums unchanged - semantically equivalent (e.g., only whitespace/comments changed)
-        return Ok(None);
+    if checksum_counts(&current_checksums) == checksum_counts(&stored_fp.checksums) {
+        // Checksums unchanged - semantically equivalent (e.g., only whitespace/comments
+        // changed, or blocks moved around)
+        return Ok((ChangeDecision::ChecksumsUnchanged, None));
     }
 
     // Find which specific blocks changed
     let changed_checksums = find_changed_checksums(&stored_fp.checksums, &current_checksums);
 
-    Ok(Some((rel_filename.to_string(), changed_checksums)))
+    // Name the current blocks that aren't in the baseline, counting duplicate
+    // checksums like `find_changed_checksums`. Removed blocks can't be named:
+    // the baseline only keeps their checksums.
+    let mut stored = checksum_counts(&stored_fp.checksums);
+    let named = current_blocks
+        .into_iter()
+        .filter(|b| match stored.get_mut(&b.checksum) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .map(|b| (b.name, b.block_type, "modified"))
+        .collect();
+
+    Ok((
+        ChangeDecision::ChecksumsChanged,
+        Some((rel_filename.to_string(), changed_checksums, named)),
+    ))
+}
+
+/// Level 1 of change detection: whether the file's mtime matches the baseline's
+fn mtime_matches(path: &Path, stored_fp: &Fingerprint) -> Result<bool> {
+    let current_mtime = std::fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs_f64();
+    Ok(mtimes_equal(current_mtime, stored_fp.mtime))
+}
+
+/// Which test files are in scope, shared by baseline, detection and coverage
+/// processing so they agree on what a test may depend on
+///
+/// Scope paths only ever restrict test files. An empty list means no
+/// restriction: every test file is in scope. Otherwise a test file is in scope
+/// when it lies under one of the scope paths; scope paths that don't exist
+/// match nothing, and are kept so callers can report them.
+struct TestScope {
+    /// Canonical existing scope paths, or `None` when unrestricted
+    paths: Option<Vec<PathBuf>>,
+    /// Scope paths that don't exist on disk
+    missing: Vec<String>,
+}
+
+impl TestScope {
+    fn new(scope_paths: &[String]) -> Self {
+        if scope_paths.is_empty() {
+            return Self {
+                paths: None,
+                missing: Vec::new(),
+            };
+        }
+        let mut existing = Vec::new();
+        let mut missing = Vec::new();
+        for p in scope_paths {
+            match std::fs::canonicalize(p) {
+                Ok(path) => existing.push(path),
+                Err(_) => missing.push(p.clone()),
+            }
+        }
+        Self {
+            paths: Some(existing),
+            missing,
+        }
+    }
+
+    fn is_restricted(&self) -> bool {
+        self.paths.is_some()
+    }
+
+    /// Whether a test file (absolute path) is in scope
+    fn contains(&self, test_file: &Path) -> bool {
+        match &self.paths {
+            None => true,
+            Some(paths) => paths.iter().any(|scope| test_file.starts_with(scope)),
+        }
+    }
+}
+
+/// Absolute path of the project root, failing if it isn't an existing directory
+///
+/// A mistyped or unmounted root would otherwise walk as an empty tree, so a
+/// baseline save would "succeed" with zero files.
+fn resolve_project_root(root: &str) -> Result<PathBuf> {
+    let path = Path::new(root);
+    if !path.exists() {
+        anyhow::bail!("Project root does not exist: {}", root);
+    }
+    if !path.is_dir() {
+        anyhow::bail!("Project root is not a directory: {}", root);
+    }
+    std::fs::canonicalize(path).with_context(|| format!("Failed to resolve project root: {}", root))
 }
 
 /// Find all Python files in a directory
 ///
 /// Scope paths only apply to test files - source files are always included.
 /// This ensures that when running a subset of tests, we still track all source
-/// file dependencies.
-fn find_python_files(root: &str, scope_paths: &[String]) -> Result<Vec<PathBuf>> {
+/// file dependencies. `test_classifier` overrides the built-in test file heuristics.
+///
+/// Fails if `root` isn't an existing directory (see `resolve_project_root`).
+/// Scope paths that don't exist are returned alongside the files so callers can
+/// report them; the remaining scope paths still apply. So are entries that
+/// vanish or turn unreadable mid-walk (e.g. temp directories removed by running
+/// tests): they are skipped rather than aborting the discovery.
+///
+/// `since_mtime` (a Unix timestamp) keeps only files modified at or after it,
+/// for incremental re-checks of recently edited files.
+///
+/// Files are returned sorted so baselines and logs don't depend on the
+/// platform's directory iteration order.
+pub(crate) fn find_python_files(
+    root: &str,
+    scope_paths: &[String],
+    test_classifier: Option<&TestClassifier>,
+    extensions: &[String],
+    since_mtime: Option<f64>,
+) -> Result<Discovery> {
     let mut files = Vec::new();
+    let mut skipped = Vec::new();
 
-    // Convert root to absolute path
-    let root_path = std::fs::canonicalize(root).unwrap_or_else(|_| PathBuf::from(root));
+    let root_path = resolve_project_root(root)?;
 
-    // Convert scope paths to absolute PathBufs for comparison
-    let scope_paths_abs: Vec<PathBuf> = scope_paths
-        .iter()
-        .map(|p| {
-            let path = PathBuf::from(p);
-            std::fs::canonicalize(&path).unwrap_or(path)
-        })
-        .collect();
+    let scope = TestScope::new(scope_paths);
 
     for entry in WalkDir::new(&root_path)
         .follow_links(false)
+        .sort_by_file_name()
         .into_iter()
         .filter_entry(|e| {
             // Skip hidden directories and common non-source directories
@@ -468,11 +1972,21 @@ fn find_python_files(root: &str, scope_paths: &[String]) -> Result<Vec<PathBuf>>
             true
         })
     {
-        let entry = entry?;
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if is_transient_walk_error(&e) => {
+                if let Some(path) = e.path() {
+                    let rel = path.strip_prefix(&root_path).unwrap_or(path);
+                    skipped.push(rel.to_string_lossy().into_owned());
+                }
+                continue;
+            }
+            Err(e) => return Err(e).context("Failed to walk project directory"),
+        };
         let path = entry.path();
 
-        // Only include .py files
-        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("py") {
+        // Only include files with a tracked extension (.py by default)
+        if path.is_file() && has_tracked_extension(path, extensions) {
             // Store absolute path
             let abs_path = if path.is_absolute() {
                 path.to_path_buf()
@@ -480,27 +1994,19 @@ fn find_python_files(root: &str, scope_paths: &[String]) -> Result<Vec<PathBuf>>
                 std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
             };
 
-            // Determine if this is a test file
-            let filename = abs_path.file_name().and_then(|s| s.to_str()).unwrap_or("");
-            let is_test_filename = filename.starts_with("test_") || filename.ends_with("_test.py");
-            let in_tests_dir = abs_path.components().any(|c| {
-                if let std::path::Component::Normal(name) = c {
-                    let name_str = name.to_string_lossy();
-                    name_str == "tests" || name_str == "test"
-                } else {
-                    false
-                }
-            });
-            let is_test_file = is_test_filename || in_tests_dir;
-
             // Scope paths only apply to test files
             // Source files are always included
-            if is_test_file && !scope_paths_abs.is_empty() {
-                let in_scope = scope_paths_abs
-                    .iter()
-                    .any(|scope| abs_path.starts_with(scope));
-                if !in_scope {
-                    continue; // Skip test files outside scope
+            if scope.is_restricted()
+                && classify_test_path(&abs_path, test_classifier)?
+                && !scope.contains(&abs_path)
+            {
+                continue; // Skip test files outside scope
+            }
+
+            if let Some(since) = since_mtime {
+                // A file whose mtime can't be read vanished mid-walk
+                if !file_mtime(path).is_some_and(|mtime| mtime >= since) {
+                    continue;
                 }
             }
 
@@ -508,7 +2014,49 @@ fn find_python_files(root: &str, scope_paths: &[String]) -> Result<Vec<PathBuf>>
         }
     }
 
-    Ok(files)
+    files.sort();
+    Ok(Discovery {
+        files,
+        missing_scope_paths: scope.missing,
+        skipped,
+    })
+}
+
+/// Whether two mtimes (Unix timestamps) denote the same modification
+///
+/// Stored mtimes went through float conversions, so they're compared with a
+/// millisecond tolerance. Shared by every mtime shortcut (baseline saves,
+/// change detection, the fingerprint cache) so they agree on what's unchanged.
+pub(crate) fn mtimes_equal(a: f64, b: f64) -> bool {
+    (a - b).abs() < 0.001
+}
+
+/// Modification time of a file as a Unix timestamp
+fn file_mtime(path: &Path) -> Option<f64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs_f64())
+}
+
+/// Files found by `find_python_files`, and what callers should report
+pub(crate) struct Discovery {
+    pub files: Vec<PathBuf>,
+    /// Scope paths that don't exist on disk
+    missing_scope_paths: Vec<String>,
+    /// Entries (relative to the root) that vanished or became unreadable mid-walk
+    skipped: Vec<String>,
+}
+
+/// Whether a walk error only affects one entry below the root
+///
+/// The root itself failing, or any other error kind, is fatal.
+fn is_transient_walk_error(e: &walkdir::Error) -> bool {
+    e.depth() > 0
+        && e.io_error().is_some_and(|io| {
+            matches!(
+                io.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+            )
+        })
 }
 
 /// Find which OLD checksums were removed/modified (these indicate blocks that changed)
@@ -516,23 +2064,42 @@ fn find_python_files(root: &str, scope_paths: &[String]) -> Result<Vec<PathBuf>>
 /// Returns the OLD checksums that are no longer present in the new version.
 /// These are the checksums that tests may have used, so any test that used
 /// these blocks should be re-run to verify the changes.
+///
+/// Checksums are compared as multisets: identical blocks (e.g. two methods
+/// with the same body) share a checksum, and editing one of them leaves one
+/// copy fewer, which still reports that checksum.
 fn find_changed_checksums(old_checksums: &[i32], new_checksums: &[i32]) -> Vec<i32> {
-    let new_set: std::collections::HashSet<i32> = new_checksums.iter().copied().collect();
+    let mut remaining = checksum_counts(new_checksums);
 
     // Return OLD checksums that are no longer in the new version
     // These represent blocks that were removed or modified
     old_checksums
         .iter()
         .copied()
-        .filter(|checksum| !new_set.contains(checksum))
+        .filter(|checksum| match remaining.get_mut(checksum) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
         .collect()
 }
 
+/// Occurrences of each checksum, for multiset comparisons
+fn checksum_counts(checksums: &[i32]) -> HashMap<i32, usize> {
+    let mut counts = HashMap::new();
+    for &checksum in checksums {
+        *counts.entry(checksum).or_insert(0) += 1;
+    }
+    counts
+}
+
 /// Process coverage data and return filtered fingerprints
 ///
 /// This function does the heavy lifting of coverage processing in Rust with
 /// parallel processing for better performance. It:
-/// 1. Filters files (only .py files in project, excludes other test files)
+/// 1. Filters files (only tracked extensions in project, excludes other test files)
 /// 2. Calculates fingerprints for each file (using cache if provided)
 /// 3. Filters blocks to only those that were executed (block-level granularity)
 /// 4. Returns fingerprints with only executed blocks
@@ -541,14 +2108,43 @@ fn find_changed_checksums(old_checksums: &[i32], new_checksums: &[i32]) -> Vec<i
 /// * `coverage_data` - Map of filename -> list of executed line numbers
 /// * `project_root` - Root directory of the project
 /// * `test_file` - Path to the current test file (to filter out other test files)
-/// * `verbose` - Whether to print debug information
+/// * `verbose` - Whether to print debug information, including how many
+///   coverage files were ignored and why (not tracked, outside the project
+///   root, another test file, outside the scope paths)
 /// * `scope_paths` - List of directory paths to limit the scope (e.g., ["tests/unit/"])
 /// * `cache` - Optional FingerprintCache to avoid re-parsing files
+/// * `test_classifier` - Optional callable `(path) -> bool` overriding the built-in
+///   test file heuristics (see `save_baseline`)
+/// * `native_artifacts` - Compiled modules (absolute, or relative to `project_root`)
+///   the test loaded; each is recorded as one opaque block keyed on its file hash
+/// * `options` - Parse settings of the database (see
+///   `PytestDiffDatabase.get_parse_options`); ignored when `cache` is given
+///   (the cache's own options apply). Branch blocks need no branch coverage:
+///   the executed lines already show which `if`/`else` bodies ran.
+/// * `extensions` - Tracked file extensions (see
+///   `PytestDiffDatabase.get_tracked_file_extensions`); defaults to `[".py"]`
+/// * `dependency_granularity` - `"block"` (default) records only executed
+///   blocks; `"file"` records every block of each touched file, so any change
+///   to the file reselects the test; `"block_without_module"` records executed
+///   blocks but the `<module>` block, so top-level edits only reselect tests
+///   that executed a changed definition. The latter under-selects: tests that
+///   read a changed constant, import, decorator or class attribute without
+///   executing a changed definition aren't reselected, so pair it with
+///   periodic full runs.
+/// * `excluded_lines` - Per file (keyed like `coverage_data`), lines that never
+///   count as executed, e.g. `# pragma: no cover` or instrumentation lines. A
+///   test doesn't depend on blocks it only reached through such lines.
+/// * `setup_lines` - Per file (keyed like `coverage_data`), lines executed
+///   during collection and fixture setup rather than the test body. They only
+///   count inside function blocks (the fixtures that ran), not at module or
+///   class level, so imports triggered by setup don't make the test depend on
+///   every imported module.
 ///
 /// # Returns
 /// * List of Fingerprint objects with only executed blocks
 #[pyfunction]
-#[pyo3(signature = (coverage_data, project_root, test_file, verbose, scope_paths, cache=None))]
+#[pyo3(signature = (coverage_data, project_root, test_file, verbose, scope_paths, cache=None, test_classifier=None, native_artifacts=Vec::new(), options=None, extensions=None, dependency_granularity="block", excluded_lines=None, setup_lines=None))]
+#[allow(clippy::too_many_arguments)]
 pub fn process_coverage_data(
     coverage_data: HashMap<String, Vec<usize>>,
     project_root: &str,
@@ -556,64 +2152,310 @@ pub fn process_coverage_data(
     verbose: bool,
     scope_paths: Vec<String>,
     cache: Option<&crate::fingerprint_cache::FingerprintCache>,
+    test_classifier: Option<&Bound<'_, PyAny>>,
+    native_artifacts: Vec<String>,
+    options: Option<ParseOptions>,
+    extensions: Option<Vec<String>>,
+    dependency_granularity: &str,
+    excluded_lines: Option<HashMap<String, Vec<usize>>>,
+    setup_lines: Option<HashMap<String, Vec<usize>>>,
 ) -> PyResult<Vec<Fingerprint>> {
-    let fingerprints = process_coverage_data_internal(
-        coverage_data,
-        project_root,
-        test_file,
+    let dependencies: DependencyGranularity = dependency_granularity
+        .parse()
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{}", e)))?;
+    let classifier = python_classifier(test_classifier);
+    let options = CoverageOptions {
         verbose,
         scope_paths,
-        cache,
-    )
-    .map_err(|e| {
-        pyo3::exceptions::PyRuntimeError::new_err(format!("Failed to process coverage data: {}", e))
-    })?;
+        test_classifier: classifier.as_ref().map(|c| c as &TestClassifier),
+        native_artifacts,
+        extensions: extensions.unwrap_or_else(|| vec![PYTHON_EXTENSION.to_string()]),
+        parse: options.unwrap_or_default(),
+        dependencies,
+        excluded_lines: excluded_lines.unwrap_or_default(),
+        setup_lines: setup_lines.unwrap_or_default(),
+    };
+    let fingerprints =
+        process_coverage_data_internal(coverage_data, project_root, test_file, cache, &options)
+            .map_err(|e| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to process coverage data: {}",
+                    e
+                ))
+            })?;
 
     Ok(fingerprints)
 }
 
-fn process_coverage_data_internal(
-    coverage_data: HashMap<String, Vec<usize>>,
+/// Settings of a coverage processing run
+///
+/// Mirrors the keyword arguments of `process_coverage_data` (see there for
+/// what each one does): which coverage files count, which of their lines, and
+/// what a test depends on in the files it executed.
+#[derive(Clone)]
+pub(crate) struct CoverageOptions<'a> {
+    pub verbose: bool,
+    pub scope_paths: Vec<String>,
+    pub test_classifier: Option<&'a TestClassifier<'a>>,
+    pub native_artifacts: Vec<String>,
+    pub extensions: Vec<String>,
+    pub parse: ParseOptions,
+    pub dependencies: DependencyGranularity,
+    pub excluded_lines: HashMap<String, Vec<usize>>,
+    pub setup_lines: HashMap<String, Vec<usize>>,
+}
+
+impl Default for CoverageOptions<'_> {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            scope_paths: Vec::new(),
+            test_classifier: None,
+            native_artifacts: Vec::new(),
+            extensions: vec![PYTHON_EXTENSION.to_string()],
+            parse: ParseOptions::default(),
+            dependencies: DependencyGranularity::default(),
+            excluded_lines: HashMap::new(),
+            setup_lines: HashMap::new(),
+        }
+    }
+}
+
+/// A block with the executed lines of its body and whether the test depends
+/// on it: `(name, start_line, end_line, lines, dependency)`
+type BlockCoverage = (String, usize, usize, Vec<usize>, bool);
+
+/// Map coverage onto the blocks of each file, for auditing dependencies
+///
+/// Diagnostic companion to `process_coverage_data`: instead of fingerprints,
+/// returns for every file (relative to `project_root`) each block as
+/// `(name, start_line, end_line, executed_lines, dependency)`, in source
+/// order. The executed lines are those within the block's body (from
+/// `body_start_line`), left after dropping `excluded_lines` and module- or
+/// class-level `setup_lines`; `dependency` tells whether
+/// `process_coverage_data` would record the block with the same
+/// `dependency_granularity`. No scope or test-file filtering is applied;
+/// files that can't be fingerprinted are left out.
+#[pyfunction]
+#[pyo3(signature = (coverage_data, project_root, options=None, dependency_granularity="block", excluded_lines=None, setup_lines=None))]
+pub fn explain_coverage_blocks(
+    coverage_data: HashMap<String, Vec<usize>>,
+    project_root: &str,
+    options: Option<ParseOptions>,
+    dependency_granularity: &str,
+    excluded_lines: Option<HashMap<String, Vec<usize>>>,
+    setup_lines: Option<HashMap<String, Vec<usize>>>,
+) -> PyResult<HashMap<String, Vec<BlockCoverage>>> {
+    let dependencies: DependencyGranularity = dependency_granularity
+        .parse()
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{}", e)))?;
+    Ok(explain_coverage_blocks_internal(
+        coverage_data,
+        project_root,
+        &CoverageOptions {
+            parse: options.unwrap_or_default(),
+            dependencies,
+            excluded_lines: excluded_lines.unwrap_or_default(),
+            setup_lines: setup_lines.unwrap_or_default(),
+            ..CoverageOptions::default()
+        },
+    ))
+}
+
+/// Only `parse`, `dependencies`, `excluded_lines` and `setup_lines` of
+/// `options` apply
+fn explain_coverage_blocks_internal(
+    coverage_data: HashMap<String, Vec<usize>>,
+    project_root: &str,
+    options: &CoverageOptions,
+) -> HashMap<String, Vec<BlockCoverage>> {
+    let CoverageOptions {
+        parse: ref options,
+        dependencies,
+        ref excluded_lines,
+        ref setup_lines,
+        ..
+    } = *options;
+    let coverage_data = remove_excluded_lines(coverage_data, excluded_lines);
+    coverage_data
+        .par_iter()
+        .filter_map(|(filename, executed_lines)| {
+            let fp = calculate_fingerprint_internal(filename, options.clone()).ok()?;
+            let blocks = fp.blocks.unwrap_or_default();
+            let setup: Option<HashSet<usize>> = setup_lines
+                .get(filename)
+                .map(|lines| lines.iter().copied().collect());
+            let (mut executed_lines, dependencies) =
+                dependency_blocks(&blocks, executed_lines, setup.as_ref(), dependencies);
+            executed_lines.sort_unstable();
+            executed_lines.dedup();
+            let explained = blocks
+                .iter()
+                .map(|block| {
+                    let executed = executed_lines
+                        .iter()
+                        .copied()
+                        .filter(|line| block.body_lines().contains(line))
+                        .collect();
+                    (
+                        block.name.clone(),
+                        block.start_line,
+                        block.end_line,
+                        executed,
+                        dependencies.contains(block),
+                    )
+                })
+                .collect();
+            Some((make_relative(&fp.filename, project_root), explained))
+        })
+        .collect()
+}
+
+/// Blocks of a file a test depends on, given the lines it executed there
+///
+/// `executed_lines` must already be free of excluded lines; module- and
+/// class-level `setup_lines` are dropped here. Returns the lines that count as
+/// executed along with the blocks. Shared by `process_coverage_data` and
+/// `explain_coverage_blocks`, so explanations match recorded dependencies.
+fn dependency_blocks(
+    blocks: &[Block],
+    executed_lines: &[usize],
+    setup_lines: Option<&HashSet<usize>>,
+    dependencies: DependencyGranularity,
+) -> (Vec<usize>, Vec<Block>) {
+    let executed_lines = match setup_lines {
+        Some(setup) => drop_setup_lines(executed_lines, setup, blocks),
+        None => executed_lines.to_vec(),
+    };
+    let blocks = match dependencies {
+        DependencyGranularity::File if executed_lines.is_empty() => Vec::new(),
+        DependencyGranularity::File => blocks.to_vec(),
+        DependencyGranularity::Block | DependencyGranularity::BlockWithoutModule => {
+            let executed: HashSet<usize> = executed_lines.iter().copied().collect();
+            let mut executed_blocks = filter_executed_blocks_rust(blocks, &executed);
+            if dependencies == DependencyGranularity::BlockWithoutModule {
+                executed_blocks.retain(|b| b.block_type != "module");
+            }
+            executed_blocks
+        }
+    };
+    (executed_lines, blocks)
+}
+
+/// Drop excluded lines from each file's executed lines
+fn remove_excluded_lines(
+    coverage_data: HashMap<String, Vec<usize>>,
+    excluded_lines: &HashMap<String, Vec<usize>>,
+) -> HashMap<String, Vec<usize>> {
+    coverage_data
+        .into_iter()
+        .map(|(filename, mut executed)| {
+            if let Some(excluded) = excluded_lines.get(&filename) {
+                let excluded: HashSet<usize> = excluded.iter().copied().collect();
+                executed.retain(|line| !excluded.contains(line));
+            }
+            (filename, executed)
+        })
+        .collect()
+}
+
+/// Drop the lines of `executed_lines` that ran during setup at module or class
+/// level (imports, definitions), keeping those inside functions (fixtures)
+fn drop_setup_lines(
+    executed_lines: &[usize],
+    setup_lines: &HashSet<usize>,
+    blocks: &[Block],
+) -> Vec<usize> {
+    let in_function = |line: usize| {
+        blocks.iter().any(|block| {
+            !matches!(block.block_type.as_str(), "module" | "class")
+                && block.body_lines().contains(&line)
+        })
+    };
+    executed_lines
+        .iter()
+        .copied()
+        .filter(|&line| !setup_lines.contains(&line) || in_function(line))
+        .collect()
+}
+
+pub(crate) fn process_coverage_data_internal(
+    coverage_data: HashMap<String, Vec<usize>>,
     project_root: &str,
     test_file: &str,
-    verbose: bool,
-    scope_paths: Vec<String>,
     cache: Option<&crate::fingerprint_cache::FingerprintCache>,
+    options: &CoverageOptions,
 ) -> Result<Vec<Fingerprint>> {
+    let CoverageOptions {
+        verbose,
+        ref scope_paths,
+        test_classifier,
+        ref native_artifacts,
+        ref extensions,
+        parse: ref options,
+        dependencies,
+        ref excluded_lines,
+        ref setup_lines,
+    } = *options;
     let project_root_path = Path::new(project_root);
     let test_file_path = Path::new(test_file);
 
-    // Convert scope paths to absolute PathBufs for comparison
-    // If scope_paths is empty, use project_root as the default scope
-    let scope_paths_abs: Vec<PathBuf> = if scope_paths.is_empty() {
-        vec![std::fs::canonicalize(project_root_path)
-            .unwrap_or_else(|_| project_root_path.to_path_buf())]
-    } else {
-        scope_paths
-            .iter()
-            .map(|p| {
-                let path = PathBuf::from(p);
-                std::fs::canonicalize(&path).unwrap_or(path)
-            })
-            .collect()
-    };
+    // Tools may spell the same file with different casing
+    let coverage_data = resolve_coverage_path_case(
+        remove_excluded_lines(coverage_data, excluded_lines),
+        project_root_path,
+        extensions,
+    );
+    let setup_lines: HashMap<String, HashSet<usize>> =
+        resolve_coverage_path_case(setup_lines.clone(), project_root_path, extensions)
+            .into_iter()
+            .map(|(filename, lines)| (filename, lines.into_iter().collect()))
+            .collect();
 
-    // Process files in parallel with rayon
-    let fingerprints: Vec<Fingerprint> = coverage_data
-        .par_iter()
-        .filter_map(|(filename, executed_lines)| {
-            let filepath = Path::new(filename);
+    // Same scope semantics as save_baseline and detect_changes
+    let scope = TestScope::new(scope_paths);
 
-            // 1. File filtering - only include relevant Python files
-            if !should_process_file(
-                filepath,
+    // Classify candidate files up front: a custom classifier may call into
+    // Python, which must not happen from the Rayon worker threads below
+    let test_files: HashSet<&str> = coverage_data
+        .keys()
+        .filter(|f| is_project_python_file(Path::new(f), project_root_path, extensions))
+        .filter_map(
+            |f| match classify_test_path(Path::new(f), test_classifier) {
+                Ok(true) => Some(Ok(f.as_str())),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            },
+        )
+        .collect::<Result<_>>()?;
+
+    // 1. File filtering - only include relevant Python files
+    let mut rejections = RejectionCounts::default();
+    let accepted: Vec<(&String, &Vec<usize>)> = coverage_data
+        .iter()
+        .filter(|(filename, _)| {
+            let rejection = coverage_rejection(
+                Path::new(filename),
                 project_root_path,
                 test_file_path,
-                &scope_paths_abs,
-            ) {
-                return None;
-            }
+                &scope,
+                test_files.contains(filename.as_str()),
+                extensions,
+            );
+            rejections.record(rejection);
+            rejection.is_none()
+        })
+        .collect();
+    if verbose {
+        if let Some(summary) = rejections.summary() {
+            eprintln!("⚠ pytest-difftest: {}", summary);
+        }
+    }
 
+    // Process files in parallel with rayon
+    let mut fingerprints: Vec<Fingerprint> = accepted
+        .par_iter()
+        .filter_map(|&(filename, executed_lines)| {
             // 2. Calculate fingerprint with all blocks (use cache if available)
             let fp = match cache {
                 Some(c) => match c.get_or_calculate_internal(filename) {
@@ -628,7 +2470,7 @@ fn process_coverage_data_internal(
                         return None;
                     }
                 },
-                None => match calculate_fingerprint_internal(filename) {
+                None => match calculate_fingerprint_internal(filename, options.clone()) {
                     Ok(fp) => fp,
                     Err(e) => {
                         if verbose {
@@ -647,9 +2489,25 @@ fn process_coverage_data_internal(
                 Some(blocks) => blocks,
                 None => return Some(fp), // No blocks info - use full fingerprint
             };
+            let (_, executed_blocks) = dependency_blocks(
+                blocks,
+                executed_lines,
+                setup_lines.get(filename),
+                dependencies,
+            );
 
-            let executed_lines_set: HashSet<usize> = executed_lines.iter().copied().collect();
-            let executed_blocks = filter_executed_blocks_rust(blocks, &executed_lines_set);
+            if dependencies == DependencyGranularity::File {
+                if executed_blocks.is_empty() {
+                    return None;
+                }
+                return Some(Fingerprint {
+                    filename: make_relative(&fp.filename, project_root),
+                    checksums: fp.checksums,
+                    file_hash: fp.file_hash,
+                    mtime: fp.mtime,
+                    blocks: None,
+                });
+            }
 
             if executed_blocks.is_empty() {
                 if verbose {
@@ -680,42 +2538,116 @@ fn process_coverage_data_internal(
         })
         .collect();
 
+    // 5. Native artifacts: whole-file opaque dependencies
+    for artifact in native_artifacts {
+        let abs_path = project_root_path.join(artifact);
+        match calculate_native_fingerprint_internal(&abs_path.to_string_lossy()) {
+            Ok(mut fp) => {
+                fp.filename = make_relative(&fp.filename, project_root);
+                fingerprints.push(fp);
+            }
+            Err(e) => {
+                if verbose {
+                    eprintln!(
+                        "⚠ pytest-difftest: Could not fingerprint {}: {}",
+                        artifact, e
+                    );
+                }
+            }
+        }
+    }
+
     Ok(fingerprints)
 }
 
-/// Check if a file should be processed based on filtering rules
-fn should_process_file(
-    filepath: &Path,
-    project_root: &Path,
-    test_file: &Path,
-    scope_paths: &[PathBuf],
-) -> bool {
-    // Must be a .py file
-    if filepath.extension().and_then(|s| s.to_str()) != Some("py") {
+/// Check that a file has a tracked extension and is inside the project root
+fn is_project_python_file(filepath: &Path, project_root: &Path, extensions: &[String]) -> bool {
+    // Must be a .py file (or another tracked extension)
+    if !has_tracked_extension(filepath, extensions) {
         return false;
     }
 
     // Must be in the project root (use Path methods for cross-platform compatibility)
-    if !filepath.starts_with(project_root) {
-        return false;
-    }
+    filepath.starts_with(project_root)
+}
 
-    // Determine if this is a test file
-    // Use Path components for cross-platform compatibility (works on both / and \)
-    let filename = filepath.file_name().and_then(|s| s.to_str()).unwrap_or("");
-    let is_test_filename = filename.starts_with("test_") || filename.ends_with("_test.py");
+/// Why a coverage file was left out of a test's dependencies
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CoverageRejection {
+    /// Not a tracked extension (e.g. `.pyx`, `.so`)
+    Untracked,
+    /// Outside the project root (installed packages, mismatched absolute paths)
+    OutsideRoot,
+    /// A test file other than the one being executed
+    OtherTestFile,
+    /// The current test file, but outside the scope paths
+    OutOfScope,
+}
 
-    // Check if any parent directory is named "tests" or "test"
-    let in_tests_dir = filepath.components().any(|c| {
-        if let std::path::Component::Normal(name) = c {
-            let name_str = name.to_string_lossy();
-            name_str == "tests" || name_str == "test"
-        } else {
-            false
+/// Number of coverage files rejected for each reason
+#[derive(Debug, Default, PartialEq, Eq)]
+struct RejectionCounts {
+    untracked: usize,
+    outside_root: usize,
+    other_test_file: usize,
+    out_of_scope: usize,
+}
+
+impl RejectionCounts {
+    fn record(&mut self, rejection: Option<CoverageRejection>) {
+        match rejection {
+            Some(CoverageRejection::Untracked) => self.untracked += 1,
+            Some(CoverageRejection::OutsideRoot) => self.outside_root += 1,
+            Some(CoverageRejection::OtherTestFile) => self.other_test_file += 1,
+            Some(CoverageRejection::OutOfScope) => self.out_of_scope += 1,
+            None => {}
         }
-    });
+    }
+
+    /// One-line report of the rejected files, or `None` when none were rejected
+    fn summary(&self) -> Option<String> {
+        let reasons: Vec<String> = [
+            (self.untracked, "not a tracked extension"),
+            (self.outside_root, "outside the project root"),
+            (self.other_test_file, "another test file"),
+            (self.out_of_scope, "outside the scope paths"),
+        ]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, reason)| format!("{} {}", count, reason))
+        .collect();
+        if reasons.is_empty() {
+            return None;
+        }
+        let total = self.untracked + self.outside_root + self.other_test_file + self.out_of_scope;
+        Some(format!(
+            "Ignored {} coverage file(s): {}",
+            total,
+            reasons.join(", ")
+        ))
+    }
+}
+
+/// Check if a file should be processed based on filtering rules
+///
+/// Returns why the file is rejected, or `None` if it should be processed.
+fn coverage_rejection(
+    filepath: &Path,
+    project_root: &Path,
+    test_file: &Path,
+    scope: &TestScope,
+    is_test_file: bool,
+    extensions: &[String],
+) -> Option<CoverageRejection> {
+    // Must be a .py file (or another tracked extension)
+    if !has_tracked_extension(filepath, extensions) {
+        return Some(CoverageRejection::Untracked);
+    }
+    // Must be in the project root
+    if !filepath.starts_with(project_root) {
+        return Some(CoverageRejection::OutsideRoot);
+    }
 
-    let is_test_file = is_test_filename || in_tests_dir;
     let is_current_test_file = filepath == test_file;
 
     // Scope paths only apply to test files, not source files
@@ -725,19 +2657,16 @@ fn should_process_file(
         // This prevents coverage contamination where test collection
         // causes all tests to depend on all test files
         if !is_current_test_file {
-            return false;
+            return Some(CoverageRejection::OtherTestFile);
         }
         // For the current test file, check scope (if running a subset of tests)
-        if !scope_paths.is_empty() {
-            let in_scope = scope_paths.iter().any(|scope| filepath.starts_with(scope));
-            if !in_scope {
-                return false;
-            }
+        if !scope.contains(filepath) {
+            return Some(CoverageRejection::OutOfScope);
         }
     }
     // Source files are always included (if they're in project root)
 
-    true
+    None
 }
 
 /// Filter blocks to only those where at least one line was executed
@@ -756,23 +2685,43 @@ fn filter_executed_blocks_rust(blocks: &[Block], executed_lines: &HashSet<usize>
     blocks
         .iter()
         .filter(|block| {
-            // Check if any line in this block's BODY was executed.
-            // We use body_start_line instead of start_line to skip decorators
-            // and `def`/`class` signature lines, which Python executes at import
-            // time. This prevents false positives where importing a module makes
-            // all functions appear "executed".
-            (block.body_start_line..=block.end_line).any(|line| executed_lines.contains(&line))
+            // Check if any line in this block's BODY was executed, so importing
+            // a module doesn't make all its functions appear "executed"
+            block
+                .body_lines()
+                .any(|line| executed_lines.contains(&line))
         })
         .cloned()
         .collect()
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::parser::Granularity;
+
+    pub(crate) fn py() -> Vec<String> {
+        vec![PYTHON_EXTENSION.to_string()]
+    }
     use std::io::Write;
     use tempfile::NamedTempFile;
 
+    /// Rewrite `path`, dated a second after its previous mtime, so mtime
+    /// checks see the edit without waiting for the clock to tick
+    pub(crate) fn rewrite(path: impl AsRef<Path>, content: impl AsRef<[u8]>) {
+        let path = path.as_ref();
+        let previous = std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .unwrap_or_else(|_| std::time::SystemTime::now());
+        std::fs::write(path, content).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(previous + std::time::Duration::from_secs(1))
+            .unwrap();
+    }
+
     #[test]
     fn test_calculate_fingerprint() {
         let mut file = NamedTempFile::new().unwrap();
@@ -780,7 +2729,7 @@ mod tests {
         file.flush().unwrap();
 
         let path = file.path().to_str().unwrap();
-        let fingerprint = calculate_fingerprint_internal(path).unwrap();
+        let fingerprint = calculate_fingerprint_internal(path, ParseOptions::default()).unwrap();
 
         assert_eq!(fingerprint.filename, path);
         assert_eq!(fingerprint.checksums.len(), 2); // module + function
@@ -788,6 +2737,64 @@ mod tests {
         assert!(fingerprint.mtime > 0.0);
     }
 
+    #[test]
+    fn test_fingerprint_source_matches_file() {
+        let mut temp_file = NamedTempFile::with_suffix(".py").unwrap();
+        let source = "import os\n\nclass A:\n    def f(self):\n        return os.sep\n";
+        temp_file.write_all(source.as_bytes()).unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        let from_file = calculate_fingerprint_internal(path, ParseOptions::default()).unwrap();
+        let from_source =
+            fingerprint_source_internal(path, source, from_file.mtime, ParseOptions::default())
+                .unwrap();
+        assert_eq!(from_source.filename, from_file.filename);
+        assert_eq!(from_source.checksums, from_file.checksums);
+        assert_eq!(from_source.file_hash, from_file.file_hash);
+        assert_eq!(from_source.mtime, from_file.mtime);
+        assert_eq!(from_source.blocks, from_file.blocks);
+
+        // Only the given name and mtime are used: no file has to exist
+        let virtual_fp =
+            fingerprint_source_internal("gen/virtual.py", source, 42.0, ParseOptions::default())
+                .unwrap();
+        assert_eq!(virtual_fp.filename, "gen/virtual.py");
+        assert_eq!(virtual_fp.mtime, 42.0);
+        assert_eq!(virtual_fp.checksums, from_file.checksums);
+        assert!(
+            fingerprint_source_internal("bad.py", "def (", 0.0, ParseOptions::default()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_supplied_mtime_fingerprint_matches_stat_one() {
+        let mut temp_file = NamedTempFile::with_suffix(".py").unwrap();
+        temp_file
+            .write_all(b"def f():\n    return 1\n\n\nclass A:\n    x = 1\n")
+            .unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        let stat = calculate_fingerprint_internal(path, ParseOptions::default()).unwrap();
+        let supplied = calculate_fingerprint_with_mtime_internal(
+            path,
+            Some(stat.mtime),
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(supplied.filename, stat.filename);
+        assert_eq!(supplied.checksums, stat.checksums);
+        assert_eq!(supplied.file_hash, stat.file_hash);
+        assert_eq!(supplied.mtime, stat.mtime);
+        assert_eq!(supplied.blocks, stat.blocks);
+
+        // The supplied mtime is taken as is: the file isn't stat'ed again
+        let taken =
+            calculate_fingerprint_with_mtime_internal(path, Some(42.0), ParseOptions::default())
+                .unwrap();
+        assert_eq!(taken.mtime, 42.0);
+        assert_eq!(taken.checksums, stat.checksums);
+    }
+
     #[test]
     fn test_fingerprint_hash_stability() {
         let mut file = NamedTempFile::new().unwrap();
@@ -797,8 +2804,8 @@ mod tests {
 
         let path = file.path().to_str().unwrap();
 
-        let fp1 = calculate_fingerprint_internal(path).unwrap();
-        let fp2 = calculate_fingerprint_internal(path).unwrap();
+        let fp1 = calculate_fingerprint_internal(path, ParseOptions::default()).unwrap();
+        let fp2 = calculate_fingerprint_internal(path, ParseOptions::default()).unwrap();
 
         assert_eq!(fp1.file_hash, fp2.file_hash);
         assert_eq!(fp1.checksums, fp2.checksums);
@@ -897,6 +2904,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_filter_executed_blocks_module_last_line_without_newline() {
+        // Only the final, non-newline-terminated line executed: the module
+        // block must still be attributed.
+        let blocks = crate::parser::parse_module_internal("def f():\n    pass\n\nf()").unwrap();
+        let executed_lines: HashSet<usize> = [4].into_iter().collect();
+        let result = filter_executed_blocks_rust(&blocks, &executed_lines);
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].name, "<module>");
+    }
+
     #[test]
     fn test_find_python_files_skips_venv() {
         // Create a temp directory with a non-hidden project root inside
@@ -916,7 +2934,9 @@ mod tests {
         std::fs::write(venv_dir.join("pyvenv.cfg"), "home = /usr/bin").unwrap();
         std::fs::write(venv_dir.join("lib").join("site.py"), "pass").unwrap();
 
-        let files = find_python_files(root.to_str().unwrap(), &[]).unwrap();
+        let files = find_python_files(root.to_str().unwrap(), &[], None, &py(), None)
+            .unwrap()
+            .files;
         let filenames: Vec<String> = files
             .iter()
             .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
@@ -932,6 +2952,2862 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_find_python_files_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        for rel in [
+            "zeta.py", "b/mod.py", "a/z.py", "a/b.py", "alpha.py", "b/a/x.py",
+        ] {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "pass").unwrap();
+        }
+
+        let files = find_python_files(root.to_str().unwrap(), &[], None, &py(), None)
+            .unwrap()
+            .files;
+        assert_eq!(files.len(), 6);
+        let mut sorted = files.clone();
+        sorted.sort();
+        assert_eq!(files, sorted);
+    }
+
+    #[test]
+    fn test_find_python_files_since_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        let now = std::time::SystemTime::now();
+        for (rel, age_secs) in [
+            ("old.py", 3600),
+            ("src/stale.py", 120),
+            ("src/recent.py", 5),
+            ("tests/test_new.py", 0),
+        ] {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "pass").unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - std::time::Duration::from_secs(age_secs))
+                .unwrap();
+        }
+        let since = (now - std::time::Duration::from_secs(60))
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+
+        let files = find_python_files(root.to_str().unwrap(), &[], None, &py(), Some(since))
+            .unwrap()
+            .files;
+        assert_eq!(
+            files,
+            vec![root.join("src/recent.py"), root.join("tests/test_new.py")]
+        );
+
+        // Composes with the test scope
+        let scope = vec![root.join("other").to_string_lossy().into_owned()];
+        let files = find_python_files(root.to_str().unwrap(), &scope, None, &py(), Some(since))
+            .unwrap()
+            .files;
+        assert_eq!(files, vec![root.join("src/recent.py")]);
+    }
+
+    #[test]
+    fn test_update_baseline_file_only_touches_one_row() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "def a():\n    return 1\n").unwrap();
+        std::fs::write(root.join("b.py"), "def b():\n    return 2\n").unwrap();
+
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+        let root_str = root.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions::default(),
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let before_a = db.get_baseline_fingerprint_rust("a.py").unwrap().unwrap();
+        let before_b = db.get_baseline_fingerprint_rust("b.py").unwrap().unwrap();
+        drop(db);
+
+        // Unchanged content reports no change
+        assert!(
+            !update_baseline_file_internal(db_path, root_str, "a.py", DEFAULT_BASELINE).unwrap()
+        );
+
+        std::fs::write(root.join("a.py"), "def a():\n    return 42\n").unwrap();
+        assert!(
+            update_baseline_file_internal(db_path, root_str, "a.py", DEFAULT_BASELINE).unwrap()
+        );
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let after_a = db.get_baseline_fingerprint_rust("a.py").unwrap().unwrap();
+        let after_b = db.get_baseline_fingerprint_rust("b.py").unwrap().unwrap();
+        assert_ne!(after_a.file_hash, before_a.file_hash);
+        assert_eq!(after_b.file_hash, before_b.file_hash);
+        assert_eq!(after_b.checksums, before_b.checksums);
+        assert_eq!(after_b.mtime, before_b.mtime);
+
+        // A named baseline is updated on its own
+        assert!(update_baseline_file_internal(db_path, root_str, "b.py", "pr").unwrap());
+        let pr = db.get_named_baseline_fingerprints("pr").unwrap();
+        assert_eq!(pr.keys().collect::<Vec<_>>(), vec!["b.py"]);
+        assert!(!update_baseline_file_internal(db_path, root_str, "b.py", "pr").unwrap());
+        assert_eq!(
+            db.get_baseline_fingerprint_rust("b.py")
+                .unwrap()
+                .unwrap()
+                .mtime,
+            before_b.mtime
+        );
+    }
+
+    #[test]
+    fn test_save_baseline_quiet_overrides_verbose() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "x = 1\n").unwrap();
+        std::fs::write(root.join("b.py"), "y = 2\n").unwrap();
+
+        let db_path = dir.path().join("diff.db");
+        let count = save_baseline_internal(
+            db_path.to_str().unwrap(),
+            root.to_str().unwrap(),
+            &SaveOptions {
+                verbose: true,
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap()
+        .count;
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_save_baseline_without_checkpoint_keeps_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "x = 1\n").unwrap();
+        let db_path = dir.path().join("diff.db");
+        let wal_path = dir.path().join("diff.db-wal");
+        let wal_len = || std::fs::metadata(&wal_path).unwrap().len();
+
+        let mut db = PytestDiffDatabase::open(db_path.to_str().unwrap()).unwrap();
+        let count = save_baseline_with_db(
+            &mut db,
+            root.to_str().unwrap(),
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+        )
+        .unwrap()
+        .count;
+        assert_eq!(count, 1);
+        db.checkpoint(Checkpoint::None).unwrap();
+        assert!(wal_len() > 0);
+
+        // The connection is still usable after the save
+        assert!(db.get_baseline_fingerprint_rust("a.py").unwrap().is_some());
+
+        db.checkpoint(Checkpoint::Truncate).unwrap();
+        assert_eq!(wal_len(), 0);
+    }
+
+    #[test]
+    fn test_save_baseline_strict_fails_on_unparseable_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("good.py"), "x = 1\n").unwrap();
+        std::fs::write(root.join("broken.py"), "def f(:\n").unwrap();
+        let db_path = dir.path().join("diff.db");
+        let save = |strict| {
+            save_baseline_internal(
+                db_path.to_str().unwrap(),
+                root.to_str().unwrap(),
+                &SaveOptions {
+                    quiet: true,
+                    strict,
+                    ..SaveOptions::default()
+                },
+                Checkpoint::Truncate,
+            )
+        };
+
+        let err = save(true).unwrap_err().to_string();
+        assert!(
+            err.contains("1 files could not be fingerprinted"),
+            "{}",
+            err
+        );
+        assert!(err.contains("broken.py"), "{}", err);
+        let db = PytestDiffDatabase::open(db_path.to_str().unwrap()).unwrap();
+        assert!(db
+            .get_baseline_fingerprint_rust("good.py")
+            .unwrap()
+            .is_none());
+        drop(db);
+
+        assert_eq!(save(false).unwrap().count, 1);
+        let db = PytestDiffDatabase::open(db_path.to_str().unwrap()).unwrap();
+        assert!(db
+            .get_baseline_fingerprint_rust("good.py")
+            .unwrap()
+            .is_some());
+        assert!(db
+            .get_baseline_fingerprint_rust("broken.py")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_save_baseline_trusting_mtime_skips_untouched_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        for rel in ["vendor/lib.py", "src/app.py"] {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "def f():\n    return 1\n").unwrap();
+        }
+        let db_path = dir.path().join("diff.db");
+        let save = |trust_mtime| {
+            save_baseline_internal(
+                db_path.to_str().unwrap(),
+                root.to_str().unwrap(),
+                &SaveOptions {
+                    quiet: true,
+                    trust_mtime,
+                    ..SaveOptions::default()
+                },
+                Checkpoint::Truncate,
+            )
+            .unwrap()
+        };
+        let baseline_hash = |rel: &str| {
+            PytestDiffDatabase::open(db_path.to_str().unwrap())
+                .unwrap()
+                .get_baseline_fingerprint_rust(rel)
+                .unwrap()
+                .unwrap()
+                .file_hash
+        };
+        save(false);
+        let vendor_hash = baseline_hash("vendor/lib.py");
+        let app_hash = baseline_hash("src/app.py");
+
+        // Rewrite vendor/lib.py behind its baseline mtime: only a read would notice
+        let vendor = root.join("vendor/lib.py");
+        let vendor_mtime = std::fs::metadata(&vendor).unwrap().modified().unwrap();
+        std::fs::write(&vendor, "def f():\n    return 2\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&vendor)
+            .unwrap()
+            .set_modified(vendor_mtime)
+            .unwrap();
+        rewrite(root.join("src/app.py"), "def f():\n    return 3\n");
+
+        assert_eq!(save(true).count, 2);
+        assert_eq!(baseline_hash("vendor/lib.py"), vendor_hash);
+        assert_ne!(baseline_hash("src/app.py"), app_hash);
+
+        // Without trusting mtimes every file is hashed again
+        save(false);
+        assert_ne!(baseline_hash("vendor/lib.py"), vendor_hash);
+    }
+
+    #[test]
+    fn test_save_baseline_records_writer_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "x = 1\n").unwrap();
+        let db_path = dir.path().join("diff.db");
+
+        let mut db = PytestDiffDatabase::open(db_path.to_str().unwrap()).unwrap();
+        assert_eq!(db.get_writer_version_internal().unwrap(), None);
+        save_baseline_with_db(
+            &mut db,
+            root.to_str().unwrap(),
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_writer_version_internal().unwrap().as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn test_save_baseline_label_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "x = 1\n").unwrap();
+        let db_path = dir.path().join("diff.db");
+
+        let mut db = PytestDiffDatabase::open(db_path.to_str().unwrap()).unwrap();
+        let save = |db: &mut PytestDiffDatabase, baseline_name: &str, label: Option<&str>| {
+            save_baseline_with_db(
+                db,
+                root.to_str().unwrap(),
+                &SaveOptions {
+                    quiet: true,
+                    baseline_name,
+                    label,
+                    ..SaveOptions::default()
+                },
+            )
+            .unwrap();
+        };
+        let label = |db: &PytestDiffDatabase, baseline_name: &str| {
+            db.get_baseline_metadata_internal(baseline_name)
+                .unwrap()
+                .get("label")
+                .cloned()
+        };
+        save(&mut db, DEFAULT_BASELINE, Some("before refactor"));
+        save(&mut db, "release", Some("v2.1 release"));
+
+        let metadata = db.get_baseline_metadata_internal(DEFAULT_BASELINE).unwrap();
+        assert_eq!(metadata["label"], "before refactor");
+        assert_eq!(metadata["writer_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(label(&db, "release").as_deref(), Some("v2.1 release"));
+
+        // The label describes one save: the next save without one clears it
+        save(&mut db, DEFAULT_BASELINE, None);
+        assert_eq!(label(&db, DEFAULT_BASELINE), None);
+        assert_eq!(label(&db, "release").as_deref(), Some("v2.1 release"));
+    }
+
+    #[test]
+    fn test_empty_scope_is_consistent_across_save_detect_and_coverage() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        for rel in ["src/lib.py", "tests/test_a.py", "tests/test_b.py"] {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "def f():\n    return 1\n").unwrap();
+        }
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        let all = vec!["src/lib.py", "tests/test_a.py", "tests/test_b.py"];
+
+        let count = save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap()
+        .count;
+        assert_eq!(count, all.len());
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let mut baseline: Vec<_> = db
+            .get_named_baseline_fingerprints(DEFAULT_BASELINE)
+            .unwrap()
+            .into_keys()
+            .collect();
+        baseline.sort();
+        assert_eq!(baseline, all);
+        drop(db);
+
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let mut examined: Vec<_> = changes.decisions.into_keys().collect();
+        examined.sort();
+        assert_eq!(examined, all);
+
+        // Coverage keeps the sources and the running test file, all of which
+        // save and detect consider in scope
+        let coverage = all
+            .iter()
+            .map(|rel| (root.join(rel).to_string_lossy().into_owned(), vec![1, 2]))
+            .collect();
+        let test_file = root.join("tests/test_a.py");
+        let mut recorded: Vec<_> = process_coverage_data_internal(
+            coverage,
+            root_str,
+            test_file.to_str().unwrap(),
+            None,
+            &CoverageOptions::default(),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|fp| fp.filename)
+        .collect();
+        recorded.sort();
+        assert_eq!(recorded, vec!["src/lib.py", "tests/test_a.py"]);
+    }
+
+    #[test]
+    fn test_find_python_files_skips_entries_removed_mid_walk() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        let tmp = root.join("zz_tmp");
+        std::fs::create_dir_all(&tmp).unwrap();
+        std::fs::write(root.join("a.py"), "pass").unwrap();
+        std::fs::write(tmp.join("scratch.py"), "pass").unwrap();
+
+        // Entries are walked by name: classifying a.py removes zz_tmp/ after
+        // it was listed but before it is read, like a test cleaning up
+        let classifier = |_: &Path| -> Result<bool> {
+            if tmp.exists() {
+                std::fs::remove_dir_all(&tmp).unwrap();
+            }
+            Ok(false)
+        };
+        let scope = vec![root.to_string_lossy().to_string()];
+        let discovery = find_python_files(
+            root.to_str().unwrap(),
+            &scope,
+            Some(&classifier),
+            &py(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(discovery.files, vec![root.join("a.py")]);
+        assert_eq!(discovery.skipped, vec!["zz_tmp"]);
+        assert!(discovery.missing_scope_paths.is_empty());
+    }
+
+    #[test]
+    fn test_hash_all_files_matches_fingerprint_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        for (rel, source) in [
+            ("a.py", "def a():\n    return 1\n"),
+            ("pkg/b.py", "# -*- coding: latin-1 -*-\nx = 1\n"),
+            ("tests/test_c.py", "def test_c():\n    pass\n"),
+        ] {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
+        }
+        let root_str = root.to_str().unwrap();
+
+        let hashes = hash_all_files_internal(root_str, &[], &py(), None).unwrap();
+        assert_eq!(hashes.len(), 3);
+        for (rel, hash) in &hashes {
+            let fp = calculate_fingerprint_internal(
+                root.join(rel).to_str().unwrap(),
+                ParseOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(hash, &fp.file_hash, "{}", rel);
+        }
+    }
+
+    #[test]
+    fn test_find_python_files_custom_classifier() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        let integration = root.join("integration");
+        std::fs::create_dir_all(&integration).unwrap();
+        std::fs::write(integration.join("runner.py"), "pass").unwrap();
+        std::fs::write(integration.join("data.py"), "pass").unwrap();
+        std::fs::write(root.join("test_app.py"), "pass").unwrap();
+
+        // Everything under integration/ is a test, except data.py
+        let classifier = |path: &Path| -> Result<bool> {
+            Ok(path.components().any(|c| c.as_os_str() == "integration")
+                && path.file_name().is_some_and(|n| n != "data.py"))
+        };
+        let scope = vec![root.join("elsewhere").to_string_lossy().to_string()];
+        let files = find_python_files(
+            root.to_str().unwrap(),
+            &scope,
+            Some(&classifier),
+            &py(),
+            None,
+        )
+        .unwrap()
+        .files;
+        let mut names: Vec<String> = files
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+
+        // runner.py is an out-of-scope test, data.py is source, and test_app.py
+        // is source too because the classifier replaces the heuristics
+        assert_eq!(names, vec!["data.py", "test_app.py"]);
+    }
+
+    #[test]
+    fn test_excluded_lines_drop_block_dependency() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        let module = root.join("src/lib.py");
+        std::fs::write(
+            &module,
+            "def used():\n    return 1\n\ndef noisy():\n    return 2  # pragma: no cover\n",
+        )
+        .unwrap();
+        let module_str = module.to_string_lossy().into_owned();
+        let blocks = calculate_fingerprint_internal(&module_str, ParseOptions::default())
+            .unwrap()
+            .blocks
+            .unwrap();
+        let checksum_of = |name: &str| blocks.iter().find(|b| b.name == name).unwrap().checksum;
+
+        let record = |excluded_lines| {
+            let fingerprints = process_coverage_data_internal(
+                HashMap::from([(module_str.clone(), vec![1, 2, 4, 5])]),
+                root.to_str().unwrap(),
+                "",
+                None,
+                &CoverageOptions {
+                    excluded_lines,
+                    ..CoverageOptions::default()
+                },
+            )
+            .unwrap();
+            fingerprints[0].checksums.clone()
+        };
+        assert!(record(HashMap::new()).contains(&checksum_of("noisy")));
+
+        // Line 5 was noisy's only executed body line
+        let checksums = record(HashMap::from([(module_str.clone(), vec![5])]));
+        assert!(!checksums.contains(&checksum_of("noisy")));
+        assert!(checksums.contains(&checksum_of("used")));
+    }
+
+    #[test]
+    fn test_file_dependency_granularity_keeps_all_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        let module = root.join("src/lib.py");
+        std::fs::write(
+            &module,
+            "def used():\n    return 1\n\ndef unused():\n    return 2\n",
+        )
+        .unwrap();
+        let module_str = module.to_string_lossy().into_owned();
+        let all = calculate_fingerprint_internal(&module_str, ParseOptions::default())
+            .unwrap()
+            .checksums;
+        assert_eq!(all.len(), 3);
+
+        let record = |dependencies| {
+            let coverage = HashMap::from([(module_str.clone(), vec![1, 2, 4])]);
+            let fingerprints = process_coverage_data_internal(
+                coverage,
+                root.to_str().unwrap(),
+                "",
+                None,
+                &CoverageOptions {
+                    dependencies,
+                    ..CoverageOptions::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(fingerprints.len(), 1);
+            assert_eq!(fingerprints[0].filename, "src/lib.py");
+            fingerprints[0].checksums.clone()
+        };
+
+        // Block granularity drops the unexecuted `unused` body
+        let executed = record(DependencyGranularity::Block);
+        assert_eq!(executed.len(), 2);
+        assert!(executed.iter().all(|c| all.contains(c)));
+        assert_eq!(record(DependencyGranularity::File), all);
+    }
+
+    #[test]
+    fn test_setup_lines_only_count_inside_functions() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let root_str = root.to_str().unwrap();
+        let path = |name: &str| root.join(name).to_string_lossy().into_owned();
+        // Imported at collection, plus a fixture run during setup
+        std::fs::write(
+            root.join("fixtures.py"),
+            "import os\nSEP = os.sep\n\ndef value():\n    return 1\n",
+        )
+        .unwrap();
+        // Imported at collection only
+        std::fs::write(root.join("plugins.py"), "HOOKS = []\n").unwrap();
+        // Called by the test body
+        std::fs::write(root.join("lib.py"), "def f():\n    return 2\n").unwrap();
+
+        let coverage = HashMap::from([
+            (path("fixtures.py"), vec![1, 2, 4, 5]),
+            (path("plugins.py"), vec![1]),
+            (path("lib.py"), vec![1, 2]),
+        ]);
+        let setup = HashMap::from([
+            (path("fixtures.py"), vec![1, 2, 4, 5]),
+            (path("plugins.py"), vec![1]),
+            (path("lib.py"), vec![1]),
+        ]);
+        let dependencies = |setup_lines| -> Vec<(String, Vec<i32>)> {
+            let mut fingerprints: Vec<_> = process_coverage_data_internal(
+                coverage.clone(),
+                root_str,
+                "",
+                None,
+                &CoverageOptions {
+                    setup_lines,
+                    ..CoverageOptions::default()
+                },
+            )
+            .unwrap()
+            .into_iter()
+            .map(|fp| (fp.filename, fp.checksums))
+            .collect();
+            fingerprints.sort();
+            fingerprints
+        };
+        let checksum = |name: &str, block: &str| {
+            calculate_fingerprint_internal(&path(name), ParseOptions::default())
+                .unwrap()
+                .blocks
+                .unwrap()
+                .into_iter()
+                .find(|b| b.name == block)
+                .unwrap()
+                .checksum
+        };
+
+        let without_setup: Vec<String> = dependencies(HashMap::new())
+            .into_iter()
+            .map(|(f, _)| f)
+            .collect();
+        assert_eq!(without_setup, vec!["fixtures.py", "lib.py", "plugins.py"]);
+
+        // Setup-time module lines are dropped; the fixture and test body still
+        // count (along with the `<module>` block enclosing them)
+        let blocks =
+            |name: &str, block: &str| vec![checksum(name, "<module>"), checksum(name, block)];
+        assert_eq!(
+            dependencies(setup),
+            vec![
+                ("fixtures.py".to_string(), blocks("fixtures.py", "value")),
+                ("lib.py".to_string(), blocks("lib.py", "f")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dependencies_without_module_block() {
+        let run = |dependencies, edited: &str| -> Vec<String> {
+            let dir = tempfile::tempdir().unwrap();
+            let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+            std::fs::create_dir_all(&root).unwrap();
+            let root_str = root.to_str().unwrap();
+            let module = root.join("lib.py");
+            std::fs::write(&module, "LIMIT = 10\n\n\ndef f():\n    return LIMIT\n").unwrap();
+            let db_path = dir.path().join("diff.db");
+            let db_path = db_path.to_str().unwrap();
+            save_baseline_internal(
+                db_path,
+                root_str,
+                &SaveOptions {
+                    quiet: true,
+                    ..SaveOptions::default()
+                },
+                Checkpoint::Truncate,
+            )
+            .unwrap();
+
+            let mut db = PytestDiffDatabase::open(db_path).unwrap();
+            // Importing runs lines 1 and 4; calling f also runs line 5
+            for (test, lines) in [("test_import", vec![1, 4]), ("test_f", vec![1, 4, 5])] {
+                let coverage = HashMap::from([(module.to_string_lossy().into_owned(), lines)]);
+                let fingerprints = process_coverage_data_internal(
+                    coverage,
+                    root_str,
+                    "",
+                    None,
+                    &CoverageOptions {
+                        dependencies,
+                        ..CoverageOptions::default()
+                    },
+                )
+                .unwrap();
+                db.save_test_execution_rust(test, fingerprints, 0.1, false, "3.12", false)
+                    .unwrap();
+            }
+            drop(db);
+
+            rewrite(&module, edited);
+            let changes = detect_changes_internal(
+                db_path,
+                root_str,
+                vec![],
+                None,
+                DEFAULT_BASELINE,
+                true,
+                true,
+            )
+            .unwrap()
+            .changes;
+            let db = PytestDiffDatabase::open(db_path).unwrap();
+            db.get_affected_tests_rust(changes.changed_blocks).unwrap()
+        };
+
+        // A top-level edit along with the function edit
+        let both = "LIMIT = 20\n\n\ndef f():\n    return LIMIT + 1\n";
+        assert_eq!(
+            run(DependencyGranularity::Block, both),
+            vec!["test_f", "test_import"]
+        );
+        // test_import only depended on the module block
+        assert_eq!(
+            run(DependencyGranularity::BlockWithoutModule, both),
+            vec!["test_f"]
+        );
+
+        // The trade-off: test_f returns LIMIT, but a top-level-only edit
+        // doesn't reselect it
+        let constant_only = "LIMIT = 20\n\n\ndef f():\n    return LIMIT\n";
+        assert_eq!(
+            run(DependencyGranularity::Block, constant_only),
+            vec!["test_f", "test_import"]
+        );
+        assert!(run(DependencyGranularity::BlockWithoutModule, constant_only).is_empty());
+    }
+
+    #[test]
+    fn test_native_artifact_change_reselects_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("_speedups.so"), b"\x7fELF v1").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let fingerprints = process_coverage_data_internal(
+            HashMap::new(),
+            root_str,
+            "",
+            None,
+            &CoverageOptions {
+                native_artifacts: vec!["_speedups.so".to_string()],
+                ..CoverageOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(fingerprints.len(), 1);
+        assert_eq!(fingerprints[0].filename, "_speedups.so");
+
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        db.save_test_execution_rust("test_native", fingerprints, 0.1, false, "3.12", false)
+            .unwrap();
+        drop(db);
+
+        // Unchanged artifact: nothing to re-run
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert!(!changes.modified.contains(&"_speedups.so".to_string()));
+
+        // Rebuilt artifact: the test is re-selected
+        std::fs::write(root.join("_speedups.so"), b"\x7fELF v2").unwrap();
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert!(changes.modified.contains(&"_speedups.so".to_string()));
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let affected = db.get_affected_tests_rust(changes.changed_blocks).unwrap();
+        assert_eq!(affected, vec!["test_native"]);
+        drop(db);
+
+        // Re-running the test after each rebuild settles it: the versions
+        // recorded by replaced executions don't count any more
+        for build in [b"\x7fELF v2".as_slice(), b"\x7fELF v3"] {
+            std::fs::write(root.join("_speedups.so"), build).unwrap();
+            let fingerprints = process_coverage_data_internal(
+                HashMap::new(),
+                root_str,
+                "",
+                None,
+                &CoverageOptions {
+                    native_artifacts: vec!["_speedups.so".to_string()],
+                    ..CoverageOptions::default()
+                },
+            )
+            .unwrap();
+            let mut db = PytestDiffDatabase::open(db_path).unwrap();
+            db.save_test_execution_rust("test_native", fingerprints, 0.1, false, "3.12", false)
+                .unwrap();
+            drop(db);
+
+            let changes = detect_changes_internal(
+                db_path,
+                root_str,
+                vec![],
+                None,
+                DEFAULT_BASELINE,
+                true,
+                false,
+            )
+            .unwrap()
+            .changes;
+            assert!(changes.modified.is_empty(), "{:?}", changes.modified);
+        }
+    }
+
+    #[test]
+    fn test_tracked_extensions_fingerprint_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("mod.py"), "def f():\n    return 1\n").unwrap();
+        std::fs::write(root.join("ok.py.jinja"), "def g():\n    return 2\n").unwrap();
+        std::fs::write(root.join("raw.py.jinja"), "def {{ name }}():\n    pass\n").unwrap();
+        std::fs::write(root.join("notes.jinja"), "{{ x }}\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        assert_eq!(db.get_tracked_extensions().unwrap(), vec![".py"]);
+        assert!(db.set_tracked_extensions(&["jinja".to_string()]).is_err());
+        db.set_tracked_extensions(&[".py.jinja".to_string()])
+            .unwrap();
+        assert_eq!(
+            db.get_tracked_extensions().unwrap(),
+            vec![".py", ".py.jinja"]
+        );
+        drop(db);
+
+        let extensions = vec![".py".to_string(), ".py.jinja".to_string()];
+        let files = find_python_files(root_str, &[], None, &extensions, None)
+            .unwrap()
+            .files;
+        let names: Vec<_> = files
+            .iter()
+            .map(|f| f.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["mod.py", "ok.py.jinja", "raw.py.jinja"]);
+
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions::default(),
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let ok = db
+            .get_baseline_fingerprint_rust("ok.py.jinja")
+            .unwrap()
+            .unwrap();
+        assert_eq!(ok.checksums.len(), 2);
+        let raw = db
+            .get_baseline_fingerprint_rust("raw.py.jinja")
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw.checksums, vec![calculate_checksum(&raw.file_hash)]);
+        assert!(db.get_native_dependency_fingerprints().unwrap().is_empty());
+        drop(db);
+
+        std::fs::write(root.join("raw.py.jinja"), "def {{ other }}():\n    pass\n").unwrap();
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert_eq!(changes.modified, vec!["raw.py.jinja".to_string()]);
+    }
+
+    #[test]
+    fn test_branch_granularity_selects_only_affected_branch() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        std::fs::write(
+            &module,
+            "def f(x):\n    if x:\n        return 1\n    else:\n        return 2\n",
+        )
+        .unwrap();
+        let root_str = root.to_str().unwrap();
+        let module_str = module.to_str().unwrap().to_string();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        db.set_granularity(Granularity::Branches).unwrap();
+        drop(db);
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        let options = ParseOptions {
+            granularity: Granularity::Branches,
+            ..ParseOptions::default()
+        };
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        for (test, lines) in [("test_a", vec![2, 3]), ("test_b", vec![2, 5])] {
+            let coverage = HashMap::from([(module_str.clone(), lines)]);
+            let fingerprints = process_coverage_data_internal(
+                coverage,
+                root_str,
+                "",
+                None,
+                &CoverageOptions {
+                    parse: options.clone(),
+                    ..CoverageOptions::default()
+                },
+            )
+            .unwrap();
+            db.save_test_execution_rust(test, fingerprints, 0.1, false, "3.12", false)
+                .unwrap();
+        }
+        drop(db);
+
+        // Only the else branch changes
+        rewrite(
+            &module,
+            "def f(x):\n    if x:\n        return 1\n    else:\n        return 3\n",
+        );
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let affected = db.get_affected_tests_rust(changes.changed_blocks).unwrap();
+        assert_eq!(affected, vec!["test_b"]);
+    }
+
+    #[test]
+    fn test_coverage_outside_project_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        let site_packages = dir.path().join("site-packages");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&site_packages).unwrap();
+        let installed = site_packages.join("mod.py");
+        std::fs::write(&installed, "def f():\n    return 1\n").unwrap();
+        let installed = installed.to_str().unwrap().to_string();
+
+        // Coverage reports the installed copy instead of the project source
+        let fingerprints = process_coverage_data_internal(
+            HashMap::from([(installed.clone(), vec![1, 2])]),
+            root.to_str().unwrap(),
+            "",
+            None,
+            &CoverageOptions {
+                verbose: true,
+                ..CoverageOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(fingerprints.is_empty());
+
+        let mut rejections = RejectionCounts::default();
+        for path in [installed.as_str(), "ext.pyx"] {
+            rejections.record(coverage_rejection(
+                Path::new(path),
+                &root,
+                Path::new(""),
+                &TestScope::new(&[]),
+                false,
+                &[PYTHON_EXTENSION.to_string()],
+            ));
+        }
+        assert_eq!(rejections.outside_root, 1);
+        assert_eq!(
+            rejections.summary().unwrap(),
+            "Ignored 2 coverage file(s): 1 not a tracked extension, 1 outside the project root"
+        );
+        assert_eq!(RejectionCounts::default().summary(), None);
+    }
+
+    #[test]
+    fn test_long_function_parts_select_only_their_tests() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        let source = "def f(x):\n    a = x + 1\n    b = a * 2\n    if x:\n        return b\n    c = b - 1\n    return c\n";
+        std::fs::write(&module, source).unwrap();
+        let root_str = root.to_str().unwrap();
+        let module_str = module.to_str().unwrap().to_string();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        db.set_max_function_lines_internal(Some(4)).unwrap();
+        drop(db);
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        let options = ParseOptions {
+            max_function_lines: Some(4),
+            ..ParseOptions::default()
+        };
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        assert_eq!(db.get_parse_options_internal().unwrap(), options);
+        for (test, lines) in [
+            ("test_early_return", vec![1, 2, 3, 4, 5]),
+            ("test_full", vec![1, 2, 3, 4, 6, 7]),
+        ] {
+            let fingerprints = process_coverage_data_internal(
+                HashMap::from([(module_str.clone(), lines)]),
+                root_str,
+                "",
+                None,
+                &CoverageOptions {
+                    parse: options.clone(),
+                    ..CoverageOptions::default()
+                },
+            )
+            .unwrap();
+            db.save_test_execution_rust(test, fingerprints, 0.1, false, "3.12", false)
+                .unwrap();
+        }
+        drop(db);
+
+        // Only the second part changes
+        rewrite(&module, source.replace("c = b - 1", "c = b - 2"));
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let affected = db.get_affected_tests_rust(changes.changed_blocks).unwrap();
+        assert_eq!(affected, vec!["test_full"]);
+
+        // The threshold is fixed once the database holds data
+        assert!(db.set_max_function_lines_internal(None).is_err());
+    }
+
+    #[test]
+    fn test_lambda_blocks_setting_applies_to_saves_and_detection() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        let source = "handler = lambda x: x + 1\nother = lambda x: x * 2\n";
+        std::fs::write(&module, source).unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        assert!(!db.lambda_blocks_enabled().unwrap());
+        assert_eq!(db.get_max_block_depth_internal().unwrap(), None);
+        db.set_lambda_blocks_internal(true).unwrap();
+        db.set_max_block_depth_internal(Some(1)).unwrap();
+        let options = ParseOptions {
+            lambda_blocks: true,
+            max_block_depth: Some(1),
+            ..ParseOptions::default()
+        };
+        assert_eq!(db.get_parse_options_internal().unwrap(), options);
+        drop(db);
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let blocks = parse_module_with_options(source, &options).unwrap();
+        let checksum = |name: &str| blocks.iter().find(|b| b.name == name).unwrap().checksum;
+
+        // The edited lambda's block changes, the other lambda's doesn't
+        rewrite(&module, source.replace("x + 1", "x + 2"));
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let changed = &changes.changed_blocks["mod.py"];
+        assert!(changed.contains(&checksum("handler")));
+        assert!(!changed.contains(&checksum("other")));
+
+        // Both settings are fixed once the database holds data
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        assert!(db.set_lambda_blocks_internal(false).is_err());
+        assert!(db.set_max_block_depth_internal(None).is_err());
+        db.set_lambda_blocks_internal(true).unwrap();
+    }
+
+    #[test]
+    fn test_missing_project_root_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+        let missing = dir.path().join("projetc");
+        let file = dir.path().join("mod.py");
+        std::fs::write(&file, "X = 1\n").unwrap();
+
+        for (root, expected) in [
+            (missing.to_str().unwrap(), "Project root does not exist"),
+            (file.to_str().unwrap(), "Project root is not a directory"),
+        ] {
+            let err = save_baseline_internal(
+                db_path,
+                root,
+                &SaveOptions {
+                    quiet: true,
+                    ..SaveOptions::default()
+                },
+                Checkpoint::Truncate,
+            )
+            .unwrap_err();
+            assert_eq!(err.to_string(), format!("{}: {}", expected, root));
+
+            let err =
+                detect_changes_internal(db_path, root, vec![], None, DEFAULT_BASELINE, true, true)
+                    .unwrap_err();
+            assert_eq!(err.to_string(), format!("{}: {}", expected, root));
+        }
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        assert!(db.get_all_baseline_fingerprints().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fork_baseline_copies_fingerprints_but_no_executions() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "def f():\n    return 1\n").unwrap();
+        std::fs::write(root.join("b.py"), "X = 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let base_path = dir.path().join("base.db");
+        let base_db = base_path.to_str().unwrap();
+        let shard_path = dir.path().join("shards").join("shard1.db");
+        let shard_db = shard_path.to_str().unwrap();
+
+        let db = PytestDiffDatabase::open(base_db).unwrap();
+        db.set_max_function_lines_internal(Some(10)).unwrap();
+        drop(db);
+        save_baseline_internal(
+            base_db,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let mut db = PytestDiffDatabase::open(base_db).unwrap();
+        let fp = calculate_fingerprint_internal(
+            root.join("a.py").to_str().unwrap(),
+            db.get_parse_options_internal().unwrap(),
+        )
+        .unwrap();
+        db.save_test_execution_rust("test_a", vec![fp], 0.1, false, "3.12", false)
+            .unwrap();
+        let baselines = db.get_all_baseline_fingerprints().unwrap();
+        drop(db);
+
+        assert_eq!(fork_baseline_internal(base_db, shard_db).unwrap(), 2);
+        let shard = PytestDiffDatabase::open(shard_db).unwrap();
+        let forked = shard.get_all_baseline_fingerprints().unwrap();
+        assert_eq!(forked.len(), 2);
+        for (filename, fp) in &baselines {
+            assert_eq!(forked[filename].checksums, fp.checksums);
+            assert_eq!(forked[filename].file_hash, fp.file_hash);
+        }
+        let stats = shard.get_stats_internal().unwrap();
+        assert_eq!(stats["test_count"], 0);
+        assert_eq!(stats["fingerprint_count"], 0);
+        assert_eq!(shard.get_max_function_lines_internal().unwrap(), Some(10));
+        drop(shard);
+
+        // A shard database already holding a baseline isn't overwritten
+        assert!(fork_baseline_internal(base_db, shard_db).is_err());
+        assert!(
+            fork_baseline_internal(dir.path().join("missing.db").to_str().unwrap(), base_db)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_ignored_version_bump_reruns_no_tests() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        let source = "__version__ = \"1.0\"\n\nDEBUG = False\n\ndef f():\n    return 1\n";
+        std::fs::write(&module, source).unwrap();
+        let root_str = root.to_str().unwrap();
+        let module_str = module.to_str().unwrap().to_string();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        db.set_ignored_blocks_internal(&["__version__".to_string()])
+            .unwrap();
+        drop(db);
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        let fingerprints = process_coverage_data_internal(
+            HashMap::from([(module_str, vec![1, 3, 5, 6])]),
+            root_str,
+            "",
+            None,
+            &CoverageOptions {
+                parse: db.get_parse_options_internal().unwrap(),
+                ..CoverageOptions::default()
+            },
+        )
+        .unwrap();
+        db.save_test_execution_rust("test_f", fingerprints, 0.1, false, "3.12", false)
+            .unwrap();
+        drop(db);
+
+        let affected_after = |source: &str| {
+            rewrite(&module, source);
+            let changes = detect_changes_internal(
+                db_path,
+                root_str,
+                vec![],
+                None,
+                DEFAULT_BASELINE,
+                true,
+                true,
+            )
+            .unwrap()
+            .changes;
+            PytestDiffDatabase::open(db_path)
+                .unwrap()
+                .get_affected_tests_rust(changes.changed_blocks)
+                .unwrap()
+        };
+        assert!(affected_after(&source.replace("1.0", "1.1")).is_empty());
+        // Other top-level code still counts
+        assert_eq!(
+            affected_after(&source.replace("False", "True")),
+            vec!["test_f"]
+        );
+
+        // The patterns are fixed once the database holds data
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        assert!(db.set_ignored_blocks_internal(&[]).is_err());
+        assert_eq!(
+            db.get_ignored_blocks_internal().unwrap(),
+            vec!["__version__"]
+        );
+    }
+
+    #[test]
+    fn test_explain_coverage_blocks_maps_lines_to_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let module = root.join("mod.py");
+        std::fs::write(
+            &module,
+            "import os\n\ndef f():\n    return 1\n\ndef g():\n    return 2\n",
+        )
+        .unwrap();
+        let coverage = HashMap::from([(module.to_str().unwrap().to_string(), vec![6, 1, 3, 4, 4])]);
+
+        let root_str = root.to_str().unwrap();
+        let explain = |options: &CoverageOptions| {
+            explain_coverage_blocks_internal(coverage.clone(), root_str, options)
+                .remove("mod.py")
+                .unwrap()
+        };
+        let entry = |name: &str, start: usize, end: usize, lines: Vec<usize>, dep: bool| {
+            (name.to_string(), start, end, lines, dep)
+        };
+        assert_eq!(
+            explain(&CoverageOptions::default()),
+            vec![
+                entry("<module>", 1, 7, vec![1, 3, 4, 6], true),
+                entry("f", 3, 4, vec![4], true),
+                // Only the `def` line ran: not a dependency
+                entry("g", 6, 7, vec![], false),
+            ]
+        );
+
+        // Same filtering as process_coverage_data
+        let module_key = module.to_str().unwrap().to_string();
+        assert_eq!(
+            explain(&CoverageOptions {
+                dependencies: DependencyGranularity::BlockWithoutModule,
+                excluded_lines: HashMap::from([(module_key.clone(), vec![4])]),
+                ..CoverageOptions::default()
+            }),
+            vec![
+                entry("<module>", 1, 7, vec![1, 3, 6], false),
+                entry("f", 3, 4, vec![], false),
+                entry("g", 6, 7, vec![], false),
+            ]
+        );
+        let file_options = CoverageOptions {
+            dependencies: DependencyGranularity::File,
+            ..CoverageOptions::default()
+        };
+        assert!(explain(&file_options).iter().all(|block| block.4));
+        let recorded =
+            process_coverage_data_internal(coverage.clone(), root_str, "", None, &file_options)
+                .unwrap();
+        assert_eq!(recorded[0].checksums.len(), 3);
+    }
+
+    #[test]
+    fn test_resolve_path_case_finds_the_real_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/mod.py"), "x = 1\n").unwrap();
+
+        let real = root.join("src/mod.py");
+        assert_eq!(resolve_path_case(&real), Some(real.clone()));
+        assert_eq!(
+            resolve_path_case(&root.join("Src/Mod.py")),
+            Some(real.clone())
+        );
+        assert_eq!(resolve_path_case(&root.join("src/missing.py")), None);
+
+        // Both spellings of one file end up as a single coverage entry
+        let coverage = HashMap::from([
+            (
+                root.join("Src/Mod.py").to_str().unwrap().to_string(),
+                vec![1],
+            ),
+            (real.to_str().unwrap().to_string(), vec![1]),
+        ]);
+        let fingerprints = process_coverage_data_internal(
+            coverage,
+            root.to_str().unwrap(),
+            "",
+            None,
+            &CoverageOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(fingerprints.len(), 1);
+        assert_eq!(fingerprints[0].filename, "src/mod.py");
+
+        // On a case-sensitive filesystem, differently-cased files stay distinct
+        // and an ambiguous spelling resolves to neither
+        std::fs::write(root.join("src/Mod.py"), "x = 2\n").unwrap();
+        if std::fs::read_to_string(&real).unwrap() == "x = 1\n" {
+            let other = root.join("src/Mod.py");
+            assert_eq!(resolve_path_case(&other), Some(other.clone()));
+            assert_eq!(resolve_path_case(&real), Some(real.clone()));
+            assert_eq!(resolve_path_case(&root.join("src/MOD.py")), None);
+        }
+    }
+
+    #[test]
+    fn test_coverage_case_is_only_resolved_for_project_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = std::fs::canonicalize(dir.path()).unwrap();
+        let root = base.join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(base.join("site")).unwrap();
+        std::fs::write(root.join("mod.py"), "x = 1\n").unwrap();
+        std::fs::write(base.join("site/lib.py"), "y = 1\n").unwrap();
+        std::fs::write(root.join("data.txt"), "z\n").unwrap();
+        let key = |path: PathBuf| path.to_string_lossy().into_owned();
+
+        let resolved = resolve_coverage_path_case(
+            HashMap::from([
+                (key(root.join("MOD.py")), vec![1]),
+                (key(base.join("site/LIB.py")), vec![1]),
+                (key(root.join("DATA.txt")), vec![1]),
+            ]),
+            &root,
+            &py(),
+        );
+        let mut keys: Vec<_> = resolved.into_keys().collect();
+        keys.sort();
+        // Outside the root or untracked: left as spelled, never looked up
+        let mut expected = vec![
+            key(root.join("mod.py")),
+            key(base.join("site/LIB.py")),
+            key(root.join("DATA.txt")),
+        ];
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_changed_block_name_recoverable_from_baseline_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        let source = "def f():\n    return 1\n\n\ndef g():\n    return 2\n";
+        std::fs::write(&module, source).unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        db.set_block_name_hashes_internal(true).unwrap();
+        drop(db);
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        rewrite(&module, source.replace("return 2", "return 3"));
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let changed = &changes.changed_blocks["mod.py"];
+        assert_eq!(changed.len(), 1);
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let name_hashes: HashMap<i32, u32> = db
+            .get_baseline_name_hashes_internal("mod.py")
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            name_hashes[&changed[0]],
+            crate::parser::block_name_hash("g")
+        );
+        // Readers that only need checksums are unaffected
+        assert_eq!(
+            db.get_baseline_fingerprint_rust("mod.py")
+                .unwrap()
+                .unwrap()
+                .checksums
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_snapshot_paths_are_portable() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(root.join("pkg")).unwrap();
+        std::fs::write(root.join("pkg").join("a.py"), "def f():\n    return 1\n").unwrap();
+        std::fs::write(root.join("pkg").join("b.py"), "x = 1\n").unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root.to_str().unwrap(),
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        // Rows as a Windows machine would have written them: one relative with
+        // backslashes, one absolute under the Windows project root
+        let conn = rusqlite::Connection::open(db_path).unwrap();
+        conn.execute(
+            "UPDATE baseline_fp SET filename = 'pkg\\a.py' WHERE filename = 'pkg/a.py'",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "UPDATE baseline_fp SET filename = 'C:\\ci\\project\\pkg\\b.py'
+             WHERE filename = 'pkg/b.py'",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let snapshot = db
+            .baseline_snapshot(DEFAULT_BASELINE, Some("C:\\ci\\project\\"))
+            .unwrap();
+        drop(db);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert!(!json.contains('\\'), "{json}");
+        let filenames: Vec<&str> = snapshot
+            .fingerprints
+            .iter()
+            .map(|fp| fp.filename.as_str())
+            .collect();
+        assert_eq!(filenames, vec!["pkg/a.py", "pkg/b.py"]);
+
+        // Another runner checks the same sources out under a different root
+        let elsewhere = std::fs::canonicalize(dir.path()).unwrap().join("checkout");
+        std::fs::create_dir_all(elsewhere.join("pkg")).unwrap();
+        for name in ["a.py", "b.py"] {
+            std::fs::copy(
+                root.join("pkg").join(name),
+                elsewhere.join("pkg").join(name),
+            )
+            .unwrap();
+        }
+        let snapshot = BaselineSnapshot::from_json(&json).unwrap();
+        let changes = detect_changes_from_snapshot_internal(
+            &snapshot,
+            elsewhere.to_str().unwrap(),
+            vec![],
+            None,
+            false,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert!(changes.modified.is_empty(), "{:?}", changes.modified);
+        assert_eq!(changes.decisions["pkg/a.py"], "hash_unchanged");
+        assert_eq!(changes.decisions["pkg/b.py"], "hash_unchanged");
+    }
+
+    #[test]
+    fn test_detect_changes_from_snapshot_matches_database() {
+        fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+            items.sort();
+            items
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let source = "def f():\n    return 1\n\n\ndef g():\n    return 2\n";
+        std::fs::write(root.join("a.py"), source).unwrap();
+        std::fs::write(root.join("b.py"), "x = 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let json =
+            serde_json::to_string(&db.baseline_snapshot(DEFAULT_BASELINE, None).unwrap()).unwrap();
+        drop(db);
+        // The runner only has the snapshot
+        let snapshot = BaselineSnapshot::from_json(&json).unwrap();
+
+        rewrite(root.join("a.py"), source.replace("return 2", "return 3"));
+        rewrite(root.join("c.py"), "y = 2\n");
+
+        let from_db = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap();
+        let from_snapshot =
+            detect_changes_from_snapshot_internal(&snapshot, root_str, vec![], None, true, true)
+                .unwrap();
+        let (db_changes, snapshot_changes) = (from_db.changes, from_snapshot.changes);
+        assert_eq!(
+            sorted(snapshot_changes.modified.clone()),
+            vec!["a.py".to_string(), "c.py".to_string()]
+        );
+        assert_eq!(
+            sorted(snapshot_changes.modified),
+            sorted(db_changes.modified)
+        );
+        assert_eq!(snapshot_changes.changed_blocks, db_changes.changed_blocks);
+        assert_eq!(
+            sorted(snapshot_changes.block_changes),
+            sorted(db_changes.block_changes)
+        );
+        assert_eq!(snapshot_changes.decisions, db_changes.decisions);
+        assert_eq!(from_snapshot.baseline_count, from_db.baseline_count);
+
+        assert!(
+            BaselineSnapshot::from_json(&json.replace("\"format\":1", "\"format\":9")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_torn_read_is_flagged_unstable() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let path = root.join("fixture.py");
+        std::fs::write(&path, "def f():\n    return 1\n").unwrap();
+        let fingerprint = || {
+            calculate_baseline_fingerprint_internal(path.to_str().unwrap(), ParseOptions::default())
+        };
+
+        let stable = verify_stable_read(&path, fingerprint, read_python_source).unwrap();
+        assert_eq!(stable.file_hash, fingerprint().unwrap().file_hash);
+
+        // A writer finishes the file between the two reads
+        let err = verify_stable_read(
+            &path,
+            || {
+                let fp = fingerprint();
+                std::fs::write(
+                    &path,
+                    "def f():\n    return 1\n\n\ndef g():\n    return 2\n",
+                )
+                .unwrap();
+                fp
+            },
+            read_python_source,
+        )
+        .unwrap_err();
+        assert!(err.is::<UnstableRead>(), "{err}");
+    }
+
+    #[test]
+    fn test_save_baseline_with_verified_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "def f():\n    return 1\n").unwrap();
+        let db_path = dir.path().join("diff.db");
+        let mut db = PytestDiffDatabase::open(db_path.to_str().unwrap()).unwrap();
+        assert!(!db.verify_reads_enabled().unwrap());
+        db.set_verify_reads_internal(true).unwrap();
+        assert!(db.verify_reads_enabled().unwrap());
+
+        let count = save_baseline_with_db(
+            &mut db,
+            root.to_str().unwrap(),
+            &SaveOptions {
+                quiet: true,
+                strict: true,
+                ..SaveOptions::default()
+            },
+        )
+        .unwrap()
+        .count;
+        assert_eq!(count, 1);
+        assert!(db
+            .get_named_baseline_fingerprints(DEFAULT_BASELINE)
+            .unwrap()
+            .contains_key("a.py"));
+    }
+
+    #[test]
+    fn test_save_baseline_skips_files_changed_between_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "def f():\n    return 1\n").unwrap();
+        std::fs::write(root.join("b.py"), "def g():\n    return 1\n").unwrap();
+        let db_path = dir.path().join("diff.db");
+        let mut db = PytestDiffDatabase::open(db_path.to_str().unwrap()).unwrap();
+        db.set_verify_reads_internal(true).unwrap();
+        let options = SaveOptions {
+            quiet: true,
+            ..SaveOptions::default()
+        };
+        save_baseline_with_db(&mut db, root.to_str().unwrap(), &options).unwrap();
+        let baseline_hash = |db: &PytestDiffDatabase| {
+            db.get_baseline_fingerprint_rust("b.py")
+                .unwrap()
+                .unwrap()
+                .file_hash
+        };
+        let saved_hash = baseline_hash(&db);
+
+        // b.py is still being written when it's read the second time
+        rewrite(root.join("b.py"), "def g():\n    return 2\n");
+        let torn_reader = |path: &Path| {
+            let content = read_python_source(path)?;
+            Ok(if path.ends_with("b.py") {
+                content + "\ndef h():\n"
+            } else {
+                content
+            })
+        };
+        let result =
+            save_baseline_with_reader(&mut db, root.to_str().unwrap(), &options, torn_reader)
+                .unwrap();
+        assert_eq!(result.unstable, vec!["b.py".to_string()]);
+        assert_eq!(result.count, 1);
+        assert_eq!(baseline_hash(&db), saved_hash);
+
+        let err = save_baseline_with_reader(
+            &mut db,
+            root.to_str().unwrap(),
+            &SaveOptions {
+                strict: true,
+                ..options.clone()
+            },
+            torn_reader,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("1 files changed while being fingerprinted"),
+            "{err}"
+        );
+
+        // Once the writer is done, the next save picks the file up
+        let result = save_baseline_with_db(&mut db, root.to_str().unwrap(), &options).unwrap();
+        assert!(result.unstable.is_empty());
+        assert_eq!(result.count, 2);
+        assert_ne!(baseline_hash(&db), saved_hash);
+    }
+
+    #[test]
+    fn test_progress_interval_controls_how_often_progress_is_reported() {
+        #[derive(Clone, Default)]
+        struct Captured(std::sync::Arc<parking_lot::Mutex<Vec<u8>>>);
+        impl Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let defaults = SaveOptions::default();
+        assert_eq!(defaults.progress_stream, ProgressStream::Auto);
+        assert_eq!(defaults.progress_interval, DEFAULT_PROGRESS_INTERVAL);
+        assert_eq!(
+            "stdout".parse::<ProgressStream>().unwrap(),
+            ProgressStream::Stdout
+        );
+        assert!("stdrr".parse::<ProgressStream>().is_err());
+
+        let captured = Captured::default();
+        let reporter = ProgressReporter::new(Some(Box::new(captured.clone())), 3, 10);
+        for _ in 0..10 {
+            reporter.tick();
+        }
+        let output = String::from_utf8(captured.0.lock().clone()).unwrap();
+        let counts: Vec<&str> = output
+            .lines()
+            .map(|line| line.split_whitespace().nth(3).unwrap())
+            .collect();
+        // Every third file, plus the last one
+        assert_eq!(counts, vec!["3/10", "6/10", "9/10", "10/10"]);
+
+        // Only "auto" follows verbose, and quiet silences every stream
+        let has_sink = |stream, verbose, quiet| {
+            ProgressReporter::for_save(stream, 3, 10, verbose, quiet)
+                .sink
+                .is_some()
+        };
+        assert!(!has_sink(ProgressStream::Auto, false, false));
+        assert!(has_sink(ProgressStream::Auto, true, false));
+        assert!(has_sink(ProgressStream::Stdout, false, false));
+        assert!(!has_sink(ProgressStream::None, true, false));
+        assert!(!has_sink(ProgressStream::Stderr, true, true));
+    }
+
+    #[test]
+    fn test_phase_timings_add_up_to_fingerprint_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let mut source = String::new();
+        for i in 0..2000 {
+            source.push_str(&format!("def f{i}(x):\n    return x + {i}\n\n"));
+        }
+        let path = root.join("big.py");
+        std::fs::write(&path, &source).unwrap();
+
+        let timings = PhaseTimings::default();
+        let start = std::time::Instant::now();
+        let fp = baseline_fingerprint(&path, &ParseOptions::default(), false, &timings).unwrap();
+        let total = start.elapsed().as_secs_f64();
+
+        let expected = calculate_baseline_fingerprint_internal(
+            path.to_str().unwrap(),
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(fp.checksums, expected.checksums);
+        assert_eq!(fp.file_hash, expected.file_hash);
+
+        let (read, hash, parse) = timings.totals();
+        assert!(read > 0.0 && hash > 0.0 && parse > 0.0);
+        let sum = read + hash + parse;
+        assert!(sum <= total, "phases {sum}s exceed total {total}s");
+    }
+
+    #[test]
+    fn test_set_granularity_requires_empty_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("mod.py"), "x = 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        assert!(db.set_granularity(Granularity::Branches).is_err());
+        // Re-setting the current granularity is a no-op
+        db.set_granularity(Granularity::Definitions).unwrap();
+        assert_eq!(db.get_granularity().unwrap(), Granularity::Definitions);
+    }
+
+    #[test]
+    fn test_renormalize_baseline_matches_fresh_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let source = "\"\"\"Doc.\"\"\"\n\ndef f():\n    \"\"\"Doc.\"\"\"\n    return 1\n";
+        std::fs::write(root.join("mod.py"), source).unwrap();
+        std::fs::write(root.join("edited.py"), "x = 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+
+        // Baseline + one recorded test in exact mode
+        let db_path = dir.path().join("renormalized.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions::default(),
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        let exact = db.get_baseline_fingerprint_rust("mod.py").unwrap().unwrap();
+        db.save_test_execution_rust("test_f", vec![exact.clone()], 0.1, false, "3.12", false)
+            .unwrap();
+
+        // Edited since the baseline: recomputing it would absorb the edit
+        rewrite(root.join("edited.py"), "x = 2\n");
+
+        let result = db
+            .renormalize_baseline_internal(root_str, Normalization::IgnoreDocstrings)
+            .unwrap();
+        assert_eq!(result.count, 1);
+        assert_eq!(result.skipped, vec!["edited.py"]);
+
+        // A named baseline would keep checksums of the old mode: refused
+        let pr = db.get_baseline_fingerprint_rust("mod.py").unwrap().unwrap();
+        db.save_named_baseline_fingerprints_batch("pr", vec![pr])
+            .unwrap();
+        let err = db
+            .renormalize_baseline_internal(root_str, Normalization::Exact)
+            .unwrap_err();
+        assert!(err.to_string().contains("(pr)"), "{}", err);
+        assert_eq!(
+            db.get_normalization().unwrap(),
+            Normalization::IgnoreDocstrings
+        );
+
+        assert_eq!(
+            db.get_normalization().unwrap(),
+            Normalization::IgnoreDocstrings
+        );
+        let renormalized = db.get_baseline_fingerprint_rust("mod.py").unwrap().unwrap();
+        drop(db);
+
+        // A fresh baseline saved directly in the new mode agrees
+        let fresh_path = dir.path().join("fresh.db");
+        let fresh_path = fresh_path.to_str().unwrap();
+        let fresh_db = PytestDiffDatabase::open(fresh_path).unwrap();
+        fresh_db
+            .set_normalization(Normalization::IgnoreDocstrings)
+            .unwrap();
+        drop(fresh_db);
+        save_baseline_internal(
+            fresh_path,
+            root_str,
+            &SaveOptions::default(),
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let fresh_db = PytestDiffDatabase::open(fresh_path).unwrap();
+        let fresh = fresh_db
+            .get_baseline_fingerprint_rust("mod.py")
+            .unwrap()
+            .unwrap();
+
+        assert_ne!(renormalized.checksums, exact.checksums);
+        assert_eq!(renormalized.checksums, fresh.checksums);
+
+        // Recorded test dependencies were remapped too: editing the function
+        // body still re-selects the test
+        std::fs::write(root.join("mod.py"), source.replace("return 1", "return 2")).unwrap();
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let affected = db.get_affected_tests_rust(changes.changed_blocks).unwrap();
+        assert_eq!(affected, vec!["test_f"]);
+    }
+
+    #[test]
+    fn test_renormalize_remaps_duplicate_blocks_and_older_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        let root_str = root.to_str().unwrap();
+        let v1 = "class A:\n    def f(self):\n        \"\"\"Doc.\"\"\"\n        return 1\n\n\n\
+                  class B:\n    def f(self):\n        \"\"\"Doc.\"\"\"\n        return 1\n\n\n\
+                  def g():\n    return 1\n";
+        let v2 = v1.replace("def g():\n    return 1", "def g():\n    return 2");
+
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        let record = |db: &mut PytestDiffDatabase, test: &str| {
+            let fp =
+                calculate_fingerprint_internal(module.to_str().unwrap(), ParseOptions::default())
+                    .unwrap();
+            let fp = Fingerprint {
+                filename: "mod.py".to_string(),
+                blocks: None,
+                ..fp
+            };
+            db.save_test_execution_rust(test, vec![fp], 0.1, false, "3.12", false)
+                .unwrap();
+        };
+
+        // test_old ran against an older version of the file than the baseline
+        std::fs::write(&module, v1).unwrap();
+        record(&mut db, "test_old");
+        rewrite(&module, &v2);
+        record(&mut db, "test_new");
+        drop(db);
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        // Both methods share one checksum, in either mode
+        let blocks = parse_module_with_options(&v2, &ParseOptions::default()).unwrap();
+        let methods: Vec<i32> = blocks
+            .iter()
+            .filter(|b| b.name == "f")
+            .map(|b| b.checksum)
+            .collect();
+        assert_eq!(methods[0], methods[1]);
+
+        let mut db = PytestDiffDatabase::open(db_path).unwrap();
+        let result = db
+            .renormalize_baseline_internal(root_str, Normalization::IgnoreDocstrings)
+            .unwrap();
+        assert_eq!(result.count, 1);
+        drop(db);
+
+        // Editing B.f reselects both, including the test recorded on v1
+        let v3 = v2.replace(
+            "\"\"\"Doc.\"\"\"\n        return 1\n\n\ndef g",
+            "\"\"\"Doc.\"\"\"\n        return 3\n\n\ndef g",
+        );
+        assert_ne!(v3, v2);
+        rewrite(&module, v3);
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            false,
+        )
+        .unwrap()
+        .changes;
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let affected = db.get_affected_tests_rust(changes.changed_blocks).unwrap();
+        assert_eq!(affected, vec!["test_new", "test_old"]);
+    }
+
+    #[test]
+    fn test_detect_changes_reports_missing_scope_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(root.join("tests")).unwrap();
+        std::fs::write(root.join("tests/test_a.py"), "def test_a():\n    pass\n").unwrap();
+        std::fs::write(root.join("lib.py"), "x = 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+
+        let bogus = root.join("tset").to_string_lossy().to_string();
+        let tests = root.join("tests").to_string_lossy().to_string();
+
+        // Only a bogus scope: reported, and no test file is selected
+        let changes = detect_changes_internal(
+            db_path.to_str().unwrap(),
+            root_str,
+            vec![bogus.clone()],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert_eq!(changes.missing_scope_paths, vec![bogus.clone()]);
+        assert_eq!(changes.modified, vec!["lib.py"]);
+
+        // Mixed: the valid scope still applies
+        let mut changes = detect_changes_internal(
+            db_path.to_str().unwrap(),
+            root_str,
+            vec![bogus.clone(), tests],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        changes.modified.sort();
+        assert_eq!(changes.missing_scope_paths, vec![bogus]);
+        assert_eq!(changes.modified, vec!["lib.py", "tests/test_a.py"]);
+    }
+
+    #[test]
+    fn test_detect_changes_flags_empty_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("lib.py"), "x = 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert!(changes.baseline_empty);
+        assert_eq!(changes.modified, vec!["lib.py"]);
+
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert!(!changes.baseline_empty);
+        assert!(changes.modified.is_empty());
+    }
+
+    #[test]
+    fn test_detect_changes_lists_named_block_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("a.py"),
+            "def keep():\n    return 1\n\ndef edit():\n    return 2\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("b.py"),
+            "class C:\n    def m(self):\n        return 3\n",
+        )
+        .unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        rewrite(
+            root.join("a.py"),
+            "def keep():\n    return 1\n\ndef edit():\n    return 20\n",
+        );
+        rewrite(
+            root.join("b.py"),
+            "class C:\n    def m(self):\n        return 30\n",
+        );
+        std::fs::write(root.join("c.py"), "def new():\n    pass\n").unwrap();
+
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let entry = |file: &str, name: &str, kind: &str| {
+            (file.to_string(), name.to_string(), kind.to_string())
+        };
+        assert_eq!(
+            changes.block_changes,
+            vec![
+                entry("a.py", "edit", "modified"),
+                entry("b.py", "C", "modified"),
+                entry("b.py", "m", "modified"),
+                entry("c.py", "<module>", "added"),
+                entry("c.py", "new", "added"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detect_changes_counts_changed_block_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("a.py"),
+            "X = 1\n\ndef f():\n    return 1\n\ndef g():\n    return 2\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("b.py"), "def h():\n    return 3\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        // Module-level edit plus both functions in a.py, one function in b.py,
+        // and a new file with a class
+        rewrite(
+            root.join("a.py"),
+            "X = 2\n\ndef f():\n    return 10\n\ndef g():\n    return 20\n",
+        );
+        rewrite(root.join("b.py"), "def h():\n    return 30\n");
+        std::fs::write(root.join("c.py"), "class C:\n    pass\n").unwrap();
+
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let expected: HashMap<String, usize> = [("module", 2), ("function", 3), ("class", 1)]
+            .into_iter()
+            .map(|(block_type, count)| (block_type.to_string(), count))
+            .collect();
+        assert_eq!(changes.changed_block_types, expected);
+        assert_eq!(
+            changes.changed_block_types.values().sum::<usize>(),
+            changes.block_changes.len()
+        );
+    }
+
+    #[test]
+    fn test_detect_changes_against_named_baselines() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        let save = |name: &str| {
+            save_baseline_internal(
+                db_path,
+                root_str,
+                &SaveOptions {
+                    quiet: true,
+                    baseline_name: name,
+                    ..SaveOptions::default()
+                },
+                Checkpoint::Truncate,
+            )
+            .unwrap()
+        };
+        let detect = |name: &str| {
+            detect_changes_internal(db_path, root_str, vec![], None, name, true, true)
+                .unwrap()
+                .changes
+                .modified
+        };
+
+        std::fs::write(root.join("a.py"), "def f():\n    return 1\n").unwrap();
+        assert_eq!(save("main").count, 1);
+        rewrite(root.join("a.py"), "def f():\n    return 2\n");
+        assert_eq!(save("release").count, 1);
+
+        assert_eq!(detect("main"), vec!["a.py"]);
+        assert!(detect("release").is_empty());
+        // The default baseline was never saved, so every file is new to it
+        assert_eq!(detect(DEFAULT_BASELINE), vec!["a.py"]);
+
+        // Updating one baseline leaves the other untouched
+        assert_eq!(save("main").count, 1);
+        assert!(detect("main").is_empty());
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        assert_eq!(
+            db.get_named_baseline_fingerprints("release").unwrap().len(),
+            1
+        );
+        assert!(db.get_all_baseline_fingerprints().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_detect_changes_reports_encoding_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("good.py"), "x = 1\n").unwrap();
+        std::fs::write(
+            root.join("mislabeled.py"),
+            b"# coding: utf-8\ns = 'caf\xe9'\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("undeclared.py"), b"s = 'caf\xe9'\n").unwrap();
+        let db_path = dir.path().join("test.db");
+
+        let changes = detect_changes_internal(
+            db_path.to_str().unwrap(),
+            root.to_str().unwrap(),
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+
+        assert_eq!(changes.modified, vec!["good.py"]);
+        assert_eq!(changes.encoding_mismatches, vec!["mislabeled.py"]);
+    }
+
+    #[test]
+    fn test_detect_changes_reports_decision_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+
+        for name in ["untouched", "touched", "reformatted", "edited"] {
+            std::fs::write(
+                root.join(format!("{}.py", name)),
+                "def f():\n    return 1\n",
+            )
+            .unwrap();
+        }
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        rewrite(root.join("touched.py"), "def f():\n    return 1\n");
+        rewrite(
+            root.join("reformatted.py"),
+            "def f():\n    return 1\n\n# trailing note\n",
+        );
+        rewrite(root.join("edited.py"), "def f():\n    return 2\n");
+        std::fs::write(root.join("added.py"), "x = 1\n").unwrap();
+
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        let decision = |file: &str| changes.decisions[file].as_str();
+        assert_eq!(decision("untouched.py"), "mtime_unchanged");
+        assert_eq!(decision("touched.py"), "hash_unchanged");
+        assert_eq!(decision("reformatted.py"), "checksums_unchanged");
+        assert_eq!(decision("edited.py"), "checksums_changed");
+        assert_eq!(decision("added.py"), "new");
+
+        let mut modified = changes.modified.clone();
+        modified.sort();
+        assert_eq!(modified, vec!["added.py", "edited.py"]);
+    }
+
+    #[test]
+    fn test_changed_files_only_stops_at_file_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+
+        for name in ["untouched", "touched", "reformatted", "edited", "broken"] {
+            std::fs::write(
+                root.join(format!("{}.py", name)),
+                "def f():\n    return 1\n",
+            )
+            .unwrap();
+        }
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        rewrite(root.join("touched.py"), "def f():\n    return 1\n");
+        rewrite(
+            root.join("reformatted.py"),
+            "def f():\n    return 1\n\n# trailing note\n",
+        );
+        rewrite(root.join("edited.py"), "def f():\n    return 2\n");
+        // Not parseable: only the hash level can report it
+        std::fs::write(root.join("broken.py"), "def f(:\n").unwrap();
+        std::fs::write(root.join("added.py"), "x = 1\n").unwrap();
+
+        for trust_mtime in [true, false] {
+            let changed =
+                changed_files_only_internal(db_path, root_str, &[], DEFAULT_BASELINE, trust_mtime)
+                    .unwrap();
+            assert_eq!(
+                changed,
+                vec!["added.py", "broken.py", "edited.py", "reformatted.py"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_detect_changes_edit_of_one_duplicate_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        let original = "class A:\n    def f(self):\n        return 1\n\n\
+                        class B:\n    def f(self):\n        return 1\n";
+        std::fs::write(&module, original).unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        // Both methods share a checksum, and the baseline keeps both copies
+        let blocks = parse_module_with_options(original, &ParseOptions::default()).unwrap();
+        let methods: Vec<i32> = blocks
+            .iter()
+            .filter(|b| b.name == "f")
+            .map(|b| b.checksum)
+            .collect();
+        assert_eq!(methods.len(), 2);
+        assert_eq!(methods[0], methods[1]);
+        let method = methods[0];
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        let stored = db.get_baseline_fingerprint_rust("mod.py").unwrap().unwrap();
+        assert_eq!(stored.checksums.iter().filter(|&&c| c == method).count(), 2);
+        drop(db);
+
+        // Edit B.f only: A.f still carries the shared checksum
+        let edited = "class A:\n    def f(self):\n        return 1\n\n\
+                      class B:\n    def f(self):\n        return 2\n";
+        std::fs::write(&module, edited).unwrap();
+        let changed = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            false,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert!(changed.changed_blocks["mod.py"].contains(&method));
+        let named_f = changed
+            .block_changes
+            .iter()
+            .filter(|(_, name, _)| name == "f")
+            .count();
+        assert_eq!(named_f, 1);
+    }
+
+    #[test]
+    fn test_find_changed_checksums_counts_duplicates() {
+        assert_eq!(find_changed_checksums(&[1, 7, 7], &[1, 7, 8]), vec![7]);
+        assert_eq!(
+            find_changed_checksums(&[1, 7, 7], &[7, 1, 7]),
+            Vec::<i32>::new()
+        );
+        assert_eq!(find_changed_checksums(&[7], &[7, 7]), Vec::<i32>::new());
+        assert_eq!(find_changed_checksums(&[7, 7], &[]), vec![7, 7]);
+    }
+
+    #[test]
+    fn test_detect_changes_reports_scan_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        for rel in ["a.py", "b.py", "tests/test_a.py"] {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "x = 1\n").unwrap();
+        }
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        std::fs::write(root.join("c.py"), "y = 2\n").unwrap();
+
+        let result = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap();
+        assert_eq!(result.files_scanned, 4);
+        assert_eq!(result.baseline_count, 3);
+        assert!(result.elapsed > 0.0);
+        assert_eq!(result.changes.modified, vec!["c.py"]);
+    }
+
+    #[test]
+    fn test_untouched_project_short_circuits_on_baseline_aggregate() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        for rel in ["a.py", "b.py", "tests/test_a.py"] {
+            let path = root.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "def f():\n    return 1\n").unwrap();
+        }
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+        let detect = |trust_mtime| {
+            detect_changes_internal(
+                db_path,
+                root_str,
+                vec![],
+                None,
+                DEFAULT_BASELINE,
+                trust_mtime,
+                true,
+            )
+            .unwrap()
+        };
+        let decisions = |result: &DetectionResult| -> HashSet<String> {
+            result.changes.decisions.values().cloned().collect()
+        };
+
+        // Level 0 decides every file: no file is compared, read or hashed
+        let result = detect(true);
+        assert!(result.changes.modified.is_empty());
+        assert_eq!(result.files_scanned, 3);
+        assert_eq!(result.changes.decisions.len(), 3);
+        assert_eq!(
+            decisions(&result),
+            HashSet::from(["aggregate_unchanged".to_string()])
+        );
+        // Without trusting mtimes, every file is verified by hash instead
+        assert_eq!(
+            decisions(&detect(false)),
+            HashSet::from(["hash_unchanged".to_string()])
+        );
+        // Decisions are only collected when asked for, on either path
+        for trust_mtime in [true, false] {
+            let result = detect_changes_internal(
+                db_path,
+                root_str,
+                vec![],
+                None,
+                DEFAULT_BASELINE,
+                trust_mtime,
+                false,
+            )
+            .unwrap();
+            assert_eq!(result.files_scanned, 3);
+            assert!(result.changes.decisions.is_empty());
+        }
+
+        // A touched file falls back to per-file detection
+        rewrite(root.join("b.py"), "def f():\n    return 2\n");
+        let result = detect(true);
+        assert_eq!(result.changes.modified, vec!["b.py"]);
+        assert_eq!(result.changes.decisions["a.py"], "mtime_unchanged");
+
+        // Writing the baseline outside save_baseline invalidates the aggregate
+        update_baseline_file_internal(db_path, root_str, "b.py", DEFAULT_BASELINE).unwrap();
+        let db = PytestDiffDatabase::open(db_path).unwrap();
+        assert_eq!(db.get_baseline_aggregate(DEFAULT_BASELINE).unwrap(), None);
+        drop(db);
+        let result = detect(true);
+        assert!(result.changes.modified.is_empty());
+        assert_eq!(
+            decisions(&result),
+            HashSet::from(["mtime_unchanged".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_detect_changes_fails_on_corrupt_baseline_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.py"), "def f():\n    return 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("diff.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        let conn = rusqlite::Connection::open(db_path).unwrap();
+        conn.execute(
+            "UPDATE baseline_fp SET method_checksums = x'0102030405' WHERE filename = 'a.py'",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+
+        // Not mistaken for a new file
+        let err = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            false,
+            false,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Corrupt checksum blob: 5 bytes"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_detect_changes_on_empty_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("empty.py"), "").unwrap();
+        std::fs::write(root.join("blank.py"), "\n   \n").unwrap();
+        let root_str = root.to_str().unwrap();
+
+        // Distinct files, same stable block checksums
+        let empty = calculate_fingerprint_internal(
+            root.join("empty.py").to_str().unwrap(),
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let blank = calculate_fingerprint_internal(
+            root.join("blank.py").to_str().unwrap(),
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert_ne!(empty.file_hash, blank.file_hash);
+        assert_eq!(empty.checksums, blank.checksums);
+
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        // First code in the empty file is a change; more whitespace is not
+        std::fs::write(root.join("empty.py"), "def f():\n    pass\n").unwrap();
+        std::fs::write(root.join("blank.py"), "\n\n\n").unwrap();
+        let changes = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            false,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert_eq!(changes.modified, vec!["empty.py"]);
+        assert_eq!(changes.decisions["blank.py"], "checksums_unchanged");
+    }
+
+    #[test]
+    fn test_detect_changes_without_trusting_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap().join("project");
+        std::fs::create_dir_all(&root).unwrap();
+        let module = root.join("mod.py");
+        std::fs::write(&module, "def f():\n    return 1\n").unwrap();
+        let root_str = root.to_str().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        save_baseline_internal(
+            db_path,
+            root_str,
+            &SaveOptions {
+                quiet: true,
+                ..SaveOptions::default()
+            },
+            Checkpoint::Truncate,
+        )
+        .unwrap();
+
+        // Simulate a cache restore: new content behind the baseline's mtime
+        let baseline_mtime = std::fs::metadata(&module).unwrap().modified().unwrap();
+        std::fs::write(&module, "def f():\n    return 2\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&module)
+            .unwrap()
+            .set_modified(baseline_mtime)
+            .unwrap();
+
+        let trusted = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            true,
+            true,
+        )
+        .unwrap()
+        .changes;
+        // Every mtime still matches the save, so level 0 decides
+        assert_eq!(trusted.decisions["mod.py"], "aggregate_unchanged");
+        assert!(trusted.modified.is_empty());
+
+        let verified = detect_changes_internal(
+            db_path,
+            root_str,
+            vec![],
+            None,
+            DEFAULT_BASELINE,
+            false,
+            true,
+        )
+        .unwrap()
+        .changes;
+        assert_eq!(verified.decisions["mod.py"], "checksums_changed");
+        assert_eq!(verified.modified, vec!["mod.py"]);
+    }
+
     #[test]
     fn test_make_relative() {
         // Standard case: path under project root
@@ -7,12 +7,13 @@ use anyhow::Result;
 use lru::LruCache;
 use parking_lot::RwLock;
 use pyo3::prelude::*;
-use std::num::NonZeroUsize;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::UNIX_EPOCH;
 
-use crate::fingerprint::calculate_fingerprint_internal;
+use crate::encoding::read_python_source;
+use crate::fingerprint::{calculate_fingerprint_with_mtime_internal, mtimes_equal};
+use crate::parser::ParseOptions;
 use crate::types::Fingerprint;
 
 /// Default maximum cache size (number of fingerprints)
@@ -20,6 +21,9 @@ use crate::types::Fingerprint;
 /// At ~5KB per fingerprint, this caps cache at ~500MB worst case
 const DEFAULT_MAX_SIZE: usize = 100_000;
 
+/// Cache entry: (mtime, fingerprint, phase of last access)
+type Entry = (f64, Fingerprint, u64);
+
 /// In-memory cache for fingerprints
 ///
 /// This cache stores parsed fingerprints to avoid re-parsing the same files
@@ -27,30 +31,34 @@ const DEFAULT_MAX_SIZE: usize = 100_000;
 /// touch the same source files.
 ///
 /// The cache uses LRU eviction: when the limit is reached, the least recently
-/// used entry is automatically evicted on insert.
+/// used entry is evicted on insert. Entries accessed during the current phase
+/// (see `begin_phase`) are never evicted, so the working set of an in-flight
+/// test isn't dropped while parallel lookups still need it; the cache may
+/// exceed its limit until the next phase starts.
 #[pyclass(unsendable)]
 pub struct FingerprintCache {
-    // Cache: filepath -> (mtime, fingerprint)
-    cache: Arc<RwLock<LruCache<String, (f64, Fingerprint)>>>,
+    // Cache: filepath -> entry
+    cache: Arc<RwLock<LruCache<String, Entry>>>,
     hits: Arc<RwLock<usize>>,
     misses: Arc<RwLock<usize>>,
+    phase: Arc<RwLock<u64>>,
     max_size: usize,
+    options: ParseOptions,
 }
 
 #[pymethods]
 impl FingerprintCache {
     /// Create a new cache with default maximum size
+    ///
+    /// `options` must be the parse options of the database the fingerprints
+    /// will be stored in (see `PytestDiffDatabase.get_parse_options`).
     #[new]
-    #[pyo3(signature = (max_size=None))]
-    pub fn new(max_size: Option<usize>) -> Self {
-        let size = max_size.unwrap_or(DEFAULT_MAX_SIZE);
-        let cap = NonZeroUsize::new(size).unwrap_or(NonZeroUsize::new(1).unwrap());
-        Self {
-            cache: Arc::new(RwLock::new(LruCache::new(cap))),
-            hits: Arc::new(RwLock::new(0)),
-            misses: Arc::new(RwLock::new(0)),
-            max_size: size,
-        }
+    #[pyo3(signature = (max_size=None, options=None))]
+    pub fn new(max_size: Option<usize>, options: Option<ParseOptions>) -> Self {
+        Self::with_options(
+            max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            options.unwrap_or_default(),
+        )
     }
 
     /// Get a fingerprint from cache or calculate it
@@ -63,6 +71,38 @@ impl FingerprintCache {
         Ok(fingerprint)
     }
 
+    /// Like `get_or_calculate`, but confirms cached entries by content hash
+    ///
+    /// For runs that rewrite sources mid-session (e.g. from fixtures), where a
+    /// matching mtime doesn't prove the content is unchanged. Reads the file on
+    /// every call, so it costs a hash per lookup.
+    pub fn get_or_calculate_verified(&self, path: &str) -> PyResult<Fingerprint> {
+        let fingerprint = self
+            .get_or_calculate_verified_internal(path)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(format!("{}", e)))?;
+        Ok(fingerprint)
+    }
+
+    /// Start a new phase (e.g. processing the next test's coverage)
+    ///
+    /// Entries used only in earlier phases become evictable again, and the
+    /// cache is trimmed back to its maximum size.
+    pub fn begin_phase(&self) {
+        let phase = {
+            let mut phase = self.phase.write();
+            *phase += 1;
+            *phase
+        };
+        self.evict_entries(&mut self.cache.write(), phase);
+    }
+
+    /// Drop the cached fingerprint of one file, forcing a recompute on next access
+    ///
+    /// Returns whether an entry existed. Hit/miss statistics are kept.
+    pub fn invalidate(&self, path: &str) -> bool {
+        self.cache.write().pop(path).is_some()
+    }
+
     /// Clear the cache
     pub fn clear(&self) {
         self.cache.write().clear();
@@ -95,7 +135,28 @@ impl FingerprintCache {
 }
 
 impl FingerprintCache {
+    pub(crate) fn with_options(max_size: usize, options: ParseOptions) -> Self {
+        Self {
+            cache: Arc::new(RwLock::new(LruCache::unbounded())),
+            hits: Arc::new(RwLock::new(0)),
+            misses: Arc::new(RwLock::new(0)),
+            phase: Arc::new(RwLock::new(0)),
+            max_size: max_size.max(1),
+            options,
+        }
+    }
+
     pub(crate) fn get_or_calculate_internal(&self, path: &str) -> Result<Fingerprint> {
+        self.lookup(path, false)
+    }
+
+    pub(crate) fn get_or_calculate_verified_internal(&self, path: &str) -> Result<Fingerprint> {
+        self.lookup(path, true)
+    }
+
+    /// Cached fingerprint if its mtime (and, with `verify`, its content hash)
+    /// still matches the file, otherwise a freshly calculated one
+    fn lookup(&self, path: &str, verify: bool) -> Result<Fingerprint> {
         let path_obj = Path::new(path);
 
         // Get current mtime
@@ -105,29 +166,160 @@ impl FingerprintCache {
             .duration_since(UNIX_EPOCH)?
             .as_secs_f64();
 
+        let phase = *self.phase.read();
+
         // Check cache (needs write lock for LRU promotion)
-        {
+        let candidate = {
             let mut cache = self.cache.write();
-            if let Some((cached_mtime, cached_fp)) = cache.get(path) {
+            match cache.get_mut(path) {
                 // Check if mtime matches (file hasn't changed)
-                if (current_mtime - cached_mtime).abs() < 0.001 {
-                    // Cache hit!
-                    *self.hits.write() += 1;
-                    return Ok(cached_fp.clone());
+                Some((cached_mtime, cached_fp, last_phase))
+                    if mtimes_equal(current_mtime, *cached_mtime) =>
+                {
+                    if !verify {
+                        // Cache hit!
+                        *last_phase = phase;
+                        *self.hits.write() += 1;
+                        return Ok(cached_fp.clone());
+                    }
+                    Some(cached_fp.clone())
+                }
+                _ => None,
+            }
+        };
+
+        // Hash outside the lock, so parallel lookups aren't serialized on I/O
+        if let Some(cached_fp) = candidate {
+            let content = read_python_source(path_obj)?;
+            if blake3::hash(content.as_bytes()).to_hex().as_str() == cached_fp.file_hash {
+                if let Some((_, _, last_phase)) = self.cache.write().get_mut(path) {
+                    *last_phase = phase;
                 }
+                *self.hits.write() += 1;
+                return Ok(cached_fp);
             }
         }
 
-        // Cache miss - calculate fingerprint
+        // Cache miss - calculate fingerprint, reusing the mtime stat'ed above
         *self.misses.write() += 1;
-        let fingerprint = calculate_fingerprint_internal(path)?;
+        let fingerprint = calculate_fingerprint_with_mtime_internal(
+            path,
+            Some(current_mtime),
+            self.options.clone(),
+        )?;
 
-        // Update cache — LruCache auto-evicts when full
+        // Update cache, then evict LRU entries not used in this phase
         {
             let mut cache = self.cache.write();
-            cache.put(path.to_string(), (current_mtime, fingerprint.clone()));
+            cache.put(
+                path.to_string(),
+                (current_mtime, fingerprint.clone(), phase),
+            );
+            self.evict_entries(&mut cache, phase);
         }
 
         Ok(fingerprint)
     }
+
+    /// Evict least recently used entries until the cache fits its maximum size
+    ///
+    /// Stops at the first entry accessed during `phase`: every entry more
+    /// recently used than it belongs to the working set as well.
+    fn evict_entries(&self, cache: &mut LruCache<String, Entry>, phase: u64) {
+        while cache.len() > self.max_size {
+            match cache.peek_lru() {
+                Some((_, (_, _, last_phase))) if *last_phase < phase => {
+                    cache.pop_lru();
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eviction_keeps_entries_used_in_current_phase() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        for name in ["a.py", "b.py", "c.py", "d.py", "e.py"] {
+            std::fs::write(path(name), "def f():\n    return 1\n").unwrap();
+        }
+        let cache = FingerprintCache::with_options(2, ParseOptions::default());
+
+        // Earlier phase: a.py and b.py fill the cache
+        cache.get_or_calculate_internal(&path("a.py")).unwrap();
+        cache.get_or_calculate_internal(&path("b.py")).unwrap();
+        cache.begin_phase();
+
+        // In-flight lookups of the working set evict the stale entries only
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        cache.get_or_calculate_internal(&path("c.py")).unwrap();
+                        cache.get_or_calculate_internal(&path("d.py")).unwrap();
+                    }
+                });
+            }
+        });
+        let (hits, misses, _) = cache.stats();
+        assert_eq!(hits + misses, 2 + 400);
+        {
+            let entries = cache.cache.read();
+            assert!(entries.contains(&path("c.py")));
+            assert!(entries.contains(&path("d.py")));
+            assert!(!entries.contains(&path("a.py")));
+            assert!(!entries.contains(&path("b.py")));
+        }
+
+        // A third file in the same phase grows the cache instead of evicting
+        cache.get_or_calculate_internal(&path("e.py")).unwrap();
+        assert_eq!(cache.size(), 3);
+
+        // The next phase trims back to the limit, least recently used first
+        cache.begin_phase();
+        assert_eq!(cache.size(), 2);
+        assert!(!cache.cache.read().contains(&path("c.py")));
+    }
+
+    #[test]
+    fn test_verified_lookup_catches_rewrite_with_same_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mod.py");
+        let path_str = path.to_str().unwrap();
+        std::fs::write(&path, "def f():\n    return 1\n").unwrap();
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let cache = FingerprintCache::with_options(10, ParseOptions::default());
+        let original = cache.get_or_calculate_internal(path_str).unwrap();
+
+        // A fixture rewrites the file, keeping its mtime
+        std::fs::write(&path, "def f():\n    return 2\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        // The default path trusts mtime and serves the stale entry
+        let trusted = cache.get_or_calculate_internal(path_str).unwrap();
+        assert_eq!(trusted.file_hash, original.file_hash);
+        assert_eq!(cache.stats().0, 1);
+
+        // The verified path notices the new content and recomputes
+        let verified = cache.get_or_calculate_verified_internal(path_str).unwrap();
+        assert_ne!(verified.file_hash, original.file_hash);
+        assert_ne!(verified.checksums, original.checksums);
+        assert_eq!(cache.stats().1, 2);
+
+        // Once refreshed, both paths hit
+        cache.get_or_calculate_verified_internal(path_str).unwrap();
+        let refreshed = cache.get_or_calculate_internal(path_str).unwrap();
+        assert_eq!(refreshed.file_hash, verified.file_hash);
+        assert_eq!(cache.stats().0, 3);
+    }
 }
@@ -8,20 +8,31 @@
 
 use pyo3::prelude::*;
 
+mod analysis;
 mod cache;
 mod database;
+mod encoding;
 mod fingerprint;
 mod fingerprint_cache;
 mod parser;
 mod types;
 
-pub use database::{ImportResult, PytestDiffDatabase};
+pub use analysis::{compute_selection, diff_trees, find_duplicate_blocks, find_uncovered_blocks};
+pub use database::{
+    BaselineSnapshot, Checkpoint, ImportResult, PytestDiffDatabase, ValidationReport,
+};
 pub use fingerprint::{
-    calculate_fingerprint, detect_changes, process_coverage_data, save_baseline,
+    calculate_fingerprint, changed_files_only, detect_changes, detect_changes_from_snapshot,
+    explain_coverage_blocks, fingerprint_source, fork_baseline, hash_all_files,
+    process_coverage_data, save_baseline, save_baseline_with_db, update_baseline_file,
+    ProgressStream, SaveOptions,
 };
 pub use fingerprint_cache::FingerprintCache;
-pub use parser::parse_module;
-pub use types::{Block, ChangedFiles, Fingerprint, TestExecution};
+pub use parser::{block_name_hash, parse_module, parse_snippet, ParseOptions};
+pub use types::{
+    Block, ChangedBlocks, ChangedFiles, DetectionResult, Fingerprint, RenormalizeResult,
+    SaveResult, SelectionResult, TestExecution,
+};
 
 /// Python module initialization
 #[pymodule]
@@ -30,17 +41,37 @@ fn _core(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Block>()?;
     m.add_class::<Fingerprint>()?;
     m.add_class::<ChangedFiles>()?;
+    m.add_class::<DetectionResult>()?;
+    m.add_class::<SaveResult>()?;
+    m.add_class::<RenormalizeResult>()?;
+    m.add_class::<SelectionResult>()?;
+    m.add_class::<ChangedBlocks>()?;
     m.add_class::<TestExecution>()?;
     m.add_class::<PytestDiffDatabase>()?;
     m.add_class::<ImportResult>()?;
+    m.add_class::<ValidationReport>()?;
     m.add_class::<FingerprintCache>()?;
+    m.add_class::<ParseOptions>()?;
 
     // Register functions
     m.add_function(wrap_pyfunction!(parse_module, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_snippet, m)?)?;
+    m.add_function(wrap_pyfunction!(block_name_hash, m)?)?;
     m.add_function(wrap_pyfunction!(calculate_fingerprint, m)?)?;
+    m.add_function(wrap_pyfunction!(fingerprint_source, m)?)?;
     m.add_function(wrap_pyfunction!(detect_changes, m)?)?;
+    m.add_function(wrap_pyfunction!(detect_changes_from_snapshot, m)?)?;
+    m.add_function(wrap_pyfunction!(changed_files_only, m)?)?;
+    m.add_function(wrap_pyfunction!(diff_trees, m)?)?;
+    m.add_function(wrap_pyfunction!(find_duplicate_blocks, m)?)?;
+    m.add_function(wrap_pyfunction!(find_uncovered_blocks, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_selection, m)?)?;
+    m.add_function(wrap_pyfunction!(hash_all_files, m)?)?;
     m.add_function(wrap_pyfunction!(save_baseline, m)?)?;
+    m.add_function(wrap_pyfunction!(fork_baseline, m)?)?;
+    m.add_function(wrap_pyfunction!(update_baseline_file, m)?)?;
     m.add_function(wrap_pyfunction!(process_coverage_data, m)?)?;
+    m.add_function(wrap_pyfunction!(explain_coverage_blocks, m)?)?;
 
     // Module metadata
     m.add("__version__", env!("CARGO_PKG_VERSION"))?;
@@ -6,18 +6,287 @@
 use anyhow::Result;
 use crc32fast::Hasher;
 use pyo3::prelude::*;
-use rustpython_parser::{ast, Parse};
-use rustpython_parser_core::source_code::RandomLocator;
+use rustpython_parser::{ast, lexer, Mode, Parse, Tok};
+use rustpython_parser_core::text_size::TextSize;
+use std::collections::HashSet;
+use std::sync::Arc;
 
 use crate::types::Block;
 
+/// How block source is normalized before it is checksummed
+///
+/// The active mode is stored in the database metadata: baselines, change
+/// detection and recorded test executions must all use the same one, or their
+/// checksums won't line up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Normalization {
+    /// Checksum the exact source lines
+    #[default]
+    Exact,
+    /// Leave module, class and function docstrings out of every checksum
+    IgnoreDocstrings,
+    /// Leave blank lines out of every checksum, except inside string literals.
+    /// Block line ranges are unaffected, so coverage still maps onto them.
+    IgnoreBlankLines,
+}
+
+/// Marker comment that opts a file out of normalization
+///
+/// Placed in the comment header at the top of a file (before any code), it
+/// forces exact checksums for that file whatever the database mode is.
+const RAW_MARKER: &str = "pytest-diff: raw";
+
+impl Normalization {
+    /// Mode that applies to `source`: exact if it carries the raw marker
+    pub fn for_source(self, source: &str) -> Normalization {
+        let marked = source
+            .lines()
+            .map(str::trim)
+            .take_while(|line| line.is_empty() || line.starts_with('#'))
+            .any(|line| line.trim_start_matches('#').trim() == RAW_MARKER);
+        if marked {
+            Normalization::Exact
+        } else {
+            self
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Normalization::Exact => "exact",
+            Normalization::IgnoreDocstrings => "ignore_docstrings",
+            Normalization::IgnoreBlankLines => "ignore_blank_lines",
+        }
+    }
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "exact" => Ok(Normalization::Exact),
+            "ignore_docstrings" => Ok(Normalization::IgnoreDocstrings),
+            "ignore_blank_lines" => Ok(Normalization::IgnoreBlankLines),
+            _ => anyhow::bail!(
+                "Unknown normalization mode '{}' (expected 'exact', 'ignore_docstrings' or 'ignore_blank_lines')",
+                s
+            ),
+        }
+    }
+}
+
+/// Which code regions get a block (and checksum) of their own
+///
+/// Like `Normalization`, the active granularity is stored in the database
+/// metadata and must be shared by everything that produces checksums.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Granularity {
+    /// Modules, classes and functions
+    #[default]
+    Definitions,
+    /// Additionally one block per `if`/`else` branch inside functions; the
+    /// function's own checksum then leaves the branch bodies out. Like every
+    /// block, a branch is selected by line coverage: a test depends on it when
+    /// it executed a line of its body.
+    Branches,
+}
+
+impl Granularity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Definitions => "definitions",
+            Granularity::Branches => "branches",
+        }
+    }
+}
+
+impl std::str::FromStr for Granularity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "definitions" => Ok(Granularity::Definitions),
+            "branches" => Ok(Granularity::Branches),
+            _ => anyhow::bail!(
+                "Unknown block granularity '{}' (expected 'definitions' or 'branches')",
+                s
+            ),
+        }
+    }
+}
+
+/// Glob patterns (`*`, `?`) naming code left out of every checksum
+///
+/// Patterns match the qualified names of definitions (`Class.method`),
+/// module- and class-level assignment targets (`__version__`), and the
+/// condition of `if NAME:` statements (`TYPE_CHECKING`, whose `else` stays).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IgnoredNames(Arc<[String]>);
+
+impl IgnoredNames {
+    pub fn new(patterns: Vec<String>) -> Self {
+        Self(patterns.into())
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.0.iter().any(|pattern| glob_match(pattern, name))
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters
+/// and `?` any single one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it currently absorbs up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Settings that decide which blocks a module yields and how they're checksummed
+///
+/// Fingerprints are only comparable when computed with the same options, so
+/// from Python get them from the database with
+/// `PytestDiffDatabase.get_parse_options` rather than building them by hand.
+#[pyclass]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub normalization: Normalization,
+    pub granularity: Granularity,
+    /// Deepest nesting level that gets its own block (unlimited if `None`)
+    pub max_block_depth: Option<usize>,
+    /// Give `name = lambda ...` assignments at module and class level, and the
+    /// lambdas nested in expressions there (decorators, comprehensions, call
+    /// arguments), a "lambda" block of their own (off by default: noisy)
+    pub lambda_blocks: bool,
+    /// Split functions longer than this many lines into "part" blocks of
+    /// consecutive body statements (never split if `None`)
+    pub max_function_lines: Option<usize>,
+    /// Code left out of every checksum; ignored definitions get no block
+    pub ignored_names: IgnoredNames,
+}
+
+#[pymethods]
+impl ParseOptions {
+    /// See `PytestDiffDatabase.set_normalization_mode`, `set_block_granularity`,
+    /// `set_max_function_lines`, `set_ignored_blocks`, `set_max_block_depth`
+    /// and `set_lambda_blocks` for what each setting does
+    #[new]
+    #[pyo3(signature = (normalization="exact", granularity="definitions", max_function_lines=None, ignored_blocks=None, max_block_depth=None, lambda_blocks=false))]
+    fn new(
+        normalization: &str,
+        granularity: &str,
+        max_function_lines: Option<usize>,
+        ignored_blocks: Option<Vec<String>>,
+        max_block_depth: Option<usize>,
+        lambda_blocks: bool,
+    ) -> PyResult<Self> {
+        let invalid = |e: anyhow::Error| pyo3::exceptions::PyValueError::new_err(format!("{}", e));
+        Ok(Self {
+            normalization: normalization.parse().map_err(invalid)?,
+            granularity: granularity.parse().map_err(invalid)?,
+            max_block_depth,
+            lambda_blocks,
+            max_function_lines,
+            ignored_names: IgnoredNames::new(ignored_blocks.unwrap_or_default()),
+        })
+    }
+
+    #[getter(normalization)]
+    fn normalization_name(&self) -> &'static str {
+        self.normalization.as_str()
+    }
+
+    #[getter(granularity)]
+    fn granularity_name(&self) -> &'static str {
+        self.granularity.as_str()
+    }
+
+    #[getter]
+    fn max_function_lines(&self) -> Option<usize> {
+        self.max_function_lines
+    }
+
+    #[getter]
+    fn ignored_blocks(&self) -> Vec<String> {
+        self.ignored_names.patterns().to_vec()
+    }
+
+    #[getter]
+    fn max_block_depth(&self) -> Option<usize> {
+        self.max_block_depth
+    }
+
+    #[getter]
+    fn lambda_blocks(&self) -> bool {
+        self.lambda_blocks
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self == other
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "ParseOptions(normalization='{}', granularity='{}', max_function_lines={:?}, ignored_blocks={:?}, max_block_depth={:?}, lambda_blocks={})",
+            self.normalization.as_str(),
+            self.granularity.as_str(),
+            self.max_function_lines,
+            self.ignored_names.patterns(),
+            self.max_block_depth,
+            self.lambda_blocks
+        )
+    }
+}
+
+/// Deepest statement nesting the block extractors descend into
+///
+/// CPython rejects source indented more than 100 levels, so real modules never
+/// reach it; deeper (generated) nesting is folded into the enclosing block
+/// instead of recursing until the stack overflows.
+const MAX_NESTING: usize = 100;
+
 /// Parse a Python module and extract all code blocks
 ///
 /// # Arguments
 /// * `source` - Python source code as a string
+/// * `options` - Parse settings (`ParseOptions`); only `max_block_depth` and
+///   `lambda_blocks` change which blocks are returned. Defaults to
+///   `ParseOptions()`.
 ///
 /// # Returns
-/// * `PyResult<Vec<Block>>` - List of blocks found in the source
+/// * `PyResult<Vec<Block>>` - List of blocks found in the source, sorted by
+///   start line; enclosing blocks come first, so the module block leads
 ///
 /// # Example
 /// ```python
@@ -25,8 +294,31 @@ use crate::types::Block;
 /// assert len(blocks) == 2  # module + function
 /// ```
 #[pyfunction]
-pub fn parse_module(source: &str) -> PyResult<Vec<Block>> {
-    let blocks = parse_module_internal(source).map_err(|e| {
+#[pyo3(signature = (source, options=None))]
+pub fn parse_module(source: &str, options: Option<ParseOptions>) -> PyResult<Vec<Block>> {
+    let options = options.unwrap_or_default();
+    let blocks = parse_module_with_options(source, &options).map_err(|e| {
+        pyo3::exceptions::PySyntaxError::new_err(format!("Failed to parse Python code: {}", e))
+    })?;
+
+    Ok(blocks)
+}
+
+/// Parse a standalone snippet of Python code and extract its code blocks
+///
+/// Unlike `parse_module`, no `<module>` block is prepended: only the
+/// functions and classes found in the snippet are returned. Common leading
+/// indentation is stripped first, so a method copied out of a class body
+/// parses as-is. Line numbers are relative to the snippet.
+///
+/// # Example
+/// ```python
+/// blocks = parse_snippet("    def foo():\n        pass\n")
+/// assert [b.name for b in blocks] == ["foo"]
+/// ```
+#[pyfunction]
+pub fn parse_snippet(source: &str) -> PyResult<Vec<Block>> {
+    let blocks = parse_snippet_internal(source).map_err(|e| {
         pyo3::exceptions::PySyntaxError::new_err(format!("Failed to parse Python code: {}", e))
     })?;
 
@@ -43,25 +335,21 @@ pub fn parse_module(source: &str) -> PyResult<Vec<Block>> {
 ///
 /// This ensures the module checksum only changes when module-level code changes,
 /// not when individual function implementations change.
-fn extract_module_skeleton(
-    source: &str,
-    parsed: &[ast::Stmt],
-    locator: &mut RandomLocator,
-) -> Result<String> {
+fn extract_module_skeleton(parsed: &[ast::Stmt], index: &LineIndex) -> Result<String> {
     use ast::Ranged;
 
-    let source_lines: Vec<&str> = source.lines().collect();
+    let source_lines = index.lines();
     let mut skeleton_parts = Vec::new();
 
     for stmt in parsed {
         match stmt {
             // Function, async function, and class definitions: include signature only
             ast::Stmt::FunctionDef(_) | ast::Stmt::AsyncFunctionDef(_) | ast::Stmt::ClassDef(_) => {
-                let start = get_line_number(locator, stmt.start());
-                let end = get_line_number(locator, stmt.end());
+                let start = get_line_number(index, stmt.start());
+                let end = get_line_number(index, stmt.end());
 
-                if start <= source_lines.len() {
-                    let def_lines = extract_signature_lines(&source_lines, start, end);
+                if start <= source_lines.len() && !index.is_excluded(start) {
+                    let def_lines = extract_signature_lines(source_lines, start, end);
                     skeleton_parts.push(def_lines.join("\n"));
                 }
             }
@@ -69,12 +357,15 @@ fn extract_module_skeleton(
             // All other statements: include completely
             // This includes: imports, assignments, expressions, etc.
             _ => {
-                let start = get_line_number(locator, stmt.start());
-                let end = get_line_number(locator, stmt.end());
+                let start = get_line_number(index, stmt.start());
+                let end = get_line_number(index, stmt.end());
 
                 if start <= source_lines.len() {
-                    let stmt_source = extract_source_lines(source, start, end)?;
-                    skeleton_parts.push(stmt_source);
+                    let stmt_source = extract_source_lines(index, start, end)?;
+                    // Empty when the whole statement is excluded (e.g. a docstring)
+                    if !stmt_source.is_empty() {
+                        skeleton_parts.push(stmt_source);
+                    }
                 }
             }
         }
@@ -154,30 +445,145 @@ fn extract_signature_lines<'a>(source_lines: &[&'a str], start: usize, end: usiz
     def_lines
 }
 
+/// Parse with default options (exact checksums, unlimited depth)
+#[cfg(test)]
+pub(crate) fn parse_module_internal(source: &str) -> Result<Vec<Block>> {
+    parse_module_with_options(source, &ParseOptions::default())
+}
+
 /// Internal implementation that returns anyhow::Result
 ///
 /// This must be used instead of `parse_module` for any code running inside
 /// Rayon parallel iterators, because the #[pyfunction] version creates PyErr
 /// objects which require the GIL — causing a deadlock when called from worker
 /// threads while the main Python thread holds the GIL.
-pub(crate) fn parse_module_internal(source: &str) -> Result<Vec<Block>> {
+pub(crate) fn parse_module_with_options(
+    source: &str,
+    options: &ParseOptions,
+) -> Result<Vec<Block>> {
+    let mut blocks: Vec<Block> = Vec::new();
+    parse_into(source, options, &mut blocks)?;
+    blocks.sort_by_key(|b| source_order(b.start_line, b.end_line));
+    Ok(blocks)
+}
+
+/// Pairs of blocks whose line ranges overlap without one containing the other
+///
+/// Blocks must be nested or disjoint: a partial overlap would attribute the
+/// same executed line to both, inflating test dependencies. Returns index
+/// pairs into `blocks`, which must be in source order (as parsed). Checked by
+/// tests over real sources rather than at parse time, so a violation never
+/// aborts a test session.
+#[cfg(test)]
+pub(crate) fn overlapping_blocks(blocks: &[Block]) -> Vec<(usize, usize)> {
+    let mut overlaps = Vec::new();
+    // Blocks enclosing the current position, innermost last
+    let mut open: Vec<usize> = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        while open
+            .last()
+            .is_some_and(|&j| blocks[j].end_line < block.start_line)
+        {
+            open.pop();
+        }
+        if let Some(&j) = open.last() {
+            if blocks[j].end_line < block.end_line {
+                overlaps.push((j, i));
+            }
+        }
+        open.push(i);
+    }
+    overlaps
+}
+
+/// Checksums of the blocks `parse_module_with_options` would return, in order
+///
+/// Streams checksums without building `Block`s, for callers that only store
+/// checksums (baselines). Keeps peak memory flat on huge generated files.
+pub(crate) fn parse_checksums_with_options(
+    source: &str,
+    options: &ParseOptions,
+) -> Result<Vec<i32>> {
+    let mut spans: Vec<(usize, usize, i32)> = Vec::new();
+    parse_into(source, options, &mut spans)?;
+    spans.sort_by_key(|&(start, end, _)| source_order(start, end));
+    Ok(spans.into_iter().map(|(_, _, checksum)| checksum).collect())
+}
+
+/// Sort key putting blocks in source order
+///
+/// Blocks are ordered by start line; a block comes before the blocks it
+/// encloses (longer first on a shared start line), so the module block is
+/// always first. Extraction emits parents before children but e.g. branch
+/// blocks before nested definitions, hence the explicit sort.
+fn source_order(start_line: usize, end_line: usize) -> (usize, std::cmp::Reverse<usize>) {
+    (start_line, std::cmp::Reverse(end_line))
+}
+
+/// Destination of extracted blocks: full `Block`s, or checksums only
+trait BlockSink {
+    /// Record a block spanning `lines` (start, end); `block` is only built by
+    /// sinks that keep block details
+    fn emit(&mut self, lines: (usize, usize), checksum: i32, block: impl FnOnce() -> Block);
+}
+
+impl BlockSink for Vec<Block> {
+    fn emit(&mut self, _lines: (usize, usize), _checksum: i32, block: impl FnOnce() -> Block) {
+        self.push(block());
+    }
+}
+
+/// Checksums with their line spans, kept for sorting into source order
+impl BlockSink for Vec<(usize, usize, i32)> {
+    fn emit(&mut self, lines: (usize, usize), checksum: i32, _block: impl FnOnce() -> Block) {
+        self.push((lines.0, lines.1, checksum));
+    }
+}
+
+fn parse_into(source: &str, options: &ParseOptions, blocks: &mut impl BlockSink) -> Result<()> {
     // Parse the source code with RustPython's parser
     let parsed =
         ast::Suite::parse(source, "<string>").map_err(|e| anyhow::anyhow!("Parse error: {}", e))?;
 
-    // Build a RandomLocator once for efficient offset-to-line lookups
-    let mut locator = RandomLocator::new(source);
-
-    let mut blocks = Vec::new();
+    // Build the line index once: every offset-to-line lookup and block source
+    // extraction below reuses it instead of rescanning the source
+    let mut index = LineIndex::new(source);
+    match options.normalization.for_source(source) {
+        Normalization::Exact => {}
+        Normalization::IgnoreDocstrings => {
+            let mut ranges = Vec::new();
+            collect_docstring_ranges(&parsed, 1, 0, &index, &mut ranges);
+            for (start, end) in ranges {
+                index.exclude_lines(start, end);
+            }
+        }
+        Normalization::IgnoreBlankLines => {
+            for line in blank_lines_outside_strings(source, &index) {
+                index.exclude_lines(line, line);
+            }
+        }
+    }
+    if !options.ignored_names.is_empty() {
+        let mut ranges = Vec::new();
+        collect_ignored_ranges(&parsed, "", 0, &options.ignored_names, &index, &mut ranges);
+        for (start, end) in ranges {
+            index.exclude_lines(start, end);
+        }
+    }
 
     // Add module-level block (skeleton only - excludes function/class bodies)
     // This ensures that changing a function body doesn't invalidate the module checksum
-    let module_skeleton = extract_module_skeleton(source, &parsed, &mut locator)?;
-    let module_checksum = calculate_checksum(&module_skeleton);
-    let line_count = source.lines().count();
-    blocks.push(Block {
+    let module_skeleton = extract_module_skeleton(&parsed, &index)?;
+    let module_checksum = if module_skeleton.trim().is_empty() {
+        empty_module_checksum()
+    } else {
+        calculate_checksum(&module_skeleton)
+    };
+    // An empty file has no lines, but blocks span at least one
+    let module_end = index.line_count().max(1);
+    blocks.emit((1, module_end), module_checksum, || Block {
         start_line: 1,
-        end_line: line_count.max(1),
+        end_line: module_end,
         checksum: module_checksum,
         name: "<module>".to_string(),
         block_type: "module".to_string(),
@@ -185,20 +591,353 @@ pub(crate) fn parse_module_internal(source: &str) -> Result<Vec<Block>> {
     });
 
     // Extract blocks from AST
-    extract_blocks_from_statements(&parsed, source, &mut blocks, &mut locator)?;
+    let scope = BlockScope::top_level(options);
+    extract_blocks_from_statements(&parsed, blocks, &index, scope)
+}
+
+/// Internal implementation of `parse_snippet` (GIL-free, see `parse_module_with_options`)
+pub(crate) fn parse_snippet_internal(source: &str) -> Result<Vec<Block>> {
+    let source = dedent(source);
+    let parsed = ast::Suite::parse(&source, "<snippet>")
+        .map_err(|e| anyhow::anyhow!("Parse error: {}", e))?;
+
+    let index = LineIndex::new(&source);
+    let mut blocks: Vec<Block> = Vec::new();
+    let scope = BlockScope::top_level(&ParseOptions::default());
+    extract_blocks_from_statements(&parsed, &mut blocks, &index, scope)?;
+    blocks.sort_by_key(|b| source_order(b.start_line, b.end_line));
 
     Ok(blocks)
 }
 
+/// Remove the leading whitespace shared by every non-blank line
+///
+/// Like `textwrap.dedent`, the prefix must match exactly: a tab and spaces
+/// are not interchangeable. Lines not starting with it (blank lines) are kept
+/// as they are, and so are line endings.
+fn dedent(source: &str) -> String {
+    let margin = source
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start_matches([' ', '\t']).len()])
+        .reduce(|common, indent| {
+            let shared = common
+                .bytes()
+                .zip(indent.bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+            &common[..shared]
+        })
+        .unwrap_or("");
+
+    if margin.is_empty() {
+        return source.to_string();
+    }
+
+    source
+        .split_inclusive('\n')
+        .map(|line| line.strip_prefix(margin).unwrap_or(line))
+        .collect()
+}
+
+/// Blank lines of `source`, except those inside (multi-line) string literals
+fn blank_lines_outside_strings(source: &str, index: &LineIndex) -> Vec<usize> {
+    let mut in_string = HashSet::new();
+    // The source already parsed, so lexing can't fail
+    for (token, range) in lexer::lex(source, Mode::Module).flatten() {
+        if matches!(token, Tok::String { .. }) {
+            let start = index.line_of(range.start().to_usize());
+            let end = index.line_of(range.end().to_usize());
+            in_string.extend(start + 1..end);
+        }
+    }
+
+    index
+        .lines()
+        .iter()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(line_no, line)| line.trim().is_empty() && !in_string.contains(line_no))
+        .map(|(line_no, _)| line_no)
+        .collect()
+}
+
+/// Return the docstring statement of a module, class or function body, if any
+fn docstring_stmt(body: &[ast::Stmt]) -> Option<&ast::Stmt> {
+    match body.first()? {
+        stmt @ ast::Stmt::Expr(expr) => match expr.value.as_ref() {
+            ast::Expr::Constant(ast::ExprConstant {
+                value: ast::Constant::Str(_),
+                ..
+            }) => Some(stmt),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Collect the line ranges of the docstring of `body` and of every nested
+/// class/function docstring
+///
+/// `min_line` is the first line a docstring may claim: a one-liner such as
+/// `def f(): "doc"` shares its line with the `def`, which must stay checksummed.
+/// Bodies nested deeper than `MAX_NESTING` keep their docstrings.
+fn collect_docstring_ranges(
+    body: &[ast::Stmt],
+    min_line: usize,
+    nesting: usize,
+    index: &LineIndex,
+    ranges: &mut Vec<(usize, usize)>,
+) {
+    use ast::Ranged;
+
+    if nesting > MAX_NESTING {
+        return;
+    }
+
+    if let Some(doc) = docstring_stmt(body) {
+        let start = get_line_number(index, doc.start()).max(min_line);
+        let end = get_line_number(index, doc.end());
+        if start <= end {
+            ranges.push((start, end));
+        }
+    }
+    for stmt in body {
+        collect_nested_docstring_ranges(stmt, nesting + 1, index, ranges);
+    }
+}
+
+/// Descend into a statement looking for class/function bodies
+fn collect_nested_docstring_ranges(
+    stmt: &ast::Stmt,
+    nesting: usize,
+    index: &LineIndex,
+    ranges: &mut Vec<(usize, usize)>,
+) {
+    use ast::Ranged;
+
+    let after_def = |s: &ast::Stmt| get_line_number(index, s.start()) + 1;
+    let nested = |body: &[ast::Stmt], ranges: &mut Vec<(usize, usize)>| {
+        if nesting >= MAX_NESTING {
+            return;
+        }
+        for s in body {
+            collect_nested_docstring_ranges(s, nesting + 1, index, ranges);
+        }
+    };
+
+    match stmt {
+        ast::Stmt::FunctionDef(f) => {
+            collect_docstring_ranges(&f.body, after_def(stmt), nesting, index, ranges)
+        }
+        ast::Stmt::AsyncFunctionDef(f) => {
+            collect_docstring_ranges(&f.body, after_def(stmt), nesting, index, ranges)
+        }
+        ast::Stmt::ClassDef(c) => {
+            collect_docstring_ranges(&c.body, after_def(stmt), nesting, index, ranges)
+        }
+        ast::Stmt::If(s) => {
+            nested(&s.body, ranges);
+            nested(&s.orelse, ranges);
+        }
+        ast::Stmt::For(s) => {
+            nested(&s.body, ranges);
+            nested(&s.orelse, ranges);
+        }
+        ast::Stmt::While(s) => {
+            nested(&s.body, ranges);
+            nested(&s.orelse, ranges);
+        }
+        ast::Stmt::With(s) => nested(&s.body, ranges),
+        ast::Stmt::Try(s) => {
+            nested(&s.body, ranges);
+            for handler in &s.handlers {
+                match handler {
+                    ast::ExceptHandler::ExceptHandler(h) => nested(&h.body, ranges),
+                }
+            }
+            nested(&s.orelse, ranges);
+            nested(&s.finalbody, ranges);
+        }
+        _ => {}
+    }
+}
+
+/// Collect the line ranges of the code `ignored` names in a module or class
+/// body (`prefix` is the class's qualified name)
+///
+/// Ignored definitions and `if NAME:` bodies are excluded whole, assignments
+/// when every target is ignored. Function bodies aren't searched: their
+/// statements are part of the function's block.
+fn collect_ignored_ranges(
+    body: &[ast::Stmt],
+    prefix: &str,
+    nesting: usize,
+    ignored: &IgnoredNames,
+    index: &LineIndex,
+    ranges: &mut Vec<(usize, usize)>,
+) {
+    use ast::Ranged;
+
+    if nesting >= MAX_NESTING {
+        return;
+    }
+    let qualify = |name: &str| {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", prefix, name)
+        }
+    };
+    let lines = |start: TextSize, end: TextSize| {
+        (get_line_number(index, start), get_line_number(index, end))
+    };
+    let definition = |name: &str, decorators: &[ast::Expr], stmt: &ast::Stmt| {
+        let start = decorators.first().map_or(stmt.start(), |d| d.start());
+        ignored
+            .matches(&qualify(name))
+            .then(|| lines(start, stmt.end()))
+    };
+    let all_ignored = |targets: &[&ast::Expr]| {
+        !targets.is_empty()
+            && targets.iter().all(|target| match target {
+                ast::Expr::Name(name) => ignored.matches(&qualify(&name.id)),
+                _ => false,
+            })
+    };
+
+    for stmt in body {
+        match stmt {
+            ast::Stmt::FunctionDef(f) => {
+                ranges.extend(definition(&f.name, &f.decorator_list, stmt))
+            }
+            ast::Stmt::AsyncFunctionDef(f) => {
+                ranges.extend(definition(&f.name, &f.decorator_list, stmt))
+            }
+            ast::Stmt::ClassDef(c) => match definition(&c.name, &c.decorator_list, stmt) {
+                Some(range) => ranges.push(range),
+                None => collect_ignored_ranges(
+                    &c.body,
+                    &qualify(&c.name),
+                    nesting + 1,
+                    ignored,
+                    index,
+                    ranges,
+                ),
+            },
+            ast::Stmt::Assign(a) if all_ignored(&a.targets.iter().collect::<Vec<_>>()) => {
+                ranges.push(lines(stmt.start(), stmt.end()))
+            }
+            ast::Stmt::AnnAssign(a) if all_ignored(&[&a.target]) => {
+                ranges.push(lines(stmt.start(), stmt.end()))
+            }
+            ast::Stmt::AugAssign(a) if all_ignored(&[&a.target]) => {
+                ranges.push(lines(stmt.start(), stmt.end()))
+            }
+            ast::Stmt::If(s) => {
+                match (dotted_name(&s.test), s.body.last()) {
+                    (Some(name), Some(last)) if ignored.matches(&name) => {
+                        ranges.push(lines(stmt.start(), last.end()))
+                    }
+                    _ => {
+                        collect_ignored_ranges(&s.body, prefix, nesting + 1, ignored, index, ranges)
+                    }
+                }
+                collect_ignored_ranges(&s.orelse, prefix, nesting + 1, ignored, index, ranges);
+            }
+            ast::Stmt::Try(s) => {
+                for body in [&s.body, &s.orelse, &s.finalbody] {
+                    collect_ignored_ranges(body, prefix, nesting + 1, ignored, index, ranges);
+                }
+                for handler in &s.handlers {
+                    let ast::ExceptHandler::ExceptHandler(h) = handler;
+                    collect_ignored_ranges(&h.body, prefix, nesting + 1, ignored, index, ranges);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `a.b.c` for a name or attribute chain, `None` for other expressions
+fn dotted_name(expr: &ast::Expr) -> Option<String> {
+    match expr {
+        ast::Expr::Name(name) => Some(name.id.to_string()),
+        ast::Expr::Attribute(attr) => Some(format!("{}.{}", dotted_name(&attr.value)?, attr.attr)),
+        _ => None,
+    }
+}
+
+/// Nesting level of the definitions being extracted, and the settings that
+/// decide which blocks get emitted
+///
+/// Top-level functions and classes are at depth 1 (the module block is depth 0).
+#[derive(Clone, Copy)]
+struct BlockScope {
+    depth: usize,
+    /// Statement nesting level, counting every compound statement (unlike
+    /// `depth`), bounded by `MAX_NESTING`
+    nesting: usize,
+    max_depth: Option<usize>,
+    granularity: Granularity,
+    /// Whether lambda assignments here get blocks (module and class level)
+    lambdas: bool,
+    max_function_lines: Option<usize>,
+}
+
+impl BlockScope {
+    fn top_level(options: &ParseOptions) -> Self {
+        Self {
+            depth: 1,
+            nesting: 0,
+            max_depth: options.max_block_depth,
+            granularity: options.granularity,
+            lambdas: options.lambda_blocks,
+            max_function_lines: options.max_function_lines,
+        }
+    }
+
+    /// Scope of definitions inside a class body
+    fn nested(self) -> Self {
+        Self {
+            depth: self.depth + 1,
+            ..self
+        }
+    }
+
+    /// Scope of definitions inside a function body
+    fn nested_in_function(self) -> Self {
+        Self {
+            lambdas: false,
+            ..self.nested()
+        }
+    }
+
+    fn emits_blocks(self) -> bool {
+        self.max_depth.is_none_or(|max| self.depth <= max)
+    }
+}
+
 /// Recursively extract blocks from a list of statements
+///
+/// Definitions nested deeper than `scope.max_depth`, or inside more than
+/// `MAX_NESTING` statements, get no block of their own: their lines stay part
+/// of the enclosing block's source, so it covers them.
 fn extract_blocks_from_statements(
     statements: &[ast::Stmt],
-    source: &str,
-    blocks: &mut Vec<Block>,
-    locator: &mut RandomLocator,
+    blocks: &mut impl BlockSink,
+    index: &LineIndex,
+    scope: BlockScope,
 ) -> Result<()> {
+    if !scope.emits_blocks() || scope.nesting >= MAX_NESTING {
+        return Ok(());
+    }
+    let scope = BlockScope {
+        nesting: scope.nesting + 1,
+        ..scope
+    };
     for stmt in statements {
-        extract_block_from_statement(stmt, source, blocks, locator)?;
+        extract_block_from_statement(stmt, blocks, index, scope)?;
     }
     Ok(())
 }
@@ -214,52 +953,467 @@ fn extract_callable_block(
     decorator_list: &[ast::Expr],
     body: &[ast::Stmt],
     stmt: &ast::Stmt,
-    source: &str,
-    blocks: &mut Vec<Block>,
-    locator: &mut RandomLocator,
+    blocks: &mut impl BlockSink,
+    index: &LineIndex,
+    scope: BlockScope,
+) -> Result<()> {
+    use ast::Ranged;
+
+    let def_line = get_line_number(index, stmt.start());
+    if index.is_excluded(def_line) {
+        // Ignored (see `IgnoredNames`): no block for it or anything inside
+        return Ok(());
+    }
+    // Include decorators in start_line so the checksum covers them
+    let start = decorator_list
+        .first()
+        .map(|d| get_line_number(index, d.start()))
+        .unwrap_or(def_line);
+    let end = get_line_number(index, stmt.end());
+
+    // With branch granularity, branch bodies are checksummed as their own
+    // blocks, so editing one branch leaves the function checksum unchanged
+    let branches = match scope.granularity {
+        Granularity::Branches if scope.emits_blocks() => {
+            collect_branches(body, index, scope.nesting)
+        }
+        _ => Vec::new(),
+    };
+
+    // Oversized functions are split into parts on statement boundaries; like
+    // branches, the parts are left out of the function checksum
+    let parts = match scope.max_function_lines {
+        Some(max_lines) if scope.emits_blocks() && end - start + 1 > max_lines => {
+            split_into_parts(body, index, max_lines)
+        }
+        _ => Vec::new(),
+    };
+
+    let mut holes = branch_ranges(&branches);
+    holes.extend(parts.iter().copied());
+    let checksum = checksum_source_lines(index, start, end, &holes)?;
+
+    // body_start_line = first line of the function body (skipping decorators + def)
+    let body_start_line = body
+        .first()
+        .map(|s| get_line_number(index, s.start()))
+        .unwrap_or(def_line);
+
+    blocks.emit((start, end), checksum, || Block {
+        start_line: start,
+        end_line: end,
+        checksum,
+        name: name.to_string(),
+        block_type: block_type.to_string(),
+        body_start_line,
+    });
+    push_branch_blocks(name, &branches, blocks, index)?;
+    for &(part_start, part_end) in &parts {
+        // Branches inside the part keep their own blocks
+        let checksum =
+            checksum_source_lines(index, part_start, part_end, &branch_ranges(&branches))?;
+        blocks.emit((part_start, part_end), checksum, || Block {
+            start_line: part_start,
+            end_line: part_end,
+            checksum,
+            name: format!("{}:part@{}", name, part_start),
+            block_type: "part".to_string(),
+            body_start_line: part_start,
+        });
+    }
+
+    // Extract nested blocks
+    extract_blocks_from_statements(body, blocks, index, scope.nested_in_function())?;
+    Ok(())
+}
+
+/// Extract a "lambda" block for `name = lambda ...` (or `name: T = lambda ...`)
+///
+/// Only single-name targets count; the block is named after the target and
+/// spans the whole assignment.
+fn extract_lambda_block(
+    target: &ast::Expr,
+    value: &ast::Expr,
+    stmt: &ast::Stmt,
+    blocks: &mut impl BlockSink,
+    index: &LineIndex,
+) -> Result<()> {
+    use ast::Ranged;
+
+    let (ast::Expr::Name(name), ast::Expr::Lambda(lambda)) = (target, value) else {
+        return Ok(());
+    };
+    let start = get_line_number(index, stmt.start());
+    if index.is_excluded(start) {
+        return Ok(());
+    }
+    let end = get_line_number(index, stmt.end());
+    let checksum = checksum_source_lines(index, start, end, &[])?;
+    let body_start_line = get_line_number(index, lambda.body.start());
+
+    blocks.emit((start, end), checksum, || Block {
+        start_line: start,
+        end_line: end,
+        checksum,
+        name: name.id.to_string(),
+        block_type: "lambda".to_string(),
+        body_start_line,
+    });
+    Ok(())
+}
+
+/// Extract "lambda" blocks for the lambdas nested in the expressions `stmt`
+/// evaluates (decorators, class bases, call arguments, comprehensions, ...)
+///
+/// Python has no def or class expressions, so lambdas are the only code a
+/// statement can define outside its body. Each outermost lambda is named
+/// `<lambda>@<line>` and spans its own lines; `name = lambda ...` assignments
+/// are left to `extract_lambda_block`. A lambda starting on a line an earlier
+/// one already covers shares its block.
+fn extract_expression_lambdas(
+    stmt: &ast::Stmt,
+    blocks: &mut impl BlockSink,
+    index: &LineIndex,
+    nesting: usize,
 ) -> Result<()> {
     use ast::Ranged;
 
-    let def_line = get_line_number(locator, stmt.start());
-    // Include decorators in start_line so the checksum covers them
-    let start = decorator_list
-        .first()
-        .map(|d| get_line_number(locator, d.start()))
-        .unwrap_or(def_line);
-    let end = get_line_number(locator, stmt.end());
+    let mut lambdas = Vec::new();
+    for expr in evaluated_expressions(stmt) {
+        collect_lambdas(expr, nesting, &mut lambdas);
+    }
+    lambdas.sort_by_key(|lambda| lambda.start());
+
+    let mut covered_until = 0;
+    for lambda in lambdas {
+        let start = get_line_number(index, lambda.start());
+        if start <= covered_until || index.is_excluded(start) {
+            continue;
+        }
+        let end = get_line_number(index, lambda.end());
+        covered_until = end;
+        let checksum = checksum_source_lines(index, start, end, &[])?;
+        let body_start_line = get_line_number(index, lambda.body.start());
+
+        blocks.emit((start, end), checksum, || Block {
+            start_line: start,
+            end_line: end,
+            checksum,
+            name: format!("<lambda>@{}", start),
+            block_type: "lambda".to_string(),
+            body_start_line,
+        });
+    }
+    Ok(())
+}
+
+/// Expressions a statement evaluates itself, not counting its body
+fn evaluated_expressions(stmt: &ast::Stmt) -> Vec<&ast::Expr> {
+    let is_named_lambda = |target: &ast::Expr, value: &ast::Expr| {
+        matches!((target, value), (ast::Expr::Name(_), ast::Expr::Lambda(_)))
+    };
+    match stmt {
+        ast::Stmt::FunctionDef(f) => f.decorator_list.iter().collect(),
+        ast::Stmt::AsyncFunctionDef(f) => f.decorator_list.iter().collect(),
+        ast::Stmt::ClassDef(c) => c
+            .decorator_list
+            .iter()
+            .chain(&c.bases)
+            .chain(c.keywords.iter().map(|k| &k.value))
+            .collect(),
+        ast::Stmt::Assign(a) => {
+            if a.targets.len() == 1 && is_named_lambda(&a.targets[0], &a.value) {
+                return Vec::new();
+            }
+            a.targets.iter().chain([&*a.value]).collect()
+        }
+        ast::Stmt::AnnAssign(a) => match &a.value {
+            Some(value) if is_named_lambda(&a.target, value) => Vec::new(),
+            value => [&*a.target, &*a.annotation]
+                .into_iter()
+                .chain(value.as_deref())
+                .collect(),
+        },
+        ast::Stmt::AugAssign(a) => vec![&*a.target, &*a.value],
+        ast::Stmt::Expr(e) => vec![&*e.value],
+        ast::Stmt::Delete(d) => d.targets.iter().collect(),
+        ast::Stmt::If(s) => vec![&*s.test],
+        ast::Stmt::While(s) => vec![&*s.test],
+        ast::Stmt::For(s) => vec![&*s.target, &*s.iter],
+        ast::Stmt::AsyncFor(s) => vec![&*s.target, &*s.iter],
+        ast::Stmt::With(s) => with_item_expressions(&s.items),
+        ast::Stmt::AsyncWith(s) => with_item_expressions(&s.items),
+        ast::Stmt::Try(s) => s
+            .handlers
+            .iter()
+            .filter_map(|handler| {
+                let ast::ExceptHandler::ExceptHandler(h) = handler;
+                h.type_.as_deref()
+            })
+            .collect(),
+        ast::Stmt::Raise(s) => s.exc.iter().chain(&s.cause).map(|e| &**e).collect(),
+        ast::Stmt::Assert(s) => [&*s.test].into_iter().chain(s.msg.as_deref()).collect(),
+        ast::Stmt::Match(s) => vec![&*s.subject],
+        ast::Stmt::Return(s) => s.value.as_deref().into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn with_item_expressions(items: &[ast::WithItem]) -> Vec<&ast::Expr> {
+    items
+        .iter()
+        .flat_map(|item| {
+            [&item.context_expr]
+                .into_iter()
+                .chain(item.optional_vars.as_deref())
+        })
+        .collect()
+}
+
+/// Collect the outermost lambdas within `expr`
+fn collect_lambdas<'a>(
+    expr: &'a ast::Expr,
+    nesting: usize,
+    lambdas: &mut Vec<&'a ast::ExprLambda>,
+) {
+    if nesting >= MAX_NESTING {
+        return;
+    }
+    let comprehensions = |generators: &'a [ast::Comprehension]| {
+        generators
+            .iter()
+            .flat_map(|g| [&g.target, &g.iter].into_iter().chain(&g.ifs))
+    };
+    let children: Vec<&ast::Expr> = match expr {
+        ast::Expr::Lambda(lambda) => {
+            lambdas.push(lambda);
+            return;
+        }
+        ast::Expr::BoolOp(e) => e.values.iter().collect(),
+        ast::Expr::NamedExpr(e) => vec![&*e.target, &*e.value],
+        ast::Expr::BinOp(e) => vec![&*e.left, &*e.right],
+        ast::Expr::UnaryOp(e) => vec![&*e.operand],
+        ast::Expr::IfExp(e) => vec![&*e.test, &*e.body, &*e.orelse],
+        ast::Expr::Dict(e) => e.keys.iter().flatten().chain(&e.values).collect(),
+        ast::Expr::Set(e) => e.elts.iter().collect(),
+        ast::Expr::ListComp(e) => [&*e.elt]
+            .into_iter()
+            .chain(comprehensions(&e.generators))
+            .collect(),
+        ast::Expr::SetComp(e) => [&*e.elt]
+            .into_iter()
+            .chain(comprehensions(&e.generators))
+            .collect(),
+        ast::Expr::GeneratorExp(e) => [&*e.elt]
+            .into_iter()
+            .chain(comprehensions(&e.generators))
+            .collect(),
+        ast::Expr::DictComp(e) => [&*e.key, &*e.value]
+            .into_iter()
+            .chain(comprehensions(&e.generators))
+            .collect(),
+        ast::Expr::Await(e) => vec![&*e.value],
+        ast::Expr::Yield(e) => e.value.as_deref().into_iter().collect(),
+        ast::Expr::YieldFrom(e) => vec![&*e.value],
+        ast::Expr::Compare(e) => [&*e.left].into_iter().chain(&e.comparators).collect(),
+        ast::Expr::Call(e) => [&*e.func]
+            .into_iter()
+            .chain(&e.args)
+            .chain(e.keywords.iter().map(|k| &k.value))
+            .collect(),
+        ast::Expr::FormattedValue(e) => [&*e.value]
+            .into_iter()
+            .chain(e.format_spec.as_deref())
+            .collect(),
+        ast::Expr::JoinedStr(e) => e.values.iter().collect(),
+        ast::Expr::Attribute(e) => vec![&*e.value],
+        ast::Expr::Subscript(e) => vec![&*e.value, &*e.slice],
+        ast::Expr::Starred(e) => vec![&*e.value],
+        ast::Expr::List(e) => e.elts.iter().collect(),
+        ast::Expr::Tuple(e) => e.elts.iter().collect(),
+        ast::Expr::Slice(e) => e
+            .lower
+            .iter()
+            .chain(&e.upper)
+            .chain(&e.step)
+            .map(|e| &**e)
+            .collect(),
+        ast::Expr::Constant(_) | ast::Expr::Name(_) => Vec::new(),
+    };
+    for child in children {
+        collect_lambdas(child, nesting + 1, lambdas);
+    }
+}
+
+/// Offset a statement's source starts at: its first decorator, if any
+///
+/// Blocks of decorated definitions start at the decorator, so ranges built
+/// from statements must too, or they would partially overlap those blocks.
+fn statement_start(stmt: &ast::Stmt) -> TextSize {
+    use ast::Ranged;
+
+    let decorators = match stmt {
+        ast::Stmt::FunctionDef(s) => &s.decorator_list,
+        ast::Stmt::AsyncFunctionDef(s) => &s.decorator_list,
+        ast::Stmt::ClassDef(s) => &s.decorator_list,
+        _ => return stmt.start(),
+    };
+    decorators.first().map_or(stmt.start(), |d| d.start())
+}
+
+/// Line ranges of consecutive body statements, each part closed once it
+/// spans at least `max_lines` lines
+///
+/// Statements are never cut, so a single oversized statement is a part of its
+/// own. Returns no parts when the body would fit in one.
+fn split_into_parts(
+    body: &[ast::Stmt],
+    index: &LineIndex,
+    max_lines: usize,
+) -> Vec<(usize, usize)> {
+    use ast::Ranged;
+
+    let mut parts = Vec::new();
+    let mut current: Option<(usize, usize)> = None;
+    for stmt in body {
+        let stmt_start = get_line_number(index, statement_start(stmt));
+        let stmt_end = get_line_number(index, stmt.end());
+        let (part_start, _) = *current.get_or_insert((stmt_start, stmt_end));
+        current = Some((part_start, stmt_end));
+        if stmt_end - part_start + 1 >= max_lines {
+            parts.extend(current.take());
+        }
+    }
+    parts.extend(current);
+    if parts.len() < 2 {
+        parts.clear();
+    }
+    parts
+}
 
-    let block_source = extract_source_lines(source, start, end)?;
-    let checksum = calculate_checksum(&block_source);
+/// One `if` or `else` body inside a function
+struct Branch {
+    /// "if" or "else"
+    keyword: &'static str,
+    /// Line of the `if` (or `elif`) the branch belongs to
+    if_line: usize,
+    start: usize,
+    end: usize,
+    /// Branches nested in this one, which it leaves out of its own checksum
+    children: Vec<Branch>,
+}
 
-    // body_start_line = first line of the function body (skipping decorators + def)
-    let body_start_line = body
-        .first()
-        .map(|s| get_line_number(locator, s.start()))
-        .unwrap_or(def_line);
+/// Collect the `if`/`else` bodies in a function body
+///
+/// Looks through loops, `with` and `try` bodies, but not into nested function
+/// or class definitions (those get branch blocks of their own). Branches
+/// inside more than `MAX_NESTING` statements stay part of the enclosing one.
+fn collect_branches(statements: &[ast::Stmt], index: &LineIndex, nesting: usize) -> Vec<Branch> {
+    use ast::Ranged;
 
-    blocks.push(Block {
-        start_line: start,
-        end_line: end,
-        checksum,
-        name: name.to_string(),
-        block_type: block_type.to_string(),
-        body_start_line,
-    });
+    let mut branches = Vec::new();
+    if nesting >= MAX_NESTING {
+        return branches;
+    }
+    let body_range = |body: &[ast::Stmt]| -> Option<(usize, usize)> {
+        let first = body.first()?;
+        let last = body.last()?;
+        Some((
+            get_line_number(index, statement_start(first)),
+            get_line_number(index, last.end()),
+        ))
+    };
 
-    // Extract nested blocks
-    extract_blocks_from_statements(body, source, blocks, locator)?;
+    for stmt in statements {
+        match stmt {
+            ast::Stmt::If(if_stmt) => {
+                let if_line = get_line_number(index, stmt.start());
+                for (keyword, body) in [("if", &if_stmt.body), ("else", &if_stmt.orelse)] {
+                    if let Some((start, end)) = body_range(body) {
+                        branches.push(Branch {
+                            keyword,
+                            if_line,
+                            start,
+                            end,
+                            children: collect_branches(body, index, nesting + 1),
+                        });
+                    }
+                }
+            }
+            ast::Stmt::For(s) => {
+                branches.extend(collect_branches(&s.body, index, nesting + 1));
+                branches.extend(collect_branches(&s.orelse, index, nesting + 1));
+            }
+            ast::Stmt::AsyncFor(s) => {
+                branches.extend(collect_branches(&s.body, index, nesting + 1));
+                branches.extend(collect_branches(&s.orelse, index, nesting + 1));
+            }
+            ast::Stmt::While(s) => {
+                branches.extend(collect_branches(&s.body, index, nesting + 1));
+                branches.extend(collect_branches(&s.orelse, index, nesting + 1));
+            }
+            ast::Stmt::With(s) => branches.extend(collect_branches(&s.body, index, nesting + 1)),
+            ast::Stmt::AsyncWith(s) => {
+                branches.extend(collect_branches(&s.body, index, nesting + 1))
+            }
+            ast::Stmt::Try(s) => {
+                branches.extend(collect_branches(&s.body, index, nesting + 1));
+                for handler in &s.handlers {
+                    let ast::ExceptHandler::ExceptHandler(h) = handler;
+                    branches.extend(collect_branches(&h.body, index, nesting + 1));
+                }
+                branches.extend(collect_branches(&s.orelse, index, nesting + 1));
+                branches.extend(collect_branches(&s.finalbody, index, nesting + 1));
+            }
+            _ => {}
+        }
+    }
+    branches
+}
+
+fn branch_ranges(branches: &[Branch]) -> Vec<(usize, usize)> {
+    branches.iter().map(|b| (b.start, b.end)).collect()
+}
+
+/// Emit a "branch" block per branch, named `<function>:<if|else>@<if line>`
+fn push_branch_blocks(
+    function: &str,
+    branches: &[Branch],
+    blocks: &mut impl BlockSink,
+    index: &LineIndex,
+) -> Result<()> {
+    for branch in branches {
+        let checksum = checksum_source_lines(
+            index,
+            branch.start,
+            branch.end,
+            &branch_ranges(&branch.children),
+        )?;
+        blocks.emit((branch.start, branch.end), checksum, || Block {
+            start_line: branch.start,
+            end_line: branch.end,
+            checksum,
+            name: format!("{}:{}@{}", function, branch.keyword, branch.if_line),
+            block_type: "branch".to_string(),
+            body_start_line: branch.start,
+        });
+        push_branch_blocks(function, &branch.children, blocks, index)?;
+    }
     Ok(())
 }
 
 /// Extract a block from a single statement
 fn extract_block_from_statement(
     stmt: &ast::Stmt,
-    source: &str,
-    blocks: &mut Vec<Block>,
-    locator: &mut RandomLocator,
+    blocks: &mut impl BlockSink,
+    index: &LineIndex,
+    scope: BlockScope,
 ) -> Result<()> {
     use ast::Ranged; // Import trait to use range() method
 
+    if scope.lambdas {
+        extract_expression_lambdas(stmt, blocks, index, scope.nesting)?;
+    }
     match stmt {
         ast::Stmt::FunctionDef(func_def) => {
             extract_callable_block(
@@ -268,9 +1422,9 @@ fn extract_block_from_statement(
                 &func_def.decorator_list,
                 &func_def.body,
                 stmt,
-                source,
                 blocks,
-                locator,
+                index,
+                scope,
             )?;
         }
         ast::Stmt::AsyncFunctionDef(async_func_def) => {
@@ -280,26 +1434,28 @@ fn extract_block_from_statement(
                 &async_func_def.decorator_list,
                 &async_func_def.body,
                 stmt,
-                source,
                 blocks,
-                locator,
+                index,
+                scope,
             )?;
         }
         ast::Stmt::ClassDef(class_def) => {
-            let def_line = get_line_number(locator, stmt.start());
+            let def_line = get_line_number(index, stmt.start());
+            if index.is_excluded(def_line) {
+                return Ok(());
+            }
             let start = class_def
                 .decorator_list
                 .first()
-                .map(|d| get_line_number(locator, d.start()))
+                .map(|d| get_line_number(index, d.start()))
                 .unwrap_or(def_line);
-            let end = get_line_number(locator, stmt.end());
+            let end = get_line_number(index, stmt.end());
 
-            let block_source = extract_source_lines(source, start, end)?;
-            let checksum = calculate_checksum(&block_source);
+            let checksum = checksum_source_lines(index, start, end, &[])?;
 
             // Class body IS executed at import time, so body_start_line = class def
             // line (skip decorators only, keep the `class` line).
-            blocks.push(Block {
+            blocks.emit((start, end), checksum, || Block {
                 start_line: start,
                 end_line: end,
                 checksum,
@@ -308,61 +1464,182 @@ fn extract_block_from_statement(
                 body_start_line: def_line,
             });
 
-            extract_blocks_from_statements(&class_def.body, source, blocks, locator)?;
+            extract_blocks_from_statements(&class_def.body, blocks, index, scope.nested())?;
+        }
+        ast::Stmt::Assign(assign) if scope.lambdas && assign.targets.len() == 1 => {
+            extract_lambda_block(&assign.targets[0], &assign.value, stmt, blocks, index)?;
+        }
+        ast::Stmt::AnnAssign(assign) if scope.lambdas => {
+            if let Some(value) = &assign.value {
+                extract_lambda_block(&assign.target, value, stmt, blocks, index)?;
+            }
         }
         // Handle other statement types that may contain nested blocks
         ast::Stmt::If(if_stmt) => {
-            extract_blocks_from_statements(&if_stmt.body, source, blocks, locator)?;
-            extract_blocks_from_statements(&if_stmt.orelse, source, blocks, locator)?;
+            extract_blocks_from_statements(&if_stmt.body, blocks, index, scope)?;
+            extract_blocks_from_statements(&if_stmt.orelse, blocks, index, scope)?;
         }
         ast::Stmt::For(for_stmt) => {
-            extract_blocks_from_statements(&for_stmt.body, source, blocks, locator)?;
-            extract_blocks_from_statements(&for_stmt.orelse, source, blocks, locator)?;
+            extract_blocks_from_statements(&for_stmt.body, blocks, index, scope)?;
+            extract_blocks_from_statements(&for_stmt.orelse, blocks, index, scope)?;
         }
         ast::Stmt::While(while_stmt) => {
-            extract_blocks_from_statements(&while_stmt.body, source, blocks, locator)?;
-            extract_blocks_from_statements(&while_stmt.orelse, source, blocks, locator)?;
+            extract_blocks_from_statements(&while_stmt.body, blocks, index, scope)?;
+            extract_blocks_from_statements(&while_stmt.orelse, blocks, index, scope)?;
         }
         ast::Stmt::With(with_stmt) => {
-            extract_blocks_from_statements(&with_stmt.body, source, blocks, locator)?;
+            extract_blocks_from_statements(&with_stmt.body, blocks, index, scope)?;
         }
         ast::Stmt::Try(try_stmt) => {
-            extract_blocks_from_statements(&try_stmt.body, source, blocks, locator)?;
+            extract_blocks_from_statements(&try_stmt.body, blocks, index, scope)?;
             for handler in &try_stmt.handlers {
                 match handler {
                     ast::ExceptHandler::ExceptHandler(h) => {
-                        extract_blocks_from_statements(&h.body, source, blocks, locator)?;
+                        extract_blocks_from_statements(&h.body, blocks, index, scope)?;
                     }
                 }
             }
-            extract_blocks_from_statements(&try_stmt.orelse, source, blocks, locator)?;
-            extract_blocks_from_statements(&try_stmt.finalbody, source, blocks, locator)?;
+            extract_blocks_from_statements(&try_stmt.orelse, blocks, index, scope)?;
+            extract_blocks_from_statements(&try_stmt.finalbody, blocks, index, scope)?;
         }
         _ => {}
     }
     Ok(())
 }
 
+/// Line-start index of a source file, built once per parse
+///
+/// Maps byte offsets to 1-indexed line numbers and line numbers back to their
+/// text. `\n`, `\r\n` and lone `\r` all terminate a line, matching how the
+/// Python parser counts lines (unlike `str::lines()`, which ignores lone `\r`).
+pub(crate) struct LineIndex<'a> {
+    /// Byte offset at which each line starts (always begins with 0)
+    starts: Vec<usize>,
+    /// Text of each line holding content, without its terminator
+    lines: Vec<&'a str>,
+    /// Lines left out of extracted block source (normalization)
+    excluded: HashSet<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub(crate) fn new(source: &'a str) -> Self {
+        let bytes = source.as_bytes();
+        let mut starts = vec![0];
+        let mut lines = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let terminator_len = match bytes[i] {
+                b'\n' => 1,
+                b'\r' if bytes.get(i + 1) == Some(&b'\n') => 2,
+                b'\r' => 1,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            lines.push(&source[*starts.last().unwrap()..i]);
+            i += terminator_len;
+            starts.push(i);
+        }
+        // Final line without a trailing newline
+        let last_start = *starts.last().unwrap();
+        if last_start < bytes.len() {
+            lines.push(&source[last_start..]);
+        }
+        Self {
+            starts,
+            lines,
+            excluded: HashSet::new(),
+        }
+    }
+
+    /// Leave lines `start..=end` out of every extracted block source
+    pub(crate) fn exclude_lines(&mut self, start: usize, end: usize) {
+        self.excluded.extend(start..=end);
+    }
+
+    /// Whether `line` is left out of extracted block source
+    pub(crate) fn is_excluded(&self, line: usize) -> bool {
+        self.excluded.contains(&line)
+    }
+
+    /// Number of the last line holding content
+    pub(crate) fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Text of every line, without terminators
+    pub(crate) fn lines(&self) -> &[&'a str] {
+        &self.lines
+    }
+
+    /// 1-indexed line number containing the byte `offset`
+    pub(crate) fn line_of(&self, offset: usize) -> usize {
+        self.starts.partition_point(|&start| start <= offset)
+    }
+}
+
 /// Convert TextSize to 1-indexed line number
-fn get_line_number(
-    locator: &mut RandomLocator,
-    offset: rustpython_parser_core::text_size::TextSize,
-) -> usize {
-    let location = locator.locate(offset);
-    location.row.get() as usize // Convert OneIndexed u32 to usize
+fn get_line_number(index: &LineIndex, offset: TextSize) -> usize {
+    index.line_of(offset.to_usize())
 }
 
 /// Extract source lines from start to end (inclusive, 1-indexed)
-fn extract_source_lines(source: &str, start: usize, end: usize) -> Result<String> {
-    let lines: Vec<&str> = source.lines().collect();
+fn extract_source_lines(index: &LineIndex, start: usize, end: usize) -> Result<String> {
+    let lines = index.lines();
+
+    if start < 1 || start > lines.len() {
+        anyhow::bail!("Start line {} out of range (1-{})", start, lines.len());
+    }
+
+    let end = end.min(lines.len());
+
+    if index.excluded.is_empty() {
+        return Ok(lines[(start - 1)..end].join("\n"));
+    }
+    Ok((start..=end)
+        .filter(|line| !index.excluded.contains(line))
+        .map(|line| lines[line - 1])
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// `calculate_checksum` of `extract_source_lines`, leaving out the inclusive
+/// line ranges in `holes`
+///
+/// Hashes line by line instead of joining the block source into a new string.
+fn checksum_source_lines(
+    index: &LineIndex,
+    start: usize,
+    end: usize,
+    holes: &[(usize, usize)],
+) -> Result<i32> {
+    let lines = index.lines();
 
     if start < 1 || start > lines.len() {
         anyhow::bail!("Start line {} out of range (1-{})", start, lines.len());
     }
 
     let end = end.min(lines.len());
+    let in_hole = |line: &usize| holes.iter().any(|(s, e)| (s..=e).contains(&line));
+    let mut hasher = Hasher::new();
+    let included = (start..=end).filter(|line| !index.excluded.contains(line) && !in_hole(line));
+    for (i, line) in included.enumerate() {
+        if i > 0 {
+            hasher.update(b"\n");
+        }
+        hasher.update(lines[line - 1].as_bytes());
+    }
+    Ok(hasher.finalize() as i32)
+}
 
-    Ok(lines[(start - 1)..end].join("\n"))
+/// Checksum of a module without code (empty, whitespace or comments only)
+///
+/// Such modules all share this one stable checksum, so whitespace edits keep
+/// them unchanged. It is derived from a marker rather than the empty string,
+/// whose CRC is 0, so it can't pass for a placeholder value.
+pub(crate) fn empty_module_checksum() -> i32 {
+    calculate_checksum("<empty module>")
 }
 
 /// Calculate CRC32 checksum for a string
@@ -374,6 +1651,16 @@ pub fn calculate_checksum(source: &str) -> i32 {
     hasher.finalize() as i32
 }
 
+/// Short hash of a block name, stored next to its checksum in baselines
+/// saved with block name hashes (see `PytestDiffDatabase.set_block_name_hashes`)
+///
+/// Names are stable across edits of the block body, so a changed checksum can
+/// be traced back to the block it came from.
+#[pyfunction]
+pub fn block_name_hash(name: &str) -> u32 {
+    crc32fast::hash(name.as_bytes())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -469,6 +1756,464 @@ def outer():
         assert!(blocks.iter().any(|b| b.name == "inner"));
     }
 
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("__version__", "__version__"));
+        assert!(glob_match("C.*", "C.method"));
+        assert!(glob_match("*_debug", "C.dump_debug"));
+        assert!(glob_match("v?", "v1"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("v?", "v"));
+        assert!(!glob_match("C.*", "D.method"));
+        assert!(!glob_match("__version__", "__version__s"));
+    }
+
+    #[test]
+    fn test_ignored_names_leave_code_out_of_checksums() {
+        let source = r#"
+from typing import TYPE_CHECKING
+__version__ = "1.0"
+
+if TYPE_CHECKING:
+    from os import PathLike
+else:
+    PathLike = str
+
+class C:
+    build: str = "a"
+
+    def keep(self):
+        return 1
+
+    @staticmethod
+    def dump_debug():
+        return 2
+"#;
+        let options = ParseOptions {
+            ignored_names: IgnoredNames::new(vec![
+                "__version__".to_string(),
+                "TYPE_CHECKING".to_string(),
+                "C.build".to_string(),
+                "*_debug".to_string(),
+            ]),
+            ..ParseOptions::default()
+        };
+        let blocks = parse_module_with_options(source, &options).unwrap();
+        let names: Vec<&str> = blocks.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, vec!["<module>", "C", "keep"]);
+
+        // Edits to ignored code don't change any checksum...
+        let edited = source
+            .replace("1.0", "2.0")
+            .replace(
+                "from os import PathLike",
+                "from pathlib import Path as PathLike",
+            )
+            .replace("\"a\"", "\"b\"")
+            .replace("return 2", "return 3");
+        assert_eq!(
+            parse_module_with_options(&edited, &options).unwrap(),
+            blocks
+        );
+        // ...but the rest of the module still counts, including the `else`
+        let edited = source.replace("PathLike = str", "PathLike = bytes");
+        assert_ne!(
+            parse_module_with_options(&edited, &options).unwrap()[0].checksum,
+            blocks[0].checksum
+        );
+    }
+
+    #[test]
+    fn test_pathologically_deep_nesting_is_folded() {
+        let depth = 1000;
+        let mut source = String::new();
+        for i in 0..depth {
+            let indent = " ".repeat(i);
+            source.push_str(&format!("{indent}def f{i}():\n"));
+            source.push_str(&format!("{indent} \"\"\"Doc.\"\"\"\n"));
+            source.push_str(&format!("{indent} if x:\n"));
+            source.push_str(&format!("{indent}  pass\n"));
+        }
+        source.push_str(&format!("{}pass\n", " ".repeat(depth)));
+
+        for (normalization, granularity) in [
+            (Normalization::Exact, Granularity::Definitions),
+            (Normalization::IgnoreDocstrings, Granularity::Branches),
+        ] {
+            let options = ParseOptions {
+                normalization,
+                granularity,
+                ..ParseOptions::default()
+            };
+            // Run on a thread with a default-sized stack, like rayon workers
+            let blocks = std::thread::scope(|s| {
+                s.spawn(|| parse_module_with_options(&source, &options))
+                    .join()
+                    .unwrap()
+            })
+            .unwrap();
+            let functions = blocks.iter().filter(|b| b.block_type == "function").count();
+            assert!(functions > 0 && functions < depth, "{functions} functions");
+            // The deepest block still covers the folded definitions
+            assert_eq!(blocks.iter().map(|b| b.end_line).max(), Some(4 * depth + 1));
+        }
+    }
+
+    #[test]
+    fn test_max_block_depth_folds_nested_blocks() {
+        let source = r#"
+def outer():
+    def middle():
+        def inner():
+            pass
+        return inner
+    return middle
+
+class C:
+    def method(self):
+        pass
+"#;
+        let with_depth = |max_block_depth| ParseOptions {
+            max_block_depth,
+            ..ParseOptions::default()
+        };
+        let names = |max_depth| -> Vec<String> {
+            parse_module_with_options(source, &with_depth(max_depth))
+                .unwrap()
+                .into_iter()
+                .map(|b| b.name)
+                .collect()
+        };
+
+        assert_eq!(names(Some(0)), vec!["<module>"]);
+        assert_eq!(names(Some(1)), vec!["<module>", "outer", "C"]);
+        assert_eq!(
+            names(Some(2)),
+            vec!["<module>", "outer", "middle", "C", "method"]
+        );
+        assert_eq!(names(Some(3)), names(None));
+        assert_eq!(names(None).len(), 6);
+
+        // Folded definitions still count toward their enclosing block
+        let folded = source.replace("pass\n        return", "return 1\n        return");
+        let outer =
+            |src: &str| parse_module_with_options(src, &with_depth(Some(1))).unwrap()[1].checksum;
+        assert_ne!(outer(source), outer(&folded));
+    }
+
+    #[test]
+    fn test_lambda_blocks_track_assigned_lambdas() {
+        let source = "handler = lambda x: x + 1\n\nclass C:\n    key: object = lambda self: (\n        self.name\n    )\n\ndef f():\n    inner = lambda: 0\n    return inner\n";
+        let with_lambdas = ParseOptions {
+            lambda_blocks: true,
+            ..ParseOptions::default()
+        };
+        let lambdas = |src: &str| -> Vec<(String, usize, usize, i32)> {
+            parse_module_with_options(src, &with_lambdas)
+                .unwrap()
+                .into_iter()
+                .filter(|b| b.block_type == "lambda")
+                .map(|b| (b.name, b.start_line, b.end_line, b.checksum))
+                .collect()
+        };
+
+        // Module and class level only, not inside functions
+        let blocks = lambdas(source);
+        let spans: Vec<_> = blocks
+            .iter()
+            .map(|(n, s, e, _)| (n.as_str(), *s, *e))
+            .collect();
+        assert_eq!(spans, vec![("handler", 1, 1), ("key", 4, 6)]);
+
+        // Editing a lambda body flips its own checksum only
+        let edited = lambdas(&source.replace("x + 1", "x + 2"));
+        assert_ne!(edited[0].3, blocks[0].3);
+        assert_eq!(edited[1].3, blocks[1].3);
+
+        // Off by default
+        let default_blocks = parse_module_internal(source).unwrap();
+        assert!(default_blocks.iter().all(|b| b.block_type != "lambda"));
+    }
+
+    #[test]
+    fn test_lambda_blocks_reach_into_expressions() {
+        let source = "import functools\n\nhandlers = [\n    (h := lambda x: x * k)\n    for k in range(3)\n]\n\n@functools.lru_cache(key=lambda a: a)\ndef f(a):\n    return a\n\nclass C(base(lambda: 1)):\n    sort = sorted(items, key=lambda i: -i)\n\nwrap = lambda: (lambda: 0)\n";
+        let with_lambdas = ParseOptions {
+            lambda_blocks: true,
+            ..ParseOptions::default()
+        };
+        let lambdas = |src: &str| -> Vec<(String, usize, usize, i32)> {
+            parse_module_with_options(src, &with_lambdas)
+                .unwrap()
+                .into_iter()
+                .filter(|b| b.block_type == "lambda")
+                .map(|b| (b.name, b.start_line, b.end_line, b.checksum))
+                .collect()
+        };
+
+        // Outermost lambdas only: the one inside `wrap` is part of its block
+        let blocks = lambdas(source);
+        let spans: Vec<_> = blocks
+            .iter()
+            .map(|(n, s, e, _)| (n.as_str(), *s, *e))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("<lambda>@4", 4, 4),
+                ("<lambda>@8", 8, 8),
+                ("<lambda>@12", 12, 12),
+                ("<lambda>@13", 13, 13),
+                ("wrap", 15, 15),
+            ]
+        );
+
+        // Editing the walrus-bound lambda in the comprehension flips its own checksum
+        let edited = lambdas(&source.replace("x * k", "x + k"));
+        assert_ne!(edited[0].3, blocks[0].3);
+        assert_eq!(edited[1..], blocks[1..]);
+
+        let parsed = parse_module_with_options(source, &with_lambdas).unwrap();
+        assert!(overlapping_blocks(&parsed).is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_blocks_are_flagged() {
+        let block = |name: &str, start_line, end_line| Block {
+            start_line,
+            end_line,
+            checksum: 0,
+            name: name.to_string(),
+            block_type: "function".to_string(),
+            body_start_line: start_line,
+        };
+        let blocks = vec![
+            block("<module>", 1, 20),
+            block("a", 2, 5),
+            block("b", 4, 8),
+            block("c", 6, 7),
+            block("d", 10, 12),
+            block("e", 11, 12),
+        ];
+        assert_eq!(overlapping_blocks(&blocks), vec![(1, 2)]);
+
+        // Real parses nest cleanly
+        let source = "@deco\ndef f(x):\n    def g():\n        return 1\n    if x:\n        return g()\n    else:\n        return 0\n\nclass C:\n    key = lambda self: 1\n    def m(self):\n        pass\n";
+        let options = ParseOptions {
+            granularity: Granularity::Branches,
+            lambda_blocks: true,
+            ..ParseOptions::default()
+        };
+        let parsed = parse_module_with_options(source, &options).unwrap();
+        assert!(overlapping_blocks(&parsed).is_empty());
+    }
+
+    #[test]
+    fn test_real_sources_never_overlap() {
+        // The package's own sources, plus the system stdlib when there is one
+        let mut roots = vec![std::path::PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../python"
+        ))];
+        for lib in ["unittest", "test/support", "asyncio", "json"] {
+            roots.push(std::path::Path::new("/usr/lib/python3.11").join(lib));
+        }
+        let options = ParseOptions {
+            granularity: Granularity::Branches,
+            lambda_blocks: true,
+            max_function_lines: Some(8),
+            ..ParseOptions::default()
+        };
+        let mut parsed = 0;
+        for root in roots.iter().filter(|root| root.is_dir()) {
+            for entry in walkdir::WalkDir::new(root).into_iter().flatten() {
+                let path = entry.path();
+                if path.extension().is_none_or(|ext| ext != "py") {
+                    continue;
+                }
+                let Ok(source) = std::fs::read_to_string(path) else {
+                    continue;
+                };
+                let Ok(blocks) = parse_module_with_options(&source, &options) else {
+                    continue;
+                };
+                let overlaps: Vec<_> = overlapping_blocks(&blocks)
+                    .into_iter()
+                    .map(|(a, b)| (&blocks[a].name, &blocks[b].name))
+                    .collect();
+                assert!(overlaps.is_empty(), "{}: {:?}", path.display(), overlaps);
+                parsed += 1;
+            }
+        }
+        assert!(parsed > 0);
+    }
+
+    #[test]
+    fn test_max_function_lines_splits_into_parts() {
+        let source =
+            "def f(x):\n    a = 1\n    b = 2\n    if x:\n        c = 3\n    d = 4\n    return a\n";
+        let options = ParseOptions {
+            max_function_lines: Some(3),
+            granularity: Granularity::Branches,
+            ..ParseOptions::default()
+        };
+        let blocks = parse_module_with_options(source, &options).unwrap();
+        let spans: Vec<(&str, usize, usize)> = blocks
+            .iter()
+            .map(|b| (b.name.as_str(), b.start_line, b.end_line))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("<module>", 1, 7),
+                ("f", 1, 7),
+                ("f:part@2", 2, 5),
+                ("f:if@4", 5, 5),
+                ("f:part@6", 6, 7),
+            ]
+        );
+        assert!(overlapping_blocks(&blocks).is_empty());
+
+        // Editing the first part leaves the function and the second part unchanged
+        let edited = source.replace("b = 2", "b = 5");
+        let edited_blocks = parse_module_with_options(&edited, &options).unwrap();
+        let changed: Vec<&str> = blocks
+            .iter()
+            .zip(&edited_blocks)
+            .filter(|(a, b)| a.checksum != b.checksum)
+            .map(|(a, _)| a.name.as_str())
+            .collect();
+        assert_eq!(changed, vec!["f:part@2"]);
+
+        // Short functions are never split
+        let short = ParseOptions {
+            max_function_lines: Some(10),
+            ..ParseOptions::default()
+        };
+        let blocks = parse_module_with_options(source, &short).unwrap();
+        assert!(blocks.iter().all(|b| b.block_type != "part"));
+    }
+
+    #[test]
+    fn test_blocks_in_source_order() {
+        let source = "def f(x):\n    def g():\n        return 1\n    if x:\n        return g()\n    else:\n        return 0\n\n@deco\nclass C:\n    def m(self):\n        pass\n";
+        let options = ParseOptions {
+            granularity: Granularity::Branches,
+            ..ParseOptions::default()
+        };
+        let blocks = parse_module_with_options(source, &options).unwrap();
+        let spans: Vec<(&str, usize)> = blocks
+            .iter()
+            .map(|b| (b.name.as_str(), b.start_line))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("<module>", 1),
+                ("f", 1),
+                ("g", 2),
+                ("f:if@4", 5),
+                ("f:else@4", 7),
+                ("C", 9),
+                ("m", 11),
+            ]
+        );
+
+        // Checksum-only parsing follows the same order
+        let checksums: Vec<i32> = blocks.iter().map(|b| b.checksum).collect();
+        assert_eq!(
+            parse_checksums_with_options(source, &options).unwrap(),
+            checksums
+        );
+    }
+
+    #[test]
+    fn test_branch_granularity_blocks() {
+        let source = "def f(x):\n    if x:\n        return 1\n    else:\n        if x is None:\n            return 2\n        return 3\n\nclass C:\n    def m(self):\n        for i in range(3):\n            if i:\n                pass\n";
+        let options = ParseOptions {
+            granularity: Granularity::Branches,
+            ..ParseOptions::default()
+        };
+        let blocks = parse_module_with_options(source, &options).unwrap();
+        let spans: Vec<(&str, usize, usize)> = blocks
+            .iter()
+            .filter(|b| b.block_type == "branch")
+            .map(|b| (b.name.as_str(), b.start_line, b.end_line))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("f:if@2", 3, 3),
+                ("f:else@2", 5, 7),
+                ("f:if@5", 6, 6),
+                ("m:if@12", 13, 13),
+            ]
+        );
+
+        let checksum = |src: &str, name: &str| {
+            parse_module_with_options(src, &options)
+                .unwrap()
+                .into_iter()
+                .find(|b| b.name == name)
+                .unwrap()
+                .checksum
+        };
+        // Editing a nested branch only changes that branch's checksum
+        let edited = source.replace("return 2", "return 20");
+        assert_eq!(checksum(source, "f"), checksum(&edited, "f"));
+        assert_eq!(checksum(source, "f:if@2"), checksum(&edited, "f:if@2"));
+        assert_eq!(checksum(source, "f:else@2"), checksum(&edited, "f:else@2"));
+        assert_ne!(checksum(source, "f:if@5"), checksum(&edited, "f:if@5"));
+        // Editing a condition changes the enclosing block
+        let edited = source.replace("if x:", "if not x:");
+        assert_ne!(checksum(source, "f"), checksum(&edited, "f"));
+
+        // Definitions granularity is unchanged
+        let default_blocks = parse_module_internal(source).unwrap();
+        assert!(default_blocks.iter().all(|b| b.block_type != "branch"));
+    }
+
+    #[test]
+    fn test_branches_and_parts_start_at_decorators() {
+        let source = "def f(x):\n    if x:\n        @wraps(x)\n        def inner():\n            return 1\n        return inner\n    return None\n";
+        let options = ParseOptions {
+            granularity: Granularity::Branches,
+            ..ParseOptions::default()
+        };
+        let blocks = parse_module_with_options(source, &options).unwrap();
+        let span = |blocks: &[Block], name: &str| {
+            let block = blocks.iter().find(|b| b.name == name).unwrap();
+            (block.start_line, block.end_line)
+        };
+        assert_eq!(span(&blocks, "inner"), (3, 5));
+        assert_eq!(span(&blocks, "f:if@2"), (3, 6));
+        assert!(overlapping_blocks(&blocks).is_empty());
+
+        // A part opening with a decorated definition starts at the decorator
+        let source = "def f():\n    a = 1\n    b = 2\n    @wraps(f)\n    def inner():\n        return a\n    return inner\n";
+        let options = ParseOptions {
+            max_function_lines: Some(2),
+            ..ParseOptions::default()
+        };
+        let blocks = parse_module_with_options(source, &options).unwrap();
+        assert_eq!(span(&blocks, "inner"), (4, 6));
+        assert_eq!(span(&blocks, "f:part@4"), (4, 6));
+        assert!(overlapping_blocks(&blocks).is_empty());
+    }
+
+    #[test]
+    fn test_granularity_from_str() {
+        assert_eq!(
+            "branches".parse::<Granularity>().unwrap(),
+            Granularity::Branches
+        );
+        assert_eq!(
+            "definitions".parse::<Granularity>().unwrap(),
+            Granularity::Definitions
+        );
+        assert!("lines".parse::<Granularity>().is_err());
+    }
+
     #[test]
     fn test_multiline_signature_with_comment_colon() {
         // A multi-line signature where an intermediate line has a trailing
@@ -628,6 +2373,310 @@ def foo(
         assert_eq!(func.body_start_line, 3);
     }
 
+    #[test]
+    fn test_parse_snippet_bare_function() {
+        let source = "def foo(x):\n    return x + 1\n";
+        let blocks = parse_snippet_internal(source).unwrap();
+
+        // No <module> framing, just the function
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].name, "foo");
+        assert_eq!(blocks[0].block_type, "function");
+        assert_eq!(blocks[0].start_line, 1);
+        assert_eq!(blocks[0].end_line, 2);
+    }
+
+    #[test]
+    fn test_parse_snippet_indented_method() {
+        // A method pasted out of a class body keeps its indentation
+        let source = "    def method(self):\n        return 1\n";
+        let blocks = parse_snippet_internal(source).unwrap();
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].name, "method");
+        assert_eq!(blocks[0].body_start_line, 2);
+    }
+
+    #[test]
+    fn test_dedent_strips_only_an_exact_common_prefix() {
+        // Tab-indented lines share the tab, spaces after it are kept
+        assert_eq!(
+            dedent("\tdef f():\n\t    return 1\n"),
+            "def f():\n    return 1\n"
+        );
+        // A tab and four spaces have no common prefix: left alone
+        assert_eq!(dedent("\tx = 1\n    y = 2\n"), "\tx = 1\n    y = 2\n");
+        assert_eq!(dedent("  \tx = 1\n    y = 2"), "\tx = 1\n  y = 2");
+        // Blank lines shorter than the margin are kept as they are
+        assert_eq!(dedent("    a\n  \n\n    b\n"), "a\n  \n\nb\n");
+    }
+
+    #[test]
+    fn test_dedent_keeps_multiline_string_contents() {
+        // The string's last lines share no indentation with the rest
+        let source = "    def f():\n        s = '''\n  shallow\n'''\n        return s\n";
+        assert_eq!(dedent(source), source);
+
+        let source = "    def f():\n        s = '''\n          deep\n    '''\n        return s\n";
+        assert_eq!(
+            dedent(source),
+            "def f():\n    s = '''\n      deep\n'''\n    return s\n"
+        );
+        let blocks = parse_snippet_internal(source).unwrap();
+        assert_eq!(blocks[0].name, "f");
+        assert_eq!(blocks[0].end_line, 5);
+    }
+
+    #[test]
+    fn test_empty_and_whitespace_only_modules() {
+        for source in ["", "\n", "   \n\t\n\n", "# just a comment\n"] {
+            let blocks = parse_module_internal(source).unwrap();
+            assert_eq!(blocks.len(), 1, "{:?}", source);
+            assert_eq!(blocks[0].name, "<module>");
+            assert_eq!(blocks[0].start_line, 1);
+            assert!(blocks[0].end_line >= 1);
+            assert_eq!(blocks[0].checksum, empty_module_checksum());
+        }
+        assert_ne!(empty_module_checksum(), 0);
+
+        // The first real code changes the module checksum or adds a block
+        let empty = parse_module_internal("").unwrap();
+        for source in ["x = 1\n", "def f():\n    pass\n", "\n\nimport os\n"] {
+            let blocks = parse_module_internal(source).unwrap();
+            assert_ne!(blocks[0].checksum, empty[0].checksum, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_module_end_line_without_trailing_newline() {
+        let source = "import os\n\nx = os.sep";
+        let blocks = parse_module_internal(source).unwrap();
+        assert_eq!(blocks[0].name, "<module>");
+        assert_eq!(blocks[0].end_line, 3);
+
+        // Lone CR line endings are counted the same way the parser counts them
+        let blocks = parse_module_internal("a = 1\rb = 2\rc = 3").unwrap();
+        assert_eq!(blocks[0].end_line, 3);
+    }
+
+    #[test]
+    fn test_line_index_line_count() {
+        assert_eq!(LineIndex::new("").line_count(), 0);
+        assert_eq!(LineIndex::new("x").line_count(), 1);
+        assert_eq!(LineIndex::new("x\n").line_count(), 1);
+        assert_eq!(LineIndex::new("x\ny").line_count(), 2);
+        assert_eq!(LineIndex::new("x\r\ny\r\n").line_count(), 2);
+        assert_eq!(LineIndex::new("x\n\n").line_count(), 2);
+    }
+
+    #[test]
+    fn test_line_index_matches_brute_force() {
+        let source = "import os\r\n\n@deco\ndef a():\n    return 1\n\nclass B:\n    def m(self):\n        pass\n\nx = 1";
+        let index = LineIndex::new(source);
+
+        // Offsets: line = 1 + number of line terminators before the offset
+        for offset in 0..source.len() {
+            let expected = 1 + source[..offset].matches('\n').count();
+            assert_eq!(index.line_of(offset), expected, "offset {}", offset);
+        }
+
+        // Block ranges: each def/class starts on the line brute-force search finds,
+        // and its text matches a fresh `str::lines()` slice
+        let brute_lines: Vec<&str> = source.lines().collect();
+        let blocks = parse_module_internal(source).unwrap();
+        assert_eq!(blocks[0].end_line, brute_lines.len());
+        for block in blocks.iter().skip(1) {
+            let keyword = if block.block_type == "class" {
+                "class"
+            } else {
+                "def"
+            };
+            let def_line = brute_lines
+                .iter()
+                .position(|l| {
+                    l.trim_start()
+                        .starts_with(&format!("{} {}", keyword, block.name))
+                })
+                .unwrap()
+                + 1;
+            assert!(block.start_line <= def_line && def_line <= block.end_line);
+            assert_eq!(
+                extract_source_lines(&index, block.start_line, block.end_line).unwrap(),
+                brute_lines[block.start_line - 1..block.end_line].join("\n")
+            );
+        }
+    }
+
+    #[test]
+    fn test_ignore_docstrings_normalization() {
+        let with_doc = "\"\"\"Module doc.\"\"\"\nimport os\n\nclass A:\n    \"\"\"Class doc.\"\"\"\n\n    def m(self):\n        \"\"\"Method doc\n        over two lines.\"\"\"\n        return 1\n";
+        let other_doc = "\"\"\"Reworded.\"\"\"\nimport os\n\nclass A:\n    \"\"\"Also reworded.\"\"\"\n\n    def m(self):\n        \"\"\"New.\"\"\"\n        return 1\n";
+
+        let checksums = |source: &str, normalization: Normalization| -> Vec<i32> {
+            let options = ParseOptions {
+                normalization,
+                ..ParseOptions::default()
+            };
+            parse_module_with_options(source, &options)
+                .unwrap()
+                .iter()
+                .map(|b| b.checksum)
+                .collect()
+        };
+
+        // Exact mode sees every docstring edit
+        assert_ne!(
+            checksums(with_doc, Normalization::Exact),
+            checksums(other_doc, Normalization::Exact)
+        );
+        // ignore_docstrings does not
+        assert_eq!(
+            checksums(with_doc, Normalization::IgnoreDocstrings),
+            checksums(other_doc, Normalization::IgnoreDocstrings)
+        );
+
+        // Code changes are still detected
+        let code_change = other_doc.replace("return 1", "return 2");
+        assert_ne!(
+            checksums(other_doc, Normalization::IgnoreDocstrings),
+            checksums(&code_change, Normalization::IgnoreDocstrings)
+        );
+    }
+
+    #[test]
+    fn test_ignore_blank_lines_normalization() {
+        let source = "import os\nCONFIG = {\n    \"a\": 1,\n}\ndef f():\n    x = 1\n    return x\ndef g():\n    return \"\"\"a\nb\"\"\"\n";
+        let spaced = "import os\n\n\nCONFIG = {\n\n    \"a\": 1,\n}\n\n\ndef f():\n    x = 1\n\n    return x\n\n\n\ndef g():\n    return \"\"\"a\nb\"\"\"\n";
+        let blocks = |source: &str, normalization: Normalization| -> Vec<Block> {
+            let options = ParseOptions {
+                normalization,
+                ..ParseOptions::default()
+            };
+            parse_module_with_options(source, &options).unwrap()
+        };
+        let checksums = |source: &str, normalization: Normalization| -> Vec<i32> {
+            blocks(source, normalization)
+                .iter()
+                .map(|b| b.checksum)
+                .collect()
+        };
+
+        // Exact mode sees the blank lines inside the module-level dict and f
+        let exact = checksums(source, Normalization::Exact);
+        let exact_spaced = checksums(spaced, Normalization::Exact);
+        assert_ne!(exact[0], exact_spaced[0]);
+        assert_ne!(exact[1], exact_spaced[1]);
+
+        // ignore_blank_lines leaves the module and function checksums alone
+        assert_eq!(
+            checksums(source, Normalization::IgnoreBlankLines),
+            checksums(spaced, Normalization::IgnoreBlankLines)
+        );
+
+        // ...while block line ranges still match the spaced source
+        let f = blocks(spaced, Normalization::IgnoreBlankLines)
+            .into_iter()
+            .find(|b| b.name == "f")
+            .unwrap();
+        assert_eq!((f.start_line, f.end_line), (10, 13));
+
+        // Blank lines inside string literals are content
+        let in_string = source.replace("a\nb", "a\n\nb");
+        assert_ne!(
+            checksums(source, Normalization::IgnoreBlankLines),
+            checksums(&in_string, Normalization::IgnoreBlankLines)
+        );
+    }
+
+    #[test]
+    fn test_raw_marker_forces_exact_checksums() {
+        let marked = "#!/usr/bin/env python\n# pytest-diff: raw\n\ndef f():\n    \"\"\"Doc.\"\"\"\n    return 1\n";
+        let checksums = |source: &str, normalization: Normalization| -> Vec<i32> {
+            let options = ParseOptions {
+                normalization,
+                ..ParseOptions::default()
+            };
+            parse_module_with_options(source, &options)
+                .unwrap()
+                .iter()
+                .map(|b| b.checksum)
+                .collect()
+        };
+
+        assert_eq!(
+            checksums(marked, Normalization::IgnoreDocstrings),
+            checksums(marked, Normalization::Exact)
+        );
+        let reworded = marked.replace("Doc.", "Other.");
+        assert_ne!(
+            checksums(marked, Normalization::IgnoreDocstrings),
+            checksums(&reworded, Normalization::IgnoreDocstrings)
+        );
+
+        // The marker only counts in the header, before any code
+        let late = "x = 1\n# pytest-diff: raw\n";
+        assert_eq!(
+            Normalization::IgnoreDocstrings.for_source(late),
+            Normalization::IgnoreDocstrings
+        );
+        assert_eq!(
+            Normalization::IgnoreDocstrings.for_source("#pytest-diff: raw\nx = 1\n"),
+            Normalization::Exact
+        );
+    }
+
+    #[test]
+    fn test_normalization_from_str() {
+        assert_eq!(
+            "exact".parse::<Normalization>().unwrap(),
+            Normalization::Exact
+        );
+        assert_eq!(
+            "ignore_docstrings".parse::<Normalization>().unwrap(),
+            Normalization::IgnoreDocstrings
+        );
+        assert_eq!(
+            "ignore_blank_lines".parse::<Normalization>().unwrap(),
+            Normalization::IgnoreBlankLines
+        );
+        assert!("bogus".parse::<Normalization>().is_err());
+    }
+
+    #[test]
+    fn test_streaming_checksums_match_blocks() {
+        // Large synthetic module covering every block kind
+        let mut source = String::from("\"\"\"Generated.\"\"\"\nimport os\n\n");
+        for i in 0..300 {
+            source.push_str(&format!(
+                "@decorator\ndef f{i}(x):\n    \"\"\"Doc {i}.\"\"\"\n    if x:\n        return {i}\n    else:\n        return -{i}\n\n\
+                 class C{i}:\n    async def m(self):\n        return {i}\n\n"
+            ));
+        }
+
+        for normalization in [
+            Normalization::Exact,
+            Normalization::IgnoreDocstrings,
+            Normalization::IgnoreBlankLines,
+        ] {
+            for granularity in [Granularity::Definitions, Granularity::Branches] {
+                let options = ParseOptions {
+                    normalization,
+                    granularity,
+                    max_block_depth: None,
+                    lambda_blocks: false,
+                    max_function_lines: None,
+                    ignored_names: IgnoredNames::default(),
+                };
+                let blocks = parse_module_with_options(&source, &options).unwrap();
+                let expected: Vec<i32> = blocks.iter().map(|b| b.checksum).collect();
+                assert_eq!(
+                    parse_checksums_with_options(&source, &options).unwrap(),
+                    expected
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_parse_invalid_syntax() {
         let source = "def foo(";
@@ -83,3 +83,16 @@ CREATE TABLE IF NOT EXISTS baseline_fp (
 
 CREATE INDEX IF NOT EXISTS ix_baseline_fp_filename
     ON baseline_fp(filename);
+
+-- Named baselines (e.g. one per branch), alongside the default baseline above
+-- The default baseline stays in baseline_fp; every other name lives here
+CREATE TABLE IF NOT EXISTS named_baseline_fp (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    baseline_name TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    method_checksums BLOB NOT NULL,
+    mtime FLOAT NOT NULL,
+    fsha TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(baseline_name, filename)  -- One baseline per file per name
+);
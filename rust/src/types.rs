@@ -80,6 +80,25 @@ impl Block {
     }
 }
 
+impl Block {
+    /// Lines of the block's body: past its decorators and `def`/`class`
+    /// signature, which Python executes at import time. Only executed body
+    /// lines show that a test executed the block.
+    pub(crate) fn body_lines(&self) -> std::ops::RangeInclusive<usize> {
+        self.body_start_line..=self.end_line
+    }
+}
+
+/// Version of the fingerprint format (checksum algorithm and encoding)
+///
+/// Bump it whenever stored checksums stop being comparable with freshly
+/// computed ones, so cached fingerprints of the old format aren't served.
+/// Databases saved before the format was recorded are format 0.
+///
+/// * 1 - first recorded format
+/// * 2 - modules without code get a dedicated checksum instead of 0
+pub const FINGERPRINT_FORMAT: u32 = 2;
+
 /// File fingerprint containing block checksums and metadata
 ///
 /// This represents the "signature" of a file at a point in time,
@@ -128,12 +147,27 @@ impl Fingerprint {
         }
     }
 
+    /// Stable identity of the file content, usable as a cross-machine cache key
+    ///
+    /// Hashes `file_hash` and `checksums` only, so fingerprints of identical
+    /// content share an id regardless of `mtime` or `filename`.
+    pub fn content_id(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&(self.file_hash.len() as u64).to_le_bytes());
+        hasher.update(self.file_hash.as_bytes());
+        for checksum in &self.checksums {
+            hasher.update(&checksum.to_le_bytes());
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+
     fn __repr__(&self) -> String {
         format!(
             "Fingerprint(file='{}', blocks={}, hash={}..)",
             self.filename,
             self.checksums.len(),
-            &self.file_hash[..8]
+            // Degraded or hand-built fingerprints may carry a short/empty hash
+            self.file_hash.chars().take(8).collect::<String>()
         )
     }
 }
@@ -151,15 +185,78 @@ pub struct ChangedFiles {
     /// Map of filename -> list of changed block checksums
     #[pyo3(get)]
     pub changed_blocks: HashMap<String, Vec<i32>>,
+
+    /// Scope paths that don't exist on disk (they matched no test files)
+    #[pyo3(get)]
+    pub missing_scope_paths: Vec<String>,
+
+    /// Paths skipped because they vanished or became unreadable during
+    /// discovery (e.g. temp directories removed by concurrently running tests)
+    #[pyo3(get)]
+    pub skipped_paths: Vec<String>,
+
+    /// Files whose bytes don't match their declared encoding (not fingerprinted)
+    #[pyo3(get)]
+    pub encoding_mismatches: Vec<String>,
+
+    /// Map of examined filename -> detection level that classified it ("new",
+    /// "aggregate_unchanged", "mtime_unchanged", "hash_unchanged",
+    /// "checksums_unchanged" or "checksums_changed"). Only filled when
+    /// requested with `explain=True`.
+    #[pyo3(get)]
+    pub decisions: HashMap<String, String>,
+
+    /// Whether the baseline was empty, in which case every file is reported
+    /// as new (typically a fresh project or the wrong database path)
+    #[pyo3(get)]
+    pub baseline_empty: bool,
+
+    /// Flat `(filename, block_name, change_kind)` list of the named changes,
+    /// for human-readable reports. `change_kind` is "added" (new file) or
+    /// "modified"; removed blocks only appear in `changed_blocks`, since the
+    /// baseline doesn't keep their names.
+    #[pyo3(get)]
+    pub block_changes: Vec<(String, String, String)>,
+
+    /// Number of entries of `block_changes` per block type ("module",
+    /// "class", "function", "branch", "file" or "native"), so callers can
+    /// weigh broad changes such as module-level edits heavier
+    #[pyo3(get)]
+    pub changed_block_types: HashMap<String, usize>,
+
+    /// `changed_block_types` per file, so `union` can merge the counts of a
+    /// file both sides examined instead of adding them up (empty for results
+    /// built from Python)
+    pub(crate) block_types_by_file: HashMap<String, HashMap<String, usize>>,
 }
 
 #[pymethods]
 impl ChangedFiles {
     #[new]
-    fn new(modified: Vec<String>, changed_blocks: HashMap<String, Vec<i32>>) -> Self {
+    #[pyo3(signature = (modified, changed_blocks, missing_scope_paths=Vec::new(), encoding_mismatches=Vec::new(), decisions=HashMap::new(), baseline_empty=false, block_changes=Vec::new(), changed_block_types=HashMap::new(), skipped_paths=Vec::new()))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        modified: Vec<String>,
+        changed_blocks: HashMap<String, Vec<i32>>,
+        missing_scope_paths: Vec<String>,
+        encoding_mismatches: Vec<String>,
+        decisions: HashMap<String, String>,
+        baseline_empty: bool,
+        block_changes: Vec<(String, String, String)>,
+        changed_block_types: HashMap<String, usize>,
+        skipped_paths: Vec<String>,
+    ) -> Self {
         Self {
             modified,
             changed_blocks,
+            missing_scope_paths,
+            skipped_paths,
+            encoding_mismatches,
+            decisions,
+            baseline_empty,
+            block_changes,
+            changed_block_types,
+            block_types_by_file: HashMap::new(),
         }
     }
 
@@ -180,6 +277,295 @@ impl ChangedFiles {
     fn total_changed_blocks(&self) -> usize {
         self.changed_blocks.values().map(|v| v.len()).sum()
     }
+
+    /// Combine with the result of another detection scope (see `union`)
+    fn merge(&self, other: PyRef<'_, ChangedFiles>) -> ChangedFiles {
+        self.union(&other)
+    }
+}
+
+impl ChangedFiles {
+    /// Combine two detection results, e.g. of separate test scopes
+    ///
+    /// Order-independent and lossless for selection: list fields become sorted
+    /// unions, and each file's changed checksums are the multiset union of
+    /// both sides (a checksum appears as often as on the side listing it
+    /// most). A file examined by both sides keeps the decision that went
+    /// furthest. `block_changes` and `changed_block_types` are merged per file
+    /// the same way, so a file both sides report counts once while
+    /// same-named blocks of one file are all kept. Results built from Python
+    /// carry no per-file type counts; theirs are added up.
+    pub fn union(&self, other: &ChangedFiles) -> ChangedFiles {
+        let no_blocks = Vec::new();
+        let changed_blocks = self
+            .changed_blocks
+            .keys()
+            .chain(other.changed_blocks.keys())
+            .map(|file| {
+                let ours = self.changed_blocks.get(file).unwrap_or(&no_blocks);
+                let theirs = other.changed_blocks.get(file).unwrap_or(&no_blocks);
+                (file.clone(), multiset_union(ours, theirs))
+            })
+            .collect();
+
+        let mut decisions = self.decisions.clone();
+        for (file, theirs) in &other.decisions {
+            let decision = decisions
+                .entry(file.clone())
+                .or_insert_with(|| theirs.clone());
+            if decision_rank(theirs) > decision_rank(decision) {
+                *decision = theirs.clone();
+            }
+        }
+
+        let mut block_types_by_file = self.block_types_by_file.clone();
+        for (file, theirs) in &other.block_types_by_file {
+            let types = block_types_by_file.entry(file.clone()).or_default();
+            for (block_type, &count) in theirs {
+                let entry = types.entry(block_type.clone()).or_default();
+                *entry = (*entry).max(count);
+            }
+        }
+        let mut changed_block_types: HashMap<String, usize> = HashMap::new();
+        let unattributed = [self, other]
+            .into_iter()
+            .filter(|side| side.block_types_by_file.is_empty())
+            .map(|side| &side.changed_block_types);
+        for types in block_types_by_file.values().chain(unattributed) {
+            for (block_type, &count) in types {
+                *changed_block_types.entry(block_type.clone()).or_default() += count;
+            }
+        }
+
+        ChangedFiles {
+            modified: sorted_union(&self.modified, &other.modified),
+            changed_blocks,
+            missing_scope_paths: sorted_union(
+                &self.missing_scope_paths,
+                &other.missing_scope_paths,
+            ),
+            skipped_paths: sorted_union(&self.skipped_paths, &other.skipped_paths),
+            encoding_mismatches: sorted_union(
+                &self.encoding_mismatches,
+                &other.encoding_mismatches,
+            ),
+            decisions,
+            baseline_empty: self.baseline_empty || other.baseline_empty,
+            block_changes: multiset_union(&self.block_changes, &other.block_changes),
+            changed_block_types,
+            block_types_by_file,
+        }
+    }
+}
+
+/// Sorted multiset union: each item as often as the side listing it most
+fn multiset_union<T: Clone + Ord>(a: &[T], b: &[T]) -> Vec<T> {
+    let mut counts: std::collections::BTreeMap<&T, (usize, usize)> = Default::default();
+    for item in a {
+        counts.entry(item).or_default().0 += 1;
+    }
+    for item in b {
+        counts.entry(item).or_default().1 += 1;
+    }
+    counts
+        .into_iter()
+        .flat_map(|(item, (x, y))| std::iter::repeat_n(item.clone(), x.max(y)))
+        .collect()
+}
+
+/// Sorted, deduplicated union of two lists
+fn sorted_union<T: Clone + Ord>(a: &[T], b: &[T]) -> Vec<T> {
+    let set: std::collections::BTreeSet<&T> = a.iter().chain(b).collect();
+    set.into_iter().cloned().collect()
+}
+
+/// How far detection went for a file, to pick between conflicting decisions
+fn decision_rank(decision: &str) -> (usize, &str) {
+    let level = match decision {
+        "aggregate_unchanged" => 0,
+        "mtime_unchanged" => 1,
+        "hash_unchanged" => 2,
+        "checksums_unchanged" => 3,
+        "checksums_changed" => 4,
+        "new" => 5,
+        _ => 6,
+    };
+    // Unknown decisions tie-break by name so the merge stays order-independent
+    (level, decision)
+}
+
+/// Result of change detection with scan metadata
+///
+/// Returned by `detect_changes(..., with_metadata=True)`, so callers can
+/// report how much work detection did without separate queries.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct DetectionResult {
+    /// The detected changes
+    #[pyo3(get)]
+    pub changes: ChangedFiles,
+
+    /// Tracked files found in the project and compared with the baseline
+    #[pyo3(get)]
+    pub files_scanned: usize,
+
+    /// Files recorded in the baseline compared against
+    #[pyo3(get)]
+    pub baseline_count: usize,
+
+    /// Wall-clock detection time in seconds
+    #[pyo3(get)]
+    pub elapsed: f64,
+}
+
+#[pymethods]
+impl DetectionResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "DetectionResult(modified={} files, scanned={} files, baseline={} files, elapsed={:.3}s)",
+            self.changes.modified.len(),
+            self.files_scanned,
+            self.baseline_count,
+            self.elapsed
+        )
+    }
+}
+
+/// Outcome of a baseline save
+#[pyclass]
+#[derive(Clone, Debug, Default)]
+pub struct SaveResult {
+    /// Files in the baseline after the save (unchanged + updated)
+    #[pyo3(get)]
+    pub count: usize,
+
+    /// Files that changed while being fingerprinted (see `set_verify_reads`),
+    /// sorted; they keep their previous baseline entry, if any
+    #[pyo3(get)]
+    pub unstable: Vec<String>,
+}
+
+#[pymethods]
+impl SaveResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "SaveResult(count={}, unstable={})",
+            self.count,
+            self.unstable.len()
+        )
+    }
+}
+
+/// Outcome of `PytestDiffDatabase.renormalize_baseline`
+#[pyclass]
+#[derive(Clone, Debug, Default)]
+pub struct RenormalizeResult {
+    /// Baseline entries recomputed under the new mode
+    #[pyo3(get)]
+    pub count: usize,
+
+    /// Baseline files left untouched because they changed since the baseline,
+    /// are missing or no longer parse, sorted; they report as modified until
+    /// saved again
+    #[pyo3(get)]
+    pub skipped: Vec<String>,
+}
+
+#[pymethods]
+impl RenormalizeResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "RenormalizeResult(count={}, skipped={})",
+            self.count,
+            self.skipped.len()
+        )
+    }
+}
+
+/// Partition of candidate tests by what a run would do with them
+///
+/// Each candidate lands in exactly one list, in candidate order.
+#[pyclass]
+#[derive(Clone, Debug, Default)]
+pub struct SelectionResult {
+    /// Recorded tests that depend on a changed block
+    #[pyo3(get)]
+    pub selected: Vec<String>,
+
+    /// Recorded tests unaffected by the changes
+    #[pyo3(get)]
+    pub skipped: Vec<String>,
+
+    /// Tests run regardless of dependencies: those living in a modified file,
+    /// or every recorded test when there is no baseline
+    #[pyo3(get)]
+    pub forced: Vec<String>,
+
+    /// Tests without a recorded execution (new or previously failed), which always run
+    #[pyo3(get)]
+    pub unknown: Vec<String>,
+}
+
+#[pymethods]
+impl SelectionResult {
+    /// All tests that would run: selected, forced and unknown
+    fn to_run(&self) -> Vec<String> {
+        let mut tests = self.selected.clone();
+        tests.extend(self.forced.iter().cloned());
+        tests.extend(self.unknown.iter().cloned());
+        tests
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "SelectionResult(selected={}, skipped={}, forced={}, unknown={})",
+            self.selected.len(),
+            self.skipped.len(),
+            self.forced.len(),
+            self.unknown.len()
+        )
+    }
+}
+
+/// Block-level differences of one file between two trees (see `diff_trees`)
+///
+/// Blocks are named by their enclosing definitions (`Class.method`); branch
+/// and part blocks keep their own names under the class (`Class.f:if@3`).
+#[pyclass]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChangedBlocks {
+    /// Blocks only in the new tree, in new source order
+    #[pyo3(get)]
+    pub added: Vec<String>,
+
+    /// Blocks only in the old tree, in old source order
+    #[pyo3(get)]
+    pub removed: Vec<String>,
+
+    /// Blocks in both trees whose checksum differs
+    #[pyo3(get)]
+    pub modified: Vec<String>,
+
+    /// Unchanged blocks reordered among their siblings
+    #[pyo3(get)]
+    pub moved: Vec<String>,
+}
+
+#[pymethods]
+impl ChangedBlocks {
+    pub fn has_changes(&self) -> bool {
+        !(self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.moved.is_empty())
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "ChangedBlocks(added={:?}, removed={:?}, modified={:?}, moved={:?})",
+            self.added, self.removed, self.modified, self.moved
+        )
+    }
 }
 
 /// Test execution record
@@ -278,6 +664,43 @@ mod tests {
         assert_eq!(block.body_start_line, 3);
     }
 
+    #[test]
+    fn test_fingerprint_repr_with_short_hash() {
+        let fp = Fingerprint::new("a.py".to_string(), vec![1], "abc".to_string(), 0.0, None);
+        assert_eq!(
+            fp.__repr__(),
+            "Fingerprint(file='a.py', blocks=1, hash=abc..)"
+        );
+
+        let fp = Fingerprint::new("a.py".to_string(), vec![], String::new(), 0.0, None);
+        assert_eq!(fp.__repr__(), "Fingerprint(file='a.py', blocks=0, hash=..)");
+
+        let fp = Fingerprint::new(
+            "a.py".to_string(),
+            vec![],
+            "0123456789".to_string(),
+            0.0,
+            None,
+        );
+        assert_eq!(
+            fp.__repr__(),
+            "Fingerprint(file='a.py', blocks=0, hash=01234567..)"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_content_id_ignores_mtime_and_filename() {
+        let fp = Fingerprint::new("a.py".to_string(), vec![1, 2], "abc".to_string(), 1.0, None);
+        let moved = Fingerprint::new("b.py".to_string(), vec![1, 2], "abc".to_string(), 2.0, None);
+        assert_eq!(fp.content_id(), moved.content_id());
+
+        let edited = Fingerprint::new("a.py".to_string(), vec![1, 3], "abc".to_string(), 1.0, None);
+        assert_ne!(fp.content_id(), edited.content_id());
+        let rehashed =
+            Fingerprint::new("a.py".to_string(), vec![1, 2], "abd".to_string(), 1.0, None);
+        assert_ne!(fp.content_id(), rehashed.content_id());
+    }
+
     #[test]
     fn test_changed_files_has_changes() {
         let changed = ChangedFiles::new(
@@ -285,15 +708,103 @@ mod tests {
             [(String::from("file1.py"), vec![123, 456])]
                 .into_iter()
                 .collect(),
+            vec![],
+            vec![],
+            HashMap::new(),
+            false,
+            vec![],
+            HashMap::new(),
+            vec![],
         );
 
         assert!(changed.has_changes());
         assert_eq!(changed.total_changed_blocks(), 2);
     }
 
+    #[test]
+    fn test_changed_files_union_of_overlapping_scopes() {
+        let changed =
+            |modified: &[&str], blocks: &[(&str, Vec<i32>)], decisions: &[(&str, &str)]| {
+                ChangedFiles {
+                    modified: modified.iter().map(|f| f.to_string()).collect(),
+                    changed_blocks: blocks
+                        .iter()
+                        .map(|(file, checksums)| (file.to_string(), checksums.clone()))
+                        .collect(),
+                    missing_scope_paths: vec![],
+                    skipped_paths: vec![],
+                    encoding_mismatches: vec![],
+                    decisions: decisions
+                        .iter()
+                        .map(|(file, decision)| (file.to_string(), decision.to_string()))
+                        .collect(),
+                    baseline_empty: false,
+                    block_changes: modified
+                        .iter()
+                        .map(|f| (f.to_string(), "f".to_string(), "modified".to_string()))
+                        .collect(),
+                    changed_block_types: [("function".to_string(), modified.len())].into(),
+                    block_types_by_file: modified
+                        .iter()
+                        .map(|f| (f.to_string(), [("function".to_string(), 1)].into()))
+                        .collect(),
+                }
+            };
+        let unit = changed(
+            &["src/a.py", "tests/unit/test_a.py"],
+            &[
+                ("src/a.py", vec![3, 1, 7, 7]),
+                ("tests/unit/test_a.py", vec![5]),
+            ],
+            &[
+                ("src/a.py", "checksums_changed"),
+                ("src/b.py", "mtime_unchanged"),
+            ],
+        );
+        let integration = changed(
+            &["src/a.py", "src/b.py"],
+            &[("src/a.py", vec![7, 2, 1]), ("src/b.py", vec![9])],
+            &[
+                ("src/a.py", "checksums_changed"),
+                ("src/b.py", "checksums_changed"),
+            ],
+        );
+
+        let merged = unit.union(&integration);
+        assert_eq!(
+            merged.modified,
+            vec!["src/a.py", "src/b.py", "tests/unit/test_a.py"]
+        );
+        assert_eq!(merged.changed_blocks["src/a.py"], vec![1, 2, 3, 7, 7]);
+        assert_eq!(merged.changed_blocks["src/b.py"], vec![9]);
+        assert_eq!(merged.changed_blocks["tests/unit/test_a.py"], vec![5]);
+        assert_eq!(merged.decisions["src/b.py"], "checksums_changed");
+        assert_eq!(merged.block_changes.len(), 3);
+        // src/a.py is reported by both sides but counted once
+        assert_eq!(merged.changed_block_types["function"], 3);
+
+        // Merging in the other order gives the same result
+        let reversed = integration.union(&unit);
+        assert_eq!(reversed.modified, merged.modified);
+        assert_eq!(reversed.changed_blocks, merged.changed_blocks);
+        assert_eq!(reversed.decisions, merged.decisions);
+        assert_eq!(reversed.block_changes, merged.block_changes);
+        assert_eq!(reversed.changed_block_types, merged.changed_block_types);
+    }
+
     #[test]
     fn test_changed_files_no_changes() {
-        let changed = ChangedFiles::new(vec![], HashMap::new());
+        let changed = ChangedFiles::new(
+            vec![],
+            HashMap::new(),
+            vec![],
+            vec![],
+            HashMap::new(),
+            false,
+            vec![],
+            HashMap::new(),
+            vec![],
+        );
 
         assert!(!changed.has_changes());
         assert_eq!(changed.total_changed_blocks(), 0);